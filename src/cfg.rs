@@ -0,0 +1,230 @@
+//! Basic blocks and control-flow graphs of Lua 5.1 functions.
+
+use crate::instruction::{Instruction, OpCode};
+use crate::undump::Function;
+use anyhow::bail;
+
+/// Why control passes along an `Edge`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// To the next instruction, which starts another block.
+    Fallthrough,
+    /// A JMP or FORPREP, or the skip of a LOADBOOL with C != 0.
+    Jump,
+    /// A comparison or TEST/TESTSET whose outcome matches its A (resp. C)
+    /// argument, so the following JMP runs.
+    True,
+    /// The outcome does not match, so the following JMP is skipped.
+    False,
+    /// Another iteration of a FORLOOP or TFORLOOP.
+    Loop,
+    /// The end of a FORLOOP or TFORLOOP.
+    LoopExit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Edge {
+    /// Index of the target block.
+    pub target: usize,
+    pub kind: EdgeKind,
+}
+
+/// A maximal run of code entered only at its start and left only at its end.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    /// Range of pcs covered, including the extra word of a SETLIST with
+    /// C == 0 and the pseudo-instructions following a CLOSURE.
+    pub start: usize,
+    pub end: usize,
+    /// Empty if the block ends with a RETURN or TAILCALL.
+    pub succs: Vec<Edge>,
+    /// Indices of the blocks with an edge to this one, in order.
+    pub preds: Vec<usize>,
+}
+
+/// The control-flow graph of one function, not including its children.
+/// Block 0 is the entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+}
+
+impl Cfg {
+    /// Index of the block containing `pc`.
+    pub fn block_of(&self, pc: usize) -> Option<usize> {
+        let i = self.blocks.partition_point(|b| b.end <= pc);
+        (i < self.blocks.len()).then_some(i)
+    }
+}
+
+/// Where control can go after an instruction, as pcs.
+enum Flow {
+    Next,
+    Branch(Vec<(i64, EdgeKind)>),
+    Exit,
+}
+
+fn flow(pc: usize, i: Instruction) -> Flow {
+    let pc = pc as i64;
+    let to = |sbx: i32| pc + 1 + i64::from(sbx);
+    match i.op {
+        OpCode::Jmp | OpCode::ForPrep => Flow::Branch(vec![(to(i.sbx()), EdgeKind::Jump)]),
+        OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::Test | OpCode::TestSet => {
+            Flow::Branch(vec![(pc + 1, EdgeKind::True), (pc + 2, EdgeKind::False)])
+        }
+        OpCode::ForLoop => Flow::Branch(vec![
+            (to(i.sbx()), EdgeKind::Loop),
+            (pc + 1, EdgeKind::LoopExit),
+        ]),
+        // The instruction after a TFORLOOP is a JMP back to the loop body.
+        OpCode::TForLoop => {
+            Flow::Branch(vec![(pc + 1, EdgeKind::Loop), (pc + 2, EdgeKind::LoopExit)])
+        }
+        OpCode::LoadBool if i.c != 0 => Flow::Branch(vec![(pc + 2, EdgeKind::Jump)]),
+        // The RETURN after a TAILCALL is only reached when the callee is a C
+        // function, so treat the call itself as leaving the function.
+        OpCode::Return | OpCode::TailCall => Flow::Exit,
+        _ => Flow::Next,
+    }
+}
+
+/// Splits `fun`'s code into basic blocks and links them.
+pub fn build(fun: &Function) -> anyhow::Result<Cfg> {
+    let code = &fun.code;
+    let mut flows = vec![];
+    let mut leaders = vec![false; code.len() + 1];
+    let mut is_insn = vec![false; code.len()];
+    leaders[0] = true;
+    let mut pc = 0;
+    while pc < code.len() {
+        let i = Instruction::decode(code[pc])?;
+        is_insn[pc] = true;
+        let next = if i.op == OpCode::SetList && i.c == 0 {
+            pc + 2
+        } else {
+            pc + 1
+        };
+        let flow = flow(pc, i);
+        match &flow {
+            Flow::Next => {}
+            Flow::Branch(targets) => {
+                for &(target, _) in targets {
+                    if !(0..code.len() as i64).contains(&target) {
+                        bail!("jump to {} out of range at pc {}", target, pc);
+                    }
+                    leaders[target as usize] = true;
+                }
+                leaders[next.min(code.len())] = true;
+            }
+            Flow::Exit => leaders[next.min(code.len())] = true,
+        }
+        flows.push((pc, next, flow));
+        pc = next;
+    }
+    let starts: Vec<usize> = (0..code.len()).filter(|&pc| leaders[pc]).collect();
+    if let Some(pc) = starts.iter().find(|&&pc| !is_insn[pc]) {
+        bail!("jump into SETLIST batch number at pc {}", pc);
+    }
+    let block_at = |pc: usize| starts.binary_search(&pc).unwrap();
+    let mut blocks: Vec<Block> = starts
+        .iter()
+        .enumerate()
+        .map(|(b, &start)| Block {
+            start,
+            end: starts.get(b + 1).copied().unwrap_or(code.len()),
+            succs: vec![],
+            preds: vec![],
+        })
+        .collect();
+    let mut flows = flows.into_iter().peekable();
+    for block in &mut blocks {
+        let mut last = None;
+        while let Some((pc, next, flow)) = flows.next_if(|&(pc, _, _)| pc < block.end) {
+            last = Some((pc, next, flow));
+        }
+        let Some((_, next, flow)) = last else {
+            continue;
+        };
+        block.succs = match flow {
+            Flow::Next if next < code.len() => vec![Edge {
+                target: block_at(next),
+                kind: EdgeKind::Fallthrough,
+            }],
+            Flow::Next | Flow::Exit => vec![],
+            Flow::Branch(targets) => targets
+                .into_iter()
+                .map(|(target, kind)| Edge {
+                    target: block_at(target as usize),
+                    kind,
+                })
+                .collect(),
+        };
+    }
+    for b in 0..blocks.len() {
+        for e in blocks[b].succs.clone() {
+            blocks[e.target].preds.push(b);
+        }
+    }
+    Ok(Cfg { blocks })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cfg::*;
+
+    #[test]
+    fn test() {
+        // local x, t = ...
+        // if x == 1 then x = 1 else x = 2 end
+        // for i = 1, 3 do x = x + i end
+        // for k in pairs(t) do x = k end
+        // local b = x > 2
+        // return f(x, b)
+        let fun = Function {
+            code: vec![
+                0x01800025, 0x00400017, 0x80004016, 0x00000001, 0x80000016, 0x00004001, 0x00000081,
+                0x000080c1, 0x00000101, 0x800000a0, 0x0001400c, 0x7fff409f, 0x0000c085, 0x008000c0,
+                0x0101009c, 0x80000016, 0x02800000, 0x000040a1, 0x7fff0016, 0x80800058, 0x80000016,
+                0x00004082, 0x00800082, 0x000100c5, 0x00000100, 0x01000140, 0x018000dd, 0x000000de,
+                0x0080001e,
+            ],
+            ..Default::default()
+        };
+        let cfg = build(&fun).unwrap();
+        use EdgeKind::*;
+        let summary: Vec<_> = cfg
+            .blocks
+            .iter()
+            .map(|b| {
+                let succs: Vec<_> = b.succs.iter().map(|e| (e.target, e.kind)).collect();
+                (b.start, b.end, succs)
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0, 2, vec![(1, True), (2, False)]),
+                (2, 3, vec![(3, Jump)]),
+                (3, 5, vec![(4, Jump)]),
+                (5, 6, vec![(4, Fallthrough)]),
+                (6, 10, vec![(6, Jump)]),
+                (10, 11, vec![(6, Fallthrough)]),
+                (11, 12, vec![(5, Loop), (7, LoopExit)]),
+                (12, 16, vec![(9, Jump)]),
+                (16, 17, vec![(9, Fallthrough)]),
+                (17, 18, vec![(10, Loop), (11, LoopExit)]),
+                (18, 19, vec![(8, Jump)]),
+                (19, 20, vec![(12, True), (13, False)]),
+                (20, 21, vec![(14, Jump)]),
+                (21, 22, vec![(15, Jump)]),
+                (22, 23, vec![(15, Fallthrough)]),
+                (23, 27, vec![]),
+                (27, 28, vec![]),
+                (28, 29, vec![]),
+            ]
+        );
+        assert_eq!(cfg.blocks[6].preds, vec![4, 5]);
+        assert_eq!(cfg.block_of(8), Some(4));
+        assert_eq!(cfg.block_of(29), None);
+    }
+}
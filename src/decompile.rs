@@ -0,0 +1,2249 @@
+//! Reconstruction of Lua 5.1 source from undumped functions.
+//!
+//! The decompiler follows the code patterns of the reference compiler:
+//! locals come from `locvars`, or `lifetime` for stripped functions, whose
+//! registers are assigned in order of activation, and everything else is a
+//! temporary consumed by the instruction that reads it. Control structures
+//! are recognized from their jumps, and conditions are rebuilt from chains
+//! of tests.
+
+use crate::disasm::{format_number, quote_string};
+use crate::instruction::{Instruction, OpCode, Rk};
+use crate::lifetime::{Names, restore};
+use crate::undump::{Constant, Function, LocVar, LuaString, VarargFlags};
+use crate::verify::verify;
+use anyhow::{Context as _, bail};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Concat,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+}
+
+impl BinOp {
+    fn token(self) -> &'static str {
+        match self {
+            BinOp::Or => "or",
+            BinOp::And => "and",
+            BinOp::Eq => "==",
+            BinOp::Ne => "~=",
+            BinOp::Lt => "<",
+            BinOp::Le => "<=",
+            BinOp::Gt => ">",
+            BinOp::Ge => ">=",
+            BinOp::Concat => "..",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Mod => "%",
+            BinOp::Pow => "^",
+        }
+    }
+    fn precedence(self) -> u8 {
+        match self {
+            BinOp::Or => 1,
+            BinOp::And => 2,
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => 3,
+            BinOp::Concat => 4,
+            BinOp::Add | BinOp::Sub => 5,
+            BinOp::Mul | BinOp::Div | BinOp::Mod => 6,
+            BinOp::Pow => 8,
+        }
+    }
+    fn is_right_assoc(self) -> bool {
+        matches!(self, BinOp::Concat | BinOp::Pow)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum UnOp {
+    Neg,
+    Not,
+    Len,
+}
+
+const UNARY_PRECEDENCE: u8 = 7;
+const ATOM_PRECEDENCE: u8 = 9;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    String(LuaString),
+    Vararg,
+    /// A local or upvalue.
+    Name(String),
+    Global(LuaString),
+    Index(Box<Expr>, Box<Expr>),
+    Call(Box<Expr>, Vec<Expr>),
+    /// `object:name(args)`; with no arguments yet while between SELF and
+    /// CALL.
+    Method(Box<Expr>, LuaString, Vec<Expr>),
+    Function(Box<Body>),
+    /// Array items have no key.
+    Table(Vec<(Option<Expr>, Expr)>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Unary(UnOp, Box<Expr>),
+    /// A call or `...` truncated to one value.
+    Paren(Box<Expr>),
+    /// Stands for a register holding a further value of the multi-valued
+    /// expression in the register below; never printed.
+    Extra,
+    /// A nil the compiler adds for a missing value of an assignment; never
+    /// printed.
+    Absent,
+}
+
+impl Expr {
+    fn binary(op: BinOp, l: Expr, r: Expr) -> Expr {
+        Expr::Binary(op, Box::new(l), Box::new(r))
+    }
+    fn not(self) -> Expr {
+        match self {
+            Expr::Binary(BinOp::Eq, l, r) => Expr::Binary(BinOp::Ne, l, r),
+            Expr::Binary(BinOp::Ne, l, r) => Expr::Binary(BinOp::Eq, l, r),
+            Expr::Unary(UnOp::Not, e) => *e,
+            e => Expr::Unary(UnOp::Not, Box::new(e)),
+        }
+    }
+    fn is_multi(&self) -> bool {
+        matches!(self, Expr::Call(..) | Expr::Method(..) | Expr::Vararg)
+    }
+    fn is_constant(&self) -> bool {
+        matches!(
+            self,
+            Expr::Nil | Expr::Boolean(_) | Expr::Number(_) | Expr::String(_)
+        )
+    }
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Binary(op, ..) => op.precedence(),
+            Expr::Unary(..) => UNARY_PRECEDENCE,
+            Expr::Number(n) if n.is_sign_negative() || !n.is_finite() => UNARY_PRECEDENCE,
+            _ => ATOM_PRECEDENCE,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Stat {
+    Local(Vec<String>, Vec<Expr>),
+    LocalFunction(String, Body),
+    Assign(Vec<Expr>, Vec<Expr>),
+    Call(Expr),
+    If(Vec<(Expr, Vec<Stat>)>, Option<Vec<Stat>>),
+    While(Expr, Vec<Stat>),
+    Repeat(Vec<Stat>, Expr),
+    NumericFor(String, Expr, Expr, Option<Expr>, Vec<Stat>),
+    GenericFor(Vec<String>, Vec<Expr>, Vec<Stat>),
+    Return(Vec<Expr>),
+    Break,
+    Do(Vec<Stat>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Body {
+    params: Vec<String>,
+    vararg: bool,
+    block: Vec<Stat>,
+}
+
+const KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "if", "in", "local",
+    "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+fn is_name(s: &[u8]) -> bool {
+    match s {
+        [first, rest @ ..] => {
+            (first.is_ascii_alphabetic() || *first == b'_')
+                && rest.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_')
+                && !KEYWORDS.iter().any(|k| k.as_bytes() == s)
+        }
+        [] => false,
+    }
+}
+
+fn format_lua_number(n: f64) -> String {
+    if n.is_nan() {
+        return "0/0".to_owned();
+    }
+    if n.is_infinite() {
+        return if n < 0.0 { "-1/0" } else { "1/0" }.to_owned();
+    }
+    let s = format_number(n);
+    if s.parse::<f64>() == Ok(n) {
+        return s;
+    }
+    // The shortest representation that reads back the same.
+    let s = n.to_string();
+    if s.len() <= 20 { s } else { format!("{:e}", n) }
+}
+
+struct Printer {
+    out: String,
+    indent: usize,
+    /// Where the last statement of the current block ends, if there is one.
+    last: Option<usize>,
+}
+
+impl Printer {
+    fn line(&mut self) {
+        self.out.push('\n');
+        for _ in 0..self.indent {
+            self.out.push('\t');
+        }
+    }
+    fn list(&mut self, exprs: &[Expr]) {
+        for (i, e) in exprs.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            self.expr(e, 0);
+        }
+    }
+    fn names(&mut self, names: &[String]) {
+        self.out.push_str(&names.join(", "));
+    }
+    /// Prints `e` parenthesized if its precedence is below `min`.
+    fn expr(&mut self, e: &Expr, min: u8) {
+        if e.precedence() < min {
+            self.out.push('(');
+            self.expr(e, 0);
+            self.out.push(')');
+            return;
+        }
+        match e {
+            Expr::Nil => self.out.push_str("nil"),
+            Expr::Boolean(b) => write!(self.out, "{}", b).unwrap(),
+            Expr::Number(n) => self.out.push_str(&format_lua_number(*n)),
+            Expr::String(s) => self.out.push_str(&quote_string(s.as_bytes())),
+            Expr::Vararg => self.out.push_str("..."),
+            Expr::Name(name) => self.out.push_str(name),
+            Expr::Global(name) if is_name(name.as_bytes()) => {
+                self.out.push_str(&name.to_str_lossy())
+            }
+            Expr::Global(name) => {
+                write!(self.out, "getfenv(1)[{}]", quote_string(name.as_bytes())).unwrap()
+            }
+            Expr::Index(t, k) => {
+                self.prefix(t);
+                match &**k {
+                    Expr::String(s) if is_name(s.as_bytes()) => {
+                        write!(self.out, ".{}", s.to_str_lossy()).unwrap()
+                    }
+                    k => {
+                        self.out.push('[');
+                        self.expr(k, 0);
+                        self.out.push(']');
+                    }
+                }
+            }
+            Expr::Call(f, args) => {
+                self.prefix(f);
+                self.out.push('(');
+                self.list(args);
+                self.out.push(')');
+            }
+            Expr::Method(object, name, args) => {
+                self.prefix(object);
+                write!(self.out, ":{}(", name.to_str_lossy()).unwrap();
+                self.list(args);
+                self.out.push(')');
+            }
+            Expr::Function(body) => {
+                self.out.push_str("function");
+                self.body(body, 0);
+            }
+            Expr::Table(items) => self.table(items),
+            Expr::Binary(op, l, r) => {
+                // Grouping does not change the code for `and` and `or`.
+                let p = op.precedence();
+                let (lp, rp) = match op {
+                    BinOp::Or | BinOp::And => (p, p),
+                    _ if op.is_right_assoc() => (p + 1, p),
+                    _ => (p, p + 1),
+                };
+                self.expr(l, lp);
+                write!(self.out, " {} ", op.token()).unwrap();
+                self.expr(r, rp);
+            }
+            Expr::Unary(op, e) => {
+                self.out.push_str(match op {
+                    UnOp::Neg => "-",
+                    UnOp::Not => "not ",
+                    UnOp::Len => "#",
+                });
+                // Keep `- -x` from becoming a comment.
+                if *op == UnOp::Neg && matches!(&**e, Expr::Unary(UnOp::Neg, _)) {
+                    self.out.push(' ');
+                }
+                self.expr(e, UNARY_PRECEDENCE);
+            }
+            Expr::Paren(e) => {
+                self.out.push('(');
+                self.expr(e, 0);
+                self.out.push(')');
+            }
+            Expr::Extra | Expr::Absent => self.out.push_str("--[[?]]"),
+        }
+    }
+    /// Prints the object of an index or call, which must be a name, index,
+    /// call or parenthesized.
+    fn prefix(&mut self, e: &Expr) {
+        match e {
+            Expr::Name(_)
+            | Expr::Index(..)
+            | Expr::Call(..)
+            | Expr::Method(..)
+            | Expr::Paren(_) => self.expr(e, 0),
+            Expr::Global(name) if is_name(name.as_bytes()) => self.expr(e, 0),
+            _ => {
+                self.out.push('(');
+                self.expr(e, 0);
+                self.out.push(')');
+            }
+        }
+    }
+    fn table(&mut self, items: &[(Option<Expr>, Expr)]) {
+        if items.is_empty() {
+            self.out.push_str("{}");
+            return;
+        }
+        self.out.push('{');
+        for (i, (k, v)) in items.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            match k {
+                Some(Expr::String(s)) if is_name(s.as_bytes()) => {
+                    write!(self.out, "{} = ", s.to_str_lossy()).unwrap()
+                }
+                Some(k) => {
+                    self.out.push('[');
+                    self.expr(k, 0);
+                    self.out.push_str("] = ");
+                }
+                None => {}
+            }
+            self.expr(v, 0);
+        }
+        self.out.push('}');
+    }
+    /// Prints a function's parameters and body, skipping the first `skip`
+    /// parameters (`self` for methods).
+    fn body(&mut self, body: &Body, skip: usize) {
+        let mut params = body.params[skip..].to_vec();
+        if body.vararg {
+            params.push("...".to_owned());
+        }
+        write!(self.out, "({})", params.join(", ")).unwrap();
+        self.block(&body.block);
+        self.line();
+        self.out.push_str("end");
+    }
+    fn block(&mut self, block: &[Stat]) {
+        self.last = None;
+        self.indent += 1;
+        for s in block {
+            self.line();
+            self.stat(s);
+        }
+        self.indent -= 1;
+    }
+    fn stat(&mut self, s: &Stat) {
+        let previous = self.last.take();
+        let start = self.out.len();
+        self.stat_body(s);
+        // A statement starting with a parenthesis would continue the one
+        // before as a call.
+        if self.out[start..].starts_with('(')
+            && let Some(end) = previous
+        {
+            self.out.insert(end, ';');
+        }
+        self.last = Some(self.out.len());
+    }
+    fn stat_body(&mut self, s: &Stat) {
+        match s {
+            Stat::Local(names, values) => {
+                self.out.push_str("local ");
+                self.names(names);
+                if !values.is_empty() {
+                    self.out.push_str(" = ");
+                    self.list(values);
+                }
+            }
+            Stat::LocalFunction(name, body) => {
+                write!(self.out, "local function {}", name).unwrap();
+                self.body(body, 0);
+            }
+            Stat::Assign(targets, values) => match (&targets[..], &values[..]) {
+                ([target], [Expr::Function(body)]) if function_name(target).is_some() => {
+                    let name = function_name(target).unwrap();
+                    let method = matches!(target, Expr::Index(..))
+                        && body.params.first().is_some_and(|p| p == "self");
+                    if method {
+                        let (prefix, last) = name.rsplit_once('.').unwrap();
+                        write!(self.out, "function {}:{}", prefix, last).unwrap();
+                        self.body(body, 1);
+                    } else {
+                        write!(self.out, "function {}", name).unwrap();
+                        self.body(body, 0);
+                    }
+                }
+                _ => {
+                    self.list(targets);
+                    self.out.push_str(" = ");
+                    self.list(values);
+                }
+            },
+            Stat::Call(call) => self.expr(call, 0),
+            Stat::If(arms, otherwise) => {
+                for (i, (cond, block)) in arms.iter().enumerate() {
+                    if i > 0 {
+                        self.line();
+                        self.out.push_str("else");
+                    }
+                    self.out.push_str("if ");
+                    self.expr(cond, 0);
+                    self.out.push_str(" then");
+                    self.block(block);
+                }
+                if let Some(block) = otherwise {
+                    self.line();
+                    self.out.push_str("else");
+                    self.block(block);
+                }
+                self.line();
+                self.out.push_str("end");
+            }
+            Stat::While(cond, block) => {
+                self.out.push_str("while ");
+                self.expr(cond, 0);
+                self.out.push_str(" do");
+                self.block(block);
+                self.line();
+                self.out.push_str("end");
+            }
+            Stat::Repeat(block, cond) => {
+                self.out.push_str("repeat");
+                self.block(block);
+                self.line();
+                self.out.push_str("until ");
+                self.expr(cond, 0);
+            }
+            Stat::NumericFor(var, start, limit, step, block) => {
+                write!(self.out, "for {} = ", var).unwrap();
+                self.expr(start, 0);
+                self.out.push_str(", ");
+                self.expr(limit, 0);
+                if let Some(step) = step {
+                    self.out.push_str(", ");
+                    self.expr(step, 0);
+                }
+                self.out.push_str(" do");
+                self.block(block);
+                self.line();
+                self.out.push_str("end");
+            }
+            Stat::GenericFor(names, exprs, block) => {
+                self.out.push_str("for ");
+                self.names(names);
+                self.out.push_str(" in ");
+                self.list(exprs);
+                self.out.push_str(" do");
+                self.block(block);
+                self.line();
+                self.out.push_str("end");
+            }
+            Stat::Return(values) => {
+                self.out.push_str("return");
+                if !values.is_empty() {
+                    self.out.push(' ');
+                    self.list(values);
+                }
+            }
+            Stat::Break => self.out.push_str("break"),
+            Stat::Do(block) => {
+                self.out.push_str("do");
+                self.block(block);
+                self.line();
+                self.out.push_str("end");
+            }
+        }
+    }
+}
+
+/// The dotted name `function` statements accept, if `target` is one.
+fn function_name(target: &Expr) -> Option<String> {
+    match target {
+        Expr::Name(name) => Some(name.clone()),
+        Expr::Global(name) if is_name(name.as_bytes()) => Some(name.to_str_lossy().into()),
+        Expr::Index(t, k) => match (function_name(t), &**k) {
+            (Some(prefix), Expr::String(s)) if is_name(s.as_bytes()) => {
+                Some(format!("{}.{}", prefix, s.to_str_lossy()))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Where control goes on falling off the end of a block, and on `break`.
+#[derive(Clone, Debug)]
+struct Scope {
+    end: usize,
+    /// `end`, plus where jumps to `end` land if the compiler threaded them
+    /// through a JMP there.
+    ends: Vec<usize>,
+    breaks: Vec<usize>,
+}
+
+impl Scope {
+    fn new(end: usize, ends: Vec<usize>, breaks: Vec<usize>) -> Scope {
+        Scope { end, ends, breaks }
+    }
+    /// The pcs a jump to `pc`, which is at or after `end`, may land on.
+    fn aliases(&self, pc: usize) -> Vec<usize> {
+        if self.ends.contains(&pc) {
+            self.ends.clone()
+        } else {
+            vec![pc]
+        }
+    }
+    fn canon(&self, pc: usize) -> usize {
+        if self.ends.contains(&pc) {
+            self.end
+        } else {
+            pc
+        }
+    }
+}
+
+/// A test instruction of a condition along with its JMP.
+#[derive(Clone)]
+struct Test<'a> {
+    /// Where the code evaluating the test's operands starts.
+    start: usize,
+    /// The condition under which the JMP is taken.
+    jumps_if: Expr,
+    target: usize,
+    /// The pc following the JMP.
+    end: usize,
+    /// The decompiler's state after the test.
+    state: Decompiler<'a>,
+}
+
+/// Rebuilds the condition of `tests[i..=j]` that is true when control
+/// reaches `t` and false when it reaches `f`, given that leaving the last of
+/// them without jumping goes to `tests[j + 1].start`, or to the end of the
+/// last test of all.
+fn build(tests: &[Test], i: usize, j: usize, t: usize, f: usize) -> Option<Expr> {
+    let fall = |k: usize| {
+        tests
+            .get(k + 1)
+            .map_or(tests[tests.len() - 1].end, |x| x.start)
+    };
+    if i == j {
+        let test = &tests[i];
+        return match (test.target, fall(i)) {
+            (target, next) if target == t && next == f => Some(test.jumps_if.clone()),
+            (target, next) if target == f && next == t => Some(test.jumps_if.clone().not()),
+            _ => None,
+        };
+    }
+    for m in i..j {
+        let s = tests[m + 1].start;
+        let internal: Vec<usize> = tests[i + 1..=m].iter().map(|x| x.start).collect();
+        let exits: Vec<usize> = tests[i..=m]
+            .iter()
+            .map(|x| x.target)
+            .chain([s])
+            .filter(|e| !internal.contains(e))
+            .collect();
+        if exits.iter().all(|&e| e == s || e == f)
+            && let (Some(l), Some(r)) = (build(tests, i, m, s, f), build(tests, m + 1, j, t, f))
+        {
+            return Some(Expr::binary(BinOp::And, l, r));
+        }
+        if exits.iter().all(|&e| e == s || e == t)
+            && let (Some(l), Some(r)) = (build(tests, i, m, t, s), build(tests, m + 1, j, t, f))
+        {
+            return Some(Expr::binary(BinOp::Or, l, r));
+        }
+    }
+    None
+}
+
+/// Picks the longest prefix of `tests` forming a condition whose false exit
+/// satisfies `accept`, returning it and the pc where the condition is true.
+fn condition<'a>(
+    tests: &[Test<'a>],
+    accept: impl Fn(usize, usize) -> bool,
+) -> Option<(Expr, usize, usize, Decompiler<'a>)> {
+    (1..=tests.len()).rev().find_map(|n| {
+        let chosen = &tests[..n];
+        let t = chosen[n - 1].end;
+        let internal: Vec<usize> = chosen[1..].iter().map(|x| x.start).collect();
+        let mut exits: Vec<usize> = chosen
+            .iter()
+            .map(|x| x.target)
+            .filter(|e| *e != t && !internal.contains(e))
+            .collect();
+        exits.dedup();
+        match exits[..] {
+            [f] if accept(t, f) => {
+                let cond = build(chosen, 0, n - 1, t, f)?;
+                // The compiler folds `not` of a constant, so the tests are
+                // rather part of a value.
+                if negates_constant(&cond) {
+                    return None;
+                }
+                Some((cond, t, f, chosen[n - 1].state.clone()))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Whether condition `e` applies `not` to a constant.
+fn negates_constant(e: &Expr) -> bool {
+    match e {
+        Expr::Unary(UnOp::Not, e) => e.is_constant() || negates_constant(e),
+        Expr::Binary(BinOp::And | BinOp::Or, l, r) => negates_constant(l) || negates_constant(r),
+        _ => false,
+    }
+}
+
+#[derive(Clone)]
+struct Decompiler<'a> {
+    fun: &'a Function,
+    /// The pcs of the JMPs back to each pc, in order.
+    back_jumps: &'a HashMap<usize, Vec<usize>>,
+    path: String,
+    /// Values computed into registers that do not (yet) hold active locals.
+    pending: HashMap<u8, Expr>,
+    /// The register holding the last of an open number of values.
+    open: Option<u8>,
+    /// Indices into `locvars` of the locals declared other than by local
+    /// statements: parameters and loop variables.
+    declared: HashSet<usize>,
+    /// The targets and values of a multiple assignment, while some of its
+    /// values are still pending.
+    multi: Option<(Vec<Expr>, Vec<Expr>)>,
+    /// A local's register holding the value of an `and`/`or` expression
+    /// being assigned to it, which writes treat as a temporary.
+    capture: Option<u8>,
+    /// The starts and ends of the blocks being decompiled, to refuse code
+    /// that would have one decompiled within itself.
+    blocks: Vec<(usize, usize)>,
+}
+
+impl<'a> Decompiler<'a> {
+    fn decode(&self, pc: usize) -> anyhow::Result<Instruction> {
+        let raw = *self.fun.code.get(pc).context("code ends early")?;
+        Instruction::decode(raw)
+    }
+    fn op(&self, pc: usize) -> Option<OpCode> {
+        self.decode(pc).ok().map(|i| i.op)
+    }
+    fn target(&self, pc: usize, i: Instruction) -> anyhow::Result<usize> {
+        usize::try_from(pc as i64 + 1 + i64::from(i.sbx()))
+            .ok()
+            .filter(|&t| t <= self.fun.code.len())
+            .with_context(|| format!("jump out of range at pc {}", pc))
+    }
+    /// Indices into `locvars` of the locals active at `pc`, which hold
+    /// registers 0, 1, ... in that order.
+    fn active(&self, pc: usize) -> Vec<usize> {
+        let pc = pc as u32;
+        (0..self.fun.locvars.len())
+            .filter(|&i| self.fun.locvars[i].is_active(pc))
+            .collect()
+    }
+    /// Like `active`, but for declaring the locals starting at `pc`. A local
+    /// dying where it starts, declared last in a block, is never active, but
+    /// takes its register there along with the others dying there.
+    fn declarable(&self, pc: usize) -> Vec<usize> {
+        let pc = pc as u32;
+        let locvars = &self.fun.locvars;
+        let empty = locvars.iter().any(|l| (l.startpc, l.endpc) == (pc, pc));
+        (0..locvars.len())
+            .filter(|&i| {
+                let l = &locvars[i];
+                l.is_active(pc) || (empty && (l.startpc..=l.endpc).contains(&pc))
+            })
+            .collect()
+    }
+    fn local(&self, r: u8, pc: usize) -> Option<usize> {
+        self.active(pc).get(usize::from(r)).copied()
+    }
+    fn name(&self, local: usize) -> String {
+        self.fun.locvars[local].varname.to_str_lossy().into_owned()
+    }
+    /// Whether a local is one the compiler made up, like `(for index)`.
+    fn is_internal(&self, local: usize) -> bool {
+        self.fun.locvars[local].varname.first() == Some(&b'(')
+    }
+    fn upvalue(&self, n: u16) -> String {
+        match self.fun.upvalues.get(usize::from(n)) {
+            Some(name) => name.to_str_lossy().into_owned(),
+            None => format!("upvalue{}", n),
+        }
+    }
+    fn constant(&self, k: usize) -> anyhow::Result<Expr> {
+        Ok(
+            match self.fun.constants.get(k).context("constant out of range")? {
+                Constant::Nil => Expr::Nil,
+                Constant::Boolean(b) => Expr::Boolean(*b),
+                Constant::Number(n) => Expr::Number(*n),
+                Constant::Integer(n) => Expr::Number(*n as f64),
+                Constant::String(s) => Expr::String(s.clone()),
+            },
+        )
+    }
+    fn global(&self, k: u32) -> anyhow::Result<Expr> {
+        match self.constant(k as usize)? {
+            Expr::String(s) => Ok(Expr::Global(s)),
+            _ => bail!("global name is not a string"),
+        }
+    }
+
+    fn read(&mut self, pc: usize, r: u8) -> anyhow::Result<Expr> {
+        if let Some(e) = self.pending.remove(&r) {
+            return Ok(e);
+        }
+        match self.local(r, pc) {
+            Some(local) => Ok(Expr::Name(self.name(local))),
+            None => bail!("register {} read before being set at pc {}", r, pc),
+        }
+    }
+    /// Reads the value an assignment stores from RK `x`. The nils the
+    /// compiler adds for missing values come from temporaries.
+    fn read_stored(&mut self, pc: usize, x: u16) -> anyhow::Result<Expr> {
+        let temp = matches!(Rk::from(x), Rk::Register(r) if self.pending.contains_key(&r));
+        let e = self.rk(pc, x)?;
+        let multi = self.multi.is_some() || !self.pending.is_empty();
+        Ok(if temp && multi && e == Expr::Nil {
+            Expr::Absent
+        } else {
+            e
+        })
+    }
+    fn rk(&mut self, pc: usize, x: u16) -> anyhow::Result<Expr> {
+        match Rk::from(x) {
+            Rk::Constant(k) => self.constant(k.into()),
+            Rk::Register(r) => self.read(pc, r),
+        }
+    }
+    /// Reads `count` registers from `from`, or up to the open value if
+    /// `None`, as an expression list.
+    fn read_list(&mut self, pc: usize, from: u8, count: Option<u8>) -> anyhow::Result<Vec<Expr>> {
+        let to = match count {
+            Some(n) => i32::from(from) + i32::from(n) - 1,
+            None => self.open.take().context("no open values")?.into(),
+        };
+        let mut list = vec![];
+        for r in i32::from(from)..=to {
+            list.push(self.read(pc, r as u8)?);
+        }
+        if count.is_some()
+            && let Some(last) = list.last_mut()
+            && last.is_multi()
+        {
+            *last = Expr::Paren(Box::new(last.clone()));
+        }
+        list.retain(|e| *e != Expr::Extra);
+        Ok(list)
+    }
+    fn write(&mut self, pc: usize, r: u8, e: Expr, stats: &mut Vec<Stat>) {
+        match self.local(r, pc) {
+            Some(local) if !self.is_internal(local) && self.capture != Some(r) => {
+                let name = self.name(local);
+                self.store(Expr::Name(name), e, stats)
+            }
+            _ => {
+                self.pending.insert(r, e);
+            }
+        }
+    }
+    /// Writes the `n` results of a call or `...` from register `a`, or
+    /// leaves them open if `None`.
+    fn write_results(&mut self, pc: usize, a: u8, n: Option<u8>, e: Expr, stats: &mut Vec<Stat>) {
+        match n {
+            None => {
+                self.pending.insert(a, e);
+                self.open = Some(a);
+            }
+            Some(0) => stats.push(Stat::Call(e)),
+            Some(n) => {
+                self.write(pc, a, e, stats);
+                for r in a + 1..a + n {
+                    self.write(pc, r, Expr::Extra, stats);
+                }
+            }
+        }
+    }
+    /// Assigns `value` to `target`. The compiler evaluates all the values of
+    /// a multiple assignment before storing them from last to first, so
+    /// stores made while other values are pending belong together.
+    fn store(&mut self, target: Expr, value: Expr, stats: &mut Vec<Stat>) {
+        let (mut targets, mut values) = self.multi.take().unwrap_or_default();
+        targets.push(target);
+        values.push(value);
+        if !self.pending.is_empty() {
+            self.multi = Some((targets, values));
+            return;
+        }
+        targets.reverse();
+        values.reverse();
+        values.retain(|e| *e != Expr::Extra);
+        // Leave out the missing values, unless that would widen a call.
+        while let [.., e, Expr::Absent] = &values[..]
+            && !e.is_multi()
+        {
+            values.pop();
+        }
+        for e in &mut values {
+            if *e == Expr::Absent {
+                *e = Expr::Nil;
+            }
+        }
+        stats.push(Stat::Assign(targets, values));
+    }
+    /// Whether a local declared by a local statement starts at `pc`.
+    fn activates(&self, pc: usize) -> bool {
+        self.fun.locvars.iter().enumerate().any(|(i, l)| {
+            l.startpc as usize == pc && !self.declared.contains(&i) && !self.is_internal(i)
+        })
+    }
+    /// Declares the locals starting at `pc` with the values pending in their
+    /// registers, returning the earliest pc where one of them dies.
+    fn activate(&mut self, pc: usize, stats: &mut Vec<Stat>) -> Option<usize> {
+        let mut names = vec![];
+        let mut values = vec![];
+        let mut end = None;
+        let mut first = 0;
+        for (r, local) in self.declarable(pc).into_iter().enumerate() {
+            let l = &self.fun.locvars[local];
+            if l.startpc as usize != pc || self.declared.contains(&local) || self.is_internal(local)
+            {
+                continue;
+            }
+            let dies = l.endpc as usize;
+            end = Some(end.map_or(dies, |end: usize| end.min(dies)));
+            if names.is_empty() {
+                first = r as u8;
+            }
+            self.declared.insert(local);
+            names.push(self.name(local));
+            values.extend(self.pending.remove(&(r as u8)));
+        }
+        if names.is_empty() {
+            return None;
+        }
+        values.retain(|e| *e != Expr::Extra);
+        if values.iter().all(|e| *e == Expr::Nil) {
+            values.clear();
+        }
+        match (&names[..], &values[..]) {
+            ([name], [Expr::Function(body)]) if self.is_local_function(pc, first, name) => {
+                stats.push(Stat::LocalFunction(name.clone(), (**body).clone()))
+            }
+            _ => stats.push(Stat::Local(names, values)),
+        }
+        end
+    }
+    /// Whether the local starting at `pc` in register `r` can be declared
+    /// with `local function`, which makes the name visible in its body: if
+    /// the closure refers to the local itself, or to no global of its name.
+    fn is_local_function(&self, pc: usize, r: u8, name: &str) -> bool {
+        (0..pc).rev().take(usize::from(u8::MAX) + 1).any(|q| {
+            let Ok(i) = self.decode(q) else { return false };
+            let Some(child) = self.fun.funs.get(i.bx() as usize) else {
+                return false;
+            };
+            if i.op != OpCode::Closure || i.a != r || q + 1 + usize::from(child.nups) != pc {
+                return false;
+            }
+            let recursive = (q + 1..pc).any(|u| {
+                self.decode(u)
+                    .is_ok_and(|i| i.op == OpCode::Move && i.b == u16::from(r))
+            });
+            recursive || !mentions_global(child, name.as_bytes())
+        })
+    }
+
+    /// Decompiles an instruction that does not branch, returning the pc of
+    /// the next one.
+    fn step(&mut self, pc: usize, i: Instruction, stats: &mut Vec<Stat>) -> anyhow::Result<usize> {
+        let (a, b, c) = (i.a, i.b, i.c);
+        match i.op {
+            OpCode::Move => {
+                let e = self.read_stored(pc, b)?;
+                self.write(pc, a, e, stats);
+            }
+            OpCode::LoadK => {
+                let e = self.constant(i.bx() as usize)?;
+                self.write(pc, a, e, stats);
+            }
+            OpCode::LoadBool if c == 0 => self.write(pc, a, Expr::Boolean(b != 0), stats),
+            OpCode::LoadNil => {
+                for r in a..=b as u8 {
+                    self.write(pc, r, Expr::Nil, stats);
+                }
+            }
+            OpCode::GetUpval => self.write(pc, a, Expr::Name(self.upvalue(b)), stats),
+            OpCode::GetGlobal => {
+                let e = self.global(i.bx())?;
+                self.write(pc, a, e, stats);
+            }
+            OpCode::GetTable => {
+                let t = self.read(pc, b as u8)?;
+                let k = self.rk(pc, c)?;
+                self.write(pc, a, Expr::Index(Box::new(t), Box::new(k)), stats);
+            }
+            OpCode::SetGlobal => {
+                let e = self.read_stored(pc, a.into())?;
+                let target = self.global(i.bx())?;
+                self.store(target, e, stats);
+            }
+            OpCode::SetUpval => {
+                let e = self.read_stored(pc, a.into())?;
+                self.store(Expr::Name(self.upvalue(b)), e, stats);
+            }
+            OpCode::SetTable => {
+                let k = self.rk(pc, b)?;
+                let v = self.read_stored(pc, c)?;
+                if let Some(Expr::Table(_)) = self.pending.get(&a) {
+                    let v = if v == Expr::Absent { Expr::Nil } else { v };
+                    // Take the array items computed so far, which SETLIST
+                    // stores later, to keep them in order.
+                    let mut array = vec![];
+                    let mut r = a + 1;
+                    while let Some(e) = self.pending.get_mut(&r) {
+                        if *e != Expr::Extra {
+                            array.push((None, std::mem::replace(e, Expr::Extra)));
+                        }
+                        r += 1;
+                    }
+                    let Some(Expr::Table(items)) = self.pending.get_mut(&a) else {
+                        unreachable!()
+                    };
+                    items.extend(array);
+                    items.push((Some(k), v));
+                } else {
+                    let t = self.read(pc, a)?;
+                    self.store(Expr::Index(Box::new(t), Box::new(k)), v, stats);
+                }
+            }
+            OpCode::NewTable => self.write(pc, a, Expr::Table(vec![]), stats),
+            OpCode::Self_ => {
+                let object = self.read(pc, b as u8)?;
+                let Expr::String(name) = self.rk(pc, c)? else {
+                    bail!("method name is not a string at pc {}", pc);
+                };
+                if !is_name(&name) {
+                    bail!("method name is not a name at pc {}", pc);
+                }
+                self.pending
+                    .insert(a, Expr::Method(Box::new(object), name, vec![]));
+                self.pending.insert(a + 1, Expr::Extra);
+            }
+            OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div | OpCode::Mod | OpCode::Pow => {
+                let op = match i.op {
+                    OpCode::Add => BinOp::Add,
+                    OpCode::Sub => BinOp::Sub,
+                    OpCode::Mul => BinOp::Mul,
+                    OpCode::Div => BinOp::Div,
+                    OpCode::Mod => BinOp::Mod,
+                    _ => BinOp::Pow,
+                };
+                let l = self.rk(pc, b)?;
+                let r = self.rk(pc, c)?;
+                self.write(pc, a, Expr::binary(op, l, r), stats);
+            }
+            OpCode::Unm | OpCode::Not | OpCode::Len => {
+                let op = match i.op {
+                    OpCode::Unm => UnOp::Neg,
+                    OpCode::Not => UnOp::Not,
+                    _ => UnOp::Len,
+                };
+                let e = Expr::Unary(op, Box::new(self.read(pc, b as u8)?));
+                self.write(pc, a, e, stats);
+            }
+            OpCode::Concat => {
+                let mut operands = vec![];
+                for r in b..=c {
+                    operands.push(self.read(pc, r as u8)?);
+                }
+                let e = operands
+                    .into_iter()
+                    .rev()
+                    .reduce(|r, l| Expr::binary(BinOp::Concat, l, r))
+                    .context("empty CONCAT")?;
+                self.write(pc, a, e, stats);
+            }
+            OpCode::Call | OpCode::TailCall => {
+                let call = self.call(pc, a, b)?;
+                if i.op == OpCode::TailCall {
+                    stats.push(Stat::Return(vec![call]));
+                    // Skip the RETURN only reached when calling C functions.
+                    if self.op(pc + 1) == Some(OpCode::Return) {
+                        return Ok(pc + 2);
+                    }
+                } else {
+                    let results = c.checked_sub(1).map(|n| n as u8);
+                    self.write_results(pc, a, results, call, stats);
+                }
+            }
+            OpCode::Return => {
+                let values = self.read_list(pc, a, b.checked_sub(1).map(|n| n as u8))?;
+                // Every function ends with a RETURN, which is implicit.
+                if !values.is_empty() || pc + 1 != self.fun.code.len() {
+                    stats.push(Stat::Return(values));
+                }
+            }
+            OpCode::SetList => {
+                let items = self.read_list(pc, a + 1, (b != 0).then_some(b as u8))?;
+                let Some(Expr::Table(table)) = self.pending.get_mut(&a) else {
+                    bail!("SETLIST without a table at pc {}", pc);
+                };
+                table.extend(items.into_iter().map(|v| (None, v)));
+                if c == 0 {
+                    return Ok(pc + 2);
+                }
+            }
+            OpCode::Close => {}
+            OpCode::Closure => {
+                let n = i.bx() as usize;
+                let child = self.fun.funs.get(n).context("function out of range")?;
+                let body = decompile_body(child, &format!("{}.{}", self.path, n))?;
+                self.write(pc, a, Expr::Function(Box::new(body)), stats);
+                // Skip the instructions passing the upvalues.
+                return Ok(pc + 1 + usize::from(child.nups));
+            }
+            OpCode::VarArg => {
+                let results = b.checked_sub(1).map(|n| n as u8);
+                self.write_results(pc, a, results, Expr::Vararg, stats);
+            }
+            op => bail!("unexpected {} at pc {}", op, pc),
+        }
+        Ok(pc + 1)
+    }
+    /// The call of the function in register `a` with `b - 1` arguments, or
+    /// up to the open value if `b` is 0.
+    fn call(&mut self, pc: usize, a: u8, b: u16) -> anyhow::Result<Expr> {
+        let args = self.read_list(pc, a + 1, b.checked_sub(1).map(|n| n as u8))?;
+        Ok(match self.read(pc, a)? {
+            Expr::Method(object, name, _) => Expr::Method(object, name, args),
+            f => Expr::Call(Box::new(f), args),
+        })
+    }
+
+    /// Decompiles the code from `start` up to `scope.end`.
+    fn block(&mut self, start: usize, scope: &Scope) -> anyhow::Result<Vec<Stat>> {
+        if self.blocks.contains(&(start, scope.end)) {
+            bail!("block from pc {} to {} within itself", start, scope.end);
+        }
+        self.blocks.push((start, scope.end));
+        let result = self.block_body(start, scope);
+        self.blocks.pop();
+        result
+    }
+    fn block_body(&mut self, start: usize, scope: &Scope) -> anyhow::Result<Vec<Stat>> {
+        let mut stats = vec![];
+        let mut pc = start;
+        while pc < scope.end {
+            // Locals dying before the end of the block were declared in a
+            // `do` block. Locals die before the CLOSE of their upvalues and
+            // the final RETURN of the function.
+            if let Some(end) = self.activate(pc, &mut stats)
+                && (end..scope.end)
+                    .any(|pc| self.op(pc) != Some(OpCode::Close) && pc + 1 != self.fun.code.len())
+            {
+                let mut block = vec![stats.pop().unwrap()];
+                block.extend(self.block(pc, &Scope::new(end, vec![end], scope.breaks.clone()))?);
+                stats.push(Stat::Do(block));
+                pc = end;
+                continue;
+            }
+            if let Some(next) = self.try_loop(pc, scope, &mut stats)? {
+                pc = next;
+                continue;
+            }
+            let i = self.decode(pc)?;
+            let before = self.clone();
+            let next = match i.op {
+                OpCode::Jmp => self.jump(pc, i, scope, &mut stats),
+                OpCode::ForPrep => self.numeric_for(pc, i, scope, &mut stats),
+                op if op.is_test() => self.test(pc, i, scope, &mut stats),
+                _ => self.step(pc, i, &mut stats),
+            };
+            pc = match next {
+                Ok(next) => next,
+                // `repeat ... until true` has no code of its own, so its
+                // breaks are the first sign of it.
+                Err(e) => match e.downcast_ref::<UnexpectedJump>() {
+                    Some(&UnexpectedJump { target, .. }) if pc < target && target <= scope.end => {
+                        *self = before;
+                        let body_scope = Scope::new(target, vec![target], vec![target]);
+                        let body = self.block(pc, &body_scope)?;
+                        stats.push(Stat::Repeat(body, Expr::Boolean(true)));
+                        target
+                    }
+                    _ => return Err(e),
+                },
+            };
+        }
+        if pc != scope.end {
+            bail!("block overruns its end at pc {}", scope.end);
+        }
+        // A local declared last in the block with a value starts and dies
+        // at its end.
+        let at_end = |l: &LocVar| (l.startpc as usize, l.endpc as usize) == (pc, pc);
+        if !self.pending.is_empty() && self.fun.locvars.iter().any(at_end) {
+            self.activate(pc, &mut stats);
+        }
+        if self.multi.is_some() {
+            bail!("unfinished assignment at pc {}", pc);
+        }
+        Ok(stats)
+    }
+    /// Decompiles a JMP not belonging to a test or loop.
+    fn jump(
+        &mut self,
+        pc: usize,
+        i: Instruction,
+        scope: &Scope,
+        stats: &mut Vec<Stat>,
+    ) -> anyhow::Result<usize> {
+        let target = self.target(pc, i)?;
+        if scope.breaks.contains(&target) {
+            stats.push(Stat::Break);
+            return Ok(pc + 1);
+        }
+        if self.op(target) == Some(OpCode::TForLoop)
+            && let Ok(back) = self.decode(target + 1)
+            && back.op == OpCode::Jmp
+            && self.target(target + 1, back)? == pc + 1
+        {
+            return self.generic_for(pc, target, scope, stats);
+        }
+        Err(UnexpectedJump { pc, target }.into())
+    }
+    fn numeric_for(
+        &mut self,
+        pc: usize,
+        i: Instruction,
+        scope: &Scope,
+        stats: &mut Vec<Stat>,
+    ) -> anyhow::Result<usize> {
+        let end = self.target(pc, i)?;
+        if self.op(end) != Some(OpCode::ForLoop) {
+            bail!("FORPREP without FORLOOP at pc {}", pc);
+        }
+        let a = i.a;
+        let start = self.read(pc, a)?;
+        let limit = self.read(pc, a + 1)?;
+        let step = Some(self.read(pc, a + 2)?).filter(|e| *e != Expr::Number(1.0));
+        let var = self.local(a + 3, pc + 1).context("loop variable missing")?;
+        self.declared.insert(var);
+        let breaks = scope.aliases(end + 1);
+        let block = self.block(pc + 1, &Scope::new(end, vec![end], breaks))?;
+        stats.push(Stat::NumericFor(self.name(var), start, limit, step, block));
+        Ok(end + 1)
+    }
+    /// Decompiles a generic for loop given its initial JMP and TFORLOOP.
+    fn generic_for(
+        &mut self,
+        pc: usize,
+        end: usize,
+        scope: &Scope,
+        stats: &mut Vec<Stat>,
+    ) -> anyhow::Result<usize> {
+        let i = self.decode(end)?;
+        let a = i.a;
+        let mut exprs = self.read_list(pc, a, Some(3))?;
+        while exprs.len() > 1 && exprs.last() == Some(&Expr::Nil) {
+            exprs.pop();
+        }
+        let mut names = vec![];
+        for r in a + 3..a + 3 + i.c as u8 {
+            let var = self.local(r, pc + 1).context("loop variable missing")?;
+            self.declared.insert(var);
+            names.push(self.name(var));
+        }
+        let breaks = scope.aliases(end + 2);
+        let block = self.block(pc + 1, &Scope::new(end, vec![end], breaks))?;
+        stats.push(Stat::GenericFor(names, exprs, block));
+        Ok(end + 2)
+    }
+
+    /// Decompiles a while or repeat loop starting at `pc`, if there is one,
+    /// returning the pc following it.
+    fn try_loop(
+        &mut self,
+        pc: usize,
+        scope: &Scope,
+        stats: &mut Vec<Stat>,
+    ) -> anyhow::Result<Option<usize>> {
+        let Some(&back) = self
+            .back_jumps
+            .get(&pc)
+            .and_then(|jumps| jumps.iter().rev().find(|&&j| j < scope.end))
+        else {
+            return Ok(None);
+        };
+        let exit = back + 1;
+        let breaks = scope.aliases(exit);
+        if back > pc
+            && self.op(back - 1).is_some_and(|op| op.is_test())
+            && let Some(stat) = self.try_repeat(pc, back, &breaks)?
+        {
+            stats.push(stat);
+            return Ok(Some(exit));
+        }
+        let canon = |x: usize| if breaks.contains(&x) { exit } else { x };
+        let tests = self.chain(pc, back, canon);
+        let body_scope = Scope::new(back, vec![back, pc], breaks.clone());
+        let stat = match condition(&tests, |_, f| f == exit) {
+            Some((cond, t, _, state)) if state.pending.is_empty() => {
+                *self = state;
+                Stat::While(cond, self.block(t, &body_scope)?)
+            }
+            _ => Stat::While(Expr::Boolean(true), self.block(pc, &body_scope)?),
+        };
+        stats.push(stat);
+        Ok(Some(exit))
+    }
+    /// Decompiles `repeat ... until` from `pc` to the JMP at `back` ending
+    /// its condition.
+    fn try_repeat(
+        &mut self,
+        pc: usize,
+        back: usize,
+        breaks: &[usize],
+    ) -> anyhow::Result<Option<Stat>> {
+        let exit = back + 1;
+        let until = |d: &Decompiler<'a>, c: usize| {
+            let tests = d.chain(c, exit, |x| x);
+            let n = tests.iter().position(|t| t.end == exit)?;
+            let cond = build(&tests[..=n], 0, n, exit, pc)?;
+            Some((cond, tests[n].state.clone()))
+        };
+        let mut fresh = self.clone();
+        fresh.pending.clear();
+        let Some(c) = (pc + 1..back).find(|&c| until(&fresh, c).is_some()) else {
+            return Ok(None);
+        };
+        let mut block = self.block(pc, &Scope::new(c, vec![c], breaks.to_vec()))?;
+        self.activate(c, &mut block);
+        let (cond, state) = until(self, c).context("repeat condition changed")?;
+        *self = state;
+        Ok(Some(Stat::Repeat(block, cond)))
+    }
+    /// Gathers the tests of a condition starting at `pc`, stepping through
+    /// the code computing their operands, up to `end`. Jump targets are
+    /// passed through `canon`.
+    fn chain(&self, pc: usize, end: usize, canon: impl Fn(usize) -> usize) -> Vec<Test<'a>> {
+        let mut state = self.clone();
+        let mut tests = vec![];
+        let mut start = pc;
+        let mut q = pc;
+        while q < end {
+            if q > pc && state.activates(q) {
+                break;
+            }
+            let Ok(i) = state.decode(q) else { break };
+            match i.op {
+                // A test leaving other values pending is part of an operand.
+                OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::Test
+                    if q > pc && state.pending.keys().any(|r| !test_registers(i).contains(r)) =>
+                {
+                    let scope = Scope::new(end, vec![end], vec![]);
+                    let mut stats = vec![];
+                    match state.value(q, i, &scope, &mut stats) {
+                        Ok(next) if stats.is_empty() => q = next,
+                        _ => break,
+                    }
+                }
+                OpCode::Eq | OpCode::Lt | OpCode::Le | OpCode::Test => {
+                    let Ok(jmp) = state.decode(q + 1) else { break };
+                    if jmp.op != OpCode::Jmp || q + 2 > end {
+                        break;
+                    }
+                    let (Ok(jumps_if), Ok(target)) =
+                        (state.jumps_if(q, i), state.target(q + 1, jmp))
+                    else {
+                        break;
+                    };
+                    q += 2;
+                    tests.push(Test {
+                        start,
+                        jumps_if,
+                        target: canon(target),
+                        end: q,
+                        state: state.clone(),
+                    });
+                    start = q;
+                }
+                // An `and`/`or` value within an operand.
+                OpCode::TestSet => {
+                    let scope = Scope::new(end, vec![end], vec![]);
+                    let mut stats = vec![];
+                    match state.value(q, i, &scope, &mut stats) {
+                        Ok(next) if stats.is_empty() => q = next,
+                        _ => break,
+                    }
+                }
+                OpCode::Jmp
+                | OpCode::ForPrep
+                | OpCode::ForLoop
+                | OpCode::TForLoop
+                | OpCode::Return
+                | OpCode::TailCall
+                | OpCode::Close => break,
+                OpCode::LoadBool if i.c != 0 => break,
+                _ => {
+                    let mut stats = vec![];
+                    match state.step(q, i, &mut stats) {
+                        Ok(next) if stats.is_empty() => q = next,
+                        _ => break,
+                    }
+                }
+            }
+        }
+        tests
+    }
+    /// The condition under which the JMP following test `i` is taken.
+    fn jumps_if(&mut self, pc: usize, i: Instruction) -> anyhow::Result<Expr> {
+        let op = match i.op {
+            OpCode::Test => {
+                let e = self.read(pc, i.a)?;
+                return Ok(if i.c != 0 { e } else { e.not() });
+            }
+            OpCode::Eq => BinOp::Eq,
+            OpCode::Lt => BinOp::Lt,
+            OpCode::Le => BinOp::Le,
+            op => bail!("unexpected {} at pc {}", op, pc),
+        };
+        // The compiler turns `x > y` into `y < x`, which shows if y is a
+        // constant or was computed after x.
+        let swapped = match (Rk::from(i.b), Rk::from(i.c)) {
+            (Rk::Constant(_), Rk::Register(_)) => true,
+            (Rk::Register(b), Rk::Register(c)) => {
+                b > c && self.pending.contains_key(&b) && self.pending.contains_key(&c)
+            }
+            _ => false,
+        };
+        let l = self.rk(pc, i.b)?;
+        let r = self.rk(pc, i.c)?;
+        let e = match op {
+            BinOp::Lt | BinOp::Le if swapped => {
+                let op = if op == BinOp::Lt {
+                    BinOp::Gt
+                } else {
+                    BinOp::Ge
+                };
+                Expr::binary(op, r, l)
+            }
+            _ => Expr::binary(op, l, r),
+        };
+        Ok(if i.a != 0 { e } else { e.not() })
+    }
+
+    /// Decompiles the code starting with a test: a comparison turned into a
+    /// boolean, an if statement, or an `and`/`or` expression.
+    fn test(
+        &mut self,
+        pc: usize,
+        i: Instruction,
+        scope: &Scope,
+        stats: &mut Vec<Stat>,
+    ) -> anyhow::Result<usize> {
+        if i.op != OpCode::TestSet {
+            let tests = self.chain(pc, scope.end, |x| scope.canon(x));
+            if let Some(next) = self.try_boolean(pc, &tests, stats) {
+                return Ok(next);
+            }
+            let mut clone = self.clone();
+            let mut if_stats = vec![];
+            match clone.if_statement(&tests, scope, &mut if_stats) {
+                Ok(next) => {
+                    *self = clone;
+                    stats.extend(if_stats);
+                    return Ok(next);
+                }
+                Err(e) => return self.value(pc, i, scope, stats).map_err(|_| e),
+            }
+        }
+        self.value(pc, i, scope, stats)
+    }
+    /// Decompiles comparisons whose outcome is stored as a boolean by a pair
+    /// of LOADBOOLs.
+    fn try_boolean(
+        &mut self,
+        pc: usize,
+        tests: &[Test<'a>],
+        stats: &mut Vec<Stat>,
+    ) -> Option<usize> {
+        tests.iter().enumerate().find_map(|(n, test)| {
+            let set_false = self.decode(test.end).ok()?;
+            let set_true = self.decode(test.end + 1).ok()?;
+            if (set_false.op, set_false.b, set_false.c) != (OpCode::LoadBool, 0, 1)
+                || (set_true.op, set_true.b, set_true.c) != (OpCode::LoadBool, 1, 0)
+                || set_false.a != set_true.a
+            {
+                return None;
+            }
+            let cond = build(&tests[..=n], 0, n, test.end + 1, test.end)?;
+            *self = test.state.clone();
+            self.write(pc, set_true.a, cond, stats);
+            Some(test.end + 2)
+        })
+    }
+    fn if_statement(
+        &mut self,
+        tests: &[Test<'a>],
+        scope: &Scope,
+        stats: &mut Vec<Stat>,
+    ) -> anyhow::Result<usize> {
+        let (cond, t, f, state) =
+            condition(tests, |t, f| t < f && f <= scope.end).context("unrecognized condition")?;
+        *self = state;
+        if !self.pending.is_empty() {
+            bail!("condition leaves values at pc {}", t);
+        }
+        let ends = |end: usize| {
+            if end == scope.end {
+                scope.ends.clone()
+            } else {
+                vec![end]
+            }
+        };
+        // A then block followed by an else block ends with a JMP past it.
+        let skip =
+            (f > t)
+                .then(|| self.decode(f - 1).ok())
+                .flatten()
+                .filter(|i| i.op == OpCode::Jmp)
+                .and_then(|i| self.target(f - 1, i).ok())
+                .map(|x| (x, scope.canon(x)))
+                .filter(|&(_, x)| f <= x && x <= scope.end)
+                // Unless it is a `break` within the block, where its locals are.
+                .filter(|_| {
+                    !self.fun.locvars.iter().any(|l| {
+                        (t..f - 1).contains(&(l.startpc as usize)) && l.endpc as usize >= f
+                    })
+                });
+        let (then_end, next) = match skip {
+            Some((_, x)) => (f - 1, x),
+            None => (f, f),
+        };
+        let mut then_ends = vec![then_end];
+        if let Some((x, _)) = skip {
+            then_ends.extend(scope.aliases(x));
+        } else if f == scope.end {
+            then_ends = scope.ends.clone();
+        }
+        let then_scope = Scope::new(then_end, then_ends, scope.breaks.clone());
+        let then_block = self.block(t, &then_scope)?;
+        let mut arms = vec![(cond, then_block)];
+        // Keep an empty else block for its JMP.
+        let mut otherwise = skip.map(|_| vec![]);
+        if next > f {
+            let else_scope = Scope::new(next, ends(next), scope.breaks.clone());
+            let mut else_block = self.block(f, &else_scope)?;
+            match else_block.pop() {
+                Some(Stat::If(more, rest)) if else_block.is_empty() => {
+                    arms.extend(more);
+                    otherwise = rest;
+                }
+                Some(s) => {
+                    else_block.push(s);
+                    otherwise = Some(else_block);
+                }
+                None => {}
+            }
+        }
+        if !self.pending.is_empty() {
+            bail!("if statement leaves values at pc {}", next);
+        }
+        // `if not x then x = y end` has the same code as `x = x or y`, as
+        // long as the jump was not threaded past the end.
+        let jmp = tests[0].end - 1;
+        if let ([(cond, block)], None, [_]) = (&arms[..], &otherwise, tests)
+            && self.decode(jmp).and_then(|i| self.target(jmp, i)).ok() == Some(next)
+            && let [Stat::Assign(targets, values)] = &block[..]
+            && let ([Expr::Name(target)], [value]) = (&targets[..], &values[..])
+        {
+            let op = match cond {
+                Expr::Name(name) if name == target => Some(BinOp::And),
+                Expr::Unary(UnOp::Not, e) if **e == Expr::Name(target.clone()) => Some(BinOp::Or),
+                _ => None,
+            };
+            if let Some(op) = op {
+                let target = Expr::Name(target.clone());
+                let value = Expr::binary(op, target.clone(), value.clone());
+                stats.push(Stat::Assign(vec![target], vec![value]));
+                return Ok(next);
+            }
+        }
+        stats.push(Stat::If(arms, otherwise));
+        Ok(next)
+    }
+
+    /// Decompiles an `and`/`or` expression starting with the test at `pc`,
+    /// returning the pc where its value is complete.
+    fn value(
+        &mut self,
+        pc: usize,
+        i: Instruction,
+        scope: &Scope,
+        stats: &mut Vec<Stat>,
+    ) -> anyhow::Result<usize> {
+        // The register of the expression is that of a TESTSET or a TEST of a
+        // temporary. Tests of locals and comparisons may only check operands
+        // whose values go unused, like `a` in `a and b or c`.
+        let mut registers = vec![];
+        for q in pc..scope.end {
+            let Ok(i) = self.decode(q) else { break };
+            let r = match i.op {
+                OpCode::TestSet => i.a,
+                OpCode::Test if q == pc || self.local(i.a, q).is_none() => i.a,
+                OpCode::LoadBool if self.is_bool_pair(q, i.a) => i.a,
+                _ => continue,
+            };
+            if !registers.contains(&r) {
+                registers.push(r);
+            }
+        }
+        // Comparisons among the operands leave a boolean through a pair of
+        // LOADBOOLs at the end.
+        let mut candidates = vec![];
+        for r in registers {
+            candidates.push((r, None));
+            if let Some(q) = (pc..scope.end).find(|&q| self.is_bool_pair(q, r)) {
+                candidates.push((r, Some(q)));
+            }
+        }
+        let mut error = None;
+        for (r, bools) in candidates {
+            let mut clone = self.clone();
+            let mut value_stats = vec![];
+            match clone.value_in(pc, i, r, bools, scope.end, &mut value_stats) {
+                Ok(next) => {
+                    *self = clone;
+                    stats.extend(value_stats);
+                    return Ok(next);
+                }
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        Err(error.unwrap_or_else(|| anyhow::anyhow!("unrecognized expression at pc {}", pc)))
+    }
+    /// Whether `pc` holds `LOADBOOL r 0 1` followed by `LOADBOOL r 1 0`.
+    fn is_bool_pair(&self, pc: usize, r: u8) -> bool {
+        let is = |pc: usize, b: u16, c: u16| {
+            self.decode(pc)
+                .is_ok_and(|i| (i.op, i.a, i.b, i.c) == (OpCode::LoadBool, r, b, c))
+        };
+        is(pc, 0, 1) && is(pc + 1, 1, 0)
+    }
+    /// Decompiles the expression in `r` whose first test is `i` at `pc`.
+    /// `bools` is where the pair of LOADBOOLs storing the outcome of
+    /// comparisons starts, if any.
+    fn value_in(
+        &mut self,
+        pc: usize,
+        mut i: Instruction,
+        r: u8,
+        bools: Option<usize>,
+        limit: usize,
+        stats: &mut Vec<Stat>,
+    ) -> anyhow::Result<usize> {
+        if self.local(r, pc).is_some() {
+            self.capture = Some(r);
+        }
+        let mut operands = vec![];
+        let mut start = pc;
+        let mut q = pc;
+        let mut end = pc;
+        // The code of the last operand ends with a JMP past the LOADBOOLs,
+        // unless it is a comparison itself.
+        let body_end = bools.map(|p| {
+            let skips = self.decode(p - 1).ok().filter(|i| i.op == OpCode::Jmp);
+            match skips.and_then(|i| self.target(p - 1, i).ok()) {
+                Some(x) if x == p + 2 && p - 1 > pc => p - 1,
+                _ => p,
+            }
+        });
+        loop {
+            let (mut value, mut if_true, mut is_value) = match i.op {
+                OpCode::TestSet => (self.read(q, i.b as u8)?, i.c != 0, true),
+                OpCode::Test => (self.read(q, i.a)?, i.c != 0, i.a == r),
+                _ => (
+                    self.jumps_if(q, Instruction { a: 1, ..i })?,
+                    i.a != 0,
+                    false,
+                ),
+            };
+            let mut target = self.target(q + 1, self.decode(q + 1)?)?;
+            // A jump to a LOADBOOL leaves the outcome of the test itself.
+            if let Some(p) = bools
+                && (target == p || target == p + 1)
+            {
+                let to_true = target == p + 1;
+                if to_true != if_true {
+                    value = value.not();
+                }
+                if_true = to_true;
+                is_value = true;
+                target = p + 2;
+            }
+            if target > limit {
+                bail!("expression jumps out of its block at pc {}", q);
+            }
+            operands.push(Operand {
+                start,
+                value,
+                jump: Some((if_true, target)),
+                is_value,
+            });
+            end = end.max(target);
+            start = q + 2;
+            let segment_end = body_end.map_or(end, |b| end.min(b));
+            match self.operand(start, segment_end, r)? {
+                Some(next) => {
+                    q = next;
+                    i = self.decode(q)?;
+                }
+                None => break,
+            }
+        }
+        match (bools, body_end) {
+            (Some(p), Some(b)) if b == p => {
+                // The last test falls through to the LOADBOOL of false.
+                let last = operands.pop().context("expression without value")?;
+                if start != p || last.jump != Some((true, p + 2)) {
+                    bail!("unrecognized expression at pc {}", pc);
+                }
+                operands.push(Operand { jump: None, ..last });
+            }
+            _ => {
+                let value = self
+                    .pending
+                    .remove(&r)
+                    .context("expression without value")?;
+                operands.push(Operand {
+                    start,
+                    value,
+                    jump: None,
+                    is_value: true,
+                });
+            }
+        }
+        if bools.is_some_and(|p| end != p + 2) {
+            bail!("unrecognized expression at pc {}", pc);
+        }
+        self.capture = None;
+        let n = operands.len() - 1;
+        let e = build_value(&operands, 0, n, end, end, end)
+            .with_context(|| format!("unrecognized expression at pc {}", pc))?;
+        self.write(pc, r, e, stats);
+        Ok(end)
+    }
+    /// Decompiles the code computing an operand of an expression in `r`
+    /// from `pc`, returning the test of the next operand if there is one
+    /// before `end`.
+    fn operand(&mut self, mut pc: usize, end: usize, r: u8) -> anyhow::Result<Option<usize>> {
+        let scope = Scope::new(end, vec![end], vec![]);
+        let mut stats = vec![];
+        while pc < end {
+            if self.activates(pc) {
+                bail!("local declared within expression at pc {}", pc);
+            }
+            let i = self.decode(pc)?;
+            if !i.op.is_test() {
+                pc = self.step(pc, i, &mut stats)?;
+                continue;
+            }
+            if self.op(pc + 1) == Some(OpCode::Jmp) && self.is_operand_test(pc, i, r) {
+                // A test of a local may rather start a nested expression,
+                // like `b` in `a and (b and x or y):f()`.
+                if i.op == OpCode::Test && i.a != r {
+                    let mut clone = self.clone();
+                    let mut nested = vec![];
+                    if let Ok(next) = clone.test(pc, i, &scope, &mut nested)
+                        && nested.is_empty()
+                        && let Ok(rest) = clone.operand(next, end, r)
+                    {
+                        *self = clone;
+                        return Ok(rest);
+                    }
+                }
+                return Ok(Some(pc));
+            }
+            pc = self.test(pc, i, &scope, &mut stats)?;
+        }
+        if pc != end || !stats.is_empty() {
+            bail!("unrecognized expression ending at pc {}", end);
+        }
+        Ok(None)
+    }
+    /// Whether test `i` checks an operand of an expression in `r` rather
+    /// than being part of a nested expression: it consumes every value
+    /// pending in `r` and above.
+    fn is_operand_test(&self, pc: usize, i: Instruction, r: u8) -> bool {
+        let used = match i.op {
+            OpCode::TestSet if i.a == r => vec![i.b as u8],
+            OpCode::Test if i.a == r => vec![r],
+            OpCode::Test if self.local(i.a, pc).is_some() => vec![],
+            OpCode::Eq | OpCode::Lt | OpCode::Le => test_registers(i),
+            _ => return false,
+        };
+        self.pending.keys().all(|&p| p < r || used.contains(&p))
+    }
+}
+
+/// The registers read by test `i`.
+fn test_registers(i: Instruction) -> Vec<u8> {
+    match i.op {
+        OpCode::Test => vec![i.a],
+        OpCode::TestSet => vec![i.b as u8],
+        _ => [i.b, i.c]
+            .into_iter()
+            .filter_map(|x| match Rk::from(x) {
+                Rk::Register(r) => Some(r),
+                Rk::Constant(_) => None,
+            })
+            .collect(),
+    }
+}
+
+/// An operand of an `and`/`or` expression in a register.
+struct Operand {
+    /// Where the code computing it starts.
+    start: usize,
+    value: Expr,
+    /// Whether its test jumps if it is true or if it is false, and where;
+    /// `None` for the last operand.
+    jump: Option<(bool, usize)>,
+    /// Whether a jump leaves its value in the register.
+    is_value: bool,
+}
+
+/// Rebuilds the expression from `operands[i..=j]` whose value goes to `t`
+/// if true and to `f` if false, where `exit` is the end of the expression.
+fn build_value(
+    operands: &[Operand],
+    i: usize,
+    j: usize,
+    t: usize,
+    f: usize,
+    exit: usize,
+) -> Option<Expr> {
+    if i == j {
+        let operand = &operands[i];
+        let jumps = |if_true: bool, target: usize| {
+            let next = operands[i + 1].start;
+            let (taken, not_taken) = if if_true { (t, f) } else { (f, t) };
+            target == taken && next == not_taken
+        };
+        return match operand.jump {
+            None => Some(operand.value.clone()),
+            Some(_) if j + 1 == operands.len() => None,
+            // An operand whose value is left may not be negated.
+            Some((if_true, target)) if target == exit => {
+                (operand.is_value && jumps(if_true, target)).then(|| operand.value.clone())
+            }
+            Some((if_true, target)) if jumps(if_true, target) => Some(operand.value.clone()),
+            Some((if_true, target)) => jumps(!if_true, target).then(|| operand.value.clone().not()),
+        };
+    }
+    (i..j).find_map(|m| {
+        let s = operands[m + 1].start;
+        let r = build_value(operands, m + 1, j, t, f, exit)?;
+        if let Some(l) = build_value(operands, i, m, t, s, exit) {
+            Some(Expr::binary(BinOp::Or, l, r))
+        } else {
+            let l = build_value(operands, i, m, s, f, exit)?;
+            Some(Expr::binary(BinOp::And, l, r))
+        }
+    })
+}
+
+/// A JMP that is no part of a statement recognized around it.
+#[derive(Debug)]
+struct UnexpectedJump {
+    pc: usize,
+    target: usize,
+}
+
+impl std::fmt::Display for UnexpectedJump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unexpected jump to {} at pc {}", self.target, self.pc)
+    }
+}
+
+impl std::error::Error for UnexpectedJump {}
+
+/// Whether `fun` or a function nested in it reads or writes a global.
+fn mentions_global(fun: &Function, name: &[u8]) -> bool {
+    let mentions = |&raw: &u32| {
+        Instruction::decode(raw).is_ok_and(|i| {
+            matches!(i.op, OpCode::GetGlobal | OpCode::SetGlobal)
+                && matches!(fun.constants.get(i.bx() as usize), Some(Constant::String(s)) if s.as_bytes() == name)
+        })
+    };
+    fun.code.iter().any(mentions) || fun.funs.iter().any(|f| mentions_global(f, name))
+}
+
+/// Decompiles the function at `path` in the tree.
+fn decompile_body(fun: &Function, path: &str) -> anyhow::Result<Body> {
+    let mut back_jumps = HashMap::<usize, Vec<usize>>::new();
+    for (j, &raw) in fun.code.iter().enumerate() {
+        if let Ok(i) = Instruction::decode(raw)
+            && i.op == OpCode::Jmp
+            && let Ok(target) = usize::try_from(j as i64 + 1 + i64::from(i.sbx()))
+            && target <= j
+        {
+            back_jumps.entry(target).or_default().push(j);
+        }
+    }
+    let mut d = Decompiler {
+        fun,
+        back_jumps: &back_jumps,
+        path: path.to_owned(),
+        pending: HashMap::new(),
+        open: None,
+        declared: HashSet::new(),
+        multi: None,
+        capture: None,
+        blocks: vec![],
+    };
+    let mut params = vec![];
+    // Parameters of a function with no code but a RETURN die where they
+    // start.
+    let declarable = d.declarable(0);
+    for r in 0..fun.num_params {
+        params.push(match declarable.get(usize::from(r)).copied() {
+            Some(local) => {
+                d.declared.insert(local);
+                d.name(local)
+            }
+            None => format!("arg{}", r + 1),
+        });
+    }
+    // The `arg` table of vararg functions.
+    if fun.is_vararg.contains(VarargFlags::HASARG)
+        && let Some(&local) = declarable.get(usize::from(fun.num_params))
+    {
+        d.declared.insert(local);
+    }
+    let end = fun.code.len();
+    let block = d
+        .block(0, &Scope::new(end, vec![end], vec![]))
+        .with_context(|| format!("cannot decompile function {}", path))?;
+    Ok(Body {
+        params,
+        vararg: fun.is_vararg.contains(VarargFlags::ISVARARG),
+        block,
+    })
+}
+
+/// Renames the locals and upvalues of `fun` and its children whose debug
+/// names are not identifiers, as obfuscators leave them, to `p1, l1, u1,
+/// ...` like stripped ones. A name is replaced the same way throughout, so
+/// that upvalues keep naming the locals they capture.
+fn rename(fun: &mut Function) {
+    fn reserve(fun: &Function, names: &mut Names) {
+        for name in fun.locvars.iter().map(|l| &l.varname).chain(&fun.upvalues) {
+            names.reserve(name);
+        }
+        for child in &fun.funs {
+            reserve(child, names);
+        }
+    }
+    fn rename_with(
+        fun: &mut Function,
+        names: &mut Names,
+        renamed: &mut HashMap<LuaString, LuaString>,
+    ) {
+        let mut replace = |name: &mut LuaString, prefix| {
+            if !is_name(name) && name.first() != Some(&b'(') {
+                *name = renamed
+                    .entry(name.clone())
+                    .or_insert_with(|| names.next(prefix))
+                    .clone();
+            }
+        };
+        let num_params = usize::from(fun.num_params);
+        for (i, l) in fun.locvars.iter_mut().enumerate() {
+            replace(&mut l.varname, if i < num_params { "p" } else { "l" });
+        }
+        for name in &mut fun.upvalues {
+            replace(name, "u");
+        }
+        for child in &mut fun.funs {
+            rename_with(child, names, renamed);
+        }
+    }
+    let mut names = Names::new(fun);
+    reserve(fun, &mut names);
+    rename_with(fun, &mut names, &mut HashMap::new());
+}
+
+/// Decompiles a main function into Lua 5.1 source. Code that `verify`
+/// rejects is refused, as its registers and operands may be out of range.
+pub fn decompile(fun: &Function) -> anyhow::Result<String> {
+    if let Some(d) = verify(fun).first() {
+        bail!("cannot decompile invalid code: {}", d);
+    }
+    // Any function in the tree may have been stripped, whether or not its
+    // line info was.
+    let mut fun = fun.clone();
+    restore(&mut fun)?;
+    rename(&mut fun);
+    let body = decompile_body(&fun, "main")?;
+    let mut p = Printer {
+        out: String::new(),
+        indent: 0,
+        last: None,
+    };
+    for s in &body.block {
+        p.stat(s);
+        p.line();
+    }
+    Ok(p.out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::decompile::*;
+
+    #[test]
+    fn test() {
+        // local t = {1, 2, x = "y"}
+        // local n = 0
+        // for i = 1, #t do
+        //   if t[i] > 1 then n = n + t[i] else n = n - 1 end
+        // end
+        // for k, v in pairs(t) do
+        //   while n > 10 do n = n / 2 end
+        // end
+        // local function add(d)
+        //   n = n + d
+        //   return n
+        // end
+        // repeat add(1) until n > 5
+        // obj:method(n, add)
+        let fun = Function {
+            code: vec![
+                0x0100400a, 0x00000041, 0x00004081, 0x8140c009, 0x01004022, 0x00010041, 0x00000081,
+                0x000000d4, 0x00000101, 0x800180a0, 0x00014186, 0x80018018, 0x80008016, 0x00014186,
+                0x0081804c, 0x80000016, 0x00c0004d, 0x7ffdc09f, 0x00014085, 0x000000c0, 0x0101009c,
+                0x8000c016, 0x83004018, 0x80004016, 0x00c0404f, 0x7ffec016, 0x000080a1, 0x7ffe4016,
+                0x000000a4, 0x00800000, 0x010000c0, 0x00000101, 0x010040dc, 0x83804018, 0x7ffe8016,
+                0x000200c5, 0x01c240cb, 0x00800140, 0x01000180, 0x020040dc, 0x0080001e,
+            ],
+            constants: vec![
+                Constant::Number(1.0),
+                Constant::Number(2.0),
+                Constant::String("x".into()),
+                Constant::String("y".into()),
+                Constant::Number(0.0),
+                Constant::String("pairs".into()),
+                Constant::Number(10.0),
+                Constant::Number(5.0),
+                Constant::String("obj".into()),
+                Constant::String("method".into()),
+            ],
+            funs: vec![Function {
+                code: vec![
+                    0x00000044, 0x0080004c, 0x00000048, 0x00000044, 0x0100005e, 0x0080001e,
+                ],
+                constants: vec![],
+                locvars: vec![LocVar {
+                    varname: "d".into(),
+                    startpc: 0,
+                    endpc: 5,
+                }],
+                upvalues: vec!["n".into()],
+                nups: 1,
+                num_params: 1,
+                maxstacksize: 2,
+                ..Default::default()
+            }],
+            locvars: vec![
+                LocVar {
+                    varname: "t".into(),
+                    startpc: 5,
+                    endpc: 40,
+                },
+                LocVar {
+                    varname: "n".into(),
+                    startpc: 6,
+                    endpc: 40,
+                },
+                LocVar {
+                    varname: "(for index)".into(),
+                    startpc: 9,
+                    endpc: 18,
+                },
+                LocVar {
+                    varname: "(for limit)".into(),
+                    startpc: 9,
+                    endpc: 18,
+                },
+                LocVar {
+                    varname: "(for step)".into(),
+                    startpc: 9,
+                    endpc: 18,
+                },
+                LocVar {
+                    varname: "i".into(),
+                    startpc: 10,
+                    endpc: 17,
+                },
+                LocVar {
+                    varname: "(for generator)".into(),
+                    startpc: 21,
+                    endpc: 28,
+                },
+                LocVar {
+                    varname: "(for state)".into(),
+                    startpc: 21,
+                    endpc: 28,
+                },
+                LocVar {
+                    varname: "(for control)".into(),
+                    startpc: 21,
+                    endpc: 28,
+                },
+                LocVar {
+                    varname: "k".into(),
+                    startpc: 22,
+                    endpc: 26,
+                },
+                LocVar {
+                    varname: "v".into(),
+                    startpc: 22,
+                    endpc: 26,
+                },
+                LocVar {
+                    varname: "add".into(),
+                    startpc: 30,
+                    endpc: 40,
+                },
+            ],
+            is_vararg: VarargFlags::ISVARARG,
+            maxstacksize: 8,
+            ..Default::default()
+        };
+        assert_eq!(
+            decompile(&fun).unwrap(),
+            "local t = {1, 2, x = \"y\"}\n\
+local n = 0\n\
+for i = 1, #t do\n\
+\tif t[i] > 1 then\n\
+\t\tn = n + t[i]\n\
+\telse\n\
+\t\tn = n - 1\n\
+\tend\n\
+end\n\
+for k, v in pairs(t) do\n\
+\twhile n > 10 do\n\
+\t\tn = n / 2\n\
+\tend\n\
+end\n\
+local function add(d)\n\
+\tn = n + d\n\
+\treturn n\n\
+end\n\
+repeat\n\
+\tadd(1)\n\
+until n > 5\n\
+obj:method(n, add)\n\
+"
+        );
+
+        // Debug names that are not identifiers are replaced, the same way
+        // in the closure capturing them.
+        let mut renamed = fun.clone();
+        renamed.locvars[1].varname = "not".into();
+        renamed.funs[0].upvalues[0] = "not".into();
+        renamed.funs[0].locvars[0].varname = LuaString(vec![0xff]);
+        let source = decompile(&renamed).unwrap();
+        assert!(source.contains("local l1 = 0\n"));
+        assert!(source.contains("local function add(p1)\n\tl1 = l1 + p1\n\treturn l1\n"));
+        assert!(source.ends_with("obj:method(l1, add)\n"));
+
+        // A method that is not a name cannot be called with `:`.
+        let mut renamed = fun.clone();
+        renamed.constants[9] = Constant::String("my method".into());
+        assert!(decompile(&renamed).is_err());
+
+        // A local declared last in a block dies where it starts.
+        // do local x = 1 end local y = 2
+        let fun = Function {
+            code: vec![0x00000001, 0x00004001, 0x0080001e],
+            constants: vec![Constant::Number(1.0), Constant::Number(2.0)],
+            locvars: vec![
+                LocVar {
+                    varname: "x".into(),
+                    startpc: 1,
+                    endpc: 1,
+                },
+                LocVar {
+                    varname: "y".into(),
+                    startpc: 2,
+                    endpc: 2,
+                },
+            ],
+            is_vararg: VarargFlags::ISVARARG,
+            maxstacksize: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            decompile(&fun).unwrap(),
+            "do\n\tlocal x = 1\nend\nlocal y = 2\n"
+        );
+
+        // do local x = 1 end print(2)
+        let fun = Function {
+            code: vec![0x00000001, 0x00004005, 0x00008041, 0x0100401c, 0x0080001e],
+            constants: vec![
+                Constant::Number(1.0),
+                Constant::String("print".into()),
+                Constant::Number(2.0),
+            ],
+            locvars: vec![LocVar {
+                varname: "x".into(),
+                startpc: 1,
+                endpc: 1,
+            }],
+            is_vararg: VarargFlags::ISVARARG,
+            maxstacksize: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            decompile(&fun).unwrap(),
+            "do\n\tlocal x = 1\nend\nprint(2)\n"
+        );
+
+        // A call starting with a parenthesis is kept from the one before.
+        // f() ("x"):upper()
+        let fun = Function {
+            code: vec![
+                0x00000005, 0x0080401c, 0x00004001, 0x0040800b, 0x0100401c, 0x0080001e,
+            ],
+            constants: vec![
+                Constant::String("f".into()),
+                Constant::String("x".into()),
+                Constant::String("upper".into()),
+            ],
+            is_vararg: VarargFlags::ISVARARG,
+            maxstacksize: 2,
+            ..Default::default()
+        };
+        assert_eq!(decompile(&fun).unwrap(), "f();\n(\"x\"):upper()\n");
+
+        // Jumps that would have a block decompiled within itself are
+        // refused rather than recursed into.
+        // EQ 0 0 K0; JMP 5; JMP 4; JMP 5; JMP 5; RETURN 0 1
+        let fun = Function {
+            code: vec![
+                0x00400017, 0x80008016, 0x80000016, 0x80000016, 0x7fffc016, 0x0080001e,
+            ],
+            constants: vec![Constant::Number(1.0)],
+            num_params: 1,
+            maxstacksize: 2,
+            ..Default::default()
+        };
+        assert!(verify(&fun).is_empty());
+        assert!(decompile(&fun).is_err());
+
+        // A CALL at register 255 of a 2-slot stack is refused, not decoded.
+        let fun = Function {
+            code: vec![28 | 255 << 6 | 1 << 14 | 2 << 23, 0x0080001e],
+            is_vararg: VarargFlags::ISVARARG,
+            maxstacksize: 2,
+            ..Default::default()
+        };
+        assert!(decompile(&fun).is_err());
+
+        // Stripped, the locals are inferred.
+        // do local x = 1 end local y = 2
+        let fun = Function {
+            code: vec![0x00000001, 0x00004001, 0x0080001e],
+            constants: vec![Constant::Number(1.0), Constant::Number(2.0)],
+            is_vararg: VarargFlags::ISVARARG,
+            maxstacksize: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            decompile(&fun).unwrap(),
+            "do\n\tlocal l1 = 1\nend\nlocal l2 = 2\n"
+        );
+
+        // local x = 1 if x then x = 2 end
+        let fun = Function {
+            code: vec![0x00000001, 0x0000001a, 0x80000016, 0x00004001, 0x0080001e],
+            constants: vec![Constant::Number(1.0), Constant::Number(2.0)],
+            is_vararg: VarargFlags::ISVARARG,
+            maxstacksize: 2,
+            ..Default::default()
+        };
+        assert_eq!(decompile(&fun).unwrap(), "local l1 = 1\nl1 = l1 and 2\n");
+
+        // local x = f() if x > 1 then x = 2 else x = 3 end return x
+        let fun = Function {
+            code: vec![
+                0x00000005, 0x0080801c, 0x80800018, 0x80004016, 0x00008001, 0x80000016, 0x0000c001,
+                0x0100001e, 0x0080001e,
+            ],
+            constants: vec![
+                Constant::String("f".into()),
+                Constant::Number(1.0),
+                Constant::Number(2.0),
+                Constant::Number(3.0),
+            ],
+            is_vararg: VarargFlags::ISVARARG,
+            maxstacksize: 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            decompile(&fun).unwrap(),
+            "local l1 = f()\n\
+if l1 > 1 then\n\
+\tl1 = 2\n\
+else\n\
+\tl1 = 3\n\
+end\n\
+return l1\n\
+"
+        );
+    }
+}
@@ -0,0 +1,575 @@
+use crate::cfg::{self, EdgeKind};
+use crate::instruction::{Instruction, OpArgMask, OpCode, OpMode, Rk};
+use crate::undump::spans::{ChunkSpans, FunctionSpans, HeaderSpans};
+use crate::undump::{Chunk, Constant, Function, Header};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result, Write};
+
+/// A `luac -l -l` style listing of a function and all of its children.
+///
+/// Where luac prints the address of a function prototype, the listing prints
+/// its path in the function tree instead, e.g. `main.3.1` for the second
+/// child of the fourth child of the main function. Vararg functions also
+/// have their `VarargFlags` spelled out after the parameter count.
+pub struct Listing<'a>(pub &'a Function);
+
+impl Display for Listing<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        print_function(f, self.0, "main", b"=?")
+    }
+}
+
+/// Formats a number like C's `%.14g`, i.e. `LUA_NUMBER_FMT`.
+pub fn format_number(n: f64) -> String {
+    if n.is_nan() {
+        return if n.is_sign_negative() { "-nan" } else { "nan" }.to_owned();
+    }
+    if n.is_infinite() {
+        return if n < 0.0 { "-inf" } else { "inf" }.to_owned();
+    }
+    if n == 0.0 {
+        return if n.is_sign_negative() { "-0" } else { "0" }.to_owned();
+    }
+    let sci = format!("{:.13e}", n);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    if !(-4..14).contains(&exp) {
+        let mantissa = trim_fraction(mantissa);
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", mantissa, sign, exp.abs())
+    } else {
+        trim_fraction(&format!("{:.*}", (13 - exp) as usize, n)).to_owned()
+    }
+}
+
+fn trim_fraction(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+/// Quotes a string constant the way luac's `PrintString` does.
+pub fn quote_string(s: &[u8]) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for &c in s {
+        match c {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            0x07 => out.push_str("\\a"),
+            0x08 => out.push_str("\\b"),
+            0x0c => out.push_str("\\f"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x0b => out.push_str("\\v"),
+            0x20..=0x7e => out.push(c.into()),
+            _ => write!(out, "\\{:03}", c).unwrap(),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 { "" } else { "s" }
+}
+
+fn print_constant(f: &mut Formatter<'_>, fun: &Function, i: usize) -> Result {
+    match fun.constants.get(i) {
+        Some(Constant::Nil) => f.write_str("nil"),
+        Some(Constant::Boolean(b)) => write!(f, "{}", b),
+        Some(Constant::Number(n)) => f.write_str(&format_number(*n)),
+        Some(Constant::Integer(n)) => write!(f, "{}", n),
+        Some(Constant::String(s)) => f.write_str(&quote_string(s)),
+        None => f.write_str("?"),
+    }
+}
+
+fn print_rk(f: &mut Formatter<'_>, fun: &Function, x: u16) -> Result {
+    match Rk::from(x) {
+        Rk::Constant(k) => print_constant(f, fun, k.into()),
+        Rk::Register(_) => f.write_str("-"),
+    }
+}
+
+fn print_header(f: &mut Formatter<'_>, fun: &Function, path: &str, source: &[u8]) -> Result {
+    let source = match source {
+        [b'@' | b'=', s @ ..] => String::from_utf8_lossy(s),
+        [0x1b, ..] => "(bstring)".into(),
+        _ => "(string)".into(),
+    };
+    let ncode = fun.code.len();
+    writeln!(
+        f,
+        "\n{} <{}:{},{}> ({} instruction{}, {} bytes at {})",
+        if fun.line_defined == 0 {
+            "main"
+        } else {
+            "function"
+        },
+        source,
+        fun.line_defined,
+        fun.last_line_defined,
+        ncode,
+        plural(ncode),
+        ncode * 4,
+        path,
+    )?;
+    write!(
+        f,
+        "{}{} param{}{}, {} slot{}, {} upvalue{}, ",
+        fun.num_params,
+        if fun.is_vararg.is_empty() { "" } else { "+" },
+        plural(fun.num_params.into()),
+        if fun.is_vararg.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", fun.is_vararg)
+        },
+        fun.maxstacksize,
+        plural(fun.maxstacksize.into()),
+        fun.nups,
+        plural(fun.nups.into()),
+    )?;
+    writeln!(
+        f,
+        "{} local{}, {} constant{}, {} function{}",
+        fun.locvars.len(),
+        plural(fun.locvars.len()),
+        fun.constants.len(),
+        plural(fun.constants.len()),
+        fun.funs.len(),
+        plural(fun.funs.len()),
+    )
+}
+
+fn print_operands(f: &mut Formatter<'_>, i: Instruction) -> Result {
+    let rk = |x: u16| match Rk::from(x) {
+        Rk::Constant(k) => -1 - i32::from(k),
+        Rk::Register(r) => r.into(),
+    };
+    match i.op.mode() {
+        OpMode::ABC => {
+            write!(f, "{}", i.a)?;
+            if i.op.b_mode() != OpArgMask::N {
+                write!(f, " {}", rk(i.b))?;
+            }
+            if i.op.c_mode() != OpArgMask::N {
+                write!(f, " {}", rk(i.c))?;
+            }
+            Ok(())
+        }
+        OpMode::ABx if i.op.b_mode() == OpArgMask::K => {
+            write!(f, "{} {}", i.a, -1 - i.bx() as i64)
+        }
+        OpMode::ABx => write!(f, "{} {}", i.a, i.bx()),
+        OpMode::AsBx if i.op == OpCode::Jmp => write!(f, "{}", i.sbx()),
+        OpMode::AsBx => write!(f, "{} {}", i.a, i.sbx()),
+        OpMode::Ax => write!(f, "{}", i.ax()),
+    }
+}
+
+fn print_comment(
+    f: &mut Formatter<'_>,
+    fun: &Function,
+    path: &str,
+    pc: usize,
+    i: Instruction,
+) -> Result {
+    match i.op {
+        OpCode::LoadK => {
+            f.write_str("\t; ")?;
+            print_constant(f, fun, i.bx() as usize)
+        }
+        OpCode::GetUpval | OpCode::SetUpval => {
+            let name = match fun.upvalues.get(usize::from(i.b)) {
+                Some(name) => name.to_str_lossy(),
+                None if fun.upvalues.is_empty() => "-".into(),
+                None => "?".into(),
+            };
+            write!(f, "\t; {}", name)
+        }
+        OpCode::GetGlobal | OpCode::SetGlobal => match fun.constants.get(i.bx() as usize) {
+            Some(Constant::String(s)) => write!(f, "\t; {}", s),
+            _ => {
+                f.write_str("\t; ")?;
+                print_constant(f, fun, i.bx() as usize)
+            }
+        },
+        OpCode::GetTable | OpCode::Self_ => match i.rk_c() {
+            Rk::Constant(k) => {
+                f.write_str("\t; ")?;
+                print_constant(f, fun, k.into())
+            }
+            Rk::Register(_) => Ok(()),
+        },
+        OpCode::SetTable
+        | OpCode::Add
+        | OpCode::Sub
+        | OpCode::Mul
+        | OpCode::Div
+        | OpCode::Mod
+        | OpCode::Pow
+        | OpCode::Eq
+        | OpCode::Lt
+        | OpCode::Le => {
+            if matches!(i.rk_b(), Rk::Constant(_)) || matches!(i.rk_c(), Rk::Constant(_)) {
+                f.write_str("\t; ")?;
+                print_rk(f, fun, i.b)?;
+                f.write_str(" ")?;
+                print_rk(f, fun, i.c)?;
+            }
+            Ok(())
+        }
+        OpCode::Jmp | OpCode::ForLoop | OpCode::ForPrep => {
+            write!(f, "\t; to {}", i.sbx() as i64 + pc as i64 + 2)
+        }
+        OpCode::Closure => write!(f, "\t; {}.{}", path, i.bx()),
+        OpCode::SetList if i.c == 0 => match fun.code.get(pc + 1) {
+            Some(c) => write!(f, "\t; {}", c),
+            None => f.write_str("\t; ?"),
+        },
+        OpCode::SetList => write!(f, "\t; {}", i.c),
+        _ => Ok(()),
+    }
+}
+
+fn print_code(f: &mut Formatter<'_>, fun: &Function, path: &str) -> Result {
+    let mut pc = 0;
+    while pc < fun.code.len() {
+        write!(f, "\t{}\t", pc + 1)?;
+        match fun.lineinfo.get(pc) {
+            Some(&line) if line > 0 => write!(f, "[{}]\t", line)?,
+            _ => f.write_str("[-]\t")?,
+        }
+        let raw = fun.code[pc];
+        match Instruction::decode(raw) {
+            std::result::Result::Ok(i) => {
+                write!(f, "{:<9}\t", i.op.name())?;
+                print_operands(f, i)?;
+                print_comment(f, fun, path, pc, i)?;
+                if i.op == OpCode::SetList && i.c == 0 {
+                    pc += 1;
+                }
+            }
+            Err(_) => write!(f, "{:<9}\t{:#010x}", "?", raw)?,
+        }
+        writeln!(f)?;
+        pc += 1;
+    }
+    Ok(())
+}
+
+fn print_function(f: &mut Formatter<'_>, fun: &Function, path: &str, source: &[u8]) -> Result {
+    let source = fun.source.as_deref().unwrap_or(source);
+    print_header(f, fun, path, source)?;
+    print_code(f, fun, path)?;
+    writeln!(f, "constants ({}) for {}:", fun.constants.len(), path)?;
+    for i in 0..fun.constants.len() {
+        write!(f, "\t{}\t", i + 1)?;
+        print_constant(f, fun, i)?;
+        writeln!(f)?;
+    }
+    writeln!(f, "locals ({}) for {}:", fun.locvars.len(), path)?;
+    for (i, l) in fun.locvars.iter().enumerate() {
+        writeln!(
+            f,
+            "\t{}\t{}\t{}\t{}",
+            i,
+            l.varname,
+            l.startpc as i64 + 1,
+            l.endpc as i64 + 1
+        )?;
+    }
+    writeln!(f, "upvalues ({}) for {}:", fun.upvalues.len(), path)?;
+    for (i, name) in fun.upvalues.iter().enumerate() {
+        writeln!(f, "\t{}\t{}", i, name)?;
+    }
+    for (i, child) in fun.funs.iter().enumerate() {
+        print_function(f, child, &format!("{}.{}", path, i), source)?;
+    }
+    Ok(())
+}
+
+/// An annotated hex dump of a Lua 5.1 chunk, one field per line, each
+/// labelled with its path as in `ChunkSpans::labels` and its value. Fields
+/// longer than 16 bytes continue on the following lines.
+pub struct Hexdump<'a> {
+    pub data: &'a [u8],
+    pub chunk: &'a Chunk,
+    pub spans: &'a ChunkSpans,
+}
+
+impl Display for Hexdump<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let mut values = HashMap::new();
+        header_values(&mut values, &self.chunk.header, &self.spans.header);
+        function_values(&mut values, &self.chunk.main, &self.spans.main, "main");
+        for (span, label) in self.spans.labels() {
+            let bytes = &self.data[span.clone()];
+            for (row, piece) in bytes.chunks(16).enumerate() {
+                let hex: Vec<_> = piece.iter().map(|b| format!("{:02x}", b)).collect();
+                let offset = span.start + row * 16;
+                if row > 0 {
+                    write!(f, "{:08x}  {}", offset, hex.join(" "))?;
+                } else {
+                    write!(f, "{:08x}  {:<47}  {}", offset, hex.join(" "), label)?;
+                    if let Some(value) = values.get(&span.start) {
+                        write!(f, " {}", value)?;
+                    }
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The control-flow graphs of a function and all of its children in Graphviz
+/// DOT, one cluster per function, named by its path as in `Listing`. Blocks
+/// are labelled with their disassembly, and edges with the outcome of the
+/// test or loop they leave.
+pub struct Dot<'a>(pub &'a Function);
+
+impl Display for Dot<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        writeln!(f, "digraph chunk {{")?;
+        writeln!(f, "\tnode [shape=box, fontname=monospace];")?;
+        print_cluster(f, self.0, "main")?;
+        writeln!(f, "}}")
+    }
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Prints a function's graph, or if it has none, a node saying why.
+fn print_cluster(f: &mut Formatter<'_>, fun: &Function, path: &str) -> Result {
+    writeln!(f, "\tsubgraph \"cluster_{}\" {{", path)?;
+    writeln!(f, "\t\tlabel=\"{}\";", path)?;
+    match cfg::build(fun) {
+        std::result::Result::Ok(cfg) => {
+            let lines = describe_code(fun, path);
+            for (b, block) in cfg.blocks.iter().enumerate() {
+                let mut label = String::new();
+                for (pc, line) in lines.iter().enumerate().take(block.end).skip(block.start) {
+                    write!(label, "{}: {}\\l", pc + 1, dot_escape(line))?;
+                }
+                writeln!(f, "\t\t\"{}/{}\" [label=\"{}\"];", path, b, label)?;
+            }
+            for (b, block) in cfg.blocks.iter().enumerate() {
+                for e in &block.succs {
+                    write!(f, "\t\t\"{}/{}\" -> \"{}/{}\"", path, b, path, e.target)?;
+                    match e.kind {
+                        EdgeKind::True => f.write_str(" [label=true]")?,
+                        EdgeKind::False => f.write_str(" [label=false]")?,
+                        EdgeKind::Loop => f.write_str(" [label=loop]")?,
+                        EdgeKind::LoopExit => f.write_str(" [label=exit]")?,
+                        EdgeKind::Fallthrough | EdgeKind::Jump => {}
+                    }
+                    writeln!(f, ";")?;
+                }
+            }
+        }
+        Err(e) => {
+            let label = dot_escape(&e.to_string());
+            writeln!(f, "\t\t\"{}\" [label=\"{}\"];", path, label)?;
+        }
+    }
+    writeln!(f, "\t}}")?;
+    for (i, child) in fun.funs.iter().enumerate() {
+        print_cluster(f, child, &format!("{}.{}", path, i))?;
+    }
+    Ok(())
+}
+
+/// An instruction as the listing prints it, minus the padding.
+struct Code<'a> {
+    fun: &'a Function,
+    path: &'a str,
+    pc: usize,
+}
+
+impl Display for Code<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match Instruction::decode(self.fun.code[self.pc]) {
+            std::result::Result::Ok(i) => {
+                write!(f, "{} ", i.op.name())?;
+                print_operands(f, i)?;
+                print_comment(f, self.fun, self.path, self.pc, i)
+            }
+            Err(_) => f.write_str("?"),
+        }
+    }
+}
+
+/// Each word of `fun`'s code as a line of the listing, minus the padding.
+fn describe_code(fun: &Function, path: &str) -> Vec<String> {
+    let mut batch = false;
+    (0..fun.code.len())
+        .map(|pc| {
+            if batch {
+                batch = false;
+                return format!("(SETLIST batch {})", fun.code[pc]);
+            }
+            batch = matches!(
+                Instruction::decode(fun.code[pc]),
+                std::result::Result::Ok(i) if i.op == OpCode::SetList && i.c == 0
+            );
+            Code { fun, path, pc }.to_string().replace('\t', " ")
+        })
+        .collect()
+}
+
+fn header_values(values: &mut HashMap<usize, String>, h: &Header, spans: &HeaderSpans) {
+    values.insert(spans.version.start, format!("{:#04x}", h.version));
+    values.insert(spans.format.start, h.format.to_string());
+    values.insert(spans.endianness.start, format!("{:?}", h.endianness));
+    values.insert(spans.sizeof_int.start, h.sizeof_int.to_string());
+    values.insert(spans.sizeof_size_t.start, h.sizeof_size_t.to_string());
+    values.insert(
+        spans.sizeof_instruction.start,
+        h.sizeof_instruction.to_string(),
+    );
+    values.insert(spans.sizeof_number.start, h.sizeof_number.to_string());
+    values.insert(spans.integral.start, h.integral.to_string());
+}
+
+fn function_values(
+    values: &mut HashMap<usize, String>,
+    fun: &Function,
+    spans: &FunctionSpans,
+    path: &str,
+) {
+    let source = match &fun.source {
+        Some(s) => quote_string(s.as_bytes()),
+        None => "none".to_owned(),
+    };
+    values.insert(spans.source.start, source);
+    for (span, value) in [
+        (&spans.line_defined, fun.line_defined.to_string()),
+        (&spans.last_line_defined, fun.last_line_defined.to_string()),
+        (&spans.nups, fun.nups.to_string()),
+        (&spans.num_params, fun.num_params.to_string()),
+        (&spans.is_vararg, fun.is_vararg.to_string()),
+        (&spans.maxstacksize, fun.maxstacksize.to_string()),
+        (&spans.code.count, fun.code.len().to_string()),
+        (&spans.constants.count, fun.constants.len().to_string()),
+        (&spans.funs.count, fun.funs.len().to_string()),
+        (&spans.lineinfo.count, fun.lineinfo.len().to_string()),
+        (&spans.locvars.count, fun.locvars.len().to_string()),
+        (&spans.upvalues.count, fun.upvalues.len().to_string()),
+    ] {
+        values.insert(span.start, value);
+    }
+    for (value, span) in describe_code(fun, path).into_iter().zip(&spans.code.items) {
+        values.insert(span.start, value);
+    }
+    for (k, span) in spans.constants.items.iter().enumerate() {
+        let value = match &fun.constants[k] {
+            Constant::Nil => "nil".to_owned(),
+            Constant::Boolean(b) => format!("boolean {}", b),
+            Constant::Number(n) => format!("number {}", format_number(*n)),
+            Constant::Integer(n) => format!("integer {}", n),
+            Constant::String(s) => format!("string {}", quote_string(s.as_bytes())),
+        };
+        values.insert(span.start, value);
+    }
+    for (i, (child, child_spans)) in fun.funs.iter().zip(&spans.funs.items).enumerate() {
+        function_values(values, child, child_spans, &format!("{}.{}", path, i));
+    }
+    for (line, span) in fun.lineinfo.iter().zip(&spans.lineinfo.items) {
+        values.insert(span.start, line.to_string());
+    }
+    for (l, span) in fun.locvars.iter().zip(&spans.locvars.items) {
+        values.insert(span.varname.start, quote_string(l.varname.as_bytes()));
+        values.insert(span.startpc.start, l.startpc.to_string());
+        values.insert(span.endpc.start, l.endpc.to_string());
+    }
+    for (name, span) in fun.upvalues.iter().zip(&spans.upvalues.items) {
+        values.insert(span.start, quote_string(name.as_bytes()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disasm::*;
+    use crate::undump::VarargFlags;
+
+    #[test]
+    fn test() {
+        let fun = Function {
+            source: Some("@wat.lua".into()),
+            line_defined: 0,
+            last_line_defined: 0,
+            nups: 0,
+            num_params: 0,
+            is_vararg: VarargFlags::ISVARARG,
+            maxstacksize: 2,
+            code: vec![1, 16449, 25165854, 8388638],
+            constants: vec![Constant::Number(42.0), Constant::String("hello".into())],
+            funs: vec![],
+            lineinfo: vec![1, 1, 1, 1],
+            locvars: vec![],
+            upvalues: vec![],
+        };
+        assert_eq!(
+            Listing(&fun).to_string(),
+            "
+main <wat.lua:0,0> (4 instructions, 16 bytes at main)
+0+ params (ISVARARG), 2 slots, 0 upvalues, 0 locals, 2 constants, 0 functions
+\t1\t[1]\tLOADK    \t0 -1\t; 42
+\t2\t[1]\tLOADK    \t1 -2\t; \"hello\"
+\t3\t[1]\tRETURN   \t0 3
+\t4\t[1]\tRETURN   \t0 1
+constants (2) for main:
+\t1\t42
+\t2\t\"hello\"
+locals (0) for main:
+upvalues (0) for main:
+"
+        );
+        assert_eq!(
+            Dot(&fun).to_string(),
+            "digraph chunk {
+\tnode [shape=box, fontname=monospace];
+\tsubgraph \"cluster_main\" {
+\t\tlabel=\"main\";
+\t\t\"main/0\" [label=\"1: LOADK 0 -1 ; 42\\l2: LOADK 1 -2 ; \\\"hello\\\"\\l3: RETURN 0 3\\l\"];
+\t\t\"main/1\" [label=\"4: RETURN 0 1\\l\"];
+\t}
+}
+"
+        );
+        let data = crate::dump::dump(&fun).unwrap();
+        let (chunk, spans) = crate::undump::undump_with_spans(&data).unwrap();
+        let (data, chunk, spans) = (&data, &chunk, &spans);
+        let hexdump = Hexdump { data, chunk, spans }.to_string();
+        let lines: Vec<_> = hexdump.lines().collect();
+        // The 17-byte source wraps onto a second, unlabelled row.
+        assert_eq!(lines.len(), spans.labels().len() + 1);
+        assert_eq!(
+            lines[1],
+            "00000004  51                                               header.version 0x51"
+        );
+        assert_eq!(lines[10], "0000001c  00");
+        assert!(lines.contains(
+            &"00000031  41 40 00 00                                      main.code[1] LOADK 1 -2 ; \"hello\""
+        ));
+        assert!(
+            lines
+                .iter()
+                .any(|l| l.ends_with("main.constants[1] string \"hello\""))
+        );
+        assert_eq!(format_number(0.1), "0.1");
+        assert_eq!(format_number(1e100), "1e+100");
+        assert_eq!(format_number(-2.5e-7), "-2.5e-07");
+        assert_eq!(format_number(1234567890123456.0), "1.2345678901235e+15");
+        assert_eq!(format_number(1.0 / 3.0), "0.33333333333333");
+        assert_eq!(quote_string(b"a\n\"\xff"), "\"a\\n\\\"\\255\"");
+    }
+}
@@ -0,0 +1,275 @@
+use crate::undump::{Chunk, Constant, Endianness, Function, Header};
+use bytes::BufMut;
+
+/// Why a function cannot be written with a header's sizes, so that loading
+/// the chunk back would not give the same function.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// A count, length or line number does not fit the header's `int` or
+    /// `size_t`, or the u32 `undump` loads an `int` into.
+    IntOutOfRange(u64),
+    /// An integer constant does not fit the header's `lua_Number`.
+    IntegerOutOfRange(i64),
+    /// A number the header's `lua_Number` cannot hold exactly.
+    InexactNumber(f64),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IntOutOfRange(n) => write!(f, "int out of range ({})", n),
+            Error::IntegerOutOfRange(n) => write!(f, "integer out of range ({})", n),
+            Error::InexactNumber(n) => write!(f, "number not representable ({})", n),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+trait LuacBufMut: BufMut {
+    fn put_header(&mut self, h: &Header) {
+        self.put_slice(b"\x1bLua");
+        self.put_u8(h.version);
+        self.put_u8(h.format);
+        self.put_u8(match h.endianness {
+            Endianness::Big => 0,
+            Endianness::Little => 1,
+        });
+        self.put_u8(h.sizeof_int);
+        self.put_u8(h.sizeof_size_t);
+        self.put_u8(h.sizeof_instruction);
+        self.put_u8(h.sizeof_number);
+        self.put_u8(h.integral.into());
+    }
+    fn put_sized(&mut self, h: &Header, size: u8, n: u64) -> Result<(), Error> {
+        if size < 8 && n >> (8 * u32::from(size)) != 0 {
+            return Err(Error::IntOutOfRange(n));
+        }
+        match h.endianness {
+            Endianness::Big => self.put_uint(n, size.into()),
+            Endianness::Little => self.put_uint_le(n, size.into()),
+        }
+        Ok(())
+    }
+    fn put_cint(&mut self, h: &Header, n: usize) -> Result<(), Error> {
+        let n = u64::try_from(n).map_err(|_| Error::IntOutOfRange(u64::MAX))?;
+        if n > u32::MAX.into() {
+            return Err(Error::IntOutOfRange(n));
+        }
+        self.put_sized(h, h.sizeof_int, n)
+    }
+    fn put_number(&mut self, h: &Header, n: f64) -> Result<(), Error> {
+        let bits = match (h.integral, h.sizeof_number) {
+            (true, _) => {
+                // Saturates, so 2^63 would come back unchanged as well.
+                let i = n as i64;
+                if i as f64 != n || n >= 2f64.powi(63) {
+                    return Err(Error::InexactNumber(n));
+                }
+                return self.put_integer(h, i);
+            }
+            (false, 4) => {
+                let f = n as f32;
+                if f64::from(f) != n && !n.is_nan() {
+                    return Err(Error::InexactNumber(n));
+                }
+                f.to_bits().into()
+            }
+            (false, _) => n.to_bits(),
+        };
+        self.put_sized(h, h.sizeof_number, bits)
+    }
+    fn put_integer(&mut self, h: &Header, n: i64) -> Result<(), Error> {
+        if !h.integral {
+            if n as f64 as i64 != n || n == i64::MAX {
+                return Err(Error::IntegerOutOfRange(n));
+            }
+            return self.put_number(h, n as f64);
+        }
+        let bits = 8 * u32::from(h.sizeof_number);
+        if bits < 64 && !(-(1 << (bits - 1))..1 << (bits - 1)).contains(&n) {
+            return Err(Error::IntegerOutOfRange(n));
+        }
+        self.put_sized(h, h.sizeof_number, n as u64 & (u64::MAX >> (64 - bits)))
+    }
+    fn put_string(&mut self, h: &Header, str: Option<&[u8]>) -> Result<(), Error> {
+        match str {
+            None => self.put_sized(h, h.sizeof_size_t, 0),
+            Some(str) => {
+                let len = u64::try_from(str.len())
+                    .ok()
+                    .and_then(|len| len.checked_add(1))
+                    .ok_or(Error::IntOutOfRange(u64::MAX))?;
+                self.put_sized(h, h.sizeof_size_t, len)?;
+                self.put_slice(str);
+                self.put_u8(0);
+                Ok(())
+            }
+        }
+    }
+    fn put_function(&mut self, h: &Header, fun: &Function) -> Result<(), Error> {
+        self.put_string(h, fun.source.as_deref())?;
+        self.put_cint(h, fun.line_defined as usize)?;
+        self.put_cint(h, fun.last_line_defined as usize)?;
+        self.put_u8(fun.nups);
+        self.put_u8(fun.num_params);
+        self.put_u8(fun.is_vararg.bits());
+        self.put_u8(fun.maxstacksize);
+        self.put_cint(h, fun.code.len())?;
+        for &i in &fun.code {
+            self.put_sized(h, 4, i.into())?;
+        }
+        self.put_cint(h, fun.constants.len())?;
+        for k in &fun.constants {
+            match k {
+                Constant::Nil => self.put_u8(0),
+                Constant::Boolean(b) => {
+                    self.put_u8(1);
+                    self.put_u8((*b).into());
+                }
+                Constant::Number(n) => {
+                    self.put_u8(3);
+                    self.put_number(h, *n)?;
+                }
+                Constant::Integer(n) => {
+                    self.put_u8(3);
+                    self.put_integer(h, *n)?;
+                }
+                Constant::String(s) => {
+                    self.put_u8(4);
+                    self.put_string(h, Some(s))?;
+                }
+            }
+        }
+        self.put_cint(h, fun.funs.len())?;
+        for f in &fun.funs {
+            self.put_function(h, f)?;
+        }
+        self.put_cint(h, fun.lineinfo.len())?;
+        for &line in &fun.lineinfo {
+            self.put_cint(h, line as usize)?;
+        }
+        self.put_cint(h, fun.locvars.len())?;
+        for l in &fun.locvars {
+            self.put_string(h, Some(&l.varname))?;
+            self.put_cint(h, l.startpc as usize)?;
+            self.put_cint(h, l.endpc as usize)?;
+        }
+        self.put_cint(h, fun.upvalues.len())?;
+        for name in &fun.upvalues {
+            self.put_string(h, Some(name))?;
+        }
+        Ok(())
+    }
+}
+impl LuacBufMut for Vec<u8> {}
+
+/// Serializes a chunk, the inverse of `undump_chunk`. Numbers are converted
+/// to the header's `lua_Number` type, failing if any value does not fit the
+/// header's sizes exactly.
+pub fn dump_chunk(chunk: &Chunk) -> Result<Vec<u8>, Error> {
+    let mut p = Vec::new();
+    p.put_header(&chunk.header);
+    p.put_function(&chunk.header, &chunk.main)?;
+    Ok(p)
+}
+
+/// Serializes a function as a Lua 5.1 chunk with the default header.
+pub fn dump(fun: &Function) -> Result<Vec<u8>, Error> {
+    let h = Header::default();
+    let mut p = Vec::new();
+    p.put_header(&h);
+    p.put_function(&h, fun)?;
+    Ok(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dump::*;
+    use crate::undump::{LuaString, undump, undump_chunk};
+
+    #[test]
+    fn test() {
+        // local a = 1
+        // return function(b, ...) return a + b, "\0" end
+        let closure = b"\
+\x1b\x4c\x75\x61\x51\x00\x01\x04\x08\x04\x08\x00\x07\x00\x00\x00\
+\x00\x00\x00\x00\x40\x63\x2e\x6c\x75\x61\x00\x00\x00\x00\x00\x00\
+\x00\x00\x00\x00\x00\x02\x02\x05\x00\x00\x00\x01\x00\x00\x00\x64\
+\x00\x00\x00\x00\x00\x00\x00\x5e\x00\x00\x01\x1e\x00\x80\x00\x01\
+\x00\x00\x00\x03\x00\x00\x00\x00\x00\x00\xf0\x3f\x01\x00\x00\x00\
+\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\
+\x01\x01\x07\x04\x05\x00\x00\x00\x84\x00\x00\x00\x8c\x00\x00\x01\
+\xc1\x00\x00\x00\x9e\x00\x80\x01\x1e\x00\x80\x00\x01\x00\x00\x00\
+\x04\x02\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x05\
+\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\x02\
+\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\x00\
+\x00\x00\x00\x62\x00\x00\x00\x00\x00\x04\x00\x00\x00\x04\x00\x00\
+\x00\x00\x00\x00\x00\x61\x72\x67\x00\x00\x00\x00\x00\x04\x00\x00\
+\x00\x01\x00\x00\x00\x02\x00\x00\x00\x00\x00\x00\x00\x61\x00\x05\
+\x00\x00\x00\x01\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\x02\
+\x00\x00\x00\x02\x00\x00\x00\x01\x00\x00\x00\x02\x00\x00\x00\x00\
+\x00\x00\x00\x61\x00\x01\x00\x00\x00\x04\x00\x00\x00\x00\x00\x00\
+\x00";
+        assert_eq!(dump(&undump(closure).unwrap()).unwrap(), closure);
+        let mut chunk = undump_chunk(closure).unwrap();
+        assert_eq!(chunk.header, Header::default());
+        for header in [
+            Header {
+                endianness: Endianness::Big,
+                sizeof_size_t: 4,
+                sizeof_number: 4,
+                ..Header::default()
+            },
+            Header {
+                sizeof_int: 8,
+                sizeof_number: 4,
+                integral: true,
+                ..Header::default()
+            },
+        ] {
+            chunk.header = header.clone();
+            let data = dump_chunk(&chunk).unwrap();
+            chunk = undump_chunk(&data).unwrap();
+            assert_eq!(chunk.header, header);
+            assert_eq!(dump_chunk(&chunk).unwrap(), data);
+        }
+        assert_eq!(chunk.main.constants, vec![Constant::Integer(1)]);
+        chunk.header = Header::default();
+        assert_eq!(dump_chunk(&chunk).unwrap(), closure);
+        let latin1 = LuaString::from(&b"caf\xe9"[..]);
+        assert_eq!(latin1.to_string(), "caf\u{fffd}");
+        let mut chunk = undump_chunk(closure).unwrap();
+        chunk.main.constants.push(Constant::String(latin1));
+        assert_eq!(undump_chunk(&dump_chunk(&chunk).unwrap()).unwrap(), chunk);
+
+        // Values that would not load back unchanged are refused.
+        let mut chunk = undump_chunk(closure).unwrap();
+        chunk.main.lineinfo = vec![300; 3];
+        chunk.header.sizeof_int = 1;
+        assert_eq!(dump_chunk(&chunk), Err(Error::IntOutOfRange(300)));
+        chunk.header.sizeof_int = 2;
+        assert!(dump_chunk(&chunk).is_ok());
+        chunk.main.lineinfo = vec![usize::MAX as u32; 3];
+        chunk.header.sizeof_int = 8;
+        assert!(dump_chunk(&chunk).is_ok());
+        let mut chunk = undump_chunk(closure).unwrap();
+        chunk.main.constants = vec![Constant::Number(1.5)];
+        chunk.header.integral = true;
+        assert_eq!(dump_chunk(&chunk), Err(Error::InexactNumber(1.5)));
+        chunk.main.constants = vec![Constant::Number(2f64.powi(63))];
+        assert_eq!(dump_chunk(&chunk), Err(Error::InexactNumber(2f64.powi(63))));
+        chunk.main.constants = vec![Constant::Integer(1 << 40)];
+        chunk.header.sizeof_number = 4;
+        assert_eq!(dump_chunk(&chunk), Err(Error::IntegerOutOfRange(1 << 40)));
+        chunk.main.constants = vec![Constant::Integer(-5)];
+        let data = dump_chunk(&chunk).unwrap();
+        assert_eq!(
+            undump_chunk(&data).unwrap().main.constants,
+            chunk.main.constants
+        );
+        chunk.main.constants = vec![Constant::Number(0.1)];
+        chunk.header.integral = false;
+        assert_eq!(dump_chunk(&chunk), Err(Error::InexactNumber(0.1)));
+    }
+}
@@ -0,0 +1,319 @@
+const SIZE_OP: u32 = 6;
+const SIZE_A: u32 = 8;
+const SIZE_B: u32 = 9;
+const SIZE_C: u32 = 9;
+const POS_A: u32 = SIZE_OP;
+const POS_C: u32 = POS_A + SIZE_A;
+const POS_B: u32 = POS_C + SIZE_C;
+const MAXARG_BX: u32 = (1 << (SIZE_B + SIZE_C)) - 1;
+const MAXARG_SBX: i32 = (MAXARG_BX >> 1) as i32;
+const BITRK: u16 = 1 << (SIZE_B - 1);
+
+/// Defines `OpCode` from a table like `luaP_opmodes`, one row per opcode:
+/// variant, name, T (is a test), A (sets register A), B mode, C mode, mode.
+macro_rules! opcodes {
+    ($($op:ident $name:literal $t:literal $a:literal $b:ident $c:ident $mode:ident,)*) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum OpCode {
+            $($op,)*
+        }
+
+        const OPCODES: &[OpCode] = &[$(OpCode::$op,)*];
+
+        impl OpCode {
+            /// The opcode's name as printed by `luac -l`.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(OpCode::$op => $name,)*
+                }
+            }
+            pub fn mode(self) -> $crate::instruction::OpMode {
+                match self {
+                    $(OpCode::$op => $crate::instruction::OpMode::$mode,)*
+                }
+            }
+            pub fn b_mode(self) -> $crate::instruction::OpArgMask {
+                match self {
+                    $(OpCode::$op => $crate::instruction::OpArgMask::$b,)*
+                }
+            }
+            pub fn c_mode(self) -> $crate::instruction::OpArgMask {
+                match self {
+                    $(OpCode::$op => $crate::instruction::OpArgMask::$c,)*
+                }
+            }
+            /// Whether the instruction writes register A.
+            pub fn sets_a(self) -> bool {
+                match self {
+                    $(OpCode::$op => $a != 0,)*
+                }
+            }
+            /// Whether the instruction is a test, i.e. the next one must be a jump.
+            pub fn is_test(self) -> bool {
+                match self {
+                    $(OpCode::$op => $t != 0,)*
+                }
+            }
+        }
+
+        impl TryFrom<u8> for OpCode {
+            type Error = anyhow::Error;
+            fn try_from(op: u8) -> anyhow::Result<OpCode> {
+                match OPCODES.get(usize::from(op)) {
+                    Some(&op) => Ok(op),
+                    None => anyhow::bail!("invalid opcode {}", op),
+                }
+            }
+        }
+
+        impl std::fmt::Display for OpCode {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+    };
+}
+
+/// Defines `Instruction` for the 32-bit layout shared by Lua 5.1 to 5.3:
+/// a 6-bit opcode, then A (8 bits), C (9 bits) and B (9 bits).
+macro_rules! abc_instruction {
+    () => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub struct Instruction {
+            pub op: OpCode,
+            pub a: u8,
+            pub b: u16,
+            pub c: u16,
+        }
+
+        impl Instruction {
+            pub fn decode(raw: u32) -> anyhow::Result<Instruction> {
+                let (op, a, b, c) = $crate::instruction::abc_fields(raw);
+                Ok(Instruction {
+                    op: OpCode::try_from(op)?,
+                    a,
+                    b,
+                    c,
+                })
+            }
+            pub fn encode(self) -> u32 {
+                $crate::instruction::abc_encode(self.op as u8, self.a, self.b, self.c)
+            }
+            pub fn abc(op: OpCode, a: u8, b: u16, c: u16) -> Instruction {
+                Instruction { op, a, b, c }
+            }
+            pub fn abx(op: OpCode, a: u8, bx: u32) -> Instruction {
+                let (b, c) = $crate::instruction::split_bx(bx);
+                Instruction { op, a, b, c }
+            }
+            pub fn asbx(op: OpCode, a: u8, sbx: i32) -> Instruction {
+                Instruction::abx(op, a, $crate::instruction::sbx_to_bx(sbx))
+            }
+            pub fn bx(self) -> u32 {
+                $crate::instruction::join_bx(self.b, self.c)
+            }
+            pub fn sbx(self) -> i32 {
+                $crate::instruction::bx_to_sbx(self.bx())
+            }
+            /// The 26-bit argument of an `OpMode::Ax` instruction, which
+            /// spans A, C and B.
+            pub fn ax(self) -> u32 {
+                u32::from(self.a) | self.bx() << 8
+            }
+            pub fn rk_b(self) -> $crate::instruction::Rk {
+                self.b.into()
+            }
+            pub fn rk_c(self) -> $crate::instruction::Rk {
+                self.c.into()
+            }
+            pub fn operands(self) -> $crate::instruction::Operands {
+                use $crate::instruction::{OpMode, Operands};
+                let a = self.a;
+                match self.op.mode() {
+                    OpMode::ABC => Operands::ABC {
+                        a,
+                        b: self.b,
+                        c: self.c,
+                    },
+                    OpMode::ABx => Operands::ABx { a, bx: self.bx() },
+                    OpMode::AsBx => Operands::AsBx { a, sbx: self.sbx() },
+                    OpMode::Ax => Operands::Ax { ax: self.ax() },
+                }
+            }
+        }
+    };
+}
+
+pub mod lua50;
+pub mod lua52;
+pub mod lua53;
+pub mod lua54;
+pub mod luajit;
+
+pub(crate) fn abc_fields(raw: u32) -> (u8, u8, u16, u16) {
+    (
+        (raw & ((1 << SIZE_OP) - 1)) as u8,
+        (raw >> POS_A) as u8,
+        ((raw >> POS_B) & ((1 << SIZE_B) - 1)) as u16,
+        ((raw >> POS_C) & ((1 << SIZE_C) - 1)) as u16,
+    )
+}
+
+pub(crate) fn abc_encode(op: u8, a: u8, b: u16, c: u16) -> u32 {
+    u32::from(op) | u32::from(a) << POS_A | u32::from(b) << POS_B | u32::from(c) << POS_C
+}
+
+pub(crate) fn split_bx(bx: u32) -> (u16, u16) {
+    ((bx >> SIZE_C) as u16, (bx & ((1 << SIZE_C) - 1)) as u16)
+}
+
+pub(crate) fn join_bx(b: u16, c: u16) -> u32 {
+    u32::from(b) << SIZE_C | u32::from(c)
+}
+
+pub(crate) fn sbx_to_bx(sbx: i32) -> u32 {
+    (sbx + MAXARG_SBX) as u32
+}
+
+pub(crate) fn bx_to_sbx(bx: u32) -> i32 {
+    bx as i32 - MAXARG_SBX
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpMode {
+    ABC,
+    ABx,
+    AsBx,
+    /// Since Lua 5.2.
+    Ax,
+}
+
+/// How an instruction uses its B or C argument (`OpArgMask` in lopcodes.h).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpArgMask {
+    /// Argument is not used.
+    N,
+    /// Argument is used as a plain number.
+    U,
+    /// Argument is a register or a jump offset.
+    R,
+    /// Argument is a constant or an RK-encoded register/constant.
+    K,
+}
+
+opcodes! {
+    Move "MOVE" 0 1 R N ABC,
+    LoadK "LOADK" 0 1 K N ABx,
+    LoadBool "LOADBOOL" 0 1 U U ABC,
+    LoadNil "LOADNIL" 0 1 R N ABC,
+    GetUpval "GETUPVAL" 0 1 U N ABC,
+    GetGlobal "GETGLOBAL" 0 1 K N ABx,
+    GetTable "GETTABLE" 0 1 R K ABC,
+    SetGlobal "SETGLOBAL" 0 0 K N ABx,
+    SetUpval "SETUPVAL" 0 0 U N ABC,
+    SetTable "SETTABLE" 0 0 K K ABC,
+    NewTable "NEWTABLE" 0 1 U U ABC,
+    Self_ "SELF" 0 1 R K ABC,
+    Add "ADD" 0 1 K K ABC,
+    Sub "SUB" 0 1 K K ABC,
+    Mul "MUL" 0 1 K K ABC,
+    Div "DIV" 0 1 K K ABC,
+    Mod "MOD" 0 1 K K ABC,
+    Pow "POW" 0 1 K K ABC,
+    Unm "UNM" 0 1 R N ABC,
+    Not "NOT" 0 1 R N ABC,
+    Len "LEN" 0 1 R N ABC,
+    Concat "CONCAT" 0 1 R R ABC,
+    Jmp "JMP" 0 0 R N AsBx,
+    Eq "EQ" 1 0 K K ABC,
+    Lt "LT" 1 0 K K ABC,
+    Le "LE" 1 0 K K ABC,
+    Test "TEST" 1 1 R U ABC,
+    TestSet "TESTSET" 1 1 R U ABC,
+    Call "CALL" 0 1 U U ABC,
+    TailCall "TAILCALL" 0 1 U U ABC,
+    Return "RETURN" 0 0 U N ABC,
+    ForLoop "FORLOOP" 0 1 R N AsBx,
+    ForPrep "FORPREP" 0 1 R N AsBx,
+    TForLoop "TFORLOOP" 1 0 N U ABC,
+    SetList "SETLIST" 0 0 U U ABC,
+    Close "CLOSE" 0 0 N N ABC,
+    Closure "CLOSURE" 0 1 U N ABx,
+    VarArg "VARARG" 0 1 U N ABC,
+}
+
+/// An RK-encoded operand: either a register or an index into the constants.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rk {
+    Register(u8),
+    /// Wider than a register, for Lua 5.0's operands up to
+    /// `MAXARG_B - MAXSTACK`.
+    Constant(u16),
+}
+
+impl From<u16> for Rk {
+    fn from(x: u16) -> Rk {
+        if x & BITRK != 0 {
+            Rk::Constant(x & !BITRK)
+        } else {
+            Rk::Register(x as u8)
+        }
+    }
+}
+
+impl From<Rk> for u16 {
+    fn from(rk: Rk) -> u16 {
+        match rk {
+            Rk::Register(r) => r.into(),
+            Rk::Constant(k) => k | BITRK,
+        }
+    }
+}
+
+/// The operands of an instruction, shaped by its opcode's `OpMode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operands {
+    ABC { a: u8, b: u16, c: u16 },
+    ABx { a: u8, bx: u32 },
+    AsBx { a: u8, sbx: i32 },
+    Ax { ax: u32 },
+}
+
+abc_instruction!();
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::*;
+
+    #[test]
+    fn test() {
+        let code = [1, 16449, 25165854, 8388638, 0x8040_40cc, 0x7fff_8016];
+        let insts: Vec<_> = code
+            .iter()
+            .map(|&i| Instruction::decode(i).unwrap())
+            .collect();
+        assert_eq!(
+            insts.iter().map(|i| i.operands()).collect::<Vec<_>>(),
+            vec![
+                Operands::ABx { a: 0, bx: 0 },
+                Operands::ABx { a: 1, bx: 1 },
+                Operands::ABC { a: 0, b: 3, c: 0 },
+                Operands::ABC { a: 0, b: 1, c: 0 },
+                Operands::ABC {
+                    a: 3,
+                    b: 256,
+                    c: 257
+                },
+                Operands::AsBx { a: 0, sbx: -1 },
+            ]
+        );
+        assert_eq!(insts[4].op, OpCode::Add);
+        assert_eq!(insts[4].rk_b(), Rk::Constant(0));
+        assert_eq!(insts[4].rk_c(), Rk::Constant(1));
+        assert_eq!(insts[5].op, OpCode::Jmp);
+        for (&raw, inst) in code.iter().zip(insts) {
+            assert_eq!(inst.encode(), raw);
+        }
+        assert!(Instruction::decode(38).is_err());
+    }
+}
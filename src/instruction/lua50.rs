@@ -0,0 +1,159 @@
+//! Lua 5.0 opcodes and its instruction layout: a 6-bit opcode, then C
+//! (9 bits), B (9 bits) and A (8 bits). Bx spans C and B.
+
+use crate::instruction::{OpMode, Operands, Rk};
+
+const SIZE_OP: u32 = 6;
+const SIZE_C: u32 = 9;
+const SIZE_B: u32 = 9;
+const POS_C: u32 = SIZE_OP;
+const POS_B: u32 = POS_C + SIZE_C;
+const POS_A: u32 = POS_B + SIZE_B;
+const MAXARG_SBX: i32 = ((1 << (SIZE_B + SIZE_C)) - 1) >> 1;
+/// RK operands at or above this index are constants (`MAXSTACK`).
+const MAXSTACK: u16 = 250;
+
+opcodes! {
+    Move "MOVE" 0 1 R N ABC,
+    LoadK "LOADK" 0 1 K N ABx,
+    LoadBool "LOADBOOL" 0 1 U U ABC,
+    LoadNil "LOADNIL" 0 1 R N ABC,
+    GetUpval "GETUPVAL" 0 1 U N ABC,
+    GetGlobal "GETGLOBAL" 0 1 K N ABx,
+    GetTable "GETTABLE" 0 1 R K ABC,
+    SetGlobal "SETGLOBAL" 0 0 K N ABx,
+    SetUpval "SETUPVAL" 0 0 U N ABC,
+    SetTable "SETTABLE" 0 0 K K ABC,
+    NewTable "NEWTABLE" 0 1 U U ABC,
+    Self_ "SELF" 0 1 R K ABC,
+    Add "ADD" 0 1 K K ABC,
+    Sub "SUB" 0 1 K K ABC,
+    Mul "MUL" 0 1 K K ABC,
+    Div "DIV" 0 1 K K ABC,
+    Pow "POW" 0 1 K K ABC,
+    Unm "UNM" 0 1 R N ABC,
+    Not "NOT" 0 1 R N ABC,
+    Concat "CONCAT" 0 1 R R ABC,
+    Jmp "JMP" 0 0 N N AsBx,
+    Eq "EQ" 1 0 K K ABC,
+    Lt "LT" 1 0 K K ABC,
+    Le "LE" 1 0 K K ABC,
+    Test "TEST" 1 1 R U ABC,
+    Call "CALL" 0 0 U U ABC,
+    TailCall "TAILCALL" 0 0 U U ABC,
+    Return "RETURN" 0 0 U N ABC,
+    ForLoop "FORLOOP" 0 0 N N AsBx,
+    TForLoop "TFORLOOP" 1 0 N U ABC,
+    TForPrep "TFORPREP" 0 0 N N AsBx,
+    SetList "SETLIST" 0 0 U N ABx,
+    SetListO "SETLISTO" 0 0 U N ABx,
+    Close "CLOSE" 0 0 N N ABC,
+    Closure "CLOSURE" 0 1 U N ABx,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub op: OpCode,
+    pub a: u8,
+    pub b: u16,
+    pub c: u16,
+}
+
+impl Instruction {
+    pub fn decode(raw: u32) -> anyhow::Result<Instruction> {
+        Ok(Instruction {
+            op: OpCode::try_from((raw & ((1 << SIZE_OP) - 1)) as u8)?,
+            a: (raw >> POS_A) as u8,
+            b: ((raw >> POS_B) & ((1 << SIZE_B) - 1)) as u16,
+            c: ((raw >> POS_C) & ((1 << SIZE_C) - 1)) as u16,
+        })
+    }
+    pub fn encode(self) -> u32 {
+        u32::from(self.op as u8)
+            | u32::from(self.a) << POS_A
+            | u32::from(self.b) << POS_B
+            | u32::from(self.c) << POS_C
+    }
+    pub fn abc(op: OpCode, a: u8, b: u16, c: u16) -> Instruction {
+        Instruction { op, a, b, c }
+    }
+    pub fn abx(op: OpCode, a: u8, bx: u32) -> Instruction {
+        Instruction {
+            op,
+            a,
+            b: (bx >> SIZE_C) as u16,
+            c: (bx & ((1 << SIZE_C) - 1)) as u16,
+        }
+    }
+    pub fn asbx(op: OpCode, a: u8, sbx: i32) -> Instruction {
+        Instruction::abx(op, a, (sbx + MAXARG_SBX) as u32)
+    }
+    pub fn bx(self) -> u32 {
+        u32::from(self.b) << SIZE_C | u32::from(self.c)
+    }
+    pub fn sbx(self) -> i32 {
+        self.bx() as i32 - MAXARG_SBX
+    }
+    pub fn rk_b(self) -> Rk {
+        rk(self.b)
+    }
+    pub fn rk_c(self) -> Rk {
+        rk(self.c)
+    }
+    pub fn operands(self) -> Operands {
+        let a = self.a;
+        match self.op.mode() {
+            OpMode::ABC => Operands::ABC {
+                a,
+                b: self.b,
+                c: self.c,
+            },
+            OpMode::ABx => Operands::ABx { a, bx: self.bx() },
+            OpMode::AsBx => Operands::AsBx { a, sbx: self.sbx() },
+            // No Lua 5.0 opcode is in this mode; it would span all but the
+            // opcode.
+            OpMode::Ax => Operands::Ax {
+                ax: self.encode() >> SIZE_OP,
+            },
+        }
+    }
+}
+
+fn rk(x: u16) -> Rk {
+    if x >= MAXSTACK {
+        Rk::Constant(x - MAXSTACK)
+    } else {
+        Rk::Register(x as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::lua50::*;
+
+    #[test]
+    fn test() {
+        // LOADK 0 0; ADD 1 0 251; JMP -2; RETURN 1 2
+        let code = [0x0000_0001, 0x0100_3ecc, 0x007f_ff54, 0x0101_001b];
+        let insts: Vec<_> = code
+            .iter()
+            .map(|&i| Instruction::decode(i).unwrap())
+            .collect();
+        assert_eq!(insts[0].operands(), Operands::ABx { a: 0, bx: 0 });
+        assert_eq!(insts[1].op, OpCode::Add);
+        assert_eq!(insts[1].rk_b(), Rk::Register(0));
+        assert_eq!(insts[1].rk_c(), Rk::Constant(1));
+        assert_eq!(insts[2].sbx(), -2);
+        let eq = Instruction::abc(OpCode::Eq, 0, 249, 511);
+        assert_eq!(eq.rk_b(), Rk::Register(249));
+        assert_eq!(eq.rk_c(), Rk::Constant(261));
+        assert_eq!(insts[3].op, OpCode::Return);
+        assert_eq!(
+            Instruction::decode(35).unwrap_err().to_string(),
+            "invalid opcode 35"
+        );
+        for (&raw, inst) in code.iter().zip(insts) {
+            assert_eq!(inst.encode(), raw);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! Lua 5.2 opcodes, which share the Lua 5.1 instruction layout.
+
+opcodes! {
+    Move "MOVE" 0 1 R N ABC,
+    LoadK "LOADK" 0 1 K N ABx,
+    LoadKX "LOADKX" 0 1 N N ABx,
+    LoadBool "LOADBOOL" 0 1 U U ABC,
+    LoadNil "LOADNIL" 0 1 U N ABC,
+    GetUpval "GETUPVAL" 0 1 U N ABC,
+    GetTabUp "GETTABUP" 0 1 U K ABC,
+    GetTable "GETTABLE" 0 1 R K ABC,
+    SetTabUp "SETTABUP" 0 0 K K ABC,
+    SetUpval "SETUPVAL" 0 0 U N ABC,
+    SetTable "SETTABLE" 0 0 K K ABC,
+    NewTable "NEWTABLE" 0 1 U U ABC,
+    Self_ "SELF" 0 1 R K ABC,
+    Add "ADD" 0 1 K K ABC,
+    Sub "SUB" 0 1 K K ABC,
+    Mul "MUL" 0 1 K K ABC,
+    Div "DIV" 0 1 K K ABC,
+    Mod "MOD" 0 1 K K ABC,
+    Pow "POW" 0 1 K K ABC,
+    Unm "UNM" 0 1 R N ABC,
+    Not "NOT" 0 1 R N ABC,
+    Len "LEN" 0 1 R N ABC,
+    Concat "CONCAT" 0 1 R R ABC,
+    Jmp "JMP" 0 0 R N AsBx,
+    Eq "EQ" 1 0 K K ABC,
+    Lt "LT" 1 0 K K ABC,
+    Le "LE" 1 0 K K ABC,
+    Test "TEST" 1 0 N U ABC,
+    TestSet "TESTSET" 1 1 R U ABC,
+    Call "CALL" 0 1 U U ABC,
+    TailCall "TAILCALL" 0 1 U U ABC,
+    Return "RETURN" 0 0 U N ABC,
+    ForLoop "FORLOOP" 0 1 R N AsBx,
+    ForPrep "FORPREP" 0 1 R N AsBx,
+    TForCall "TFORCALL" 0 0 N U ABC,
+    TForLoop "TFORLOOP" 0 1 R N AsBx,
+    SetList "SETLIST" 0 0 U U ABC,
+    Closure "CLOSURE" 0 1 U N ABx,
+    VarArg "VARARG" 0 1 U N ABC,
+    ExtraArg "EXTRAARG" 0 0 U U Ax,
+}
+
+abc_instruction!();
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::lua52::*;
+    use crate::instruction::{Operands, Rk};
+
+    #[test]
+    fn test() {
+        // GETTABUP 0 0 -1; EXTRAARG 300000
+        let code = [0x0040_0006, 0x0124_f827];
+        let insts: Vec<_> = code
+            .iter()
+            .map(|&i| Instruction::decode(i).unwrap())
+            .collect();
+        assert_eq!(insts[0].op, OpCode::GetTabUp);
+        assert_eq!(insts[0].rk_c(), Rk::Constant(0));
+        assert_eq!(insts[1].operands(), Operands::Ax { ax: 300000 });
+        assert_eq!(
+            Instruction::decode(40).unwrap_err().to_string(),
+            "invalid opcode 40"
+        );
+        for (&raw, inst) in code.iter().zip(insts) {
+            assert_eq!(inst.encode(), raw);
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Lua 5.3 opcodes, which share the Lua 5.1 instruction layout and add
+//! integer division and the bitwise operators.
+
+opcodes! {
+    Move "MOVE" 0 1 R N ABC,
+    LoadK "LOADK" 0 1 K N ABx,
+    LoadKX "LOADKX" 0 1 N N ABx,
+    LoadBool "LOADBOOL" 0 1 U U ABC,
+    LoadNil "LOADNIL" 0 1 U N ABC,
+    GetUpval "GETUPVAL" 0 1 U N ABC,
+    GetTabUp "GETTABUP" 0 1 U K ABC,
+    GetTable "GETTABLE" 0 1 R K ABC,
+    SetTabUp "SETTABUP" 0 0 K K ABC,
+    SetUpval "SETUPVAL" 0 0 U N ABC,
+    SetTable "SETTABLE" 0 0 K K ABC,
+    NewTable "NEWTABLE" 0 1 U U ABC,
+    Self_ "SELF" 0 1 R K ABC,
+    Add "ADD" 0 1 K K ABC,
+    Sub "SUB" 0 1 K K ABC,
+    Mul "MUL" 0 1 K K ABC,
+    Mod "MOD" 0 1 K K ABC,
+    Pow "POW" 0 1 K K ABC,
+    Div "DIV" 0 1 K K ABC,
+    IDiv "IDIV" 0 1 K K ABC,
+    BAnd "BAND" 0 1 K K ABC,
+    BOr "BOR" 0 1 K K ABC,
+    BXor "BXOR" 0 1 K K ABC,
+    Shl "SHL" 0 1 K K ABC,
+    Shr "SHR" 0 1 K K ABC,
+    Unm "UNM" 0 1 R N ABC,
+    BNot "BNOT" 0 1 R N ABC,
+    Not "NOT" 0 1 R N ABC,
+    Len "LEN" 0 1 R N ABC,
+    Concat "CONCAT" 0 1 R R ABC,
+    Jmp "JMP" 0 0 R N AsBx,
+    Eq "EQ" 1 0 K K ABC,
+    Lt "LT" 1 0 K K ABC,
+    Le "LE" 1 0 K K ABC,
+    Test "TEST" 1 0 N U ABC,
+    TestSet "TESTSET" 1 1 R U ABC,
+    Call "CALL" 0 1 U U ABC,
+    TailCall "TAILCALL" 0 1 U U ABC,
+    Return "RETURN" 0 0 U N ABC,
+    ForLoop "FORLOOP" 0 1 R N AsBx,
+    ForPrep "FORPREP" 0 1 R N AsBx,
+    TForCall "TFORCALL" 0 0 N U ABC,
+    TForLoop "TFORLOOP" 0 1 R N AsBx,
+    SetList "SETLIST" 0 0 U U ABC,
+    Closure "CLOSURE" 0 1 U N ABx,
+    VarArg "VARARG" 0 1 U N ABC,
+    ExtraArg "EXTRAARG" 0 0 U U Ax,
+}
+
+abc_instruction!();
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::Rk;
+    use crate::instruction::lua53::*;
+
+    #[test]
+    fn test() {
+        // IDIV 2 0 -2; BOR 2 2 3; SHL 3 -3 -4
+        let code = [0x0040_4093, 0x0100_c095, 0x8140_c0d7];
+        let insts: Vec<_> = code
+            .iter()
+            .map(|&i| Instruction::decode(i).unwrap())
+            .collect();
+        let ops: Vec<_> = insts.iter().map(|i| i.op).collect();
+        assert_eq!(ops, vec![OpCode::IDiv, OpCode::BOr, OpCode::Shl]);
+        assert_eq!(insts[0].rk_c(), Rk::Constant(1));
+        assert_eq!(insts[2].rk_b(), Rk::Constant(2));
+        assert_eq!(OpCode::BNot.name(), "BNOT");
+        assert_eq!(
+            Instruction::decode(47).unwrap_err().to_string(),
+            "invalid opcode 47"
+        );
+        for (&raw, inst) in code.iter().zip(insts) {
+            assert_eq!(inst.encode(), raw);
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! Lua 5.4 opcodes and its instruction layout: a 7-bit opcode, then A
+//! (8 bits), k (1 bit), B (8 bits) and C (8 bits). Bx spans k, B and C, and
+//! Ax and sJ span everything but the opcode.
+
+const SIZE_OP: u32 = 7;
+const SIZE_A: u32 = 8;
+const SIZE_B: u32 = 8;
+const SIZE_BX: u32 = 17;
+const POS_A: u32 = SIZE_OP;
+const POS_K: u32 = POS_A + SIZE_A;
+const POS_B: u32 = POS_K + 1;
+const POS_C: u32 = POS_B + SIZE_B;
+const SIZE_AX: u32 = 25;
+const OFFSET_SBX: i32 = ((1 << SIZE_BX) - 1) >> 1;
+const OFFSET_SJ: i32 = ((1 << SIZE_AX) - 1) >> 1;
+const OFFSET_SC: i32 = ((1 << SIZE_B) - 1) >> 1;
+
+/// Like `crate::instruction::OpMode`, plus the signed jump format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OpMode {
+    ABC,
+    ABx,
+    AsBx,
+    Ax,
+    /// A signed 25-bit jump offset, used only by `JMP`.
+    SJ,
+}
+
+/// Defines `OpCode` from a table like `luaP_opmodes`, one row per opcode:
+/// variant, name, MM (calls a metamethod), OT (sets top for the next
+/// instruction), IT (uses top from the previous one), T (is a test),
+/// A (sets register A), mode.
+macro_rules! opcodes54 {
+    ($($op:ident $name:literal $mm:literal $ot:literal $it:literal $t:literal $a:literal $mode:ident,)*) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum OpCode {
+            $($op,)*
+        }
+
+        const OPCODES: &[OpCode] = &[$(OpCode::$op,)*];
+
+        impl OpCode {
+            /// The opcode's name as printed by `luac -l`.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(OpCode::$op => $name,)*
+                }
+            }
+            pub fn mode(self) -> OpMode {
+                match self {
+                    $(OpCode::$op => OpMode::$mode,)*
+                }
+            }
+            /// Whether the instruction is a metamethod call for the previous one.
+            pub fn is_mm(self) -> bool {
+                match self {
+                    $(OpCode::$op => $mm != 0,)*
+                }
+            }
+            /// Whether the instruction sets the stack top when C is zero.
+            pub fn sets_top(self) -> bool {
+                match self {
+                    $(OpCode::$op => $ot != 0,)*
+                }
+            }
+            /// Whether the instruction uses the stack top when B is zero.
+            pub fn uses_top(self) -> bool {
+                match self {
+                    $(OpCode::$op => $it != 0,)*
+                }
+            }
+            /// Whether the instruction writes register A.
+            pub fn sets_a(self) -> bool {
+                match self {
+                    $(OpCode::$op => $a != 0,)*
+                }
+            }
+            /// Whether the instruction is a test, i.e. the next one must be a jump.
+            pub fn is_test(self) -> bool {
+                match self {
+                    $(OpCode::$op => $t != 0,)*
+                }
+            }
+        }
+
+        impl TryFrom<u8> for OpCode {
+            type Error = anyhow::Error;
+            fn try_from(op: u8) -> anyhow::Result<OpCode> {
+                match OPCODES.get(usize::from(op)) {
+                    Some(&op) => Ok(op),
+                    None => anyhow::bail!("invalid opcode {}", op),
+                }
+            }
+        }
+
+        impl std::fmt::Display for OpCode {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+    };
+}
+
+opcodes54! {
+    Move "MOVE" 0 0 0 0 1 ABC,
+    LoadI "LOADI" 0 0 0 0 1 AsBx,
+    LoadF "LOADF" 0 0 0 0 1 AsBx,
+    LoadK "LOADK" 0 0 0 0 1 ABx,
+    LoadKX "LOADKX" 0 0 0 0 1 ABx,
+    LoadFalse "LOADFALSE" 0 0 0 0 1 ABC,
+    LFalseSkip "LFALSESKIP" 0 0 0 0 1 ABC,
+    LoadTrue "LOADTRUE" 0 0 0 0 1 ABC,
+    LoadNil "LOADNIL" 0 0 0 0 1 ABC,
+    GetUpval "GETUPVAL" 0 0 0 0 1 ABC,
+    SetUpval "SETUPVAL" 0 0 0 0 0 ABC,
+    GetTabUp "GETTABUP" 0 0 0 0 1 ABC,
+    GetTable "GETTABLE" 0 0 0 0 1 ABC,
+    GetI "GETI" 0 0 0 0 1 ABC,
+    GetField "GETFIELD" 0 0 0 0 1 ABC,
+    SetTabUp "SETTABUP" 0 0 0 0 0 ABC,
+    SetTable "SETTABLE" 0 0 0 0 0 ABC,
+    SetI "SETI" 0 0 0 0 0 ABC,
+    SetField "SETFIELD" 0 0 0 0 0 ABC,
+    NewTable "NEWTABLE" 0 0 0 0 1 ABC,
+    Self_ "SELF" 0 0 0 0 1 ABC,
+    AddI "ADDI" 0 0 0 0 1 ABC,
+    AddK "ADDK" 0 0 0 0 1 ABC,
+    SubK "SUBK" 0 0 0 0 1 ABC,
+    MulK "MULK" 0 0 0 0 1 ABC,
+    ModK "MODK" 0 0 0 0 1 ABC,
+    PowK "POWK" 0 0 0 0 1 ABC,
+    DivK "DIVK" 0 0 0 0 1 ABC,
+    IDivK "IDIVK" 0 0 0 0 1 ABC,
+    BAndK "BANDK" 0 0 0 0 1 ABC,
+    BOrK "BORK" 0 0 0 0 1 ABC,
+    BXorK "BXORK" 0 0 0 0 1 ABC,
+    ShrI "SHRI" 0 0 0 0 1 ABC,
+    ShlI "SHLI" 0 0 0 0 1 ABC,
+    Add "ADD" 0 0 0 0 1 ABC,
+    Sub "SUB" 0 0 0 0 1 ABC,
+    Mul "MUL" 0 0 0 0 1 ABC,
+    Mod "MOD" 0 0 0 0 1 ABC,
+    Pow "POW" 0 0 0 0 1 ABC,
+    Div "DIV" 0 0 0 0 1 ABC,
+    IDiv "IDIV" 0 0 0 0 1 ABC,
+    BAnd "BAND" 0 0 0 0 1 ABC,
+    BOr "BOR" 0 0 0 0 1 ABC,
+    BXor "BXOR" 0 0 0 0 1 ABC,
+    Shl "SHL" 0 0 0 0 1 ABC,
+    Shr "SHR" 0 0 0 0 1 ABC,
+    MmBin "MMBIN" 1 0 0 0 0 ABC,
+    MmBinI "MMBINI" 1 0 0 0 0 ABC,
+    MmBinK "MMBINK" 1 0 0 0 0 ABC,
+    Unm "UNM" 0 0 0 0 1 ABC,
+    BNot "BNOT" 0 0 0 0 1 ABC,
+    Not "NOT" 0 0 0 0 1 ABC,
+    Len "LEN" 0 0 0 0 1 ABC,
+    Concat "CONCAT" 0 0 0 0 1 ABC,
+    Close "CLOSE" 0 0 0 0 0 ABC,
+    Tbc "TBC" 0 0 0 0 0 ABC,
+    Jmp "JMP" 0 0 0 0 0 SJ,
+    Eq "EQ" 0 0 0 1 0 ABC,
+    Lt "LT" 0 0 0 1 0 ABC,
+    Le "LE" 0 0 0 1 0 ABC,
+    EqK "EQK" 0 0 0 1 0 ABC,
+    EqI "EQI" 0 0 0 1 0 ABC,
+    LtI "LTI" 0 0 0 1 0 ABC,
+    LeI "LEI" 0 0 0 1 0 ABC,
+    GtI "GTI" 0 0 0 1 0 ABC,
+    GeI "GEI" 0 0 0 1 0 ABC,
+    Test "TEST" 0 0 0 1 0 ABC,
+    TestSet "TESTSET" 0 0 0 1 1 ABC,
+    Call "CALL" 0 1 1 0 1 ABC,
+    TailCall "TAILCALL" 0 1 1 0 1 ABC,
+    Return "RETURN" 0 0 1 0 0 ABC,
+    Return0 "RETURN0" 0 0 0 0 0 ABC,
+    Return1 "RETURN1" 0 0 0 0 0 ABC,
+    ForLoop "FORLOOP" 0 0 0 0 1 ABx,
+    ForPrep "FORPREP" 0 0 0 0 1 ABx,
+    TForPrep "TFORPREP" 0 0 0 0 0 ABx,
+    TForCall "TFORCALL" 0 0 0 0 0 ABC,
+    TForLoop "TFORLOOP" 0 0 0 0 1 ABx,
+    SetList "SETLIST" 0 0 1 0 0 ABC,
+    Closure "CLOSURE" 0 0 0 0 1 ABx,
+    VarArg "VARARG" 0 1 0 0 1 ABC,
+    VarArgPrep "VARARGPREP" 0 0 1 0 1 ABC,
+    ExtraArg "EXTRAARG" 0 0 0 0 0 Ax,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub op: OpCode,
+    pub a: u8,
+    pub k: bool,
+    pub b: u8,
+    pub c: u8,
+}
+
+impl Instruction {
+    pub fn decode(raw: u32) -> anyhow::Result<Instruction> {
+        Ok(Instruction {
+            op: OpCode::try_from((raw & ((1 << SIZE_OP) - 1)) as u8)?,
+            a: (raw >> POS_A) as u8,
+            k: raw >> POS_K & 1 != 0,
+            b: (raw >> POS_B) as u8,
+            c: (raw >> POS_C) as u8,
+        })
+    }
+    pub fn encode(self) -> u32 {
+        u32::from(self.op as u8)
+            | u32::from(self.a) << POS_A
+            | u32::from(self.k) << POS_K
+            | u32::from(self.b) << POS_B
+            | u32::from(self.c) << POS_C
+    }
+    pub fn abck(op: OpCode, a: u8, b: u8, c: u8, k: bool) -> Instruction {
+        Instruction { op, a, k, b, c }
+    }
+    pub fn abx(op: OpCode, a: u8, bx: u32) -> Instruction {
+        Instruction {
+            op,
+            a,
+            k: bx & 1 != 0,
+            b: (bx >> 1) as u8,
+            c: (bx >> (1 + SIZE_B)) as u8,
+        }
+    }
+    pub fn asbx(op: OpCode, a: u8, sbx: i32) -> Instruction {
+        Instruction::abx(op, a, (sbx + OFFSET_SBX) as u32)
+    }
+    pub fn iax(op: OpCode, ax: u32) -> Instruction {
+        Instruction::abx(op, ax as u8, ax >> SIZE_A)
+    }
+    pub fn isj(op: OpCode, sj: i32) -> Instruction {
+        Instruction::iax(op, (sj + OFFSET_SJ) as u32)
+    }
+    pub fn bx(self) -> u32 {
+        u32::from(self.k) | u32::from(self.b) << 1 | u32::from(self.c) << (1 + SIZE_B)
+    }
+    pub fn sbx(self) -> i32 {
+        self.bx() as i32 - OFFSET_SBX
+    }
+    /// The 25-bit argument of an `OpMode::Ax` instruction.
+    pub fn ax(self) -> u32 {
+        u32::from(self.a) | self.bx() << SIZE_A
+    }
+    /// The jump offset of an `OpMode::SJ` instruction.
+    pub fn sj(self) -> i32 {
+        self.ax() as i32 - OFFSET_SJ
+    }
+    /// B as a signed immediate, e.g. the integer of `EQI`.
+    pub fn sb(self) -> i32 {
+        i32::from(self.b) - OFFSET_SC
+    }
+    /// C as a signed immediate, e.g. the addend of `ADDI`.
+    pub fn sc(self) -> i32 {
+        i32::from(self.c) - OFFSET_SC
+    }
+    pub fn operands(self) -> Operands {
+        let a = self.a;
+        match self.op.mode() {
+            OpMode::ABC => Operands::ABC {
+                a,
+                b: self.b,
+                c: self.c,
+                k: self.k,
+            },
+            OpMode::ABx => Operands::ABx { a, bx: self.bx() },
+            OpMode::AsBx => Operands::AsBx { a, sbx: self.sbx() },
+            OpMode::Ax => Operands::Ax { ax: self.ax() },
+            OpMode::SJ => Operands::SJ { sj: self.sj() },
+        }
+    }
+}
+
+/// The operands of an instruction, shaped by its opcode's `OpMode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operands {
+    ABC { a: u8, b: u8, c: u8, k: bool },
+    ABx { a: u8, bx: u32 },
+    AsBx { a: u8, sbx: i32 },
+    Ax { ax: u32 },
+    SJ { sj: i32 },
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::lua54::*;
+
+    #[test]
+    fn test() {
+        // LOADI 1 -5; ADDI 2 1 3; MMBINI 1 3 6 0; JMP -3; EQK 1 0 1; EXTRAARG 300000
+        let code = [
+            0x7ffd_0081,
+            0x8201_0115,
+            0x0682_00af,
+            0x7fff_fe38,
+            0x0000_80bc,
+            0x0249_f052,
+        ];
+        let insts: Vec<_> = code
+            .iter()
+            .map(|&i| Instruction::decode(i).unwrap())
+            .collect();
+        assert_eq!(insts[0].operands(), Operands::AsBx { a: 1, sbx: -5 });
+        assert_eq!(insts[1].op, OpCode::AddI);
+        assert_eq!(insts[1].sc(), 3);
+        assert!(insts[2].op.is_mm());
+        assert_eq!(insts[3].operands(), Operands::SJ { sj: -3 });
+        assert!(insts[4].op.is_test());
+        assert!(insts[4].k);
+        assert_eq!(insts[5].operands(), Operands::Ax { ax: 300000 });
+        assert_eq!(
+            Instruction::decode(83).unwrap_err().to_string(),
+            "invalid opcode 83"
+        );
+        for (&raw, inst) in code.iter().zip(insts) {
+            assert_eq!(inst.encode(), raw);
+        }
+        assert_eq!(Instruction::isj(OpCode::Jmp, -3).encode(), code[3]);
+        assert_eq!(Instruction::iax(OpCode::ExtraArg, 300000).encode(), code[5]);
+    }
+}
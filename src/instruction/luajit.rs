@@ -0,0 +1,322 @@
+//! LuaJIT 2.x bytecode: an 8-bit opcode, then A (8 bits), C (8 bits) and
+//! B (8 bits), with D spanning C and B.
+
+/// Offset of jump targets in D (`BCBIAS_J`).
+const BCBIAS_J: i32 = 0x8000;
+
+/// The bytecode dump version, which also fixes the opcode numbering: LuaJIT
+/// 2.1 inserted `ISTYPE`, `ISNUM`, `TGETR` and `TSETR`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    LuaJit20 = 1,
+    LuaJit21 = 2,
+}
+
+impl TryFrom<u8> for Version {
+    type Error = anyhow::Error;
+    fn try_from(v: u8) -> anyhow::Result<Version> {
+        match v {
+            1 => Ok(Version::LuaJit20),
+            2 => Ok(Version::LuaJit21),
+            _ => anyhow::bail!("invalid LuaJIT bytecode version {}", v),
+        }
+    }
+}
+
+/// What an operand means (the operand modes of `BCDEF` in lj_bc.h).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BcMode {
+    /// Operand is not used.
+    None,
+    /// Destination register.
+    Dst,
+    /// Base register of a call or a range of slots.
+    Base,
+    /// Source register.
+    Var,
+    /// Base register, read only.
+    RBase,
+    /// Upvalue index.
+    Uv,
+    /// Unsigned literal.
+    Lit,
+    /// Signed 16-bit literal.
+    LitS,
+    /// Primitive: 0 is nil, 1 false, 2 true.
+    Pri,
+    /// Index into the number constants.
+    Num,
+    /// Index into the GC constants, counted from the end.
+    Str,
+    Tab,
+    Func,
+    CData,
+    /// Jump offset, biased by 0x8000.
+    Jump,
+}
+
+/// Defines `OpCode` from `BCDEF`, one row per opcode: variant, name, A mode,
+/// B mode, C/D mode, and whether it is new in LuaJIT 2.1.
+macro_rules! bcdef {
+    ($($op:ident $name:literal $a:ident $b:ident $cd:ident $new:literal,)*) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum OpCode {
+            $($op,)*
+        }
+
+        const OPCODES: &[OpCode] = &[$(OpCode::$op,)*];
+
+        impl OpCode {
+            /// The opcode's name as printed by `luajit -bl`.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(OpCode::$op => $name,)*
+                }
+            }
+            pub fn a_mode(self) -> BcMode {
+                match self {
+                    $(OpCode::$op => BcMode::$a,)*
+                }
+            }
+            /// `BcMode::None` for instructions in AD format.
+            pub fn b_mode(self) -> BcMode {
+                match self {
+                    $(OpCode::$op => BcMode::$b,)*
+                }
+            }
+            pub fn cd_mode(self) -> BcMode {
+                match self {
+                    $(OpCode::$op => BcMode::$cd,)*
+                }
+            }
+            /// Whether the opcode exists in bytecode of the given version.
+            pub fn is_in(self, version: Version) -> bool {
+                match self {
+                    $(OpCode::$op => $new == 0 || version == Version::LuaJit21,)*
+                }
+            }
+        }
+
+        impl std::fmt::Display for OpCode {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.name())
+            }
+        }
+    };
+}
+
+bcdef! {
+    IsLt "ISLT" Var None Var 0,
+    IsGe "ISGE" Var None Var 0,
+    IsLe "ISLE" Var None Var 0,
+    IsGt "ISGT" Var None Var 0,
+    IsEqV "ISEQV" Var None Var 0,
+    IsNeV "ISNEV" Var None Var 0,
+    IsEqS "ISEQS" Var None Str 0,
+    IsNeS "ISNES" Var None Str 0,
+    IsEqN "ISEQN" Var None Num 0,
+    IsNeN "ISNEN" Var None Num 0,
+    IsEqP "ISEQP" Var None Pri 0,
+    IsNeP "ISNEP" Var None Pri 0,
+    IsTC "ISTC" Dst None Var 0,
+    IsFC "ISFC" Dst None Var 0,
+    IsT "IST" None None Var 0,
+    IsF "ISF" None None Var 0,
+    IsType "ISTYPE" Var None Lit 1,
+    IsNum "ISNUM" Var None Lit 1,
+    Mov "MOV" Dst None Var 0,
+    Not "NOT" Dst None Var 0,
+    Unm "UNM" Dst None Var 0,
+    Len "LEN" Dst None Var 0,
+    AddVN "ADDVN" Dst Var Num 0,
+    SubVN "SUBVN" Dst Var Num 0,
+    MulVN "MULVN" Dst Var Num 0,
+    DivVN "DIVVN" Dst Var Num 0,
+    ModVN "MODVN" Dst Var Num 0,
+    AddNV "ADDNV" Dst Var Num 0,
+    SubNV "SUBNV" Dst Var Num 0,
+    MulNV "MULNV" Dst Var Num 0,
+    DivNV "DIVNV" Dst Var Num 0,
+    ModNV "MODNV" Dst Var Num 0,
+    AddVV "ADDVV" Dst Var Var 0,
+    SubVV "SUBVV" Dst Var Var 0,
+    MulVV "MULVV" Dst Var Var 0,
+    DivVV "DIVVV" Dst Var Var 0,
+    ModVV "MODVV" Dst Var Var 0,
+    Pow "POW" Dst Var Var 0,
+    Cat "CAT" Dst RBase RBase 0,
+    KStr "KSTR" Dst None Str 0,
+    KCData "KCDATA" Dst None CData 0,
+    KShort "KSHORT" Dst None LitS 0,
+    KNum "KNUM" Dst None Num 0,
+    KPri "KPRI" Dst None Pri 0,
+    KNil "KNIL" Base None Base 0,
+    UGet "UGET" Dst None Uv 0,
+    USetV "USETV" Uv None Var 0,
+    USetS "USETS" Uv None Str 0,
+    USetN "USETN" Uv None Num 0,
+    USetP "USETP" Uv None Pri 0,
+    UClo "UCLO" RBase None Jump 0,
+    FNew "FNEW" Dst None Func 0,
+    TNew "TNEW" Dst None Lit 0,
+    TDup "TDUP" Dst None Tab 0,
+    GGet "GGET" Dst None Str 0,
+    GSet "GSET" Var None Str 0,
+    TGetV "TGETV" Dst Var Var 0,
+    TGetS "TGETS" Dst Var Str 0,
+    TGetB "TGETB" Dst Var Lit 0,
+    TGetR "TGETR" Dst Var Var 1,
+    TSetV "TSETV" Var Var Var 0,
+    TSetS "TSETS" Var Var Str 0,
+    TSetB "TSETB" Var Var Lit 0,
+    TSetM "TSETM" Base None Num 0,
+    TSetR "TSETR" Var Var Var 1,
+    CallM "CALLM" Base Lit Lit 0,
+    Call "CALL" Base Lit Lit 0,
+    CallMT "CALLMT" Base None Lit 0,
+    CallT "CALLT" Base None Lit 0,
+    IterC "ITERC" Base Lit Lit 0,
+    IterN "ITERN" Base Lit Lit 0,
+    VArg "VARG" Base Lit Lit 0,
+    IsNext "ISNEXT" Base None Jump 0,
+    RetM "RETM" Base None Lit 0,
+    Ret "RET" RBase None Lit 0,
+    Ret0 "RET0" RBase None Lit 0,
+    Ret1 "RET1" RBase None Lit 0,
+    ForI "FORI" Base None Jump 0,
+    JForI "JFORI" Base None Jump 0,
+    ForL "FORL" Base None Jump 0,
+    IForL "IFORL" Base None Jump 0,
+    JForL "JFORL" Base None Lit 0,
+    IterL "ITERL" Base None Jump 0,
+    IIterL "IITERL" Base None Jump 0,
+    JIterL "JITERL" Base None Lit 0,
+    Loop "LOOP" RBase None Jump 0,
+    ILoop "ILOOP" RBase None Jump 0,
+    JLoop "JLOOP" RBase None Lit 0,
+    Jmp "JMP" RBase None Jump 0,
+    FuncF "FUNCF" RBase None None 0,
+    IFuncF "IFUNCF" RBase None None 0,
+    JFuncF "JFUNCF" RBase None Lit 0,
+    FuncV "FUNCV" RBase None None 0,
+    IFuncV "IFUNCV" RBase None None 0,
+    JFuncV "JFUNCV" RBase None Lit 0,
+    FuncC "FUNCC" RBase None None 0,
+    FuncCW "FUNCCW" RBase None None 0,
+}
+
+impl OpCode {
+    /// The opcode numbered `op` in bytecode of the given version.
+    pub fn from_u8(op: u8, version: Version) -> anyhow::Result<OpCode> {
+        match OPCODES.iter().filter(|op| op.is_in(version)).nth(op.into()) {
+            Some(&op) => Ok(op),
+            None => anyhow::bail!("invalid opcode {}", op),
+        }
+    }
+    /// The inverse of `from_u8`; `None` if the opcode is not in `version`.
+    pub fn to_u8(self, version: Version) -> Option<u8> {
+        OPCODES
+            .iter()
+            .filter(|op| op.is_in(version))
+            .position(|&op| op == self)
+            .map(|i| i as u8)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    pub op: OpCode,
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+}
+
+impl Instruction {
+    pub fn decode(raw: u32, version: Version) -> anyhow::Result<Instruction> {
+        Ok(Instruction {
+            op: OpCode::from_u8(raw as u8, version)?,
+            a: (raw >> 8) as u8,
+            c: (raw >> 16) as u8,
+            b: (raw >> 24) as u8,
+        })
+    }
+    /// Encodes the instruction, if its opcode exists in `version`.
+    pub fn encode(self, version: Version) -> Option<u32> {
+        let op = self.op.to_u8(version)?;
+        Some(
+            u32::from(op)
+                | u32::from(self.a) << 8
+                | u32::from(self.c) << 16
+                | u32::from(self.b) << 24,
+        )
+    }
+    pub fn abc(op: OpCode, a: u8, b: u8, c: u8) -> Instruction {
+        Instruction { op, a, b, c }
+    }
+    pub fn ad(op: OpCode, a: u8, d: u16) -> Instruction {
+        let [c, b] = d.to_le_bytes();
+        Instruction { op, a, b, c }
+    }
+    pub fn d(self) -> u16 {
+        u16::from_le_bytes([self.c, self.b])
+    }
+    /// D as a jump offset, relative to the next instruction.
+    pub fn jump(self) -> i32 {
+        i32::from(self.d()) - BCBIAS_J
+    }
+    pub fn operands(self) -> Operands {
+        if self.op.b_mode() == BcMode::None {
+            Operands::AD {
+                a: self.a,
+                d: self.d(),
+            }
+        } else {
+            Operands::ABC {
+                a: self.a,
+                b: self.b,
+                c: self.c,
+            }
+        }
+    }
+}
+
+/// The operands of an instruction, shaped by its opcode's `b_mode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operands {
+    ABC { a: u8, b: u8, c: u8 },
+    AD { a: u8, d: u16 },
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::luajit::*;
+
+    #[test]
+    fn test() {
+        // FORI 1 => +5; MULVN 6 4 0; TSETV 6 5 4
+        let code = [0x8004_014d, 0x0400_0618, 0x0504_063c];
+        let v21 = Version::LuaJit21;
+        let insts: Vec<_> = code
+            .iter()
+            .map(|&i| Instruction::decode(i, v21).unwrap())
+            .collect();
+        assert_eq!(insts[0].op, OpCode::ForI);
+        assert_eq!(insts[0].jump(), 4);
+        assert_eq!(insts[0].operands(), Operands::AD { a: 1, d: 0x8004 });
+        assert_eq!(insts[1].op, OpCode::MulVN);
+        assert_eq!(insts[1].op.cd_mode(), BcMode::Num);
+        assert_eq!(insts[2].operands(), Operands::ABC { a: 6, b: 5, c: 4 });
+        for (&raw, inst) in code.iter().zip(insts) {
+            assert_eq!(inst.encode(v21), Some(raw));
+        }
+        let v20 = Version::LuaJit20;
+        assert_eq!(OpCode::from_u8(16, v20).unwrap(), OpCode::Mov);
+        assert_eq!(OpCode::from_u8(16, v21).unwrap(), OpCode::IsType);
+        assert_eq!(OpCode::TSetR.to_u8(v20), None);
+        assert_eq!(OpCode::FuncCW.to_u8(v20), Some(92));
+        assert_eq!(
+            OpCode::from_u8(93, v20).unwrap_err().to_string(),
+            "invalid opcode 93"
+        );
+    }
+}
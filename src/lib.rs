@@ -1 +1,9 @@
+pub mod cfg;
+pub mod decompile;
+pub mod disasm;
+pub mod dump;
+pub mod instruction;
+pub mod lifetime;
+pub mod probe;
 pub mod undump;
+pub mod verify;
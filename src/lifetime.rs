@@ -0,0 +1,1462 @@
+//! Register lifetime analysis, inferring the locals of stripped Lua 5.1
+//! functions.
+//!
+//! `luac -s` drops `locvars` and the upvalue names, leaving registers as the
+//! only trace of locals. The reference compiler keeps locals below its
+//! temporaries and consumes each temporary once, by an instruction reading
+//! the topmost live registers and writing its result no higher. A value read
+//! twice, read from below another live value, captured by a closure or never
+//! read at all is therefore held by a local, whose scope runs to the end of
+//! the innermost block around its declaration, or until its register is next
+//! used for a temporary.
+
+use crate::instruction::{Instruction, OpCode, Rk};
+use crate::undump::{Constant, Function, LocVar, LuaString, VarargFlags};
+use crate::verify::MAXSTACK;
+use anyhow::bail;
+use std::collections::{HashMap, HashSet};
+use std::ops::RangeInclusive;
+
+/// How an instruction reads a register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Read {
+    /// Uses up the temporary there, if it holds one.
+    Consume,
+    /// Leaves the value for a later instruction, like TEST does for the
+    /// value of an `and`/`or` expression.
+    Keep,
+    /// Passes it to a closure as an upvalue, which only locals can be.
+    Capture,
+}
+
+#[derive(Clone, Debug)]
+struct Access {
+    op: OpCode,
+    reads: Vec<(u8, Read)>,
+    writes: Vec<u8>,
+    /// The pcs control can go to next.
+    succs: Vec<usize>,
+}
+
+impl Access {
+    fn reads(&self, r: u8) -> bool {
+        self.reads.iter().any(|&(s, _)| s == r)
+    }
+    /// Whether the value in `r` before the instruction is read, which a
+    /// local function capturing itself does not.
+    fn reads_before(&self, r: u8) -> bool {
+        self.reads
+            .iter()
+            .any(|&(s, how)| s == r && (how != Read::Capture || !self.writes.contains(&r)))
+    }
+    /// Whether the write of `r` stands on the way to `succ`; a TESTSET only
+    /// writes when its JMP is taken.
+    fn kills(&self, r: u8, pc: usize, succ: usize) -> bool {
+        self.writes.contains(&r) && (self.op != OpCode::TestSet || succ == pc + 1)
+    }
+}
+
+/// A set of registers.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+struct Regs([u64; 4]);
+
+impl Regs {
+    fn insert(&mut self, r: u8) {
+        self.0[usize::from(r / 64)] |= 1 << (r % 64);
+    }
+    fn remove(&mut self, r: u8) {
+        self.0[usize::from(r / 64)] &= !(1 << (r % 64));
+    }
+    fn contains(self, r: u8) -> bool {
+        self.0[usize::from(r / 64)] & (1 << (r % 64)) != 0
+    }
+    fn union(self, other: Regs) -> Regs {
+        Regs(std::array::from_fn(|i| self.0[i] | other.0[i]))
+    }
+}
+
+/// Registers `from..to`, leaving out any past the last one.
+fn span(from: u16, to: u16) -> impl Iterator<Item = u8> {
+    (from..to).filter_map(|r| u8::try_from(r).ok())
+}
+
+/// Decodes a table size hint, like `luaO_fb2int`.
+fn fb2int(x: u16) -> usize {
+    let x = usize::from(x);
+    if x < 8 {
+        x
+    } else {
+        ((x & 7) + 8) << ((x >> 3) - 1)
+    }
+}
+
+/// The registers each instruction reads and writes, indexed by pc; `None`
+/// for the extra word of a SETLIST and the pseudo-instructions of a CLOSURE.
+fn accesses(fun: &Function) -> anyhow::Result<Vec<Option<Access>>> {
+    let code = &fun.code;
+    let len = code.len();
+    let mut accesses: Vec<Option<Access>> = vec![None; len];
+    // The register holding the first of an open number of values.
+    let mut open: Option<u8> = None;
+    let mut pc = 0;
+    while pc < len {
+        let i = Instruction::decode(code[pc])?;
+        let (a, b, c) = (u16::from(i.a), i.b, i.c);
+        let mut reads = vec![];
+        let mut writes = vec![];
+        let mut next = pc + 1;
+        let mut succs = None;
+        let target = usize::try_from(pc as i64 + 1 + i64::from(i.sbx()))
+            .ok()
+            .filter(|&t| t < len);
+        let rk = |x: u16, reads: &mut Vec<(u8, Read)>| {
+            if let Rk::Register(r) = Rk::from(x) {
+                reads.push((r, Read::Consume));
+            }
+        };
+        // Registers `from..from + n`, or up to the open values if `b` is
+        // zero.
+        let to_top = |from: u16, b: u16, n: u16| match (b, open) {
+            (0, Some(top)) => span(from, u16::from(top) + 1),
+            (0, None) => span(from, from),
+            _ => span(from, from + n),
+        };
+        match i.op {
+            OpCode::Move | OpCode::Unm | OpCode::Not | OpCode::Len => {
+                reads.push((i.b as u8, Read::Consume));
+                writes.push(i.a);
+            }
+            OpCode::LoadK
+            | OpCode::GetUpval
+            | OpCode::GetGlobal
+            | OpCode::NewTable
+            | OpCode::LoadBool => {
+                writes.push(i.a);
+                if i.op == OpCode::LoadBool && c != 0 {
+                    succs = Some(vec![pc + 2]);
+                }
+            }
+            OpCode::LoadNil => writes.extend(span(a, b + 1)),
+            OpCode::GetTable => {
+                reads.push((i.b as u8, Read::Consume));
+                rk(c, &mut reads);
+                writes.push(i.a);
+            }
+            OpCode::SetGlobal | OpCode::SetUpval => reads.push((i.a, Read::Consume)),
+            OpCode::SetTable => {
+                reads.push((i.a, Read::Consume));
+                rk(b, &mut reads);
+                rk(c, &mut reads);
+            }
+            OpCode::Self_ => {
+                reads.push((i.b as u8, Read::Consume));
+                rk(c, &mut reads);
+                writes.extend(span(a, a + 2));
+            }
+            OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div | OpCode::Mod | OpCode::Pow => {
+                rk(b, &mut reads);
+                rk(c, &mut reads);
+                writes.push(i.a);
+            }
+            OpCode::Concat => {
+                reads.extend(span(b, c + 1).map(|r| (r, Read::Consume)));
+                writes.push(i.a);
+            }
+            OpCode::Jmp => succs = Some(target.into_iter().collect()),
+            OpCode::Eq | OpCode::Lt | OpCode::Le => {
+                rk(b, &mut reads);
+                rk(c, &mut reads);
+                succs = Some(vec![pc + 1, pc + 2]);
+            }
+            OpCode::Test => {
+                reads.push((i.a, Read::Keep));
+                succs = Some(vec![pc + 1, pc + 2]);
+            }
+            OpCode::TestSet => {
+                reads.push((i.b as u8, Read::Consume));
+                writes.push(i.a);
+                succs = Some(vec![pc + 1, pc + 2]);
+            }
+            OpCode::Call | OpCode::TailCall => {
+                reads.extend(to_top(a, b, b).map(|r| (r, Read::Consume)));
+                if i.op == OpCode::TailCall {
+                    succs = Some(vec![]);
+                } else if c == 0 {
+                    writes.push(i.a);
+                    open = Some(i.a);
+                } else {
+                    writes.extend(span(a, a + c - 1));
+                }
+            }
+            OpCode::Return => {
+                reads.extend(to_top(a, b, b.saturating_sub(1)).map(|r| (r, Read::Consume)));
+                succs = Some(vec![]);
+            }
+            OpCode::ForLoop => {
+                reads.extend(span(a, a + 3).map(|r| (r, Read::Keep)));
+                writes.extend([i.a, i.a.saturating_add(3)]);
+                succs = Some(target.into_iter().chain([pc + 1]).collect());
+            }
+            OpCode::ForPrep => {
+                reads.extend(span(a, a + 3).map(|r| (r, Read::Consume)));
+                writes.push(i.a);
+                succs = Some(target.into_iter().collect());
+            }
+            OpCode::TForLoop => {
+                reads.extend(span(a, a + 3).map(|r| (r, Read::Keep)));
+                writes.extend(span(a + 3, a + 3 + c));
+                succs = Some(vec![pc + 1, pc + 2]);
+            }
+            OpCode::SetList => {
+                reads.push((i.a, Read::Keep));
+                reads.extend(to_top(a + 1, b, b).map(|r| (r, Read::Consume)));
+                if c == 0 {
+                    next = pc + 2;
+                }
+            }
+            OpCode::Close => {}
+            OpCode::Closure => {
+                writes.push(i.a);
+                let Some(child) = fun.funs.get(i.bx() as usize) else {
+                    bail!("closure of missing function at pc {}", pc);
+                };
+                for raw in code.iter().skip(pc + 1).take(usize::from(child.nups)) {
+                    let pseudo = Instruction::decode(*raw)?;
+                    if pseudo.op == OpCode::Move {
+                        reads.push((pseudo.b as u8, Read::Capture));
+                    }
+                    next += 1;
+                }
+            }
+            OpCode::VarArg => {
+                if b == 0 {
+                    writes.push(i.a);
+                    open = Some(i.a);
+                } else {
+                    writes.extend(span(a, a + b - 1));
+                }
+            }
+        }
+        let succs = succs
+            .unwrap_or_else(|| vec![next])
+            .into_iter()
+            .filter(|&s| s < len)
+            .collect();
+        accesses[pc] = Some(Access {
+            op: i.op,
+            reads,
+            writes,
+            succs,
+        });
+        pc = next;
+    }
+    for pc in 0..len {
+        let Some(access) = &accesses[pc] else {
+            continue;
+        };
+        match access.op {
+            // The JMP into a generic for loop hands its generator, state and
+            // control over to the TFORLOOP.
+            OpCode::TForLoop => {
+                let Some(Some(back)) = accesses.get(pc + 1) else {
+                    continue;
+                };
+                let i = Instruction::decode(code[pc])?;
+                let Some(&body) = back.succs.first() else {
+                    continue;
+                };
+                if back.op == OpCode::Jmp
+                    && let Some(Some(prep)) = body.checked_sub(1).map(|p| &mut accesses[p])
+                    && prep.op == OpCode::Jmp
+                    && prep.succs == [pc]
+                {
+                    let a = u16::from(i.a);
+                    prep.reads
+                        .extend(span(a, a + 3).map(|r| (r, Read::Consume)));
+                }
+            }
+            // The record fields of a table constructor leave the table for
+            // the next field.
+            OpCode::NewTable => {
+                let i = Instruction::decode(code[pc])?;
+                let mut fields = fb2int(i.c);
+                // The size hint is rounded up, so stop at the statements
+                // after the constructor.
+                for q in pc + 1..len {
+                    let Some(later) = &accesses[q] else {
+                        continue;
+                    };
+                    if fields == 0 || later.writes.contains(&i.a) {
+                        break;
+                    }
+                    if later.op == OpCode::SetTable {
+                        if later.reads[0] == (i.a, Read::Consume) {
+                            accesses[q].as_mut().unwrap().reads[0].1 = Read::Keep;
+                            fields -= 1;
+                        } else if later.reads[0].0 < i.a {
+                            break;
+                        }
+                    } else if is_statement(later, code[q]) || is_jump_between(&accesses, q) {
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(accesses)
+}
+
+/// The registers live on entry to each instruction.
+fn liveness(accesses: &[Option<Access>]) -> Vec<Regs> {
+    let mut live = vec![Regs::default(); accesses.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for pc in (0..accesses.len()).rev() {
+            let Some(access) = &accesses[pc] else {
+                continue;
+            };
+            let mut out = Regs::default();
+            for &succ in &access.succs {
+                let mut regs = live[succ];
+                for &r in &access.writes {
+                    if access.kills(r, pc, succ) {
+                        regs.remove(r);
+                    }
+                }
+                out = out.union(regs);
+            }
+            for &(r, _) in &access.reads {
+                // A local function captures itself after it is written.
+                if access.reads_before(r) {
+                    out.insert(r);
+                }
+            }
+            if out != live[pc] {
+                live[pc] = out;
+                changed = true;
+            }
+        }
+    }
+    live
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Param,
+    /// The `arg` table of a vararg function.
+    Arg,
+    /// A local the compiler made up, like `(for index)`.
+    Internal(&'static str),
+    Var,
+}
+
+#[derive(Clone, Debug)]
+struct Local {
+    start: usize,
+    end: usize,
+    kind: Kind,
+}
+
+struct Analysis<'a> {
+    fun: &'a Function,
+    accesses: Vec<Option<Access>>,
+    live: Vec<Regs>,
+    /// Whether the value written to a register at a pc is held by a local.
+    held: HashMap<(usize, u8), bool>,
+    /// The end of the loop starting at each pc.
+    loops: HashMap<usize, usize>,
+    /// Where `break` jumps to.
+    exits: HashSet<usize>,
+    /// Forward jumps, including the skip of a LOADBOOL.
+    jumps: Vec<(usize, usize)>,
+    /// Where the table constructor started by the NEWTABLE at each pc ends.
+    tables: HashMap<usize, usize>,
+}
+
+impl<'a> Analysis<'a> {
+    fn new(fun: &'a Function) -> anyhow::Result<Analysis<'a>> {
+        let accesses = accesses(fun)?;
+        // Keep registers, and the register after the last, within a u8.
+        if fun.maxstacksize > MAXSTACK {
+            bail!("stack size {} exceeds {}", fun.maxstacksize, MAXSTACK);
+        }
+        let params =
+            u16::from(fun.num_params) + u16::from(fun.is_vararg.contains(VarargFlags::HASARG));
+        if params > u16::from(fun.maxstacksize) {
+            bail!("{} parameters exceed stack size", params);
+        }
+        for (pc, access) in accesses.iter().enumerate() {
+            if let Some(access) = access
+                && let Some(r) = access
+                    .reads
+                    .iter()
+                    .map(|&(r, _)| r)
+                    .chain(access.writes.iter().copied())
+                    .find(|&r| r >= fun.maxstacksize)
+            {
+                bail!("register {} out of range at pc {}", r, pc);
+            }
+        }
+        let live = liveness(&accesses);
+        let mut loops = HashMap::new();
+        let mut exits = HashSet::new();
+        let mut jumps = vec![];
+        let mut tables = HashMap::new();
+        for (pc, access) in accesses.iter().enumerate() {
+            let Some(access) = access else {
+                continue;
+            };
+            let is_test = |pc: usize| {
+                pc.checked_sub(1)
+                    .and_then(|p| accesses[p].as_ref())
+                    .is_some_and(|a| a.op.is_test())
+            };
+            match (access.op, access.succs.as_slice()) {
+                (OpCode::ForLoop, _) => {
+                    exits.insert(pc + 1);
+                }
+                (OpCode::TForLoop, _) => {
+                    exits.insert(pc + 2);
+                }
+                (OpCode::Jmp, &[target]) if target <= pc => {
+                    // A loop ending in a condition keeps its locals for it.
+                    let end = if is_test(pc) { pc + 1 } else { pc };
+                    let e = loops.entry(target).or_insert(end);
+                    *e = end.max(*e);
+                    exits.insert(pc + 1);
+                }
+                (OpCode::Jmp, &[target]) => jumps.push((pc, target)),
+                (OpCode::LoadBool, &[target]) => jumps.push((pc, target)),
+                (OpCode::NewTable, _) => {
+                    let t = access.writes[0];
+                    let mut end = pc + 1;
+                    for (q, field) in accesses.iter().enumerate().skip(pc + 1) {
+                        let Some(field) = field else {
+                            continue;
+                        };
+                        if field.writes.contains(&t) || field.reads.contains(&(t, Read::Consume)) {
+                            break;
+                        }
+                        if matches!(field.op, OpCode::SetTable | OpCode::SetList)
+                            && field.reads[0] == (t, Read::Keep)
+                        {
+                            end = q + 1;
+                        }
+                    }
+                    tables.insert(pc, end);
+                }
+                _ => {}
+            }
+        }
+        Ok(Analysis {
+            fun,
+            accesses,
+            live,
+            held: HashMap::new(),
+            loops,
+            exits,
+            jumps,
+            tables,
+        })
+    }
+
+    fn access(&self, pc: usize) -> Option<&Access> {
+        self.accesses.get(pc).and_then(Option::as_ref)
+    }
+
+    /// Whether the value written to `r` at `pc` is held by a local, following
+    /// it along every path to the instructions reading it.
+    fn is_held(&mut self, pc: usize, r: u8) -> bool {
+        if let Some(&held) = self.held.get(&(pc, r)) {
+            return held;
+        }
+        let held = self.follow(pc, r);
+        self.held.insert((pc, r), held);
+        held
+    }
+    /// Where a block ending at `end` drops its locals: before the CLOSE of
+    /// their upvalues, if any.
+    fn close(&self, end: usize) -> usize {
+        match end.checked_sub(1).and_then(|pc| self.access(pc)) {
+            Some(access) if access.op == OpCode::Close => end - 1,
+            _ => end,
+        }
+    }
+    /// Whether the value written to `r` at `pc` is ever read.
+    fn is_read(&self, pc: usize, r: u8) -> bool {
+        let access = self.access(pc).unwrap();
+        let stack = access
+            .succs
+            .iter()
+            .copied()
+            .filter(|&succ| access.kills(r, pc, succ))
+            .collect();
+        self.reaches_read(stack, r)
+    }
+    /// Whether `to` can be reached from `from`.
+    fn reaches(&self, from: usize, to: usize) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack = vec![from];
+        while let Some(q) = stack.pop() {
+            if !seen.insert(q) {
+                continue;
+            }
+            let Some(access) = self.access(q) else {
+                continue;
+            };
+            if access.succs.contains(&to) {
+                return true;
+            }
+            stack.extend(&access.succs);
+        }
+        false
+    }
+    /// Whether `pc` can be reached from the start without writing `r`.
+    fn unwritten(&self, pc: usize, r: u8) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack = vec![0];
+        while let Some(q) = stack.pop() {
+            if q == pc {
+                return true;
+            }
+            if !seen.insert(q) {
+                continue;
+            }
+            let Some(access) = self.access(q) else {
+                continue;
+            };
+            stack.extend(
+                access
+                    .succs
+                    .iter()
+                    .filter(|&&succ| !access.kills(r, q, succ)),
+            );
+        }
+        false
+    }
+    /// Whether `r` may be read from one of the pcs in `stack` on before it
+    /// is written.
+    fn reaches_read(&self, stack: Vec<usize>, r: u8) -> bool {
+        self.search(stack, r, |access, _| access.reads_before(r))
+    }
+    /// Whether an instruction for which `found` holds may be reached from
+    /// one of the pcs in `stack` on before `r` is written.
+    fn search(&self, mut stack: Vec<usize>, r: u8, found: impl Fn(&Access, usize) -> bool) -> bool {
+        let mut seen = HashSet::new();
+        while let Some(q) = stack.pop() {
+            if !seen.insert(q) {
+                continue;
+            }
+            let Some(access) = self.access(q) else {
+                continue;
+            };
+            if found(access, q) {
+                return true;
+            }
+            stack.extend(
+                access
+                    .succs
+                    .iter()
+                    .filter(|&&succ| !access.kills(r, q, succ)),
+            );
+        }
+        false
+    }
+    /// The pcs reading the value written to `r` at `def`, or held by `r` from
+    /// the start of the function if `None`.
+    fn uses(&self, def: Option<usize>, r: u8) -> HashSet<usize> {
+        let mut stack = match def {
+            Some(pc) => {
+                let access = self.access(pc).unwrap();
+                access
+                    .succs
+                    .iter()
+                    .copied()
+                    .filter(|&succ| access.kills(r, pc, succ))
+                    .collect()
+            }
+            None => vec![0],
+        };
+        let mut seen = HashSet::new();
+        let mut uses = HashSet::new();
+        while let Some(q) = stack.pop() {
+            if !seen.insert(q) {
+                continue;
+            }
+            let Some(access) = self.access(q) else {
+                continue;
+            };
+            if access.reads_before(r) {
+                uses.insert(q);
+            }
+            stack.extend(
+                access
+                    .succs
+                    .iter()
+                    .filter(|&&succ| !access.kills(r, q, succ)),
+            );
+        }
+        uses
+    }
+    fn follow(&self, pc: usize, r: u8) -> bool {
+        let access = self.access(pc).unwrap();
+        if access.reads.contains(&(r, Read::Capture)) {
+            return true;
+        }
+        // The compiler only copies a local to a temporary for an
+        // instruction needing its operands in consecutive registers, or for
+        // the last part of a value like `a or b`.
+        let moved = access.op == OpCode::Move
+            && !(pc >= 2
+                && self.access(pc - 2).is_some_and(|a| a.op.is_test())
+                && self.access(pc - 1).is_some_and(|a| a.succs == [pc + 1]));
+        let fun = self.fun;
+        // Several results are only taken for locals, a generic for loop or
+        // the values of a multiple assignment.
+        let i = Instruction::decode(fun.code[pc]).unwrap();
+        let results = match access.op {
+            OpCode::Call => i.c,
+            OpCode::VarArg => i.b,
+            _ => 0,
+        };
+        if results > 2 {
+            let store = |op| {
+                matches!(
+                    op,
+                    OpCode::Move
+                        | OpCode::SetGlobal
+                        | OpCode::SetUpval
+                        | OpCode::SetTable
+                        | OpCode::Jmp
+                        | OpCode::TForLoop
+                )
+            };
+            if self
+                .uses(Some(pc), r)
+                .into_iter()
+                .any(|q| !store(self.access(q).unwrap().op))
+            {
+                return true;
+            }
+        }
+        let mut read = false;
+        let mut seen = HashSet::new();
+        let mut stack: Vec<(usize, bool, bool)> = access
+            .succs
+            .iter()
+            .filter(|&&succ| access.kills(r, pc, succ))
+            .map(|&succ| (succ, false, false))
+            .collect();
+        while let Some((q, consumed, crossed)) = stack.pop() {
+            if !seen.insert((q, consumed, crossed)) {
+                continue;
+            }
+            let Some(access) = self.access(q) else {
+                continue;
+            };
+            let mut consumed = consumed;
+            for &(s, how) in &access.reads {
+                // A closure capturing its own register is a local function,
+                // and loops read their own locals.
+                if s != r
+                    || (how == Read::Capture && access.writes.contains(&r))
+                    || matches!(access.op, OpCode::ForLoop | OpCode::TForLoop)
+                {
+                    continue;
+                }
+                // Filling in a table is not using it.
+                read |=
+                    !(how == Read::Keep && matches!(access.op, OpCode::SetTable | OpCode::SetList));
+                // Except for the values of a multiple assignment, stored
+                // after the last one.
+                let stored = crossed && matches!(access.op, OpCode::SetGlobal | OpCode::SetUpval);
+                if moved && !stored && reads_anywhere(access.op, fun.code[q]) {
+                    return true;
+                }
+                // Temporaries only outlive a write to a register below them,
+                // or a store, when they are values of a multiple assignment.
+                if crossed
+                    && !matches!(
+                        access.op,
+                        OpCode::Move | OpCode::SetTable | OpCode::SetGlobal | OpCode::SetUpval
+                    )
+                {
+                    return true;
+                }
+                match how {
+                    // A temporary tested for `a and b` is replaced when the
+                    // test falls through, by the value of an expression if it
+                    // is still wanted after the jump.
+                    Read::Keep if access.op == OpCode::Test => {
+                        if self.folds(pc, q) {
+                            return true;
+                        }
+                        let wanted = self.reaches_read(vec![q + 1], r);
+                        let statement = |access: &Access, q| {
+                            access.reads(r) || (wanted && is_statement(access, fun.code[q]))
+                        };
+                        if self.search(vec![q + 2], r, statement) {
+                            return true;
+                        }
+                    }
+                    Read::Keep => {}
+                    Read::Capture => return true,
+                    Read::Consume => {
+                        // A temporary is read once, as the topmost live value,
+                        // and replaced by a result no higher.
+                        let above = (r.saturating_add(1)..=u8::MAX)
+                            .any(|t| t > r && self.live[q].contains(t) && !access.reads(t));
+                        // Nor does a table or key come after the value stored.
+                        let result = access.writes.iter().min().is_some_and(|&w| w > r)
+                            || (access.op == OpCode::SetTable
+                                && Instruction::decode(fun.code[q]).is_ok_and(|i| {
+                                    i.a > r
+                                        || (Rk::from(i.c) == Rk::Register(r)
+                                            && matches!(Rk::from(i.b), Rk::Register(k) if k > r))
+                                }));
+                        if consumed || above || result {
+                            return true;
+                        }
+                        consumed = true;
+                    }
+                }
+            }
+            // A loop takes over the values it starts from.
+            if matches!(access.op, OpCode::ForPrep | OpCode::Jmp) && access.reads(r) {
+                continue;
+            }
+            // The jumps of a condition only drop temporaries they compute.
+            if access.op.is_test()
+                && !consumed
+                && !access.reads(r)
+                && !access.writes.contains(&r)
+                && q + 2 < self.live.len()
+                && self.live[q + 1].contains(r) != self.live[q + 2].contains(r)
+            {
+                return true;
+            }
+            // Nor a call statement, or a jump between statements.
+            let statement = match access.op {
+                OpCode::Call => Instruction::decode(fun.code[q]).is_ok_and(|i| i.c == 1),
+                OpCode::Jmp => is_jump_between(&self.accesses, q),
+                _ => false,
+            };
+            if statement && !access.reads(r) && self.live[q].contains(r) {
+                return true;
+            }
+            let crossed = crossed
+                || access.writes.iter().any(|&w| w < r)
+                || matches!(access.op, OpCode::SetGlobal | OpCode::SetUpval);
+            for &succ in &access.succs {
+                if !access.kills(r, q, succ) {
+                    stack.push((succ, consumed, crossed));
+                }
+            }
+        }
+        !read
+    }
+    /// Whether the TEST at `test` would have been folded away had the
+    /// constant written at `def` been a temporary: the compiler only tests
+    /// `nil` for a condition or `a and b`, and a number or string for
+    /// `a or b`.
+    fn folds(&self, def: usize, test: usize) -> bool {
+        let (Ok(i), Ok(t)) = (
+            Instruction::decode(self.fun.code[def]),
+            Instruction::decode(self.fun.code[test]),
+        ) else {
+            return false;
+        };
+        match i.op {
+            OpCode::LoadK => t.c == 0,
+            // The LOADBOOLs giving a comparison's value are jumped to or skip.
+            OpCode::LoadBool => i.c == 0 && !self.jumps.iter().any(|&(_, to)| to == def),
+            OpCode::LoadNil => t.c != 0,
+            _ => false,
+        }
+    }
+    /// Where a local in `regs` whose value is written before `pc` in the
+    /// block from `start` to `end` starts: after the jumps and tests of the
+    /// expression giving its value.
+    fn start(&self, (start, end): (usize, usize), pc: usize, regs: &RangeInclusive<u8>) -> usize {
+        let mut pc = pc;
+        loop {
+            if let Some(test) = self.access(pc)
+                && test.op == OpCode::Test
+                && regs.contains(&test.reads[0].0)
+                && !(pc > 0
+                    && self
+                        .access(pc - 1)
+                        .is_some_and(|a| a.writes.contains(&test.reads[0].0))
+                    && self.folds(pc - 1, pc))
+                && let Some(&[x]) = self.access(pc + 1).map(|j| j.succs.as_slice())
+                && x > pc + 2
+                && self
+                    .access(x - 1)
+                    .is_some_and(|a| a.writes.contains(&test.reads[0].0))
+                && (pc + 2..x - 1).all(|q| {
+                    self.access(q).is_none_or(|a| {
+                        !is_statement(a, self.fun.code[q])
+                            && !a.reads(test.reads[0].0)
+                            && !a
+                                .writes
+                                .iter()
+                                .any(|&w| regs.contains(&w) && w != test.reads[0].0)
+                    })
+                })
+            {
+                pc = x;
+                continue;
+            }
+            match self
+                .jumps
+                .iter()
+                .filter(|&&(from, to)| {
+                    (start..pc).contains(&from) && pc < to && to <= end && !self.exits.contains(&to)
+                })
+                .map(|&(_, to)| to)
+                .max()
+            {
+                Some(to) => pc = to,
+                None => return pc,
+            }
+        }
+    }
+}
+
+/// Whether the JMP at `pc` jumps between statements rather than within an
+/// expression: it follows no test, and does not skip the LOADBOOLs giving a
+/// comparison's value.
+fn is_jump_between(accesses: &[Option<Access>], pc: usize) -> bool {
+    let access = |pc: usize| accesses.get(pc).and_then(Option::as_ref);
+    let after_test = pc > 0 && access(pc - 1).is_some_and(|a| a.op.is_test());
+    let to_value = access(pc).unwrap().succs.first().is_some_and(|&x| {
+        x >= 2 && access(x - 2).is_some_and(|a| a.op == OpCode::LoadBool && a.succs == [x])
+    });
+    access(pc).is_some_and(|a| a.op == OpCode::Jmp) && !after_test && !to_value
+}
+
+/// Whether the instruction `raw` can only be a statement.
+fn is_statement(access: &Access, raw: u32) -> bool {
+    match access.op {
+        OpCode::SetGlobal | OpCode::SetUpval | OpCode::Return => true,
+        OpCode::SetTable => access.reads[0].1 == Read::Consume,
+        OpCode::Call => Instruction::decode(raw).is_ok_and(|i| i.c == 1),
+        _ => false,
+    }
+}
+
+/// Whether the instruction `raw` can read its register operands from
+/// anywhere, including locals.
+fn reads_anywhere(op: OpCode, raw: u32) -> bool {
+    match op {
+        OpCode::GetTable
+        | OpCode::SetTable
+        | OpCode::Self_
+        | OpCode::Add
+        | OpCode::Sub
+        | OpCode::Mul
+        | OpCode::Div
+        | OpCode::Mod
+        | OpCode::Pow
+        | OpCode::Unm
+        | OpCode::Not
+        | OpCode::Len
+        | OpCode::Eq
+        | OpCode::Lt
+        | OpCode::Le
+        | OpCode::Test
+        | OpCode::TestSet
+        | OpCode::SetGlobal
+        | OpCode::SetUpval => true,
+        OpCode::Return => Instruction::decode(raw).is_ok_and(|i| i.b == 2),
+        _ => false,
+    }
+}
+
+/// Infers the locals of a function, in the order `locvars` lists them, with
+/// names given by `names`.
+fn infer(fun: &Function, names: &mut Names) -> anyhow::Result<Vec<LocVar>> {
+    // Not even a RETURN, so nothing holds a local.
+    if fun.code.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut analysis = Analysis::new(fun)?;
+    // Values found to be held by locals, and registers holding locals from
+    // the start of the function without ever being written.
+    let mut forced = HashSet::new();
+    let mut preset = 0;
+    for r in 0..=u8::MAX {
+        if analysis.live[0].contains(r) {
+            preset = r + 1;
+        }
+    }
+    // Each pass either forces another (pc, register) or raises `preset`, so
+    // there are at most that many passes before the locals settle.
+    let mut passes = (fun.code.len() + 1) * usize::from(MAXSTACK) + 1;
+    let locals = loop {
+        let Some(left) = passes.checked_sub(1) else {
+            bail!("locals did not settle");
+        };
+        passes = left;
+        let (locals, more) = walk(&mut analysis, &forced, preset);
+        let mut changed = false;
+        for (pc, r) in more {
+            changed |= match pc {
+                Some(pc) => forced.insert((pc, r)),
+                None => preset <= r,
+            };
+            if pc.is_none() {
+                preset = preset.max(r + 1);
+            }
+        }
+        if !changed {
+            break locals;
+        }
+    };
+    let mut locals: Vec<(u8, Local)> = locals
+        .into_iter()
+        .filter(|(_, l)| l.start <= l.end)
+        .collect();
+    locals.sort_by_key(|(r, l)| (l.start, *r));
+    Ok(locals
+        .into_iter()
+        .map(|(_, l)| LocVar {
+            varname: match l.kind {
+                Kind::Param => names.next("p"),
+                Kind::Arg => "arg".into(),
+                Kind::Internal(name) => name.into(),
+                Kind::Var => names.next("l"),
+            },
+            startpc: l.start as u32,
+            endpc: l.end as u32,
+        })
+        .collect())
+}
+
+/// One pass over the code in order, tracking the locals active at each pc
+/// from the values `is_held` finds and those `forced` to be.
+///
+/// Returns the locals with their registers, and the values, or registers
+/// from the start if not written, found to be held by locals on the way
+/// that were not yet known to be.
+#[allow(clippy::type_complexity)]
+fn walk(
+    analysis: &mut Analysis,
+    forced: &HashSet<(usize, u8)>,
+    preset: u8,
+) -> (Vec<(u8, Local)>, Vec<(Option<usize>, u8)>) {
+    let fun = analysis.fun;
+    let len = fun.code.len();
+    let mut locals: Vec<(u8, Local)> = vec![];
+    let mut more = vec![];
+    // Indices into `locals` of the active ones, holding registers 0, 1, ...
+    let mut active: Vec<usize> = vec![];
+    // The start and end of the blocks around the current pc.
+    let mut blocks = vec![(0, len.saturating_sub(1))];
+    let mut elses: HashMap<usize, usize> = HashMap::new();
+    // The writes to the registers of locals while active, by index.
+    let mut defs: HashMap<usize, Vec<Option<usize>>> = HashMap::new();
+    let declare = |locals: &mut Vec<(u8, Local)>, active: &mut Vec<usize>, local| {
+        locals.push((active.len() as u8, local));
+        active.push(locals.len() - 1);
+    };
+    let params = fun.num_params;
+    let has_arg = fun.is_vararg.contains(VarargFlags::HASARG);
+    for r in 0..(params + u8::from(has_arg)).max(preset) {
+        let kind = match r {
+            r if r < params => Kind::Param,
+            r if r == params && has_arg => Kind::Arg,
+            _ => Kind::Var,
+        };
+        let end = blocks[0].1;
+        declare(
+            &mut locals,
+            &mut active,
+            Local {
+                start: 0,
+                end,
+                kind,
+            },
+        );
+        defs.insert(locals.len() - 1, vec![None]);
+    }
+    for pc in 0..len {
+        let Some(access) = analysis.access(pc).cloned() else {
+            continue;
+        };
+        active.retain(|&l| locals[l].1.end > pc);
+        while blocks.len() > 1 && blocks.last().unwrap().1 <= pc {
+            blocks.pop();
+        }
+        let clamp = |blocks: &[(usize, usize)], end: usize| end.min(blocks.last().unwrap().1);
+        if let Some(end) = elses.remove(&pc) {
+            blocks.push((pc, clamp(&blocks, analysis.close(end))));
+        }
+        if let Some(&end) = analysis.loops.get(&pc) {
+            blocks.push((pc, clamp(&blocks, analysis.close(end))));
+        }
+        // The first register of a loop's locals, where they end, how many
+        // are variables and where the body ends.
+        let mut for_loop = None;
+        match (access.op, access.succs.as_slice()) {
+            (OpCode::ForPrep, &[q]) => {
+                let a = Instruction::decode(fun.code[pc]).unwrap().a;
+                for_loop = Some((a, q + 1, 1, q));
+            }
+            (OpCode::Jmp, &[q])
+                if analysis.access(q).is_some_and(|a| a.op == OpCode::TForLoop)
+                    && analysis.access(q + 1).is_some_and(|a| a.succs == [pc + 1]) =>
+            {
+                let i = Instruction::decode(fun.code[q]).unwrap();
+                for_loop = Some((i.a, q + 2, i.c, q));
+            }
+            // The then block of an if, or the body of a while loop; TESTSETs
+            // and comparisons choosing between LOADBOOLs only make values.
+            (OpCode::Jmp, &[x])
+                if x > pc
+                    && pc > 0
+                    && analysis
+                        .access(pc - 1)
+                        .is_some_and(|a| a.op.is_test() && a.op != OpCode::TestSet)
+                    && !analysis
+                        .access(x - 1)
+                        .is_some_and(|a| a.op == OpCode::LoadBool && a.succs == [x + 1])
+                    && !is_or(analysis, x) =>
+            {
+                let mut end = x;
+                if x >= pc + 2
+                    && let Some(skip) = analysis.access(x - 1)
+                    && skip.op == OpCode::Jmp
+                    && !analysis.access(x - 2).is_some_and(|a| a.op.is_test())
+                    && let [y] = skip.succs[..]
+                    && !analysis.exits.contains(&y)
+                {
+                    end = x - 1;
+                    if y > x {
+                        elses.insert(x, y);
+                    }
+                }
+                blocks.push((pc + 1, clamp(&blocks, analysis.close(end))));
+            }
+            _ => {}
+        }
+        if let Some((a, end, vars, body)) = for_loop {
+            end_locals(&mut locals, &mut active, a, pc);
+            gap(analysis, &active, pc, a, &mut more);
+            let outer = clamp(&blocks, analysis.close(end));
+            for name in ["(for index)", "(for limit)", "(for step)"]
+                .into_iter()
+                .take(if access.op == OpCode::ForPrep { 3 } else { 0 })
+                .chain(
+                    ["(for generator)", "(for state)", "(for control)"]
+                        .into_iter()
+                        .take(if access.op == OpCode::Jmp { 3 } else { 0 }),
+                )
+            {
+                let kind = Kind::Internal(name);
+                declare(
+                    &mut locals,
+                    &mut active,
+                    Local {
+                        start: pc,
+                        end: outer,
+                        kind,
+                    },
+                );
+            }
+            let end = clamp(&blocks, body);
+            for _ in 0..vars {
+                let kind = Kind::Var;
+                declare(
+                    &mut locals,
+                    &mut active,
+                    Local {
+                        start: pc + 1,
+                        end,
+                        kind,
+                    },
+                );
+            }
+            blocks.push((pc + 1, clamp(&blocks, analysis.close(body))));
+            continue;
+        }
+        if matches!(access.op, OpCode::ForLoop | OpCode::TForLoop) {
+            continue;
+        }
+        let mut writes = access.writes.clone();
+        writes.sort();
+        // A table constructor is always built in a free register, moved to
+        // a local it is assigned to.
+        if access.op == OpCode::NewTable
+            && active
+                .get(usize::from(writes[0]))
+                .is_some_and(|&l| locals[l].1.start <= pc)
+        {
+            end_locals(&mut locals, &mut active, writes[0], pc);
+        }
+        let mut top = None;
+        for &r in &writes {
+            let held = forced.contains(&(pc, r)) || analysis.is_held(pc, r);
+            // A local whose only value is dropped unread, in the block it is
+            // declared in, ends there, like one declared last in a block.
+            if held
+                && usize::from(r) + 1 == active.len()
+                && let l = active[usize::from(r)]
+                && locals[l].1.kind == Kind::Var
+                && blocks.last().unwrap().0 <= locals[l].1.start
+                && locals[l].1.start <= pc
+                && let Some(&[Some(def)]) = defs.get(&l).map(Vec::as_slice)
+                && !analysis.is_read(def, r)
+                && analysis
+                    .access(def)
+                    .is_some_and(|a| a.op != OpCode::LoadNil)
+            {
+                end_locals(&mut locals, &mut active, r, pc);
+            }
+            if usize::from(r) < active.len() {
+                let l = active[usize::from(r)];
+                // A value merging with an earlier one of the local is
+                // assigned to it rather than a temporary, as are the parts
+                // of the expression it starts with.
+                let assigned = held || locals[l].1.start > pc || {
+                    let uses = analysis.uses(Some(pc), r);
+                    defs.get(&l).is_some_and(|defs| {
+                        defs.iter()
+                            .any(|&def| !analysis.uses(def, r).is_disjoint(&uses))
+                    })
+                };
+                if assigned {
+                    defs.entry(l).or_default().push(Some(pc));
+                } else {
+                    end_locals(&mut locals, &mut active, r, pc);
+                }
+            } else if held {
+                // A value meeting the register unwritten from the start, or
+                // a value of a later branch before the register is written,
+                // is assigned to a local declared there.
+                let uses = analysis.uses(Some(pc), r);
+                let first = (0..pc)
+                    .all(|q| analysis.access(q).is_none_or(|a| !a.writes.contains(&r)))
+                    && (pc + 1..len).any(|q| {
+                        analysis.access(q).is_some_and(|a| a.writes.contains(&r))
+                            && !analysis.reaches(pc, q)
+                            && !analysis.uses(Some(q), r).is_disjoint(&uses)
+                    });
+                if first || uses.into_iter().any(|u| analysis.unwritten(u, r)) {
+                    more.push((None, r));
+                } else {
+                    top = Some(r);
+                }
+            }
+        }
+        // Several results of one call are all declared together.
+        if top.is_some() && matches!(access.op, OpCode::Call | OpCode::VarArg) {
+            top = writes.last().copied();
+        }
+        let first = writes
+            .iter()
+            .copied()
+            .find(|&r| usize::from(r) >= active.len());
+        if let Some(first) = first
+            && top.is_none_or(|top| first < top)
+        {
+            gap(analysis, &active, pc, first, &mut more);
+        }
+        if let Some(top) = top {
+            // A TESTSET's value is only complete after its JMP.
+            let mut next = pc + 1 + access_width(analysis, pc) + usize::from(access.op.is_test());
+            if let Some(&end) = analysis.tables.get(&pc) {
+                next = end;
+            }
+            // The jumps of a value like `a and b` look like those of an if
+            // statement, whose block the value outlives.
+            let regs = active.len() as u8..=top;
+            let read = access
+                .writes
+                .iter()
+                .any(|&w| regs.contains(&w) && analysis.is_read(pc, w));
+            let (from, end) = blocks
+                .iter()
+                .rev()
+                .map(|&(start, end)| (analysis.start((start, end), next, &regs), end))
+                .find(|&(from, end)| from < end || (from == end && !read))
+                .unwrap_or((next, blocks[0].1));
+            // A value outliving the if statement in one of whose branches it
+            // is written is assigned to a local declared before the statement.
+            let (inner, _) = *blocks.last().unwrap();
+            if (next..from).any(|q| {
+                analysis.access(q).is_some_and(|a| a.op == OpCode::Jmp)
+                    && is_jump_between(&analysis.accesses, q)
+            }) {
+                for r in regs.clone() {
+                    let def = if analysis.unwritten(inner, r) {
+                        None
+                    } else {
+                        (0..inner)
+                            .rev()
+                            .find(|&q| analysis.access(q).is_some_and(|a| a.writes.contains(&r)))
+                    };
+                    more.push((def, r));
+                }
+            }
+            while active.len() <= usize::from(top) {
+                let kind = Kind::Var;
+                declare(
+                    &mut locals,
+                    &mut active,
+                    Local {
+                        start: from,
+                        end,
+                        kind,
+                    },
+                );
+                defs.insert(locals.len() - 1, vec![Some(pc)]);
+            }
+        }
+    }
+    (locals, more)
+}
+
+/// Whether `x` is jumped to by a test of a condition like `a or b`, landing
+/// just after the test and JMP of the next part.
+fn is_or(analysis: &Analysis, x: usize) -> bool {
+    x >= 2
+        && analysis.access(x - 2).is_some_and(|a| a.op.is_test())
+        && analysis
+            .access(x - 1)
+            .is_some_and(|a| a.op == OpCode::Jmp && a.succs.iter().all(|&y| y > x))
+}
+
+/// The number of words after the instruction at `pc` that belong to it.
+fn access_width(analysis: &Analysis, pc: usize) -> usize {
+    (pc + 1..analysis.accesses.len())
+        .take_while(|&q| analysis.accesses[q].is_none())
+        .count()
+}
+
+/// Ends the active locals holding registers `r` and up at `pc`, where their
+/// registers are reused for temporaries.
+fn end_locals(locals: &mut [(u8, Local)], active: &mut Vec<usize>, r: u8, pc: usize) {
+    for l in active.drain(usize::from(r).min(active.len())..) {
+        let local = &mut locals[l].1;
+        local.end = pc.max(local.start);
+    }
+}
+
+/// Records the registers below `r` holding no live value at `pc` above the
+/// active locals, which a temporary in `r` shows to be held by locals: their
+/// last writes, or the start of the function if never written.
+fn gap(
+    analysis: &Analysis,
+    active: &[usize],
+    pc: usize,
+    r: u8,
+    more: &mut Vec<(Option<usize>, u8)>,
+) {
+    let access = analysis.access(pc).unwrap();
+    for g in active.len() as u8..r {
+        if analysis.live[pc].contains(g) || access.reads(g) {
+            continue;
+        }
+        // A register not written on some path from the start holds a local
+        // declared there, its LOADNIL left out.
+        let def = if analysis.unwritten(pc, g) {
+            None
+        } else {
+            (0..pc)
+                .rev()
+                .find(|&q| analysis.access(q).is_some_and(|a| a.writes.contains(&g)))
+        };
+        more.push((def, g));
+    }
+}
+
+/// Synthesizes names, skipping those of globals and any reserved.
+pub(crate) struct Names {
+    taken: HashSet<Vec<u8>>,
+    counts: HashMap<&'static str, usize>,
+}
+
+impl Names {
+    pub(crate) fn new(fun: &Function) -> Names {
+        let mut taken = HashSet::new();
+        globals(fun, &mut taken);
+        Names {
+            taken,
+            counts: HashMap::new(),
+        }
+    }
+    /// Keeps `name` from being synthesized, e.g. as a local already has it.
+    pub(crate) fn reserve(&mut self, name: &[u8]) {
+        self.taken.insert(name.to_vec());
+    }
+    pub(crate) fn next(&mut self, prefix: &'static str) -> LuaString {
+        let count = self.counts.entry(prefix).or_default();
+        loop {
+            *count += 1;
+            let name = format!("{}{}", prefix, count);
+            if !self.taken.contains(name.as_bytes()) {
+                return name.as_str().into();
+            }
+        }
+    }
+}
+
+/// Collects the names of the globals `fun` and its children use.
+fn globals(fun: &Function, names: &mut HashSet<Vec<u8>>) {
+    for i in fun
+        .code
+        .iter()
+        .filter_map(|&raw| Instruction::decode(raw).ok())
+    {
+        if matches!(i.op, OpCode::GetGlobal | OpCode::SetGlobal)
+            && let Some(Constant::String(s)) = fun.constants.get(i.bx() as usize)
+        {
+            names.insert(s.as_bytes().to_vec());
+        }
+    }
+    for child in &fun.funs {
+        globals(child, names);
+    }
+}
+
+/// Infers the locals of a stripped function from its code alone, in the
+/// order `locvars` lists them, naming them `p1, p2, ...` for parameters and
+/// `l1, l2, ...` for the rest.
+pub fn infer_locvars(fun: &Function) -> anyhow::Result<Vec<LocVar>> {
+    infer(fun, &mut Names::new(fun))
+}
+
+/// Fills in the `locvars` and upvalue names of `fun` and its children where
+/// they were stripped, giving locals names unique in the whole tree.
+pub fn restore(fun: &mut Function) -> anyhow::Result<()> {
+    restore_with(fun, &mut Names::new(fun))
+}
+
+fn restore_with(fun: &mut Function, names: &mut Names) -> anyhow::Result<()> {
+    // A function with no locals infers none, so there is no telling it
+    // from one stripped.
+    if fun.locvars.is_empty() {
+        fun.locvars = infer(fun, names)?;
+    }
+    let mut pc = 0;
+    while pc < fun.code.len() {
+        let i = Instruction::decode(fun.code[pc])?;
+        pc += 1;
+        if i.op != OpCode::Closure {
+            continue;
+        }
+        let Some(child) = fun.funs.get(i.bx() as usize) else {
+            bail!("closure of missing function at pc {}", pc - 1);
+        };
+        let nups = usize::from(child.nups);
+        let (at, next) = ((pc - 1) as u32, (pc + nups) as u32);
+        let mut upvalues = vec![];
+        for raw in fun.code.iter().skip(pc).take(nups) {
+            let pseudo = Instruction::decode(*raw)?;
+            let n = upvalues.len();
+            upvalues.push(
+                match pseudo.op {
+                    // A local function is only declared after its closure, and
+                    // a block may end at the CLOSE of the locals captured.
+                    OpCode::Move => {
+                        let b = usize::from(pseudo.b);
+                        fun.local_name(b, next)
+                            .or_else(|| fun.local_name(b, at))
+                            .cloned()
+                    }
+                    _ => fun.upvalues.get(usize::from(pseudo.b)).cloned(),
+                }
+                .unwrap_or_else(|| format!("upvalue{}", n).as_str().into()),
+            );
+        }
+        pc += nups;
+        let child = &mut fun.funs[i.bx() as usize];
+        if child.upvalues.is_empty() {
+            child.upvalues = upvalues;
+        }
+    }
+    for child in &mut fun.funs {
+        restore_with(child, names)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lifetime::*;
+
+    #[test]
+    fn test() {
+        // local t = {}
+        // for i = 1, 3 do
+        //   local v = i * 2
+        //   t[i] = v + v
+        // end
+        // local function f(x)
+        //   return x + #t
+        // end
+        // print(f(1))
+        let mut fun = Function {
+            code: vec![
+                0x0000000a, 0x00000041, 0x00004081, 0x000000c1, 0x80008060, 0x0240814e, 0x0281418c,
+                0x02018009, 0x7ffec05f, 0x00000064, 0x00000000, 0x0000c085, 0x008000c0, 0x00000101,
+                0x010000dc, 0x0000409c, 0x0080001e,
+            ],
+            constants: vec![
+                Constant::Number(1.0),
+                Constant::Number(3.0),
+                Constant::Number(2.0),
+                Constant::String("print".into()),
+            ],
+            funs: vec![Function {
+                code: vec![0x00000044, 0x00800054, 0x0000404c, 0x0100005e, 0x0080001e],
+                nups: 1,
+                num_params: 1,
+                maxstacksize: 2,
+                ..Default::default()
+            }],
+            is_vararg: VarargFlags::ISVARARG,
+            maxstacksize: 7,
+            ..Default::default()
+        };
+        let scopes = |locvars: &[LocVar]| {
+            locvars
+                .iter()
+                .map(|l| (l.varname.to_string(), l.startpc, l.endpc))
+                .collect::<Vec<_>>()
+        };
+        let expected = [
+            ("l1", 1, 16),
+            ("(for index)", 4, 9),
+            ("(for limit)", 4, 9),
+            ("(for step)", 4, 9),
+            ("l2", 5, 8),
+            ("l3", 6, 8),
+            ("l4", 11, 16),
+        ]
+        .map(|(name, startpc, endpc)| (name.to_string(), startpc, endpc));
+        assert_eq!(scopes(&infer_locvars(&fun).unwrap()), expected);
+
+        restore(&mut fun).unwrap();
+        assert_eq!(scopes(&fun.locvars), expected);
+        assert_eq!(scopes(&fun.funs[0].locvars), [("p1".to_string(), 0, 4)]);
+        assert_eq!(fun.funs[0].upvalues, ["l1".into()]);
+
+        // A RETURN of register 255, live from the start, is refused.
+        let fun = Function {
+            code: vec![30 | 255 << 6 | 2 << 23],
+            maxstacksize: 255,
+            ..Default::default()
+        };
+        assert!(infer_locvars(&fun).is_err());
+
+        // A function with no code at all has no locals.
+        let mut fun = Function::default();
+        assert!(infer_locvars(&fun).unwrap().is_empty());
+        restore(&mut fun).unwrap();
+        assert!(fun.locvars.is_empty());
+    }
+}
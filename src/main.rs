@@ -1,15 +1,123 @@
-use clap::Parser;
+use anyhow::bail;
+use clap::{Parser, Subcommand};
+use yellowmoon::decompile::decompile;
+use yellowmoon::disasm::{Dot, Hexdump, Listing};
+use yellowmoon::probe::{Dialect, probe};
+use yellowmoon::undump;
+use yellowmoon::verify::verify;
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    filename: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Same as `debug FILENAME`.
+    #[arg(required = true)]
+    filename: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print a `luac -l -l` style listing of a Lua 5.1 chunk.
+    List { filename: String },
+    /// Print the undumped chunk header and function tree.
+    Debug {
+        filename: String,
+        /// Print as much of a damaged Lua 5.1 chunk as loads, followed by
+        /// what went wrong.
+        #[arg(long)]
+        lenient: bool,
+    },
+    /// Print a Lua 5.1 chunk as hex, labelling each field with its value.
+    Hexdump { filename: String },
+    /// Print the control-flow graphs of a Lua 5.1 chunk's functions in
+    /// Graphviz DOT.
+    Dot { filename: String },
+    /// Check a Lua 5.1 chunk's code as the reference loader does, printing
+    /// each problem found.
+    Verify { filename: String },
+    /// Print Lua 5.1 source reconstructed from a chunk.
+    Decompile { filename: String },
+    /// Print the dialect and header of a file without loading it.
+    Probe { filename: String },
 }
 
 fn main() -> Result<(), anyhow::Error> {
     let cli = Cli::parse();
-    println!(
-        "{:#?}",
-        yellowmoon::undump::undump(&std::fs::read(cli.filename)?)?
-    );
+    let command = cli.command.unwrap_or(Command::Debug {
+        filename: cli.filename.unwrap_or_default(),
+        lenient: false,
+    });
+    match command {
+        Command::List { filename } => {
+            let data = std::fs::read(filename)?;
+            match probe(&data)?.dialect()? {
+                Dialect::Lua51 => print!("{}", Listing(&undump::undump(&data)?)),
+                dialect => bail!("cannot list {} files", dialect),
+            }
+        }
+        Command::Debug { filename, lenient } => {
+            let data = std::fs::read(filename)?;
+            match probe(&data)?.dialect()? {
+                Dialect::Lua51 if lenient => {
+                    println!("{:#?}", undump::undump_lenient(&data, Default::default())?)
+                }
+                dialect if lenient => bail!("cannot leniently load {} files", dialect),
+                Dialect::Lua50 => println!("{:#?}", undump::lua50::undump_chunk(&data)?),
+                Dialect::Lua51 => println!("{:#?}", undump::undump_chunk(&data)?),
+                Dialect::Lua52 => println!("{:#?}", undump::lua52::undump_chunk(&data)?),
+                Dialect::Lua53 => println!("{:#?}", undump::lua53::undump_chunk(&data)?),
+                Dialect::Lua54 => println!("{:#?}", undump::lua54::undump_chunk(&data)?),
+                Dialect::LuaJit(_) => println!("{:#?}", undump::luajit::undump_chunk(&data)?),
+                Dialect::Source => bail!("{} is not a precompiled chunk", Dialect::Source),
+            }
+        }
+        Command::Hexdump { filename } => {
+            let data = std::fs::read(filename)?;
+            match probe(&data)?.dialect()? {
+                Dialect::Lua51 => {
+                    let (chunk, spans) = undump::undump_with_spans(&data)?;
+                    let data = &data;
+                    let (chunk, spans) = (&chunk, &spans);
+                    print!("{}", Hexdump { data, chunk, spans })
+                }
+                dialect => bail!("cannot hexdump {} files", dialect),
+            }
+        }
+        Command::Dot { filename } => {
+            let data = std::fs::read(filename)?;
+            match probe(&data)?.dialect()? {
+                Dialect::Lua51 => print!("{}", Dot(&undump::undump(&data)?)),
+                dialect => bail!("cannot graph {} files", dialect),
+            }
+        }
+        Command::Verify { filename } => {
+            let data = std::fs::read(filename)?;
+            match probe(&data)?.dialect()? {
+                Dialect::Lua51 => {
+                    let diagnostics = verify(&undump::undump(&data)?);
+                    for d in &diagnostics {
+                        println!("{}", d);
+                    }
+                    if !diagnostics.is_empty() {
+                        bail!("{} problems found", diagnostics.len());
+                    }
+                }
+                dialect => bail!("cannot verify {} files", dialect),
+            }
+        }
+        Command::Decompile { filename } => {
+            let data = std::fs::read(filename)?;
+            match probe(&data)?.dialect()? {
+                Dialect::Lua51 => print!("{}", decompile(&undump::undump(&data)?)?),
+                dialect => bail!("cannot decompile {} files", dialect),
+            }
+        }
+        Command::Probe { filename } => {
+            let probe = probe(&std::fs::read(filename)?)?;
+            println!("{}", probe.dialect()?);
+            println!("{:#?}", probe);
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,144 @@
+//! Detection of which Lua dialect, if any, wrote a file.
+
+use crate::instruction::luajit::Version;
+use crate::undump::{self, Error, ErrorKind, Header, luajit};
+use std::fmt::{Display, Formatter};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dialect {
+    Lua50,
+    Lua51,
+    Lua52,
+    Lua53,
+    Lua54,
+    LuaJit(Version),
+    /// Lua source text rather than a precompiled chunk.
+    Source,
+}
+
+impl Display for Dialect {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Dialect::Lua50 => "Lua 5.0",
+            Dialect::Lua51 => "Lua 5.1",
+            Dialect::Lua52 => "Lua 5.2",
+            Dialect::Lua53 => "Lua 5.3",
+            Dialect::Lua54 => "Lua 5.4",
+            Dialect::LuaJit(Version::LuaJit20) => "LuaJIT 2.0",
+            Dialect::LuaJit(Version::LuaJit21) => "LuaJIT 2.1",
+            Dialect::Source => "Lua source",
+        })
+    }
+}
+
+/// What `probe` found at the start of a file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Probe {
+    /// A chunk written by the reference implementation; `header.version`
+    /// tells which release.
+    Lua(Header),
+    LuaJit(luajit::Header),
+    Source,
+}
+
+impl Probe {
+    /// Fails if a `Probe::Lua` header has a version no dialect has.
+    pub fn dialect(&self) -> Result<Dialect, Error> {
+        Ok(match self {
+            Probe::Lua(h) => match h.version {
+                0x50 => Dialect::Lua50,
+                0x51 => Dialect::Lua51,
+                0x52 => Dialect::Lua52,
+                0x53 => Dialect::Lua53,
+                0x54 => Dialect::Lua54,
+                value => return Err(bad_version(value)),
+            },
+            Probe::LuaJit(h) => Dialect::LuaJit(h.version),
+            Probe::Source => Dialect::Source,
+        })
+    }
+}
+
+fn bad_version(value: u8) -> Error {
+    Error {
+        kind: ErrorKind::BadHeader {
+            field: "luac version",
+            value,
+        },
+        offset: 4,
+        path: vec![],
+    }
+}
+
+/// Identifies the dialect of `data` and reads its header, without loading
+/// any functions. As in `lua_load`, anything that does not start with an
+/// escape character is taken to be source text.
+pub fn probe(data: &[u8]) -> Result<Probe, Error> {
+    let err = |kind, offset| Error {
+        kind,
+        offset,
+        path: vec![],
+    };
+    match data {
+        [0x1b, b'L', b'J', ..] => Ok(Probe::LuaJit(luajit::undump_header(data)?)),
+        [0x1b, b'L', b'u', b'a', version, ..] => Ok(Probe::Lua(match version {
+            0x50 => undump::lua50::undump_header(data)?,
+            0x51 => undump::undump_header(data)?,
+            0x52 => undump::lua52::undump_header(data)?,
+            0x53 => undump::lua53::undump_header(data)?,
+            0x54 => undump::lua54::undump_header(data)?,
+            &value => return Err(bad_version(value)),
+        })),
+        [0x1b, rest @ ..] if b"Lua".starts_with(rest) || b"LJ".starts_with(rest) => {
+            Err(err(ErrorKind::Truncated("header"), data.len()))
+        }
+        [0x1b, ..] => Err(err(ErrorKind::BadSignature, 0)),
+        _ => Ok(Probe::Source),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::probe::*;
+
+    #[test]
+    fn test() {
+        let dialect = |data: &[u8]| probe(data).unwrap().dialect().unwrap();
+        assert_eq!(dialect(b"print('hi')\n"), Dialect::Source);
+        assert_eq!(dialect(b""), Dialect::Source);
+        assert_eq!(
+            dialect(b"\x1bLua\x51\x00\x01\x04\x08\x04\x08\x00"),
+            Dialect::Lua51
+        );
+        let lua54 = b"\
+\x1b\x4c\x75\x61\x54\x00\x19\x93\x0d\x0a\x1a\x0a\x04\x08\x08\x78\
+\x56\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x28\x77\x40";
+        match probe(lua54).unwrap() {
+            Probe::Lua(h) => {
+                assert_eq!(h.version, 0x54);
+                assert_eq!(h.sizeof_integer, 8);
+            }
+            p => panic!("{:?}", p),
+        }
+        match probe(b"\x1bLJ\x02\x02").unwrap() {
+            Probe::LuaJit(h) => {
+                assert_eq!(h.version, Version::LuaJit21);
+                assert!(h.is_stripped());
+            }
+            p => panic!("{:?}", p),
+        }
+        assert_eq!(
+            probe(b"\x1bLua\x55").unwrap_err().to_string(),
+            "bad luac version (0x55) at offset 4 in main"
+        );
+        assert_eq!(probe(b"\x1bLuX").unwrap_err().kind, ErrorKind::BadSignature);
+        let truncated = ErrorKind::Truncated("header");
+        assert_eq!(probe(b"\x1bLu").unwrap_err().kind, truncated);
+        assert_eq!(probe(b"\x1bL").unwrap_err().kind, truncated);
+        let h = Header {
+            version: 0x55,
+            ..Header::default()
+        };
+        assert_eq!(Probe::Lua(h).dialect(), Err(bad_version(0x55)));
+    }
+}
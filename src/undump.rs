@@ -1,163 +1,1024 @@
-use anyhow::{Ok, Result, bail, ensure};
+use crate::instruction::Instruction;
 use bytes::Buf;
+use std::borrow::Cow;
+use std::cell::Cell;
 
-#[derive(Debug, PartialEq)]
+pub mod borrowed;
+pub mod lua50;
+pub mod lua52;
+pub mod lua53;
+pub mod lua54;
+pub mod luajit;
+pub mod spans;
+mod stream;
+
+use spans::{ChunkSpans, FunctionSpans, LocVarSpans, Span};
+pub use stream::{undump_load, undump_read, undump_read_with_limits};
+
+/// A Lua string, which is an arbitrary byte sequence rather than UTF-8.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LuaString(pub Vec<u8>);
+
+impl LuaString {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+    /// Decodes the string as UTF-8, replacing invalid sequences with U+FFFD.
+    pub fn to_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+}
+
+impl std::ops::Deref for LuaString {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<&str> for LuaString {
+    fn from(s: &str) -> LuaString {
+        LuaString(s.as_bytes().to_vec())
+    }
+}
+
+impl From<&[u8]> for LuaString {
+    fn from(s: &[u8]) -> LuaString {
+        LuaString(s.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for LuaString {
+    fn from(s: Vec<u8>) -> LuaString {
+        LuaString(s)
+    }
+}
+
+impl std::fmt::Debug for LuaString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\"{}\"", self.0.escape_ascii())
+    }
+}
+
+/// Displays the string lossily; see `to_str_lossy`.
+impl std::fmt::Display for LuaString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_str_lossy())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Constant {
     Nil,
     Boolean(bool),
     Number(f64),
-    String(String),
+    /// A number from a build with an integral `lua_Number`.
+    Integer(i64),
+    String(LuaString),
 }
 
-#[derive(Debug, PartialEq)]
+impl Constant {
+    pub fn as_boolean(&self) -> Option<bool> {
+        match *self {
+            Constant::Boolean(b) => Some(b),
+            _ => None,
+        }
+    }
+    /// The constant's value as a float, converting `Integer`s.
+    pub fn as_number(&self) -> Option<f64> {
+        match *self {
+            Constant::Number(n) => Some(n),
+            Constant::Integer(n) => Some(n as f64),
+            _ => None,
+        }
+    }
+    pub fn as_string(&self) -> Option<&LuaString> {
+        match self {
+            Constant::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+/// The `is_vararg` bits of a Lua 5.1 function.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct VarargFlags(u8);
+
+impl VarargFlags {
+    /// The function has a legacy `arg` parameter (`LUA_COMPAT_VARARG`).
+    pub const HASARG: VarargFlags = VarargFlags(1);
+    /// The function is declared with `...`.
+    pub const ISVARARG: VarargFlags = VarargFlags(2);
+    /// The function uses `arg` rather than `...`, so calls must build it.
+    pub const NEEDSARG: VarargFlags = VarargFlags(4);
+    const NAMES: [(VarargFlags, &'static str); 3] = [
+        (VarargFlags::HASARG, "HASARG"),
+        (VarargFlags::ISVARARG, "ISVARARG"),
+        (VarargFlags::NEEDSARG, "NEEDSARG"),
+    ];
+
+    /// `None` if any bit is not one of the flags above.
+    pub fn from_bits(bits: u8) -> Option<VarargFlags> {
+        (bits & !7 == 0).then_some(VarargFlags(bits))
+    }
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+    pub fn contains(self, other: VarargFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for VarargFlags {
+    type Output = VarargFlags;
+    fn bitor(self, rhs: VarargFlags) -> VarargFlags {
+        VarargFlags(self.0 | rhs.0)
+    }
+}
+
+/// Displays the set flags joined by `|`, or `0` if there are none.
+impl std::fmt::Display for VarargFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return f.write_str("0");
+        }
+        let mut sep = "";
+        for (flag, name) in VarargFlags::NAMES {
+            if self.contains(flag) {
+                write!(f, "{}{}", sep, name)?;
+                sep = "|";
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for VarargFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "VarargFlags({})", self)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct LocVar {
-    varname: String,
-    startpc: u32,
-    endpc: u32,
+    pub(crate) varname: LuaString,
+    pub(crate) startpc: u32,
+    pub(crate) endpc: u32,
 }
 
-#[derive(Debug, PartialEq)]
+impl LocVar {
+    pub fn varname(&self) -> &LuaString {
+        &self.varname
+    }
+    /// The first instruction where the variable is active.
+    pub fn startpc(&self) -> u32 {
+        self.startpc
+    }
+    /// The first instruction where the variable is dead.
+    pub fn endpc(&self) -> u32 {
+        self.endpc
+    }
+    /// Whether the variable is active at instruction `pc`.
+    pub fn is_active(&self, pc: u32) -> bool {
+        (self.startpc..self.endpc).contains(&pc)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
 pub struct Function {
-    source: String,
-    line_defined: u32,
-    last_line_defined: u32,
-    nups: u8,
-    num_params: u8,
-    is_vararg: u8,
-    maxstacksize: u8,
-    code: Vec<u32>,
-    constants: Vec<Constant>,
-    funs: Vec<Function>,
-    lineinfo: Vec<u32>,
-    locvars: Vec<LocVar>,
-    upvalues: Vec<String>,
+    /// `None` if stripped or, for nested functions, inherited from the parent.
+    pub(crate) source: Option<LuaString>,
+    pub(crate) line_defined: u32,
+    pub(crate) last_line_defined: u32,
+    pub(crate) nups: u8,
+    pub(crate) num_params: u8,
+    pub(crate) is_vararg: VarargFlags,
+    pub(crate) maxstacksize: u8,
+    pub(crate) code: Vec<u32>,
+    pub(crate) constants: Vec<Constant>,
+    pub(crate) funs: Vec<Function>,
+    pub(crate) lineinfo: Vec<u32>,
+    pub(crate) locvars: Vec<LocVar>,
+    pub(crate) upvalues: Vec<LuaString>,
+}
+
+impl Function {
+    /// Decodes each word of `code`. The extra word following a SETLIST with
+    /// C == 0 is raw data rather than an instruction and is decoded as-is.
+    pub fn instructions(&self) -> impl Iterator<Item = anyhow::Result<Instruction>> + '_ {
+        self.code.iter().map(|&i| Instruction::decode(i))
+    }
+    /// The chunk name, e.g. `@file.lua`; see the field for when it is `None`.
+    pub fn source(&self) -> Option<&LuaString> {
+        self.source.as_ref()
+    }
+    /// Zero for the main function.
+    pub fn line_defined(&self) -> u32 {
+        self.line_defined
+    }
+    pub fn last_line_defined(&self) -> u32 {
+        self.last_line_defined
+    }
+    pub fn nups(&self) -> u8 {
+        self.nups
+    }
+    pub fn num_params(&self) -> u8 {
+        self.num_params
+    }
+    pub fn is_vararg(&self) -> VarargFlags {
+        self.is_vararg
+    }
+    pub fn maxstacksize(&self) -> u8 {
+        self.maxstacksize
+    }
+    /// The raw instruction words; see `instructions` to decode them.
+    pub fn code(&self) -> &[u32] {
+        &self.code
+    }
+    pub fn constants(&self) -> &[Constant] {
+        &self.constants
+    }
+    /// Nested function prototypes, indexed by CLOSURE's Bx.
+    pub fn functions(&self) -> &[Function] {
+        &self.funs
+    }
+    /// The source line of each instruction; empty if stripped.
+    pub fn lineinfo(&self) -> &[u32] {
+        &self.lineinfo
+    }
+    /// The source line of instruction `pc`, if not stripped.
+    pub fn line(&self, pc: usize) -> Option<u32> {
+        self.lineinfo.get(pc).copied()
+    }
+    /// Local variable debug info, in order of declaration; empty if stripped.
+    pub fn locvars(&self) -> &[LocVar] {
+        &self.locvars
+    }
+    /// Debug names of the `nups` upvalues; empty if stripped.
+    pub fn upvalues(&self) -> &[LuaString] {
+        &self.upvalues
+    }
+    /// The debug name of the `n`th local variable active at `pc`, the same
+    /// way `luaF_getlocalname` finds it.
+    pub fn local_name(&self, n: usize, pc: u32) -> Option<&LuaString> {
+        self.locvars
+            .iter()
+            .take_while(|l| l.startpc <= pc)
+            .filter(|l| pc < l.endpc)
+            .nth(n)
+            .map(|l| &l.varname)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endianness {
+    Big,
+    Little,
+}
+
+/// The chunk header, describing the C types of the Lua build that wrote it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub format: u8,
+    pub endianness: Endianness,
+    /// Zero since Lua 5.4, which writes `int` and `size_t` as varints.
+    pub sizeof_int: u8,
+    pub sizeof_size_t: u8,
+    pub sizeof_instruction: u8,
+    pub sizeof_number: u8,
+    /// Size of `lua_Integer` since Lua 5.3, which replaces `integral`; zero
+    /// before then.
+    pub sizeof_integer: u8,
+    /// Whether `lua_Number` is an integer type rather than floating point.
+    pub integral: bool,
+}
+
+impl Default for Header {
+    /// The header written by a stock 64-bit little-endian build.
+    fn default() -> Header {
+        Header {
+            version: 0x51,
+            format: 0,
+            endianness: Endianness::Little,
+            sizeof_int: 4,
+            sizeof_size_t: 8,
+            sizeof_instruction: 4,
+            sizeof_number: 8,
+            sizeof_integer: 0,
+            integral: false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Chunk {
+    pub header: Header,
+    pub main: Function,
+}
+
+/// Bounds on what undumping may consume, so that a small crafted chunk
+/// cannot exhaust memory or the stack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// Deepest allowed nesting of functions, counting the main function's
+    /// children as depth 1.
+    pub max_depth: usize,
+    /// Most elements allowed in any one list, e.g. a function's constants.
+    pub max_count: usize,
+    /// Most bytes of lists and strings allowed across the whole chunk.
+    pub max_alloc: usize,
+}
+
+impl Limits {
+    pub const UNLIMITED: Limits = Limits {
+        max_depth: usize::MAX,
+        max_count: usize::MAX,
+        max_alloc: usize::MAX,
+    };
+}
+
+impl Default for Limits {
+    /// Generous limits for chunks from stock compilers; 200 is Lua 5.1's
+    /// `LUAI_MAXCCALLS`, which bounds how deeply its parser nests functions.
+    fn default() -> Limits {
+        Limits {
+            max_depth: 200,
+            max_count: 1 << 24,
+            max_alloc: 256 << 20,
+        }
+    }
+}
+
+/// Which of the `Limits` a chunk exceeded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Depth,
+    Count,
+    Alloc,
+}
+
+impl std::fmt::Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Limit::Depth => "nesting depth",
+            Limit::Count => "count",
+            Limit::Alloc => "allocation",
+        })
+    }
+}
+
+/// What went wrong while undumping; see `Error`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The input ended in the middle of the named item.
+    Truncated(&'static str),
+    BadSignature,
+    /// A header field has a value this crate cannot load.
+    BadHeader {
+        field: &'static str,
+        value: u8,
+    },
+    /// Header flags this crate does not know, as in a LuaJIT chunk: just
+    /// the unknown bits.
+    BadFlags(u32),
+    /// An `int` or `size_t` does not fit the type it is loaded into.
+    IntOutOfRange(u64),
+    /// A null (zero length) string where Lua requires a real one.
+    NullString,
+    InvalidBoolean(u8),
+    InvalidConstantType(u8),
+    /// A Lua 5.1 `is_vararg` byte with bits other than `VarargFlags`.
+    InvalidVarargFlags(u8),
+    /// A Lua 5.4 upvalue's variable kind is not one `lparser.h` defines.
+    InvalidUpvalueKind(u8),
+    /// The chunk is followed by this many extraneous bytes.
+    TrailingBytes(usize),
+    /// A LuaJIT prototype's contents do not fill its declared length.
+    ProtoLength {
+        declared: usize,
+        actual: usize,
+    },
+    /// A LuaJIT dump does not leave exactly one prototype, the main function,
+    /// once every child has been claimed; carries how many were left.
+    UnbalancedProtos(usize),
+    /// Reading a streamed chunk failed.
+    Io(std::io::ErrorKind),
+    /// Loading would exceed one of the `Limits`, reaching `value`.
+    LimitExceeded {
+        limit: Limit,
+        value: usize,
+    },
+    /// `undump_lenient` skipped this many bytes after a function it could
+    /// not load, to reach what follows it, or gave up on them if nothing
+    /// following it could be found.
+    Skipped(usize),
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::Truncated(what) => write!(f, "truncated {}", what),
+            ErrorKind::BadSignature => f.write_str("bad signature"),
+            ErrorKind::BadHeader { field, value } => write!(f, "bad {} ({:#04x})", field, value),
+            ErrorKind::BadFlags(bits) => write!(f, "unknown header flags ({:#x})", bits),
+            ErrorKind::IntOutOfRange(n) => write!(f, "int out of range ({})", n),
+            ErrorKind::NullString => f.write_str("unexpected null string"),
+            ErrorKind::InvalidBoolean(b) => write!(f, "invalid boolean {}", b),
+            ErrorKind::InvalidConstantType(t) => write!(f, "invalid constant type {}", t),
+            ErrorKind::InvalidVarargFlags(v) => write!(f, "invalid vararg flags {:#04x}", v),
+            ErrorKind::InvalidUpvalueKind(k) => write!(f, "invalid upvalue kind {}", k),
+            ErrorKind::TrailingBytes(n) => write!(f, "extraneous bytes ({})", n),
+            ErrorKind::ProtoLength { declared, actual } => {
+                write!(f, "prototype length {} but read {}", declared, actual)
+            }
+            ErrorKind::UnbalancedProtos(n) => write!(f, "unbalanced prototypes ({})", n),
+            ErrorKind::Io(e) => write!(f, "read error ({})", e),
+            ErrorKind::LimitExceeded { limit, value } => {
+                write!(f, "{} limit exceeded ({})", limit, value)
+            }
+            ErrorKind::Skipped(n) => write!(f, "skipped {} unreadable bytes", n),
+        }
+    }
+}
+
+/// An undump failure, located by byte offset into the chunk and by the path
+/// of child function indices from the main function to the one being loaded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub offset: usize,
+    pub path: Vec<usize>,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at offset {} in main", self.kind, self.offset)?;
+        for i in &self.path {
+            write!(f, ".{}", i)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Clone)]
+struct Context {
+    header: Header,
+    /// Length of the whole chunk, to turn remaining lengths into offsets.
+    len: usize,
+    path: Vec<usize>,
+    limits: Limits,
+    /// Bytes charged against `limits.max_alloc` so far.
+    allocated: Cell<usize>,
+    /// Whether to record the spans of list items; see `undump_with_spans`.
+    record_spans: bool,
+    /// Problems loading got past, if lenient; see `undump_lenient`.
+    diagnostics: Option<Vec<Error>>,
+    /// How many bytes a lenient load looks through for the next sibling of
+    /// a function it could not load, if it looks at all.
+    resync: Option<usize>,
+    /// How many siblings follow the function being loaded, at each level
+    /// of nesting below the main function, for `resync` to check.
+    pending: Vec<usize>,
+}
+
+impl Context {
+    fn new(data: &[u8]) -> Context {
+        Context::with_limits(data, Limits::default())
+    }
+    fn with_limits(data: &[u8], limits: Limits) -> Context {
+        Context {
+            header: Header::default(),
+            len: data.len(),
+            path: vec![],
+            limits,
+            allocated: Cell::new(0),
+            record_spans: false,
+            diagnostics: None,
+            resync: None,
+            pending: vec![],
+        }
+    }
 }
 
 trait LuacBuf: Buf {
-    fn get_string(&mut self) -> Result<String> {
-        ensure!(self.remaining() >= 8, "truncated string length");
-        let len = self.get_u64_le().try_into()?;
-        ensure!(self.remaining() >= len, "truncated string contents");
-        let str = if len == 0 {
-            "".to_owned()
-        } else {
-            String::from_utf8_lossy(self.take(len - 1).chunk()).to_string()
+    fn offset(&self, cx: &Context) -> usize {
+        cx.len - self.remaining()
+    }
+    fn error(&self, cx: &Context, offset: usize, kind: ErrorKind) -> Error {
+        Error {
+            kind,
+            offset,
+            path: cx.path.clone(),
+        }
+    }
+    /// Ensures the next `n` bytes are in `chunk()`, or fails naming `what`.
+    fn need(&mut self, cx: &Context, n: usize, what: &'static str) -> Result<(), Error> {
+        if self.remaining() < n {
+            return Err(self.error(cx, self.offset(cx), ErrorKind::Truncated(what)));
+        }
+        Ok(())
+    }
+    fn limit(&self, cx: &Context, limit: Limit, value: usize) -> Error {
+        let kind = ErrorKind::LimitExceeded { limit, value };
+        self.error(cx, self.offset(cx), kind)
+    }
+    /// Counts `bytes` against `max_alloc`.
+    fn charge(&self, cx: &Context, bytes: usize) -> Result<(), Error> {
+        let total = cx.allocated.get().saturating_add(bytes);
+        if total > cx.limits.max_alloc {
+            return Err(self.limit(cx, Limit::Alloc, total));
+        }
+        cx.allocated.set(total);
+        Ok(())
+    }
+    /// Makes room for a list of `n` elements, within the limits.
+    fn alloc<T>(&self, cx: &Context, n: usize) -> Result<Vec<T>, Error> {
+        if n > cx.limits.max_count {
+            return Err(self.limit(cx, Limit::Count, n));
+        }
+        self.charge(cx, n.saturating_mul(size_of::<T>()))?;
+        Ok(Vec::with_capacity(n))
+    }
+    /// Descends into child function `i`; pop `cx.path` when done.
+    fn enter(&self, cx: &mut Context, i: usize) -> Result<(), Error> {
+        cx.path.push(i);
+        if cx.path.len() > cx.limits.max_depth {
+            return Err(self.limit(cx, Limit::Depth, cx.path.len()));
+        }
+        Ok(())
+    }
+    /// Skips to the next sibling of a function that failed to load,
+    /// returning how many bytes were skipped, if the input allows looking
+    /// ahead.
+    fn resync(&mut self, _cx: &Context) -> Option<usize> {
+        None
+    }
+    fn finish(&self, cx: &Context) -> Result<(), Error> {
+        if self.has_remaining() {
+            let kind = ErrorKind::TrailingBytes(self.remaining());
+            return Err(self.error(cx, self.offset(cx), kind));
+        }
+        Ok(())
+    }
+    fn get_header_byte(
+        &mut self,
+        cx: &Context,
+        field: &'static str,
+        valid: impl Fn(u8) -> bool,
+    ) -> Result<u8, Error> {
+        let offset = self.offset(cx);
+        let value = self.get_u8();
+        if !valid(value) {
+            return Err(self.error(cx, offset, ErrorKind::BadHeader { field, value }));
+        }
+        Ok(value)
+    }
+    fn get_header(&mut self, cx: &mut Context, version: u8) -> Result<(), Error> {
+        self.need(cx, 12, "header")?;
+        if self.get_u32().to_be_bytes() != *b"\x1bLua" {
+            return Err(self.error(cx, 0, ErrorKind::BadSignature));
+        }
+        let version = self.get_header_byte(cx, "luac version", |v| v == version)?;
+        let format = self.get_header_byte(cx, "luac format", |v| v == 0)?;
+        let endianness = match self.get_header_byte(cx, "endianness", |v| v <= 1)? {
+            0 => Endianness::Big,
+            _ => Endianness::Little,
         };
+        let sizeof_int = self.get_header_byte(cx, "sizeof(int)", |v| matches!(v, 1..=8))?;
+        let sizeof_size_t = self.get_header_byte(cx, "sizeof(size_t)", |v| matches!(v, 1..=8))?;
+        let sizeof_instruction = self.get_header_byte(cx, "sizeof(Instruction)", |v| v == 4)?;
+        let sizeof_number =
+            self.get_header_byte(cx, "sizeof(lua_Number)", |v| matches!(v, 4 | 8))?;
+        let integral = self.get_header_byte(cx, "lua_Number integral flag", |v| v <= 1)? != 0;
+        cx.header = Header {
+            version,
+            format,
+            endianness,
+            sizeof_int,
+            sizeof_size_t,
+            sizeof_instruction,
+            sizeof_number,
+            sizeof_integer: 0,
+            integral,
+        };
+        Ok(())
+    }
+    fn get_sized(&mut self, cx: &Context, size: u8) -> u64 {
+        match cx.header.endianness {
+            Endianness::Big => self.get_uint(size.into()),
+            Endianness::Little => self.get_uint_le(size.into()),
+        }
+    }
+    fn get_cint(&mut self, cx: &Context) -> Result<u32, Error> {
+        let offset = self.offset(cx);
+        let n = self.get_sized(cx, cx.header.sizeof_int);
+        u32::try_from(n).map_err(|_| self.error(cx, offset, ErrorKind::IntOutOfRange(n)))
+    }
+    fn get_number(&mut self, cx: &Context) -> Constant {
+        let h = &cx.header;
+        let n = self.get_sized(cx, h.sizeof_number);
+        match (h.integral, h.sizeof_number) {
+            (false, 4) => Constant::Number(f32::from_bits(n as u32).into()),
+            (false, _) => Constant::Number(f64::from_bits(n)),
+            (true, 4) => Constant::Integer((n as i32).into()),
+            (true, _) => Constant::Integer(n as i64),
+        }
+    }
+    fn get_string(&mut self, cx: &Context) -> Result<Option<LuaString>, Error> {
+        let size_t = cx.header.sizeof_size_t;
+        self.need(cx, size_t.into(), "string length")?;
+        let offset = self.offset(cx);
+        let len = self.get_sized(cx, size_t);
+        let len = usize::try_from(len)
+            .map_err(|_| self.error(cx, offset, ErrorKind::IntOutOfRange(len)))?;
+        if len == 0 {
+            return Ok(None);
+        }
+        self.need(cx, len, "string contents")?;
+        self.charge(cx, len - 1)?;
+        let str = LuaString::from(&self.chunk()[..len - 1]);
+        // As in `lundump.c`, the terminator is dropped unchecked.
         self.advance(len);
-        Ok(str)
-    }
-    fn get_function(&mut self) -> Result<Function> {
-        let source = self.get_string()?;
-        ensure!(self.remaining() >= 16, "truncated function header");
-        let line_defined = self.get_u32_le();
-        let last_line_defined = self.get_u32_le();
-        let nups = self.get_u8();
-        let num_params = self.get_u8();
+        Ok(Some(str))
+    }
+    fn get_nonnull_string(&mut self, cx: &Context) -> Result<LuaString, Error> {
+        let offset = self.offset(cx);
+        match self.get_string(cx)? {
+            Some(str) => Ok(str),
+            None => Err(self.error(cx, offset, ErrorKind::NullString)),
+        }
+    }
+    fn get_constant(&mut self, cx: &Context) -> Result<Constant, Error> {
+        self.need(cx, 1, "constants")?;
+        let offset = self.offset(cx);
+        let ttype = self.get_u8();
+        Ok(match ttype {
+            0 => Constant::Nil,
+            1 => {
+                self.need(cx, 1, "constants")?;
+                Constant::Boolean(self.get_u8() != 0)
+            }
+            3 => {
+                self.need(cx, cx.header.sizeof_number.into(), "constants")?;
+                self.get_number(cx)
+            }
+            4 => Constant::String(self.get_nonnull_string(cx)?),
+            _ => return Err(self.error(cx, offset, ErrorKind::InvalidConstantType(ttype))),
+        })
+    }
+    fn get_locvar(&mut self, cx: &Context) -> Result<LocVar, Error> {
+        let varname = self.get_nonnull_string(cx)?;
+        self.need(cx, 2 * usize::from(cx.header.sizeof_int), "debug locvars")?;
+        let startpc = self.get_cint(cx)?;
+        let endpc = self.get_cint(cx)?;
+        Ok(LocVar {
+            varname,
+            startpc,
+            endpc,
+        })
+    }
+    fn get_function(&mut self, cx: &mut Context) -> Result<Function, Error> {
+        let mut fun = Function::default();
+        self.get_function_into(cx, &mut fun, &mut FunctionSpans::default())?;
+        Ok(fun)
+    }
+    /// The span from `*at` to here, moving `*at` up to here.
+    fn since(&self, cx: &Context, at: &mut usize) -> Span {
+        let span = *at..self.offset(cx);
+        *at = span.end;
+        span
+    }
+    /// Appends `since` to `items` if recording spans.
+    fn item(&self, cx: &Context, at: &mut usize, items: &mut Vec<Span>) {
+        let span = self.since(cx, at);
+        if cx.record_spans {
+            items.push(span);
+        }
+    }
+    /// Loads a function field by field, so that on failure `fun` keeps
+    /// everything read before the error; see `undump_lenient`. The spans of
+    /// list items and children are only recorded if `cx.record_spans` is set.
+    fn get_function_into(
+        &mut self,
+        cx: &mut Context,
+        fun: &mut Function,
+        spans: &mut FunctionSpans,
+    ) -> Result<(), Error> {
+        let int = usize::from(cx.header.sizeof_int);
+        let mut at = self.offset(cx);
+        spans.whole.start = at;
+        fun.source = self.get_string(cx)?;
+        spans.source = self.since(cx, &mut at);
+        self.need(cx, 3 * int + 4, "function header")?;
+        fun.line_defined = self.get_cint(cx)?;
+        spans.line_defined = self.since(cx, &mut at);
+        fun.last_line_defined = self.get_cint(cx)?;
+        spans.last_line_defined = self.since(cx, &mut at);
+        fun.nups = self.get_u8();
+        spans.nups = self.since(cx, &mut at);
+        fun.num_params = self.get_u8();
+        spans.num_params = self.since(cx, &mut at);
         let is_vararg = self.get_u8();
-        let maxstacksize = self.get_u8();
-        let codelen = self.get_u32_le().try_into()?;
-        ensure!(
-            self.remaining() >= codelen * 4 + 4,
-            "truncated function code"
-        );
-        let mut code = Vec::with_capacity(codelen);
+        fun.is_vararg = match VarargFlags::from_bits(is_vararg) {
+            Some(flags) => flags,
+            None => {
+                let e = self.error(cx, at, ErrorKind::InvalidVarargFlags(is_vararg));
+                let Some(diagnostics) = &mut cx.diagnostics else {
+                    return Err(e);
+                };
+                diagnostics.push(e);
+                VarargFlags(is_vararg & 7)
+            }
+        };
+        spans.is_vararg = self.since(cx, &mut at);
+        fun.maxstacksize = self.get_u8();
+        spans.maxstacksize = self.since(cx, &mut at);
+        let codelen = self.get_cint(cx)? as usize;
+        spans.code.count = self.since(cx, &mut at);
+        self.need(cx, codelen * 4 + int, "function code")?;
+        fun.code = self.alloc(cx, codelen)?;
         for _ in 0..codelen {
-            code.push(self.get_u32_le());
+            fun.code.push(self.get_sized(cx, 4) as u32);
+            self.item(cx, &mut at, &mut spans.code.items);
         }
-        let constlen = self.get_u32_le().try_into()?;
-        let mut constants = Vec::with_capacity(constlen);
+        let constlen = self.get_cint(cx)? as usize;
+        spans.constants.count = self.since(cx, &mut at);
+        fun.constants = self.alloc(cx, constlen)?;
         for _ in 0..constlen {
-            ensure!(self.remaining() >= 1, "truncated constants");
-            let ttype = self.get_u8();
-            constants.push(match ttype {
-                0 => Ok(Constant::Nil),
-                1 => {
-                    ensure!(self.remaining() >= 1);
-                    Ok(Constant::Boolean(self.get_u8() != 0))
-                }
-                3 => {
-                    ensure!(self.remaining() >= 8);
-                    Ok(Constant::Number(self.get_f64_le()))
+            fun.constants.push(self.get_constant(cx)?);
+            self.item(cx, &mut at, &mut spans.constants.items);
+        }
+        self.need(cx, int, "functions")?;
+        let funlen = self.get_cint(cx)? as usize;
+        spans.funs.count = self.since(cx, &mut at);
+        fun.funs = self.alloc(cx, funlen)?;
+        let depth = cx.path.len();
+        cx.pending.push(funlen);
+        for i in 0..funlen {
+            *cx.pending.last_mut().unwrap() = funlen - i - 1;
+            self.enter(cx, i)?;
+            fun.funs.push(Function::default());
+            let child = fun.funs.last_mut().unwrap();
+            let result = if cx.record_spans {
+                spans.funs.items.push(FunctionSpans::default());
+                let child_spans = spans.funs.items.last_mut().unwrap();
+                self.get_function_into(cx, child, child_spans)
+            } else {
+                self.get_function_into(cx, child, &mut FunctionSpans::default())
+            };
+            if let Err(e) = result {
+                // Carry on with the next sibling, if one can be found.
+                if cx.resync.is_none() {
+                    return Err(e);
                 }
-                4 => Ok(Constant::String(self.get_string()?)),
-                _ => bail!("invalid constant type {}", ttype),
-            }?);
-        }
-        ensure!(self.remaining() >= 4, "truncated functions");
-        let funlen = self.get_u32_le().try_into()?;
-        let mut funs = Vec::with_capacity(funlen);
-        for _ in 0..funlen {
-            funs.push(self.get_function()?);
-        }
-        ensure!(self.remaining() >= 4, "truncated debug lineinfo size");
-        let sizelineinfo = self.get_u32_le().try_into()?;
-        ensure!(
-            self.remaining() >= 4 * sizelineinfo,
-            "truncated debug lineinfo"
-        );
-        let mut lineinfo = Vec::with_capacity(sizelineinfo);
+                cx.path.truncate(depth + 1);
+                cx.pending.truncate(depth + 1);
+                let offset = self.offset(cx);
+                let Some(skipped) = self.resync(cx) else {
+                    // Report what was given up on, once, in place of `e`.
+                    let lost = self.offset(cx) - offset;
+                    if lost == 0 {
+                        return Err(e);
+                    }
+                    cx.diagnostics.as_mut().unwrap().push(e);
+                    return Err(self.error(cx, offset, ErrorKind::Skipped(lost)));
+                };
+                let skip = self.error(cx, offset, ErrorKind::Skipped(skipped));
+                cx.diagnostics.as_mut().unwrap().extend([e, skip]);
+            }
+            at = self.offset(cx);
+            cx.path.pop();
+        }
+        cx.pending.pop();
+        self.get_debug_into(cx, fun, spans, at)
+    }
+    /// Loads the debug info ending a function, from `at`.
+    fn get_debug_into(
+        &mut self,
+        cx: &mut Context,
+        fun: &mut Function,
+        spans: &mut FunctionSpans,
+        mut at: usize,
+    ) -> Result<(), Error> {
+        let int = usize::from(cx.header.sizeof_int);
+        self.need(cx, int, "debug lineinfo size")?;
+        let sizelineinfo = self.get_cint(cx)? as usize;
+        spans.lineinfo.count = self.since(cx, &mut at);
+        self.need(cx, int * sizelineinfo, "debug lineinfo")?;
+        fun.lineinfo = self.alloc(cx, sizelineinfo)?;
         for _ in 0..sizelineinfo {
-            lineinfo.push(self.get_u32_le());
+            fun.lineinfo.push(self.get_cint(cx)?);
+            self.item(cx, &mut at, &mut spans.lineinfo.items);
         }
-        ensure!(self.remaining() >= 4, "truncated debug locvars size");
-        let sizelocvars = self.get_u32_le().try_into()?;
-        let mut locvars = Vec::with_capacity(sizelocvars);
+        self.need(cx, int, "debug locvars size")?;
+        let sizelocvars = self.get_cint(cx)? as usize;
+        spans.locvars.count = self.since(cx, &mut at);
+        fun.locvars = self.alloc(cx, sizelocvars)?;
         for _ in 0..sizelocvars {
-            let varname = self.get_string()?;
-            ensure!(self.remaining() >= 8, "truncated debug locvars");
-            let startpc = self.get_u32_le();
-            let endpc = self.get_u32_le();
-            locvars.push(LocVar {
-                varname,
-                startpc,
-                endpc,
-            });
-        }
-        ensure!(self.remaining() >= 4, "truncated debug upvalues size");
-        let sizeupvalues = self.get_u32_le().try_into()?;
-        let mut upvalues = Vec::with_capacity(sizeupvalues);
+            fun.locvars.push(self.get_locvar(cx)?);
+            let end = self.offset(cx);
+            if cx.record_spans {
+                spans.locvars.items.push(LocVarSpans {
+                    varname: at..end - 2 * int,
+                    startpc: end - 2 * int..end - int,
+                    endpc: end - int..end,
+                });
+            }
+            at = end;
+        }
+        self.need(cx, int, "debug upvalues size")?;
+        let sizeupvalues = self.get_cint(cx)? as usize;
+        spans.upvalues.count = self.since(cx, &mut at);
+        fun.upvalues = self.alloc(cx, sizeupvalues)?;
         for _ in 0..sizeupvalues {
-            upvalues.push(self.get_string()?);
-        }
-        let fun = Function {
-            source,
-            line_defined,
-            last_line_defined,
-            nups,
-            num_params,
-            is_vararg,
-            maxstacksize,
-            code,
-            constants,
-            funs,
-            lineinfo,
-            locvars,
-            upvalues,
+            fun.upvalues.push(self.get_nonnull_string(cx)?);
+            self.item(cx, &mut at, &mut spans.upvalues.items);
+        }
+        spans.whole.end = at;
+        Ok(())
+    }
+}
+impl LuacBuf for &[u8] {
+    /// Finds the first offset within `cx.resync` bytes at which a function
+    /// with code loads strictly. An offset from which the rest of the chunk
+    /// loads too, to where the input ends, is preferred: the siblings left,
+    /// the debug info ending their parent, and so on up to the main
+    /// function. This passes over the children of the failed function,
+    /// which are followed by its own debug info instead. With no sibling
+    /// left, only the debug info ending the parent is looked for, and that
+    /// must chain to the end. If nothing is found, consumes the rest, so
+    /// that the enclosing functions don't search it again, unless no
+    /// sibling was left, for the parent to look for its own.
+    fn resync(&mut self, cx: &Context) -> Option<usize> {
+        let &left = cx.pending.last()?;
+        let mut candidate = cx.clone();
+        candidate.record_spans = false;
+        candidate.resync = None;
+        let loads = |p: &mut &[u8], candidate: &mut Context| {
+            candidate.diagnostics = None;
+            p.get_function(candidate)
+                .is_ok_and(|fun| !fun.code.is_empty())
         };
-        Ok(fun)
+        let window = self.len().min(cx.resync.unwrap_or(0));
+        let mut first = None;
+        let mut chained = None;
+        for k in 0..window {
+            candidate.allocated.set(cx.allocated.get());
+            let mut p = &self[k..];
+            if left > 0 {
+                if !loads(&mut p, &mut candidate) {
+                    continue;
+                }
+                first.get_or_insert(k);
+            }
+            let mut followers = cx.pending.iter().rev();
+            let siblings = followers.next().unwrap().saturating_sub(1);
+            let rest = [siblings]
+                .into_iter()
+                .chain(followers.copied())
+                .all(|left| {
+                    (0..left).all(|_| loads(&mut p, &mut candidate)) && {
+                        candidate.diagnostics = Some(vec![]);
+                        p.get_debug_into(
+                            &mut candidate,
+                            &mut Function::default(),
+                            &mut FunctionSpans::default(),
+                            0,
+                        )
+                        .is_ok()
+                    }
+                });
+            if rest && p.is_empty() {
+                chained = Some(k);
+                break;
+            }
+        }
+        let skipped = chained.or(first);
+        if skipped.is_some() || left > 0 {
+            self.advance(skipped.unwrap_or(self.len()));
+        }
+        skipped
     }
 }
-impl LuacBuf for &[u8] {}
 
-pub fn undump(data: &[u8]) -> Result<Function> {
+/// Reads just the chunk header, e.g. to inspect a chunk before loading it.
+pub fn undump_header(data: &[u8]) -> Result<Header, Error> {
+    let mut p = data;
+    let mut cx = Context::new(data);
+    p.get_header(&mut cx, 0x51)?;
+    Ok(cx.header)
+}
+
+pub fn undump_chunk(data: &[u8]) -> Result<Chunk, Error> {
+    undump_chunk_with_limits(data, Limits::default())
+}
+
+pub fn undump_chunk_with_limits(data: &[u8], limits: Limits) -> Result<Chunk, Error> {
     let mut p = data;
-    ensure!(p.remaining() >= 12, "truncated header");
-    ensure!(p.get_u32().to_be_bytes() == *b"\x1bLua", "bad signature");
-    ensure!(p.get_u8() == 0x51, "bad luac version");
-    ensure!(p.get_u8() == 0x0, "bad luac format");
-    ensure!(p.get_u8() == 0x1, "bad endianness");
-    ensure!(p.get_u8() == 0x4, "bad sizeof(int)");
-    ensure!(p.get_u8() == 0x8, "bad sizeof(size_t)");
-    ensure!(p.get_u8() == 0x4, "bad sizeof(Instruction)");
-    ensure!(p.get_u8() == 0x8, "bad sizeof(lua_Number)");
-    ensure!(p.get_u8() == 0x0, "lua_Number must be floating point");
-    let fun = p.get_function()?;
-    ensure!(!p.has_remaining(), "extraneous bytes ({})", p.remaining());
-    Ok(fun)
+    let mut cx = Context::with_limits(data, limits);
+    p.get_header(&mut cx, 0x51)?;
+    let main = p.get_function(&mut cx)?;
+    p.finish(&cx)?;
+    Ok(Chunk {
+        header: cx.header,
+        main,
+    })
+}
+
+pub fn undump(data: &[u8]) -> Result<Function, Error> {
+    Ok(undump_chunk(data)?.main)
+}
+
+/// Undumps a chunk along with the byte span of each of its fields.
+pub fn undump_with_spans(data: &[u8]) -> Result<(Chunk, ChunkSpans), Error> {
+    let mut p = data;
+    let mut cx = Context::new(data);
+    cx.record_spans = true;
+    p.get_header(&mut cx, 0x51)?;
+    let mut main = Function::default();
+    let mut spans = ChunkSpans::default();
+    p.get_function_into(&mut cx, &mut main, &mut spans.main)?;
+    p.finish(&cx)?;
+    let chunk = Chunk {
+        header: cx.header,
+        main,
+    };
+    Ok((chunk, spans))
+}
+
+/// What `undump_lenient` salvaged from a possibly damaged chunk.
+#[derive(Debug, PartialEq)]
+pub struct Partial {
+    pub header: Header,
+    /// The function tree as far as it could be loaded. A function that
+    /// failed is cut short, its unread fields left empty or zero. Loading
+    /// resumes at its next sibling if one can be found further on, and
+    /// otherwise stops, leaving the functions after it missing.
+    pub main: Function,
+    /// What went wrong, in order; empty if the chunk loaded cleanly.
+    pub diagnostics: Vec<Error>,
+}
+
+/// How forgiving `undump_lenient` is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LenientOptions {
+    /// Accept bytes after the chunk rather than reporting them.
+    pub allow_trailing: bool,
+    /// How many bytes to look through for the next sibling of a function
+    /// that could not be loaded; each is tried as the start of one, so
+    /// this bounds the time spent on a damaged chunk.
+    pub max_skip: usize,
+}
+
+impl Default for LenientOptions {
+    fn default() -> LenientOptions {
+        LenientOptions {
+            allow_trailing: false,
+            max_skip: 1 << 16,
+        }
+    }
+}
+
+/// Loads as much of a damaged chunk as possible, for forensics. Only an
+/// unreadable header is a hard error; see `Partial` for the rest.
+pub fn undump_lenient(data: &[u8], options: LenientOptions) -> Result<Partial, Error> {
+    undump_lenient_with_limits(data, options, Limits::default())
+}
+
+pub fn undump_lenient_with_limits(
+    data: &[u8],
+    options: LenientOptions,
+    limits: Limits,
+) -> Result<Partial, Error> {
+    let mut p = data;
+    let mut cx = Context::with_limits(data, limits);
+    cx.diagnostics = Some(vec![]);
+    cx.resync = Some(options.max_skip);
+    p.get_header(&mut cx, 0x51)?;
+    let mut main = Function::default();
+    let result = p.get_function_into(&mut cx, &mut main, &mut FunctionSpans::default());
+    let mut diagnostics = cx.diagnostics.take().unwrap();
+    match result {
+        Ok(()) if options.allow_trailing => {}
+        Ok(()) => diagnostics.extend(p.finish(&cx).err()),
+        Err(e) => diagnostics.push(e),
+    }
+    Ok(Partial {
+        header: cx.header,
+        main,
+        diagnostics,
+    })
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::dump::dump;
     use crate::undump::*;
 
     #[test]
@@ -174,20 +1035,246 @@ mod tests {
         assert_eq!(
             undump(return42hello).unwrap(),
             Function {
-                source: "@wat.lua".to_owned(),
+                source: Some("@wat.lua".into()),
                 line_defined: 0,
                 last_line_defined: 0,
                 nups: 0,
                 num_params: 0,
-                is_vararg: 2,
+                is_vararg: VarargFlags::ISVARARG,
                 maxstacksize: 2,
                 code: vec![1, 16449, 25165854, 8388638],
-                constants: vec![Constant::Number(42.0), Constant::String("hello".to_owned())],
+                constants: vec![Constant::Number(42.0), Constant::String("hello".into())],
                 funs: vec![],
                 lineinfo: vec![1, 1, 1, 1],
                 locvars: vec![],
                 upvalues: vec![],
             }
-        )
+        );
+        let err = |data: &[u8]| undump(data).unwrap_err();
+        let mut data = return42hello.to_vec();
+        data.push(0);
+        assert_eq!(
+            err(&data),
+            Error {
+                kind: ErrorKind::TrailingBytes(1),
+                offset: return42hello.len(),
+                path: vec![],
+            }
+        );
+        assert_eq!(
+            err(&return42hello[..20]),
+            Error {
+                kind: ErrorKind::Truncated("string contents"),
+                offset: 20,
+                path: vec![],
+            }
+        );
+        let mut data = return42hello.to_vec();
+        data[4] = 0x52;
+        assert_eq!(
+            err(&data).kind,
+            ErrorKind::BadHeader {
+                field: "luac version",
+                value: 0x52
+            }
+        );
+        let mut data = return42hello.to_vec();
+        data[65] = 9;
+        assert_eq!(err(&data).kind, ErrorKind::InvalidConstantType(9));
+        assert_eq!(err(&data).offset, 65);
+        let mut data = return42hello.to_vec();
+        data[39] = 0x0a;
+        assert_eq!(
+            err(&data).to_string(),
+            "invalid vararg flags 0x0a at offset 39 in main"
+        );
+        let flags = VarargFlags::ISVARARG | VarargFlags::NEEDSARG;
+        assert_eq!(flags.to_string(), "ISVARARG|NEEDSARG");
+        assert_eq!(VarargFlags::from_bits(6), Some(flags));
+        // Like `lua_load`, string terminators go unchecked, and any byte
+        // but 0 is true.
+        let mut data = return42hello.to_vec();
+        data[28] = b'!';
+        data[88] = b'!';
+        let fun = undump(&data).unwrap();
+        assert_eq!(fun.source(), Some(&"@wat.lua".into()));
+        assert_eq!(fun.constants()[1].as_string(), Some(&"hello".into()));
+        let mut fun = undump(return42hello).unwrap();
+        fun.constants[0] = Constant::Boolean(true);
+        let mut data = dump(&fun).unwrap();
+        let (_, spans) = undump_with_spans(&data).unwrap();
+        data[spans.main.constants.items[0].start + 1] = 2;
+        assert_eq!(undump(&data).unwrap().constants[0], Constant::Boolean(true));
+        let fun = undump(return42hello).unwrap();
+        assert_eq!(fun.source(), Some(&"@wat.lua".into()));
+        assert_eq!(fun.constants()[0].as_number(), Some(42.0));
+        assert_eq!(fun.constants()[1].as_string(), Some(&"hello".into()));
+        assert_eq!(fun.line(3), Some(1));
+        assert_eq!(fun.line(4), None);
+        assert!(fun.functions().is_empty());
+        let locvar = LocVar {
+            varname: "x".into(),
+            startpc: 1,
+            endpc: 3,
+        };
+        assert!(!locvar.is_active(0) && locvar.is_active(2) && !locvar.is_active(3));
+        let mut outer = fun;
+        outer.locvars = vec![locvar];
+        assert_eq!(outer.local_name(0, 2), Some(&"x".into()));
+        assert_eq!(outer.local_name(0, 3), None);
+        outer.locvars.clear();
+        outer.funs.push(undump(return42hello).unwrap());
+        let data = dump(&outer).unwrap();
+        let e = err(&data[..data.len() - 40]);
+        assert_eq!(e.kind, ErrorKind::Truncated("debug lineinfo"));
+        assert_eq!(e.path, vec![0]);
+        assert_eq!(
+            e.to_string(),
+            format!("truncated debug lineinfo at offset {} in main.0", e.offset)
+        );
+        let limited = |data: &[u8], limits| undump_chunk_with_limits(data, limits).unwrap_err();
+        let mut data = return42hello.to_vec();
+        data[61..65].copy_from_slice(&[0xff; 4]);
+        assert_eq!(
+            err(&data),
+            Error {
+                kind: ErrorKind::LimitExceeded {
+                    limit: Limit::Count,
+                    value: 0xffff_ffff
+                },
+                offset: 65,
+                path: vec![],
+            }
+        );
+        let limits = Limits {
+            max_alloc: 20,
+            ..Limits::default()
+        };
+        assert_eq!(
+            limited(return42hello, limits).to_string(),
+            "allocation limit exceeded (24) at offset 45 in main"
+        );
+        let mut nested = undump(return42hello).unwrap();
+        for _ in 0..3 {
+            let mut parent = undump(return42hello).unwrap();
+            parent.funs.push(nested);
+            nested = parent;
+        }
+        let data = dump(&nested).unwrap();
+        let limits = Limits {
+            max_depth: 2,
+            ..Limits::default()
+        };
+        let e = limited(&data, limits);
+        assert_eq!(e.path, vec![0, 0, 0]);
+        assert_eq!(
+            e.kind,
+            ErrorKind::LimitExceeded {
+                limit: Limit::Depth,
+                value: 3
+            }
+        );
+        assert!(undump_chunk_with_limits(&data, Limits::UNLIMITED).is_ok());
+        let partial = undump_lenient(&data[..data.len() - 100], LenientOptions::default()).unwrap();
+        let mut damaged = &partial.main;
+        for _ in 0..3 {
+            assert_eq!(damaged.code, nested.code);
+            damaged = &damaged.funs[0];
+        }
+        assert_eq!(damaged.constants.len(), 2);
+        assert!(damaged.lineinfo.is_empty());
+        assert_eq!(partial.diagnostics.len(), 1);
+        assert_eq!(partial.diagnostics[0].path, vec![0, 0, 0]);
+        let mut data = return42hello.to_vec();
+        data.extend([0; 12]);
+        let partial = undump_lenient(&data, LenientOptions::default()).unwrap();
+        assert_eq!(partial.main, undump(return42hello).unwrap());
+        assert_eq!(
+            partial.diagnostics[0].to_string(),
+            "extraneous bytes (12) at offset 121 in main"
+        );
+        let options = LenientOptions {
+            allow_trailing: true,
+            ..LenientOptions::default()
+        };
+        assert!(
+            undump_lenient(&data, options)
+                .unwrap()
+                .diagnostics
+                .is_empty()
+        );
+        let mut outer = undump(return42hello).unwrap();
+        outer.funs = vec![undump(return42hello).unwrap(); 3];
+        let mut data = dump(&outer).unwrap();
+        let (_, spans) = undump_with_spans(&data).unwrap();
+        let child = &spans.main.funs.items;
+        data[child[0].constants.items[0].start] = 9;
+        data[child[2].is_vararg.start] = 0x10;
+        let partial = undump_lenient(&data, LenientOptions::default()).unwrap();
+        let kinds: Vec<_> = partial.diagnostics.iter().map(|e| &e.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                &ErrorKind::InvalidConstantType(9),
+                &ErrorKind::Skipped(child[1].whole.start - child[0].constants.items[0].start - 1),
+                &ErrorKind::InvalidVarargFlags(0x10),
+            ]
+        );
+        assert_eq!(partial.diagnostics[2].path, vec![2]);
+        assert!(partial.main.funs[0].constants.is_empty());
+        assert_eq!(partial.main.funs[1], outer.funs[1]);
+        assert_eq!(partial.main.funs[2].code, outer.funs[2].code);
+        assert_eq!(partial.main.upvalues, outer.upvalues);
+        let options = LenientOptions {
+            max_skip: 10,
+            ..LenientOptions::default()
+        };
+        let partial = undump_lenient(&data, options).unwrap();
+        assert_eq!(partial.main.funs.len(), 1);
+        let start = child[0].constants.items[0].start + 1;
+        assert_eq!(
+            partial.diagnostics[1].kind,
+            ErrorKind::Skipped(data.len() - start)
+        );
+
+        // Intact siblings between two damaged ones are kept.
+        let mut outer = undump(return42hello).unwrap();
+        outer.funs = vec![undump(return42hello).unwrap(); 10];
+        let mut data = dump(&outer).unwrap();
+        let (_, spans) = undump_with_spans(&data).unwrap();
+        let child = &spans.main.funs.items;
+        data[child[0].constants.items[0].start] = 9;
+        data[child[9].constants.items[0].start] = 9;
+        let partial = undump_lenient(&data, LenientOptions::default()).unwrap();
+        assert_eq!(partial.main.funs.len(), 10);
+        assert_eq!(partial.main.funs[1..9], outer.funs[1..9]);
+        assert!(partial.main.funs[9].constants.is_empty());
+        assert_eq!(partial.main.lineinfo, outer.lineinfo);
+        let kinds: Vec<_> = partial.diagnostics.iter().map(|e| &e.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                &ErrorKind::InvalidConstantType(9),
+                &ErrorKind::Skipped(child[1].whole.start - child[0].constants.items[0].start - 1),
+                &ErrorKind::InvalidConstantType(9),
+                &ErrorKind::Skipped(child[9].whole.end - child[9].constants.items[0].start - 1),
+            ]
+        );
+
+        // Loading resumes at the next sibling, not a child of the function
+        // that failed.
+        let mut outer = undump(return42hello).unwrap();
+        outer.funs = vec![undump(return42hello).unwrap(); 2];
+        outer.funs[0].funs = vec![undump(return42hello).unwrap()];
+        let mut data = dump(&outer).unwrap();
+        let (_, spans) = undump_with_spans(&data).unwrap();
+        let child = &spans.main.funs.items;
+        data[child[0].constants.items[0].start] = 9;
+        let partial = undump_lenient(&data, LenientOptions::default()).unwrap();
+        assert_eq!(
+            partial.diagnostics[1].kind,
+            ErrorKind::Skipped(child[1].whole.start - child[0].constants.items[0].start - 1)
+        );
+        assert_eq!(partial.main.funs[1], outer.funs[1]);
     }
 }
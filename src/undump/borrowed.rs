@@ -0,0 +1,407 @@
+//! A zero-copy loader for Lua 5.1 chunks, whose functions borrow their
+//! strings, code and line info from the input rather than copying them.
+
+use crate::instruction::Instruction;
+use crate::undump::{
+    self, Context, Endianness, Error, ErrorKind, Header, Limit, Limits, LuacBuf, VarargFlags,
+};
+use bytes::Buf;
+
+/// A run of `int`s or instructions, decoded on access.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Words<'a> {
+    bytes: &'a [u8],
+    size: u8,
+    endianness: Endianness,
+}
+
+impl<'a> Words<'a> {
+    pub fn len(&self) -> usize {
+        self.bytes.len() / usize::from(self.size)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+    /// The undecoded bytes.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+    pub fn get(&self, i: usize) -> Option<u32> {
+        let size = usize::from(self.size);
+        let mut word = self.bytes.get(i.checked_mul(size)?..)?.get(..size)?;
+        // Wider words were checked to fit when loaded; see `get_words`.
+        Some(match self.endianness {
+            Endianness::Big => word.get_uint(size),
+            Endianness::Little => word.get_uint_le(size),
+        } as u32)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = u32> + 'a {
+        let words = *self;
+        (0..words.len()).map(move |i| words.get(i).unwrap())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Constant<'a> {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    /// A number from a build with an integral `lua_Number`.
+    Integer(i64),
+    String(&'a [u8]),
+}
+
+impl Constant<'_> {
+    pub fn to_owned(&self) -> undump::Constant {
+        match *self {
+            Constant::Nil => undump::Constant::Nil,
+            Constant::Boolean(b) => undump::Constant::Boolean(b),
+            Constant::Number(n) => undump::Constant::Number(n),
+            Constant::Integer(n) => undump::Constant::Integer(n),
+            Constant::String(s) => undump::Constant::String(s.into()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LocVar<'a> {
+    pub varname: &'a [u8],
+    pub startpc: u32,
+    pub endpc: u32,
+}
+
+/// A borrowed counterpart of `undump::Function`; see there for the fields.
+#[derive(Debug, PartialEq)]
+pub struct Function<'a> {
+    source: Option<&'a [u8]>,
+    line_defined: u32,
+    last_line_defined: u32,
+    nups: u8,
+    num_params: u8,
+    is_vararg: VarargFlags,
+    maxstacksize: u8,
+    code: Words<'a>,
+    constants: Vec<Constant<'a>>,
+    funs: Vec<Function<'a>>,
+    lineinfo: Words<'a>,
+    locvars: Vec<LocVar<'a>>,
+    upvalues: Vec<&'a [u8]>,
+}
+
+impl<'a> Function<'a> {
+    pub fn source(&self) -> Option<&'a [u8]> {
+        self.source
+    }
+    pub fn line_defined(&self) -> u32 {
+        self.line_defined
+    }
+    pub fn last_line_defined(&self) -> u32 {
+        self.last_line_defined
+    }
+    pub fn nups(&self) -> u8 {
+        self.nups
+    }
+    pub fn num_params(&self) -> u8 {
+        self.num_params
+    }
+    pub fn is_vararg(&self) -> VarargFlags {
+        self.is_vararg
+    }
+    pub fn maxstacksize(&self) -> u8 {
+        self.maxstacksize
+    }
+    pub fn code(&self) -> Words<'a> {
+        self.code
+    }
+    pub fn instructions(&self) -> impl Iterator<Item = anyhow::Result<Instruction>> + 'a {
+        self.code.iter().map(Instruction::decode)
+    }
+    pub fn constants(&self) -> &[Constant<'a>] {
+        &self.constants
+    }
+    pub fn functions(&self) -> &[Function<'a>] {
+        &self.funs
+    }
+    pub fn lineinfo(&self) -> Words<'a> {
+        self.lineinfo
+    }
+    pub fn locvars(&self) -> &[LocVar<'a>] {
+        &self.locvars
+    }
+    pub fn upvalues(&self) -> &[&'a [u8]] {
+        &self.upvalues
+    }
+    /// Copies the function tree out of the input.
+    pub fn to_owned(&self) -> undump::Function {
+        undump::Function {
+            source: self.source.map(Into::into),
+            line_defined: self.line_defined,
+            last_line_defined: self.last_line_defined,
+            nups: self.nups,
+            num_params: self.num_params,
+            is_vararg: self.is_vararg,
+            maxstacksize: self.maxstacksize,
+            code: self.code.iter().collect(),
+            constants: self.constants.iter().map(Constant::to_owned).collect(),
+            funs: self.funs.iter().map(Function::to_owned).collect(),
+            lineinfo: self.lineinfo.iter().collect(),
+            locvars: self
+                .locvars
+                .iter()
+                .map(|l| undump::LocVar {
+                    varname: l.varname.into(),
+                    startpc: l.startpc,
+                    endpc: l.endpc,
+                })
+                .collect(),
+            upvalues: self.upvalues.iter().map(|&s| s.into()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Chunk<'a> {
+    pub header: Header,
+    pub main: Function<'a>,
+}
+
+/// Borrows the next `len` bytes; the caller has checked they are there.
+fn take<'a>(p: &mut &'a [u8], len: usize) -> &'a [u8] {
+    let (taken, rest) = p.split_at(len);
+    *p = rest;
+    taken
+}
+
+/// Makes room for a list of `n` elements, charging the limits as much as
+/// the copying loader does for a list of `T`s, so that both reject the same
+/// chunks.
+fn alloc_as<T, U>(p: &&[u8], cx: &Context, n: usize) -> Result<Vec<U>, Error> {
+    if n > cx.limits.max_count {
+        return Err(p.limit(cx, Limit::Count, n));
+    }
+    p.charge(cx, n.saturating_mul(size_of::<T>()))?;
+    Ok(Vec::with_capacity(n))
+}
+
+/// Reads a count, which the caller has checked is there, then borrows that
+/// many words of `size` bytes. Words wider than 4 bytes are checked to fit
+/// a `u32`, as the copying loader does.
+fn get_words<'a>(
+    p: &mut &'a [u8],
+    cx: &Context,
+    size: u8,
+    what: &'static str,
+) -> Result<Words<'a>, Error> {
+    let n = p.get_cint(cx)? as usize;
+    let len = n * usize::from(size);
+    p.need(cx, len, what)?;
+    alloc_as::<u32, ()>(p, cx, n)?;
+    if size > 4 {
+        let mut q = *p;
+        for _ in 0..n {
+            q.get_cint(cx)?;
+        }
+    }
+    Ok(Words {
+        bytes: take(p, len),
+        size,
+        endianness: cx.header.endianness,
+    })
+}
+
+fn get_string<'a>(p: &mut &'a [u8], cx: &Context) -> Result<Option<&'a [u8]>, Error> {
+    let size_t = cx.header.sizeof_size_t;
+    p.need(cx, size_t.into(), "string length")?;
+    let offset = p.offset(cx);
+    let len = p.get_sized(cx, size_t);
+    let len =
+        usize::try_from(len).map_err(|_| p.error(cx, offset, ErrorKind::IntOutOfRange(len)))?;
+    if len == 0 {
+        return Ok(None);
+    }
+    p.need(cx, len, "string contents")?;
+    p.charge(cx, len - 1)?;
+    let str = take(p, len - 1);
+    // As in `lundump.c`, the terminator is dropped unchecked.
+    p.advance(1);
+    Ok(Some(str))
+}
+
+fn get_nonnull_string<'a>(p: &mut &'a [u8], cx: &Context) -> Result<&'a [u8], Error> {
+    let offset = p.offset(cx);
+    get_string(p, cx)?.ok_or_else(|| p.error(cx, offset, ErrorKind::NullString))
+}
+
+fn get_constant<'a>(p: &mut &'a [u8], cx: &Context) -> Result<Constant<'a>, Error> {
+    p.need(cx, 1, "constants")?;
+    if p.chunk()[0] == 4 {
+        p.advance(1);
+        return Ok(Constant::String(get_nonnull_string(p, cx)?));
+    }
+    // Other constants own nothing, so the copying loader can read them.
+    Ok(match p.get_constant(cx)? {
+        undump::Constant::Nil => Constant::Nil,
+        undump::Constant::Boolean(b) => Constant::Boolean(b),
+        undump::Constant::Number(n) => Constant::Number(n),
+        undump::Constant::Integer(n) => Constant::Integer(n),
+        undump::Constant::String(_) => unreachable!("string constants are borrowed above"),
+    })
+}
+
+fn get_function<'a>(p: &mut &'a [u8], cx: &mut Context) -> Result<Function<'a>, Error> {
+    let int = usize::from(cx.header.sizeof_int);
+    let source = get_string(p, cx)?;
+    p.need(cx, 3 * int + 4, "function header")?;
+    let line_defined = p.get_cint(cx)?;
+    let last_line_defined = p.get_cint(cx)?;
+    let nups = p.get_u8();
+    let num_params = p.get_u8();
+    let offset = p.offset(cx);
+    let is_vararg = p.get_u8();
+    let is_vararg = VarargFlags::from_bits(is_vararg)
+        .ok_or_else(|| p.error(cx, offset, ErrorKind::InvalidVarargFlags(is_vararg)))?;
+    let maxstacksize = p.get_u8();
+    let code = get_words(p, cx, 4, "function code")?;
+    p.need(cx, int, "function code")?;
+    let constlen = p.get_cint(cx)? as usize;
+    let mut constants = alloc_as::<undump::Constant, _>(p, cx, constlen)?;
+    for _ in 0..constlen {
+        constants.push(get_constant(p, cx)?);
+    }
+    p.need(cx, int, "functions")?;
+    let funlen = p.get_cint(cx)? as usize;
+    let mut funs = alloc_as::<undump::Function, _>(p, cx, funlen)?;
+    for i in 0..funlen {
+        p.enter(cx, i)?;
+        funs.push(get_function(p, cx)?);
+        cx.path.pop();
+    }
+    p.need(cx, int, "debug lineinfo size")?;
+    let lineinfo = get_words(p, cx, cx.header.sizeof_int, "debug lineinfo")?;
+    p.need(cx, int, "debug locvars size")?;
+    let sizelocvars = p.get_cint(cx)? as usize;
+    let mut locvars = alloc_as::<undump::LocVar, _>(p, cx, sizelocvars)?;
+    for _ in 0..sizelocvars {
+        let varname = get_nonnull_string(p, cx)?;
+        p.need(cx, 2 * int, "debug locvars")?;
+        locvars.push(LocVar {
+            varname,
+            startpc: p.get_cint(cx)?,
+            endpc: p.get_cint(cx)?,
+        });
+    }
+    p.need(cx, int, "debug upvalues size")?;
+    let sizeupvalues = p.get_cint(cx)? as usize;
+    let mut upvalues = alloc_as::<undump::LuaString, _>(p, cx, sizeupvalues)?;
+    for _ in 0..sizeupvalues {
+        upvalues.push(get_nonnull_string(p, cx)?);
+    }
+    Ok(Function {
+        source,
+        line_defined,
+        last_line_defined,
+        nups,
+        num_params,
+        is_vararg,
+        maxstacksize,
+        code,
+        constants,
+        funs,
+        lineinfo,
+        locvars,
+        upvalues,
+    })
+}
+
+pub fn undump_chunk(data: &[u8]) -> Result<Chunk<'_>, Error> {
+    undump_chunk_with_limits(data, Limits::default())
+}
+
+pub fn undump_chunk_with_limits(data: &[u8], limits: Limits) -> Result<Chunk<'_>, Error> {
+    let mut p = data;
+    let mut cx = Context::with_limits(data, limits);
+    p.get_header(&mut cx, 0x51)?;
+    let main = get_function(&mut p, &mut cx)?;
+    p.finish(&cx)?;
+    Ok(Chunk {
+        header: cx.header,
+        main,
+    })
+}
+
+pub fn undump(data: &[u8]) -> Result<Function<'_>, Error> {
+    Ok(undump_chunk(data)?.main)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dump::{dump, dump_chunk};
+    use crate::instruction::OpCode;
+    use crate::undump::borrowed::*;
+
+    #[test]
+    fn test() {
+        let return42hello = b"\
+\x1b\x4c\x75\x61\x51\x00\x01\x04\x08\x04\x08\x00\x09\x00\x00\x00\
+\x00\x00\x00\x00\x40\x77\x61\x74\x2e\x6c\x75\x61\x00\x00\x00\x00\
+\x00\x00\x00\x00\x00\x00\x00\x02\x02\x04\x00\x00\x00\x01\x00\x00\
+\x00\x41\x40\x00\x00\x1e\x00\x80\x01\x1e\x00\x80\x00\x02\x00\x00\
+\x00\x03\x00\x00\x00\x00\x00\x00\x45\x40\x04\x06\x00\x00\x00\x00\
+\x00\x00\x00\x68\x65\x6c\x6c\x6f\x00\x00\x00\x00\x00\x04\x00\x00\
+\x00\x01\x00\x00\x00\x01\x00\x00\x00\x01\x00\x00\x00\x01\x00\x00\
+\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+        let fun = undump(return42hello).unwrap();
+        let hello = match fun.constants()[1] {
+            Constant::String(s) => s,
+            c => panic!("{:?}", c),
+        };
+        assert_eq!(hello, b"hello");
+        assert!(return42hello.as_ptr_range().contains(&hello.as_ptr()));
+        let mut data = return42hello.to_vec();
+        data[88] = b'!';
+        assert_eq!(
+            undump(&data).unwrap().constants()[1],
+            Constant::String(b"hello")
+        );
+        assert_eq!(fun.code().get(1), Some(16449));
+        assert_eq!(fun.code().get(4), None);
+        let ops: Vec<_> = fun.instructions().map(|i| i.unwrap().op).collect();
+        assert_eq!(
+            ops,
+            vec![OpCode::LoadK, OpCode::LoadK, OpCode::Return, OpCode::Return]
+        );
+        let owned = fun.to_owned();
+        assert_eq!(owned, crate::undump::undump(return42hello).unwrap());
+        let mut big = owned;
+        big.funs.push(crate::undump::undump(return42hello).unwrap());
+        big.locvars.push(crate::undump::LocVar {
+            varname: "x".into(),
+            startpc: 0,
+            endpc: 4,
+        });
+        let data = dump(&big).unwrap();
+        assert_eq!(undump(&data).unwrap().to_owned(), big);
+        assert_eq!(
+            undump(&data[..data.len() - 1]).unwrap_err(),
+            crate::undump::undump(&data[..data.len() - 1]).unwrap_err()
+        );
+        for max_alloc in (0..200).step_by(4) {
+            let limits = Limits {
+                max_alloc,
+                ..Limits::default()
+            };
+            assert_eq!(
+                undump_chunk_with_limits(&data, limits).err(),
+                crate::undump::undump_chunk_with_limits(&data, limits).err()
+            );
+        }
+        let mut chunk = crate::undump::undump_chunk(&data).unwrap();
+        chunk.header.sizeof_int = 8;
+        let mut wide = dump_chunk(&chunk).unwrap();
+        let (_, spans) = crate::undump::undump_with_spans(&wide).unwrap();
+        wide[spans.main.lineinfo.items[0].start + 4] = 1;
+        let e = crate::undump::undump(&wide).unwrap_err();
+        assert_eq!(e.kind, ErrorKind::IntOutOfRange(1 << 32 | 1));
+        assert_eq!(undump(&wide).unwrap_err(), e);
+    }
+}
@@ -0,0 +1,312 @@
+//! Loader for Lua 5.0 chunks.
+
+use crate::instruction::lua50::Instruction;
+use crate::undump::{
+    Constant, Context, Endianness, Error, ErrorKind, Header, Limits, LocVar, LuaString, LuacBuf,
+};
+use bytes::Buf;
+
+/// The number a 5.0 header carries so that loaders can check the format of
+/// `lua_Number`. Only its integer part is compared.
+pub const TEST_NUMBER: f64 = 3.141_592_653_589_793e7;
+
+#[derive(Debug, PartialEq)]
+pub struct Function {
+    /// `None` if stripped or, for nested functions, inherited from the parent.
+    pub(crate) source: Option<LuaString>,
+    pub(crate) line_defined: u32,
+    pub(crate) nups: u8,
+    pub(crate) num_params: u8,
+    pub(crate) is_vararg: u8,
+    pub(crate) maxstacksize: u8,
+    pub(crate) lineinfo: Vec<u32>,
+    pub(crate) locvars: Vec<LocVar>,
+    /// Debug names of the `nups` upvalues; empty if stripped.
+    pub(crate) upvalues: Vec<LuaString>,
+    pub(crate) constants: Vec<Constant>,
+    pub(crate) funs: Vec<Function>,
+    pub(crate) code: Vec<u32>,
+}
+
+impl Function {
+    pub fn instructions(&self) -> impl Iterator<Item = anyhow::Result<Instruction>> + '_ {
+        self.code.iter().map(|&i| Instruction::decode(i))
+    }
+    /// The chunk name, e.g. `@file.lua`; `None` if stripped or, for nested
+    /// functions, inherited from the parent.
+    pub fn source(&self) -> Option<&LuaString> {
+        self.source.as_ref()
+    }
+    /// Zero for the main function.
+    pub fn line_defined(&self) -> u32 {
+        self.line_defined
+    }
+    pub fn nups(&self) -> u8 {
+        self.nups
+    }
+    pub fn num_params(&self) -> u8 {
+        self.num_params
+    }
+    /// Nonzero if the function is declared with `...`.
+    pub fn is_vararg(&self) -> u8 {
+        self.is_vararg
+    }
+    pub fn maxstacksize(&self) -> u8 {
+        self.maxstacksize
+    }
+    /// The raw instruction words; see `instructions` to decode them.
+    pub fn code(&self) -> &[u32] {
+        &self.code
+    }
+    pub fn constants(&self) -> &[Constant] {
+        &self.constants
+    }
+    /// Nested function prototypes, indexed by CLOSURE's Bx.
+    pub fn functions(&self) -> &[Function] {
+        &self.funs
+    }
+    /// The source line of each instruction; empty if stripped.
+    pub fn lineinfo(&self) -> &[u32] {
+        &self.lineinfo
+    }
+    /// The source line of instruction `pc`, if not stripped.
+    pub fn line(&self, pc: usize) -> Option<u32> {
+        self.lineinfo.get(pc).copied()
+    }
+    /// Local variable debug info, in order of declaration; empty if stripped.
+    pub fn locvars(&self) -> &[LocVar] {
+        &self.locvars
+    }
+    /// Debug names of the `nups` upvalues; empty if stripped.
+    pub fn upvalues(&self) -> &[LuaString] {
+        &self.upvalues
+    }
+}
+
+/// A Lua 5.0 chunk. Its header has no format byte and no integral flag;
+/// `integral` is inferred from how `TEST_NUMBER` was written.
+#[derive(Debug, PartialEq)]
+pub struct Chunk {
+    pub header: Header,
+    pub main: Function,
+}
+
+trait Luac50Buf: LuacBuf {
+    fn get_header50(&mut self, cx: &mut Context) -> Result<(), Error> {
+        self.need(cx, 14, "header")?;
+        if self.get_u32().to_be_bytes() != *b"\x1bLua" {
+            return Err(self.error(cx, 0, ErrorKind::BadSignature));
+        }
+        let version = self.get_header_byte(cx, "luac version", |v| v == 0x50)?;
+        let endianness = match self.get_header_byte(cx, "endianness", |v| v <= 1)? {
+            0 => Endianness::Big,
+            _ => Endianness::Little,
+        };
+        let sizeof_int = self.get_header_byte(cx, "sizeof(int)", |v| matches!(v, 1..=8))?;
+        let sizeof_size_t = self.get_header_byte(cx, "sizeof(size_t)", |v| matches!(v, 1..=8))?;
+        let sizeof_instruction = self.get_header_byte(cx, "sizeof(Instruction)", |v| v == 4)?;
+        self.get_header_byte(cx, "SIZE_OP", |v| v == 6)?;
+        self.get_header_byte(cx, "SIZE_A", |v| v == 8)?;
+        self.get_header_byte(cx, "SIZE_B", |v| v == 9)?;
+        self.get_header_byte(cx, "SIZE_C", |v| v == 9)?;
+        let sizeof_number =
+            self.get_header_byte(cx, "sizeof(lua_Number)", |v| matches!(v, 4 | 8))?;
+        cx.header = Header {
+            version,
+            format: 0,
+            endianness,
+            sizeof_int,
+            sizeof_size_t,
+            sizeof_instruction,
+            sizeof_number,
+            sizeof_integer: 0,
+            integral: false,
+        };
+        self.need(cx, sizeof_number.into(), "header")?;
+        let offset = self.offset(cx);
+        let value = self.chunk()[0];
+        let n = self.get_sized(cx, sizeof_number);
+        let (float, int) = match sizeof_number {
+            4 => (f32::from_bits(n as u32).into(), (n as i32).into()),
+            _ => (f64::from_bits(n), n as i64),
+        };
+        let expected = TEST_NUMBER as i64;
+        cx.header.integral = if float as i64 == expected {
+            false
+        } else if int == expected {
+            true
+        } else {
+            let kind = ErrorKind::BadHeader {
+                field: "test number",
+                value,
+            };
+            return Err(self.error(cx, offset, kind));
+        };
+        Ok(())
+    }
+    fn get_constant50(&mut self, cx: &Context) -> Result<Constant, Error> {
+        self.need(cx, 1, "constants")?;
+        let offset = self.offset(cx);
+        let ttype = self.get_u8();
+        Ok(match ttype {
+            0 => Constant::Nil,
+            3 => {
+                self.need(cx, cx.header.sizeof_number.into(), "constants")?;
+                self.get_number(cx)
+            }
+            4 => Constant::String(self.get_nonnull_string(cx)?),
+            _ => return Err(self.error(cx, offset, ErrorKind::InvalidConstantType(ttype))),
+        })
+    }
+    fn get_function50(&mut self, cx: &mut Context) -> Result<Function, Error> {
+        let int = usize::from(cx.header.sizeof_int);
+        let source = self.get_string(cx)?;
+        self.need(cx, 2 * int + 4, "function header")?;
+        let line_defined = self.get_cint(cx)?;
+        let nups = self.get_u8();
+        let num_params = self.get_u8();
+        let is_vararg = self.get_u8();
+        let maxstacksize = self.get_u8();
+        let sizelineinfo = self.get_cint(cx)? as usize;
+        self.need(cx, int * sizelineinfo, "debug lineinfo")?;
+        let mut lineinfo = self.alloc(cx, sizelineinfo)?;
+        for _ in 0..sizelineinfo {
+            lineinfo.push(self.get_cint(cx)?);
+        }
+        self.need(cx, int, "debug locvars size")?;
+        let sizelocvars = self.get_cint(cx)? as usize;
+        let mut locvars = self.alloc(cx, sizelocvars)?;
+        for _ in 0..sizelocvars {
+            locvars.push(self.get_locvar(cx)?);
+        }
+        self.need(cx, int, "debug upvalues size")?;
+        let offset = self.offset(cx);
+        let sizeupvalues = self.get_cint(cx)?;
+        if sizeupvalues != 0 && sizeupvalues != u32::from(nups) {
+            let kind = ErrorKind::BadHeader {
+                field: "upvalue count",
+                value: sizeupvalues as u8,
+            };
+            return Err(self.error(cx, offset, kind));
+        }
+        let mut upvalues = self.alloc(cx, sizeupvalues as usize)?;
+        for _ in 0..sizeupvalues {
+            upvalues.push(self.get_nonnull_string(cx)?);
+        }
+        self.need(cx, int, "constants")?;
+        let constlen = self.get_cint(cx)? as usize;
+        let mut constants = self.alloc(cx, constlen)?;
+        for _ in 0..constlen {
+            constants.push(self.get_constant50(cx)?);
+        }
+        self.need(cx, int, "functions")?;
+        let funlen = self.get_cint(cx)? as usize;
+        let mut funs = self.alloc(cx, funlen)?;
+        for i in 0..funlen {
+            self.enter(cx, i)?;
+            funs.push(self.get_function50(cx)?);
+            cx.path.pop();
+        }
+        self.need(cx, int, "function code")?;
+        let codelen = self.get_cint(cx)? as usize;
+        self.need(cx, codelen * 4, "function code")?;
+        let mut code = self.alloc(cx, codelen)?;
+        for _ in 0..codelen {
+            code.push(self.get_sized(cx, 4) as u32);
+        }
+        Ok(Function {
+            source,
+            line_defined,
+            nups,
+            num_params,
+            is_vararg,
+            maxstacksize,
+            lineinfo,
+            locvars,
+            upvalues,
+            constants,
+            funs,
+            code,
+        })
+    }
+}
+impl Luac50Buf for &[u8] {}
+
+/// Reads just the chunk header, e.g. to inspect a chunk before loading it.
+pub fn undump_header(data: &[u8]) -> Result<Header, Error> {
+    let mut p = data;
+    let mut cx = Context::new(data);
+    p.get_header50(&mut cx)?;
+    Ok(cx.header)
+}
+
+pub fn undump_chunk(data: &[u8]) -> Result<Chunk, Error> {
+    undump_chunk_with_limits(data, Limits::default())
+}
+
+pub fn undump_chunk_with_limits(data: &[u8], limits: Limits) -> Result<Chunk, Error> {
+    let mut p = data;
+    let mut cx = Context::with_limits(data, limits);
+    p.get_header50(&mut cx)?;
+    let main = p.get_function50(&mut cx)?;
+    p.finish(&cx)?;
+    Ok(Chunk {
+        header: cx.header,
+        main,
+    })
+}
+
+pub fn undump(data: &[u8]) -> Result<Function, Error> {
+    Ok(undump_chunk(data)?.main)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::lua50::OpCode;
+    use crate::undump::lua50::*;
+
+    #[test]
+    fn test() {
+        // local a = 1
+        // return a + 2
+        let add = b"\
+\x1b\x4c\x75\x61\x50\x01\x04\x08\x04\x06\x08\x09\x09\x08\xb6\x09\
+\x93\x68\xe7\xf5\x7d\x41\x07\x00\x00\x00\x00\x00\x00\x00\x40\x61\
+\x2e\x6c\x75\x61\x00\x00\x00\x00\x00\x00\x00\x00\x02\x04\x00\x00\
+\x00\x01\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\
+\x00\x01\x00\x00\x00\x02\x00\x00\x00\x00\x00\x00\x00\x61\x00\x01\
+\x00\x00\x00\x04\x00\x00\x00\x00\x00\x00\x00\x02\x00\x00\x00\x03\
+\x00\x00\x00\x00\x00\x00\xf0\x3f\x03\x00\x00\x00\x00\x00\x00\x00\
+\x40\x00\x00\x00\x00\x04\x00\x00\x00\x01\x00\x00\x00\xcc\x3e\x00\
+\x01\x1b\x00\x01\x01\x1b\x80\x00\x00";
+        let chunk = undump_chunk(add).unwrap();
+        assert_eq!(chunk.header.version, 0x50);
+        assert!(!chunk.header.integral);
+        let main = &chunk.main;
+        assert_eq!(main.source(), Some(&"@a.lua".into()));
+        assert_eq!(
+            main.constants(),
+            [Constant::Number(1.0), Constant::Number(2.0)]
+        );
+        assert_eq!(main.locvars()[0].varname(), &"a".into());
+        assert_eq!(main.lineinfo(), [1, 2, 2, 2]);
+        let ops: Vec<_> = main.instructions().map(|i| i.unwrap().op).collect();
+        assert_eq!(
+            ops,
+            vec![OpCode::LoadK, OpCode::Add, OpCode::Return, OpCode::Return]
+        );
+        let mut data = add.to_vec();
+        data[21] = 0;
+        assert_eq!(
+            undump(&data).unwrap_err(),
+            Error {
+                kind: ErrorKind::BadHeader {
+                    field: "test number",
+                    value: 182
+                },
+                offset: 14,
+                path: vec![],
+            }
+        );
+    }
+}
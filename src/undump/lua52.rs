@@ -0,0 +1,325 @@
+//! Loader for Lua 5.2 chunks.
+
+use crate::instruction::lua52::Instruction;
+use crate::undump::{
+    Constant, Context, Error, ErrorKind, Header, Limits, LocVar, LuaString, LuacBuf,
+};
+
+const LUAC_TAIL: &[u8] = b"\x19\x93\r\n\x1a\n";
+
+/// The name Lua 5.2 gives the main function's first upvalue, through which
+/// it accesses globals.
+pub const LUA_ENV: &str = "_ENV";
+
+/// Where a closure finds an upvalue when it is created.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UpvalDesc {
+    /// Whether the upvalue is a register of the enclosing function, rather
+    /// than one of its upvalues.
+    pub(crate) instack: bool,
+    pub(crate) idx: u8,
+}
+
+impl UpvalDesc {
+    pub fn instack(&self) -> bool {
+        self.instack
+    }
+    /// The register or upvalue index, depending on `instack`.
+    pub fn idx(&self) -> u8 {
+        self.idx
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Function {
+    pub(crate) line_defined: u32,
+    pub(crate) last_line_defined: u32,
+    pub(crate) num_params: u8,
+    pub(crate) is_vararg: u8,
+    pub(crate) maxstacksize: u8,
+    pub(crate) code: Vec<u32>,
+    pub(crate) constants: Vec<Constant>,
+    pub(crate) funs: Vec<Function>,
+    pub(crate) upvalues: Vec<UpvalDesc>,
+    /// `None` if stripped. Unlike Lua 5.1, every function carries its own.
+    pub(crate) source: Option<LuaString>,
+    pub(crate) lineinfo: Vec<u32>,
+    pub(crate) locvars: Vec<LocVar>,
+    /// Debug names of `upvalues`; empty if stripped.
+    pub(crate) upvalue_names: Vec<LuaString>,
+}
+
+impl Function {
+    pub fn instructions(&self) -> impl Iterator<Item = anyhow::Result<Instruction>> + '_ {
+        self.code.iter().map(|&i| Instruction::decode(i))
+    }
+    /// The chunk name, e.g. `@file.lua`; `None` if stripped.
+    pub fn source(&self) -> Option<&LuaString> {
+        self.source.as_ref()
+    }
+    /// Zero for the main function.
+    pub fn line_defined(&self) -> u32 {
+        self.line_defined
+    }
+    pub fn last_line_defined(&self) -> u32 {
+        self.last_line_defined
+    }
+    pub fn num_params(&self) -> u8 {
+        self.num_params
+    }
+    /// Nonzero if the function is declared with `...`.
+    pub fn is_vararg(&self) -> u8 {
+        self.is_vararg
+    }
+    pub fn maxstacksize(&self) -> u8 {
+        self.maxstacksize
+    }
+    /// The raw instruction words; see `instructions` to decode them.
+    pub fn code(&self) -> &[u32] {
+        &self.code
+    }
+    pub fn constants(&self) -> &[Constant] {
+        &self.constants
+    }
+    /// Nested function prototypes, indexed by CLOSURE's Bx.
+    pub fn functions(&self) -> &[Function] {
+        &self.funs
+    }
+    /// Where each upvalue is found when a closure is created.
+    pub fn upvalues(&self) -> &[UpvalDesc] {
+        &self.upvalues
+    }
+    /// The source line of each instruction; empty if stripped.
+    pub fn lineinfo(&self) -> &[u32] {
+        &self.lineinfo
+    }
+    /// The source line of instruction `pc`, if not stripped.
+    pub fn line(&self, pc: usize) -> Option<u32> {
+        self.lineinfo.get(pc).copied()
+    }
+    /// Local variable debug info, in order of declaration; empty if stripped.
+    pub fn locvars(&self) -> &[LocVar] {
+        &self.locvars
+    }
+    /// Debug names of `upvalues`; empty if stripped.
+    pub fn upvalue_names(&self) -> &[LuaString] {
+        &self.upvalue_names
+    }
+    /// The debug name of upvalue `i`, if not stripped.
+    pub fn upvalue_name(&self, i: usize) -> Option<&LuaString> {
+        self.upvalue_names.get(i)
+    }
+}
+
+/// A Lua 5.2 chunk. When loaded, the main function's first upvalue, if any,
+/// is set to the global environment; see `LUA_ENV`.
+#[derive(Debug, PartialEq)]
+pub struct Chunk {
+    pub header: Header,
+    pub main: Function,
+}
+
+trait Luac52Buf: LuacBuf {
+    fn get_tail(&mut self, cx: &Context) -> Result<(), Error> {
+        self.need(cx, LUAC_TAIL.len(), "header")?;
+        for &expected in LUAC_TAIL {
+            self.get_header_byte(cx, "LUAC_TAIL", |v| v == expected)?;
+        }
+        Ok(())
+    }
+    fn get_function52(&mut self, cx: &mut Context) -> Result<Function, Error> {
+        let int = usize::from(cx.header.sizeof_int);
+        self.need(cx, 3 * int + 3, "function header")?;
+        let line_defined = self.get_cint(cx)?;
+        let last_line_defined = self.get_cint(cx)?;
+        let num_params = self.get_u8();
+        let is_vararg = self.get_u8();
+        let maxstacksize = self.get_u8();
+        let codelen = self.get_cint(cx)? as usize;
+        self.need(cx, codelen * 4 + int, "function code")?;
+        let mut code = self.alloc(cx, codelen)?;
+        for _ in 0..codelen {
+            code.push(self.get_sized(cx, 4) as u32);
+        }
+        let constlen = self.get_cint(cx)? as usize;
+        let mut constants = self.alloc(cx, constlen)?;
+        for _ in 0..constlen {
+            constants.push(self.get_constant(cx)?);
+        }
+        self.need(cx, int, "functions")?;
+        let funlen = self.get_cint(cx)? as usize;
+        let mut funs = self.alloc(cx, funlen)?;
+        for i in 0..funlen {
+            self.enter(cx, i)?;
+            funs.push(self.get_function52(cx)?);
+            cx.path.pop();
+        }
+        self.need(cx, int, "upvalues size")?;
+        let sizeupvalues = self.get_cint(cx)? as usize;
+        self.need(cx, 2 * sizeupvalues, "upvalues")?;
+        let mut upvalues = self.alloc(cx, sizeupvalues)?;
+        for _ in 0..sizeupvalues {
+            let offset = self.offset(cx);
+            let instack = self.get_u8();
+            if instack > 1 {
+                return Err(self.error(cx, offset, ErrorKind::InvalidBoolean(instack)));
+            }
+            upvalues.push(UpvalDesc {
+                instack: instack != 0,
+                idx: self.get_u8(),
+            });
+        }
+        let source = self.get_string(cx)?;
+        self.need(cx, int, "debug lineinfo size")?;
+        let sizelineinfo = self.get_cint(cx)? as usize;
+        self.need(cx, int * sizelineinfo, "debug lineinfo")?;
+        let mut lineinfo = self.alloc(cx, sizelineinfo)?;
+        for _ in 0..sizelineinfo {
+            lineinfo.push(self.get_cint(cx)?);
+        }
+        self.need(cx, int, "debug locvars size")?;
+        let sizelocvars = self.get_cint(cx)? as usize;
+        let mut locvars = self.alloc(cx, sizelocvars)?;
+        for _ in 0..sizelocvars {
+            locvars.push(self.get_locvar(cx)?);
+        }
+        self.need(cx, int, "debug upvalues size")?;
+        let sizeupvalnames = self.get_cint(cx)? as usize;
+        let mut upvalue_names = self.alloc(cx, sizeupvalnames)?;
+        for _ in 0..sizeupvalnames {
+            upvalue_names.push(self.get_nonnull_string(cx)?);
+        }
+        Ok(Function {
+            line_defined,
+            last_line_defined,
+            num_params,
+            is_vararg,
+            maxstacksize,
+            code,
+            constants,
+            funs,
+            upvalues,
+            source,
+            lineinfo,
+            locvars,
+            upvalue_names,
+        })
+    }
+}
+impl Luac52Buf for &[u8] {}
+
+/// Reads just the chunk header, e.g. to inspect a chunk before loading it.
+pub fn undump_header(data: &[u8]) -> Result<Header, Error> {
+    let mut p = data;
+    let mut cx = Context::new(data);
+    p.get_header(&mut cx, 0x52)?;
+    p.get_tail(&cx)?;
+    Ok(cx.header)
+}
+
+pub fn undump_chunk(data: &[u8]) -> Result<Chunk, Error> {
+    undump_chunk_with_limits(data, Limits::default())
+}
+
+pub fn undump_chunk_with_limits(data: &[u8], limits: Limits) -> Result<Chunk, Error> {
+    let mut p = data;
+    let mut cx = Context::with_limits(data, limits);
+    p.get_header(&mut cx, 0x52)?;
+    p.get_tail(&cx)?;
+    let main = p.get_function52(&mut cx)?;
+    p.finish(&cx)?;
+    Ok(Chunk {
+        header: cx.header,
+        main,
+    })
+}
+
+pub fn undump(data: &[u8]) -> Result<Function, Error> {
+    Ok(undump_chunk(data)?.main)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::lua52::OpCode;
+    use crate::undump::lua52::*;
+
+    #[test]
+    fn test() {
+        // local a = 1
+        // return function(b) print(a + b) end
+        let closure = b"\
+\x1b\x4c\x75\x61\x52\x00\x01\x04\x08\x04\x08\x00\x19\x93\x0d\x0a\
+\x1a\x0a\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x02\x04\x00\x00\
+\x00\x01\x00\x00\x00\x65\x00\x00\x00\x5f\x00\x00\x01\x1f\x00\x80\
+\x00\x01\x00\x00\x00\x03\x00\x00\x00\x00\x00\x00\xf0\x3f\x01\x00\
+\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\x01\x00\x03\x05\x00\x00\
+\x00\x46\x00\x40\x00\x85\x00\x80\x00\x8d\x00\x00\x01\x5d\x40\x00\
+\x01\x1f\x00\x80\x00\x01\x00\x00\x00\x04\x06\x00\x00\x00\x00\x00\
+\x00\x00\x70\x72\x69\x6e\x74\x00\x00\x00\x00\x00\x02\x00\x00\x00\
+\x00\x00\x01\x00\x07\x00\x00\x00\x00\x00\x00\x00\x40\x64\x2e\x6c\
+\x75\x61\x00\x05\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\x02\
+\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\x01\x00\x00\x00\x02\
+\x00\x00\x00\x00\x00\x00\x00\x62\x00\x00\x00\x00\x00\x05\x00\x00\
+\x00\x02\x00\x00\x00\x05\x00\x00\x00\x00\x00\x00\x00\x5f\x45\x4e\
+\x56\x00\x02\x00\x00\x00\x00\x00\x00\x00\x61\x00\x01\x00\x00\x00\
+\x01\x00\x07\x00\x00\x00\x00\x00\x00\x00\x40\x64\x2e\x6c\x75\x61\
+\x00\x04\x00\x00\x00\x01\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\
+\x00\x02\x00\x00\x00\x01\x00\x00\x00\x02\x00\x00\x00\x00\x00\x00\
+\x00\x61\x00\x01\x00\x00\x00\x04\x00\x00\x00\x01\x00\x00\x00\x05\
+\x00\x00\x00\x00\x00\x00\x00\x5f\x45\x4e\x56\x00";
+        let chunk = undump_chunk(closure).unwrap();
+        assert_eq!(chunk.header.version, 0x52);
+        let main = &chunk.main;
+        assert_eq!(main.constants(), [Constant::Number(1.0)]);
+        assert_eq!(
+            main.upvalues,
+            vec![UpvalDesc {
+                instack: true,
+                idx: 0
+            }]
+        );
+        assert_eq!(main.upvalue_name(0), Some(&LUA_ENV.into()));
+        let f = &main.functions()[0];
+        assert_eq!(f.source(), Some(&"@d.lua".into()));
+        assert_eq!(f.constants, vec![Constant::String("print".into())]);
+        assert_eq!(
+            f.upvalues,
+            vec![
+                UpvalDesc {
+                    instack: false,
+                    idx: 0
+                },
+                UpvalDesc {
+                    instack: true,
+                    idx: 0
+                },
+            ]
+        );
+        assert_eq!(f.upvalue_names(), [LUA_ENV.into(), "a".into()]);
+        assert_eq!(
+            (f.upvalues()[1].instack(), f.upvalues()[1].idx()),
+            (true, 0)
+        );
+        assert_eq!(f.locvars[0].varname, "b".into());
+        let ops: Vec<_> = f.instructions().map(|i| i.unwrap().op).collect();
+        assert_eq!(
+            ops,
+            vec![
+                OpCode::GetTabUp,
+                OpCode::GetUpval,
+                OpCode::Add,
+                OpCode::Call,
+                OpCode::Return
+            ]
+        );
+        let mut data = closure.to_vec();
+        data[17] = b'\r';
+        assert_eq!(
+            undump(&data).unwrap_err().kind,
+            ErrorKind::BadHeader {
+                field: "LUAC_TAIL",
+                value: b'\r'
+            }
+        );
+    }
+}
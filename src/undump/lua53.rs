@@ -0,0 +1,431 @@
+//! Loader for Lua 5.3 chunks.
+
+use crate::instruction::lua53::Instruction;
+pub use crate::undump::lua52::{LUA_ENV, UpvalDesc};
+use crate::undump::{
+    Constant, Context, Endianness, Error, ErrorKind, Header, Limits, LocVar, LuaString, LuacBuf,
+};
+use bytes::Buf;
+
+const LUAC_DATA: &[u8] = b"\x19\x93\r\n\x1a\n";
+const LUAC_INT: u64 = 0x5678;
+const LUAC_NUM: f64 = 370.5;
+
+/// Longest string Lua 5.3 interns; longer ones are dumped as long strings.
+pub const LUAI_MAXSHORTLEN: usize = 40;
+
+#[derive(Debug, PartialEq)]
+pub struct Function {
+    /// `None` if stripped, or if the same as the parent's.
+    pub(crate) source: Option<LuaString>,
+    pub(crate) line_defined: u32,
+    pub(crate) last_line_defined: u32,
+    pub(crate) num_params: u8,
+    pub(crate) is_vararg: u8,
+    pub(crate) maxstacksize: u8,
+    pub(crate) code: Vec<u32>,
+    pub(crate) constants: Vec<Constant>,
+    pub(crate) upvalues: Vec<UpvalDesc>,
+    pub(crate) funs: Vec<Function>,
+    pub(crate) lineinfo: Vec<u32>,
+    pub(crate) locvars: Vec<LocVar>,
+    /// Debug names of `upvalues`; empty if stripped.
+    pub(crate) upvalue_names: Vec<LuaString>,
+}
+
+impl Function {
+    pub fn instructions(&self) -> impl Iterator<Item = anyhow::Result<Instruction>> + '_ {
+        self.code.iter().map(|&i| Instruction::decode(i))
+    }
+    /// The chunk name, e.g. `@file.lua`; `None` if stripped or the same as the parent's.
+    pub fn source(&self) -> Option<&LuaString> {
+        self.source.as_ref()
+    }
+    /// Zero for the main function.
+    pub fn line_defined(&self) -> u32 {
+        self.line_defined
+    }
+    pub fn last_line_defined(&self) -> u32 {
+        self.last_line_defined
+    }
+    pub fn num_params(&self) -> u8 {
+        self.num_params
+    }
+    /// Nonzero if the function is declared with `...`.
+    pub fn is_vararg(&self) -> u8 {
+        self.is_vararg
+    }
+    pub fn maxstacksize(&self) -> u8 {
+        self.maxstacksize
+    }
+    /// The raw instruction words; see `instructions` to decode them.
+    pub fn code(&self) -> &[u32] {
+        &self.code
+    }
+    pub fn constants(&self) -> &[Constant] {
+        &self.constants
+    }
+    /// Nested function prototypes, indexed by CLOSURE's Bx.
+    pub fn functions(&self) -> &[Function] {
+        &self.funs
+    }
+    /// Where each upvalue is found when a closure is created.
+    pub fn upvalues(&self) -> &[UpvalDesc] {
+        &self.upvalues
+    }
+    /// The source line of each instruction; empty if stripped.
+    pub fn lineinfo(&self) -> &[u32] {
+        &self.lineinfo
+    }
+    /// The source line of instruction `pc`, if not stripped.
+    pub fn line(&self, pc: usize) -> Option<u32> {
+        self.lineinfo.get(pc).copied()
+    }
+    /// Local variable debug info, in order of declaration; empty if stripped.
+    pub fn locvars(&self) -> &[LocVar] {
+        &self.locvars
+    }
+    /// Debug names of `upvalues`; empty if stripped.
+    pub fn upvalue_names(&self) -> &[LuaString] {
+        &self.upvalue_names
+    }
+    /// The debug name of upvalue `i`, if not stripped.
+    pub fn upvalue_name(&self, i: usize) -> Option<&LuaString> {
+        self.upvalue_names.get(i)
+    }
+}
+
+/// A Lua 5.3 chunk. The header's byte order is inferred from `LUAC_INT`, and
+/// its `integral` flag is always false since numbers may be either kind.
+#[derive(Debug, PartialEq)]
+pub struct Chunk {
+    pub header: Header,
+    pub main: Function,
+}
+
+pub(super) trait Luac53Buf: LuacBuf {
+    /// Reads the header shared by Lua 5.3 and 5.4, which drops the sizes of
+    /// `int` and `size_t` since it writes them as varints.
+    fn get_header53(&mut self, cx: &mut Context, version: u8) -> Result<(), Error> {
+        let sizes = if version < 0x54 { 5 } else { 3 };
+        self.need(cx, 4 + 2 + LUAC_DATA.len() + sizes, "header")?;
+        if self.get_u32().to_be_bytes() != *b"\x1bLua" {
+            return Err(self.error(cx, 0, ErrorKind::BadSignature));
+        }
+        let version = self.get_header_byte(cx, "luac version", |v| v == version)?;
+        let format = self.get_header_byte(cx, "luac format", |v| v == 0)?;
+        for &expected in LUAC_DATA {
+            self.get_header_byte(cx, "LUAC_DATA", |v| v == expected)?;
+        }
+        let (mut sizeof_int, mut sizeof_size_t) = (0, 0);
+        if version < 0x54 {
+            sizeof_int = self.get_header_byte(cx, "sizeof(int)", |v| matches!(v, 1..=8))?;
+            sizeof_size_t = self.get_header_byte(cx, "sizeof(size_t)", |v| matches!(v, 1..=8))?;
+        }
+        let sizeof_instruction = self.get_header_byte(cx, "sizeof(Instruction)", |v| v == 4)?;
+        let sizeof_integer =
+            self.get_header_byte(cx, "sizeof(lua_Integer)", |v| matches!(v, 4 | 8))?;
+        let sizeof_number =
+            self.get_header_byte(cx, "sizeof(lua_Number)", |v| matches!(v, 4 | 8))?;
+        self.need(
+            cx,
+            usize::from(sizeof_integer) + usize::from(sizeof_number),
+            "header",
+        )?;
+        let offset = self.offset(cx);
+        let int = self.chunk()[..sizeof_integer.into()].to_vec();
+        self.advance(int.len());
+        let endianness = if int.iter().rev().fold(0, |n, &b| n << 8 | u64::from(b)) == LUAC_INT {
+            Endianness::Little
+        } else if int.iter().fold(0, |n, &b| n << 8 | u64::from(b)) == LUAC_INT {
+            Endianness::Big
+        } else {
+            let kind = ErrorKind::BadHeader {
+                field: "LUAC_INT",
+                value: int[0],
+            };
+            return Err(self.error(cx, offset, kind));
+        };
+        cx.header = Header {
+            version,
+            format,
+            endianness,
+            sizeof_int,
+            sizeof_size_t,
+            sizeof_instruction,
+            sizeof_number,
+            sizeof_integer,
+            integral: false,
+        };
+        let offset = self.offset(cx);
+        let value = self.chunk()[0];
+        if self.get_number(cx) != Constant::Number(LUAC_NUM) {
+            let kind = ErrorKind::BadHeader {
+                field: "LUAC_NUM",
+                value,
+            };
+            return Err(self.error(cx, offset, kind));
+        }
+        Ok(())
+    }
+    fn get_integer(&mut self, cx: &Context) -> i64 {
+        let n = self.get_sized(cx, cx.header.sizeof_integer);
+        match cx.header.sizeof_integer {
+            4 => (n as i32).into(),
+            _ => n as i64,
+        }
+    }
+    fn get_string53(&mut self, cx: &Context) -> Result<Option<LuaString>, Error> {
+        self.need(cx, 1, "string length")?;
+        let offset = self.offset(cx);
+        let mut len = u64::from(self.get_u8());
+        if len == 0xff {
+            let size_t = cx.header.sizeof_size_t;
+            self.need(cx, size_t.into(), "string length")?;
+            len = self.get_sized(cx, size_t);
+        }
+        let len = usize::try_from(len)
+            .map_err(|_| self.error(cx, offset, ErrorKind::IntOutOfRange(len)))?;
+        if len == 0 {
+            return Ok(None);
+        }
+        self.need(cx, len - 1, "string contents")?;
+        self.charge(cx, len - 1)?;
+        let str = LuaString::from(&self.chunk()[..len - 1]);
+        self.advance(len - 1);
+        Ok(Some(str))
+    }
+    fn get_nonnull_string53(&mut self, cx: &Context) -> Result<LuaString, Error> {
+        let offset = self.offset(cx);
+        match self.get_string53(cx)? {
+            Some(str) => Ok(str),
+            None => Err(self.error(cx, offset, ErrorKind::NullString)),
+        }
+    }
+    fn get_constant53(&mut self, cx: &Context) -> Result<Constant, Error> {
+        self.need(cx, 1, "constants")?;
+        let offset = self.offset(cx);
+        let ttype = self.get_u8();
+        Ok(match ttype {
+            0 => Constant::Nil,
+            1 => {
+                self.need(cx, 1, "constants")?;
+                let b = self.get_u8();
+                if b > 1 {
+                    return Err(self.error(cx, offset + 1, ErrorKind::InvalidBoolean(b)));
+                }
+                Constant::Boolean(b != 0)
+            }
+            3 => {
+                self.need(cx, cx.header.sizeof_number.into(), "constants")?;
+                self.get_number(cx)
+            }
+            0x13 => {
+                self.need(cx, cx.header.sizeof_integer.into(), "constants")?;
+                Constant::Integer(self.get_integer(cx))
+            }
+            // Short and long strings are told apart by length alone.
+            4 | 0x14 => Constant::String(self.get_nonnull_string53(cx)?),
+            _ => return Err(self.error(cx, offset, ErrorKind::InvalidConstantType(ttype))),
+        })
+    }
+    fn get_function53(&mut self, cx: &mut Context) -> Result<Function, Error> {
+        let int = usize::from(cx.header.sizeof_int);
+        let source = self.get_string53(cx)?;
+        self.need(cx, 3 * int + 3, "function header")?;
+        let line_defined = self.get_cint(cx)?;
+        let last_line_defined = self.get_cint(cx)?;
+        let num_params = self.get_u8();
+        let is_vararg = self.get_u8();
+        let maxstacksize = self.get_u8();
+        let codelen = self.get_cint(cx)? as usize;
+        self.need(cx, codelen * 4 + int, "function code")?;
+        let mut code = self.alloc(cx, codelen)?;
+        for _ in 0..codelen {
+            code.push(self.get_sized(cx, 4) as u32);
+        }
+        let constlen = self.get_cint(cx)? as usize;
+        let mut constants = self.alloc(cx, constlen)?;
+        for _ in 0..constlen {
+            constants.push(self.get_constant53(cx)?);
+        }
+        self.need(cx, int, "upvalues size")?;
+        let sizeupvalues = self.get_cint(cx)? as usize;
+        self.need(cx, 2 * sizeupvalues, "upvalues")?;
+        let mut upvalues = self.alloc(cx, sizeupvalues)?;
+        for _ in 0..sizeupvalues {
+            let offset = self.offset(cx);
+            let instack = self.get_u8();
+            if instack > 1 {
+                return Err(self.error(cx, offset, ErrorKind::InvalidBoolean(instack)));
+            }
+            upvalues.push(UpvalDesc {
+                instack: instack != 0,
+                idx: self.get_u8(),
+            });
+        }
+        self.need(cx, int, "functions")?;
+        let funlen = self.get_cint(cx)? as usize;
+        let mut funs = self.alloc(cx, funlen)?;
+        for i in 0..funlen {
+            self.enter(cx, i)?;
+            funs.push(self.get_function53(cx)?);
+            cx.path.pop();
+        }
+        self.need(cx, int, "debug lineinfo size")?;
+        let sizelineinfo = self.get_cint(cx)? as usize;
+        self.need(cx, int * sizelineinfo, "debug lineinfo")?;
+        let mut lineinfo = self.alloc(cx, sizelineinfo)?;
+        for _ in 0..sizelineinfo {
+            lineinfo.push(self.get_cint(cx)?);
+        }
+        self.need(cx, int, "debug locvars size")?;
+        let sizelocvars = self.get_cint(cx)? as usize;
+        let mut locvars = self.alloc(cx, sizelocvars)?;
+        for _ in 0..sizelocvars {
+            let varname = self.get_nonnull_string53(cx)?;
+            self.need(cx, 2 * int, "debug locvars")?;
+            locvars.push(LocVar {
+                varname,
+                startpc: self.get_cint(cx)?,
+                endpc: self.get_cint(cx)?,
+            });
+        }
+        self.need(cx, int, "debug upvalues size")?;
+        let sizeupvalnames = self.get_cint(cx)? as usize;
+        let mut upvalue_names = self.alloc(cx, sizeupvalnames)?;
+        for _ in 0..sizeupvalnames {
+            upvalue_names.push(self.get_nonnull_string53(cx)?);
+        }
+        Ok(Function {
+            source,
+            line_defined,
+            last_line_defined,
+            num_params,
+            is_vararg,
+            maxstacksize,
+            code,
+            constants,
+            upvalues,
+            funs,
+            lineinfo,
+            locvars,
+            upvalue_names,
+        })
+    }
+}
+impl Luac53Buf for &[u8] {}
+
+/// Reads just the chunk header, e.g. to inspect a chunk before loading it.
+pub fn undump_header(data: &[u8]) -> Result<Header, Error> {
+    let mut p = data;
+    let mut cx = Context::new(data);
+    p.get_header53(&mut cx, 0x53)?;
+    Ok(cx.header)
+}
+
+pub fn undump_chunk(data: &[u8]) -> Result<Chunk, Error> {
+    undump_chunk_with_limits(data, Limits::default())
+}
+
+pub fn undump_chunk_with_limits(data: &[u8], limits: Limits) -> Result<Chunk, Error> {
+    let mut p = data;
+    let mut cx = Context::with_limits(data, limits);
+    p.get_header53(&mut cx, 0x53)?;
+    p.need(&cx, 1, "header")?;
+    let offset = p.offset(&cx);
+    let nups = p.get_u8();
+    let main = p.get_function53(&mut cx)?;
+    if usize::from(nups) != main.upvalues.len() {
+        let kind = ErrorKind::BadHeader {
+            field: "upvalue count",
+            value: nups,
+        };
+        return Err(p.error(&cx, offset, kind));
+    }
+    p.finish(&cx)?;
+    Ok(Chunk {
+        header: cx.header,
+        main,
+    })
+}
+
+pub fn undump(data: &[u8]) -> Result<Function, Error> {
+    Ok(undump_chunk(data)?.main)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::lua53::OpCode;
+    use crate::undump::lua53::*;
+
+    #[test]
+    fn test() {
+        // local a, b = 7, 2.5
+        // return a // 2 | a ~ 1 << 3, b, "xxx...", a string of 41 x's
+        let chunk = b"\
+\x1b\x4c\x75\x61\x53\x00\x19\x93\x0d\x0a\x1a\x0a\x04\x08\x04\x08\
+\x08\x78\x56\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x28\x77\
+\x40\x01\x07\x40\x65\x2e\x6c\x75\x61\x00\x00\x00\x00\x00\x00\x00\
+\x00\x00\x01\x05\x09\x00\x00\x00\x01\x00\x00\x00\x41\x40\x00\x00\
+\x93\x80\x40\x00\xd6\xc0\x40\x00\x95\xc0\x00\x01\xc0\x00\x80\x00\
+\x01\x01\x01\x00\xa6\x00\x00\x02\x26\x00\x80\x00\x05\x00\x00\x00\
+\x13\x07\x00\x00\x00\x00\x00\x00\x00\x03\x00\x00\x00\x00\x00\x00\
+\x04\x40\x13\x02\x00\x00\x00\x00\x00\x00\x00\x13\x08\x00\x00\x00\
+\x00\x00\x00\x00\x14\x2a\x78\x78\x78\x78\x78\x78\x78\x78\x78\x78\
+\x78\x78\x78\x78\x78\x78\x78\x78\x78\x78\x78\x78\x78\x78\x78\x78\
+\x78\x78\x78\x78\x78\x78\x78\x78\x78\x78\x78\x78\x78\x78\x78\x01\
+\x00\x00\x00\x01\x00\x00\x00\x00\x00\x09\x00\x00\x00\x01\x00\x00\
+\x00\x01\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\
+\x00\x02\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\
+\x00\x02\x00\x00\x00\x02\x61\x02\x00\x00\x00\x09\x00\x00\x00\x02\
+\x62\x02\x00\x00\x00\x09\x00\x00\x00\x01\x00\x00\x00\x05\x5f\x45\
+\x4e\x56";
+        let c = undump_chunk(chunk).unwrap();
+        assert_eq!(c.header.version, 0x53);
+        assert_eq!(c.header.endianness, Endianness::Little);
+        assert_eq!(c.header.sizeof_integer, 8);
+        let main = &c.main;
+        assert_eq!(main.source(), Some(&"@e.lua".into()));
+        let long = LuaString::from("x".repeat(LUAI_MAXSHORTLEN + 1).as_str());
+        assert_eq!(
+            main.constants(),
+            [
+                Constant::Integer(7),
+                Constant::Number(2.5),
+                Constant::Integer(2),
+                Constant::Integer(8),
+                Constant::String(long),
+            ]
+        );
+        assert_eq!(main.upvalue_name(0), Some(&LUA_ENV.into()));
+        let ops: Vec<_> = main.instructions().map(|i| i.unwrap().op).collect();
+        assert_eq!(
+            ops,
+            vec![
+                OpCode::LoadK,
+                OpCode::LoadK,
+                OpCode::IDiv,
+                OpCode::BXor,
+                OpCode::BOr,
+                OpCode::Move,
+                OpCode::LoadK,
+                OpCode::Return,
+                OpCode::Return
+            ]
+        );
+        let mut data = chunk.to_vec();
+        data[17] = 0x79;
+        assert_eq!(
+            undump(&data).unwrap_err(),
+            Error {
+                kind: ErrorKind::BadHeader {
+                    field: "LUAC_INT",
+                    value: 0x79
+                },
+                offset: 17,
+                path: vec![],
+            }
+        );
+    }
+}
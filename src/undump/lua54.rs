@@ -0,0 +1,488 @@
+//! Loader for Lua 5.4 chunks.
+
+use crate::instruction::lua54::Instruction;
+pub use crate::undump::lua52::LUA_ENV;
+use crate::undump::lua53::Luac53Buf;
+use crate::undump::{Constant, Context, Error, ErrorKind, Header, Limits, LuaString, LuacBuf};
+use bytes::Buf;
+
+/// Marks a `lineinfo` entry whose line is in `abslineinfo` instead.
+pub const ABSLINEINFO: i8 = -0x80;
+
+/// The kind of variable an upvalue refers to (`VDKREG` etc. in lparser.h).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VarKind {
+    Regular,
+    Const,
+    ToClose,
+    /// A compile-time constant, which is never actually captured.
+    CompileTimeConst,
+}
+
+/// Where a closure finds an upvalue when it is created.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UpvalDesc {
+    /// Whether the upvalue is a register of the enclosing function, rather
+    /// than one of its upvalues.
+    pub(crate) instack: bool,
+    pub(crate) idx: u8,
+    pub(crate) kind: VarKind,
+}
+
+impl UpvalDesc {
+    pub fn instack(&self) -> bool {
+        self.instack
+    }
+    /// The register or upvalue index, depending on `instack`.
+    pub fn idx(&self) -> u8 {
+        self.idx
+    }
+    pub fn kind(&self) -> VarKind {
+        self.kind
+    }
+}
+
+/// An absolute line number for `pc`, so that lines can be found without
+/// summing every `lineinfo` delta from the start of the function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AbsLineInfo {
+    pub(crate) pc: u32,
+    pub(crate) line: u32,
+}
+
+impl AbsLineInfo {
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+}
+
+/// Like the Lua 5.1 `LocVar`, but Lua 5.4 dumps a missing name as a null
+/// string.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocVar {
+    pub(crate) varname: Option<LuaString>,
+    pub(crate) startpc: u32,
+    pub(crate) endpc: u32,
+}
+
+impl LocVar {
+    pub fn varname(&self) -> Option<&LuaString> {
+        self.varname.as_ref()
+    }
+    /// The first instruction where the variable is active.
+    pub fn startpc(&self) -> u32 {
+        self.startpc
+    }
+    /// The first instruction where the variable is dead.
+    pub fn endpc(&self) -> u32 {
+        self.endpc
+    }
+    /// Whether the variable is active at instruction `pc`.
+    pub fn is_active(&self, pc: u32) -> bool {
+        (self.startpc..self.endpc).contains(&pc)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Function {
+    /// `None` if stripped, or if the same as the parent's.
+    pub(crate) source: Option<LuaString>,
+    pub(crate) line_defined: u32,
+    pub(crate) last_line_defined: u32,
+    pub(crate) num_params: u8,
+    pub(crate) is_vararg: u8,
+    pub(crate) maxstacksize: u8,
+    pub(crate) code: Vec<u32>,
+    pub(crate) constants: Vec<Constant>,
+    pub(crate) upvalues: Vec<UpvalDesc>,
+    pub(crate) funs: Vec<Function>,
+    /// Line deltas from the previous instruction, or `ABSLINEINFO`.
+    pub(crate) lineinfo: Vec<i8>,
+    pub(crate) abslineinfo: Vec<AbsLineInfo>,
+    pub(crate) locvars: Vec<LocVar>,
+    /// Debug names of `upvalues`, `None` where missing; empty if stripped.
+    pub(crate) upvalue_names: Vec<Option<LuaString>>,
+}
+
+impl Function {
+    pub fn instructions(&self) -> impl Iterator<Item = anyhow::Result<Instruction>> + '_ {
+        self.code.iter().map(|&i| Instruction::decode(i))
+    }
+    /// The chunk name, e.g. `@file.lua`; `None` if stripped or the same as
+    /// the parent's.
+    pub fn source(&self) -> Option<&LuaString> {
+        self.source.as_ref()
+    }
+    /// Zero for the main function.
+    pub fn line_defined(&self) -> u32 {
+        self.line_defined
+    }
+    pub fn last_line_defined(&self) -> u32 {
+        self.last_line_defined
+    }
+    pub fn num_params(&self) -> u8 {
+        self.num_params
+    }
+    /// Nonzero if the function is declared with `...`.
+    pub fn is_vararg(&self) -> u8 {
+        self.is_vararg
+    }
+    pub fn maxstacksize(&self) -> u8 {
+        self.maxstacksize
+    }
+    /// The raw instruction words; see `instructions` to decode them.
+    pub fn code(&self) -> &[u32] {
+        &self.code
+    }
+    pub fn constants(&self) -> &[Constant] {
+        &self.constants
+    }
+    /// Nested function prototypes, indexed by CLOSURE's Bx.
+    pub fn functions(&self) -> &[Function] {
+        &self.funs
+    }
+    /// Where each upvalue is found when a closure is created.
+    pub fn upvalues(&self) -> &[UpvalDesc] {
+        &self.upvalues
+    }
+    /// Line deltas from the previous instruction, or `ABSLINEINFO`; empty if
+    /// stripped. See `line` to resolve them.
+    pub fn lineinfo(&self) -> &[i8] {
+        &self.lineinfo
+    }
+    pub fn abslineinfo(&self) -> &[AbsLineInfo] {
+        &self.abslineinfo
+    }
+    /// Local variable debug info, in order of declaration; empty if stripped.
+    pub fn locvars(&self) -> &[LocVar] {
+        &self.locvars
+    }
+    /// Debug names of `upvalues`, `None` where missing; empty if stripped.
+    pub fn upvalue_names(&self) -> &[Option<LuaString>] {
+        &self.upvalue_names
+    }
+    /// The debug name of upvalue `i`, if not stripped or missing.
+    pub fn upvalue_name(&self, i: usize) -> Option<&LuaString> {
+        self.upvalue_names.get(i)?.as_ref()
+    }
+    /// The source line of the instruction at `pc`, as `luaG_getfuncline`
+    /// computes it; `None` if stripped.
+    pub fn line(&self, pc: usize) -> Option<u32> {
+        if pc >= self.lineinfo.len() {
+            return None;
+        }
+        let (mut basepc, mut line) = match self
+            .abslineinfo
+            .iter()
+            .rev()
+            .find(|abs| abs.pc as usize <= pc)
+        {
+            Some(abs) => (abs.pc as usize + 1, i64::from(abs.line)),
+            None => (0, i64::from(self.line_defined)),
+        };
+        while basepc <= pc {
+            line += i64::from(self.lineinfo[basepc]);
+            basepc += 1;
+        }
+        u32::try_from(line).ok()
+    }
+}
+
+/// A Lua 5.4 chunk. The header's byte order is inferred from `LUAC_INT`, and
+/// it records no sizes of `int` or `size_t`.
+#[derive(Debug, PartialEq)]
+pub struct Chunk {
+    pub header: Header,
+    pub main: Function,
+}
+
+trait Luac54Buf: Luac53Buf {
+    /// Reads a `loadUnsigned` varint: big-endian groups of 7 bits, the last
+    /// of which has its high bit set.
+    fn get_varint(&mut self, cx: &Context, limit: u64, what: &'static str) -> Result<u64, Error> {
+        let offset = self.offset(cx);
+        let mut x: u64 = 0;
+        loop {
+            self.need(cx, 1, what)?;
+            let b = self.get_u8();
+            if x >= limit >> 7 {
+                return Err(self.error(cx, offset, ErrorKind::IntOutOfRange(x)));
+            }
+            x = x << 7 | u64::from(b & 0x7f);
+            if b & 0x80 != 0 {
+                return Ok(x);
+            }
+        }
+    }
+    fn get_vint(&mut self, cx: &Context, what: &'static str) -> Result<u32, Error> {
+        Ok(self.get_varint(cx, i32::MAX as u64, what)? as u32)
+    }
+    fn get_string54(&mut self, cx: &Context) -> Result<Option<LuaString>, Error> {
+        let len = self.get_varint(cx, usize::MAX as u64, "string length")? as usize;
+        if len == 0 {
+            return Ok(None);
+        }
+        self.need(cx, len - 1, "string contents")?;
+        self.charge(cx, len - 1)?;
+        let str = LuaString::from(&self.chunk()[..len - 1]);
+        self.advance(len - 1);
+        Ok(Some(str))
+    }
+    fn get_nonnull_string54(&mut self, cx: &Context) -> Result<LuaString, Error> {
+        let offset = self.offset(cx);
+        match self.get_string54(cx)? {
+            Some(str) => Ok(str),
+            None => Err(self.error(cx, offset, ErrorKind::NullString)),
+        }
+    }
+    fn get_constant54(&mut self, cx: &Context) -> Result<Constant, Error> {
+        self.need(cx, 1, "constants")?;
+        let offset = self.offset(cx);
+        let ttype = self.get_u8();
+        Ok(match ttype {
+            0 => Constant::Nil,
+            1 => Constant::Boolean(false),
+            0x11 => Constant::Boolean(true),
+            3 => {
+                self.need(cx, cx.header.sizeof_integer.into(), "constants")?;
+                Constant::Integer(self.get_integer(cx))
+            }
+            0x13 => {
+                self.need(cx, cx.header.sizeof_number.into(), "constants")?;
+                self.get_number(cx)
+            }
+            4 | 0x14 => Constant::String(self.get_nonnull_string54(cx)?),
+            _ => return Err(self.error(cx, offset, ErrorKind::InvalidConstantType(ttype))),
+        })
+    }
+    fn get_upvaldesc(&mut self, cx: &Context) -> Result<UpvalDesc, Error> {
+        self.need(cx, 3, "upvalues")?;
+        let offset = self.offset(cx);
+        let instack = self.get_u8();
+        if instack > 1 {
+            return Err(self.error(cx, offset, ErrorKind::InvalidBoolean(instack)));
+        }
+        let idx = self.get_u8();
+        let kind = match self.get_u8() {
+            0 => VarKind::Regular,
+            1 => VarKind::Const,
+            2 => VarKind::ToClose,
+            3 => VarKind::CompileTimeConst,
+            k => return Err(self.error(cx, offset + 2, ErrorKind::InvalidUpvalueKind(k))),
+        };
+        Ok(UpvalDesc {
+            instack: instack != 0,
+            idx,
+            kind,
+        })
+    }
+    fn get_function54(&mut self, cx: &mut Context) -> Result<Function, Error> {
+        let source = self.get_string54(cx)?;
+        let line_defined = self.get_vint(cx, "function header")?;
+        let last_line_defined = self.get_vint(cx, "function header")?;
+        self.need(cx, 3, "function header")?;
+        let num_params = self.get_u8();
+        let is_vararg = self.get_u8();
+        let maxstacksize = self.get_u8();
+        let codelen = self.get_vint(cx, "function code")? as usize;
+        self.need(cx, codelen * 4, "function code")?;
+        let mut code = self.alloc(cx, codelen)?;
+        for _ in 0..codelen {
+            code.push(self.get_sized(cx, 4) as u32);
+        }
+        let constlen = self.get_vint(cx, "constants")? as usize;
+        let mut constants = self.alloc(cx, constlen)?;
+        for _ in 0..constlen {
+            constants.push(self.get_constant54(cx)?);
+        }
+        let sizeupvalues = self.get_vint(cx, "upvalues size")? as usize;
+        let mut upvalues = self.alloc(cx, sizeupvalues)?;
+        for _ in 0..sizeupvalues {
+            upvalues.push(self.get_upvaldesc(cx)?);
+        }
+        let funlen = self.get_vint(cx, "functions")? as usize;
+        let mut funs = self.alloc(cx, funlen)?;
+        for i in 0..funlen {
+            self.enter(cx, i)?;
+            funs.push(self.get_function54(cx)?);
+            cx.path.pop();
+        }
+        let sizelineinfo = self.get_vint(cx, "debug lineinfo size")? as usize;
+        self.need(cx, sizelineinfo, "debug lineinfo")?;
+        let mut lineinfo = self.alloc(cx, sizelineinfo)?;
+        for _ in 0..sizelineinfo {
+            lineinfo.push(self.get_i8());
+        }
+        let sizeabslineinfo = self.get_vint(cx, "debug abslineinfo size")? as usize;
+        let mut abslineinfo = self.alloc(cx, sizeabslineinfo)?;
+        for _ in 0..sizeabslineinfo {
+            abslineinfo.push(AbsLineInfo {
+                pc: self.get_vint(cx, "debug abslineinfo")?,
+                line: self.get_vint(cx, "debug abslineinfo")?,
+            });
+        }
+        let sizelocvars = self.get_vint(cx, "debug locvars size")? as usize;
+        let mut locvars = self.alloc(cx, sizelocvars)?;
+        for _ in 0..sizelocvars {
+            locvars.push(LocVar {
+                varname: self.get_string54(cx)?,
+                startpc: self.get_vint(cx, "debug locvars")?,
+                endpc: self.get_vint(cx, "debug locvars")?,
+            });
+        }
+        // Like Lua, take any nonzero count to mean one name per upvalue.
+        let mut upvalue_names = vec![];
+        if self.get_vint(cx, "debug upvalues size")? != 0 {
+            for _ in 0..sizeupvalues {
+                upvalue_names.push(self.get_string54(cx)?);
+            }
+        }
+        Ok(Function {
+            source,
+            line_defined,
+            last_line_defined,
+            num_params,
+            is_vararg,
+            maxstacksize,
+            code,
+            constants,
+            upvalues,
+            funs,
+            lineinfo,
+            abslineinfo,
+            locvars,
+            upvalue_names,
+        })
+    }
+}
+impl Luac54Buf for &[u8] {}
+
+/// Reads just the chunk header, e.g. to inspect a chunk before loading it.
+pub fn undump_header(data: &[u8]) -> Result<Header, Error> {
+    let mut p = data;
+    let mut cx = Context::new(data);
+    p.get_header53(&mut cx, 0x54)?;
+    Ok(cx.header)
+}
+
+pub fn undump_chunk(data: &[u8]) -> Result<Chunk, Error> {
+    undump_chunk_with_limits(data, Limits::default())
+}
+
+pub fn undump_chunk_with_limits(data: &[u8], limits: Limits) -> Result<Chunk, Error> {
+    let mut p = data;
+    let mut cx = Context::with_limits(data, limits);
+    p.get_header53(&mut cx, 0x54)?;
+    p.need(&cx, 1, "header")?;
+    let offset = p.offset(&cx);
+    let nups = p.get_u8();
+    let main = p.get_function54(&mut cx)?;
+    if usize::from(nups) != main.upvalues.len() {
+        let kind = ErrorKind::BadHeader {
+            field: "upvalue count",
+            value: nups,
+        };
+        return Err(p.error(&cx, offset, kind));
+    }
+    p.finish(&cx)?;
+    Ok(Chunk {
+        header: cx.header,
+        main,
+    })
+}
+
+pub fn undump(data: &[u8]) -> Result<Function, Error> {
+    Ok(undump_chunk(data)?.main)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::lua54::OpCode;
+    use crate::undump::lua54::*;
+
+    #[test]
+    fn test() {
+        // local x <const> = {}
+        // local y <close> = nil
+        // local f = function(a) return x, y, a // 2 end
+        // (200 blank lines)
+        // return f(3.5), "yyy...", a string of 41 y's
+        let chunk = b"\
+\x1b\x4c\x75\x61\x54\x00\x19\x93\x0d\x0a\x1a\x0a\x04\x08\x08\x78\
+\x56\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x28\x77\x40\x01\
+\x87\x40\x67\x2e\x6c\x75\x61\x80\x80\x00\x01\x05\x8c\x51\x00\x00\
+\x00\x13\x00\x00\x00\x52\x00\x00\x00\x88\x00\x00\x00\xb7\x00\x00\
+\x00\x4f\x01\x00\x00\x80\x01\x02\x00\x03\x02\x00\x00\xc4\x01\x02\
+\x02\x03\x82\x00\x00\xc6\x81\x03\x01\xc6\x81\x01\x01\x82\x13\x00\
+\x00\x00\x00\x00\x00\x0c\x40\x14\xaa\x79\x79\x79\x79\x79\x79\x79\
+\x79\x79\x79\x79\x79\x79\x79\x79\x79\x79\x79\x79\x79\x79\x79\x79\
+\x79\x79\x79\x79\x79\x79\x79\x79\x79\x79\x79\x79\x79\x79\x79\x79\
+\x79\x79\x81\x01\x00\x00\x81\x80\x83\x83\x01\x00\x04\x86\x89\x00\
+\x00\x00\x09\x01\x01\x00\x9c\x01\x00\x00\x30\x00\x00\x0c\xc6\x00\
+\x04\x00\xc7\x00\x01\x00\x81\x03\x02\x00\x00\x00\x00\x00\x00\x00\
+\x82\x01\x00\x01\x01\x01\x02\x80\x86\x00\x00\x00\x00\x00\x00\x80\
+\x81\x82\x61\x80\x86\x82\x82\x78\x82\x79\x8c\x01\x00\x00\x01\x00\
+\x01\x80\x00\x00\x00\x00\x00\x81\x86\x01\xcb\x83\x82\x78\x83\x8c\
+\x82\x79\x84\x8c\x82\x66\x86\x8c\x81\x85\x5f\x45\x4e\x56";
+        let c = undump_chunk(chunk).unwrap();
+        assert_eq!(c.header.version, 0x54);
+        assert_eq!(c.header.sizeof_int, 0);
+        let main = &c.main;
+        assert_eq!(main.source, Some("@g.lua".into()));
+        let long = LuaString::from("y".repeat(41).as_str());
+        assert_eq!(
+            main.constants,
+            vec![Constant::Number(3.5), Constant::String(long)]
+        );
+        assert_eq!(main.lineinfo[6], ABSLINEINFO);
+        assert_eq!(main.abslineinfo(), [AbsLineInfo { pc: 6, line: 203 }]);
+        let lines: Vec<_> = (0..main.code.len()).map(|pc| main.line(pc)).collect();
+        assert_eq!(lines[..3], [Some(1); 3]);
+        assert_eq!(lines[5..7], [Some(3), Some(203)]);
+        assert_eq!(main.line(main.code.len()), None);
+        let f = &main.functions()[0];
+        assert_eq!(f.source(), None);
+        assert_eq!(f.constants, vec![Constant::Integer(2)]);
+        let kinds: Vec<_> = f.upvalues().iter().map(|u| u.kind()).collect();
+        assert_eq!(kinds, vec![VarKind::Const, VarKind::ToClose]);
+        assert_eq!(f.upvalue_names, vec![Some("x".into()), Some("y".into())]);
+        assert_eq!(f.locvars()[0].varname(), Some(&"a".into()));
+        let ops: Vec<_> = f.instructions().map(|i| i.unwrap().op).collect();
+        assert_eq!(
+            ops,
+            vec![
+                OpCode::GetUpval,
+                OpCode::GetUpval,
+                OpCode::IDivK,
+                OpCode::MmBinK,
+                OpCode::Return,
+                OpCode::Return0
+            ]
+        );
+        let mut data = chunk.to_vec();
+        let upvals = 0xc1;
+        assert_eq!(data[upvals..upvals + 3], [1, 0, 1]);
+        data[upvals + 2] = 4;
+        assert_eq!(
+            undump(&data).unwrap_err().kind,
+            ErrorKind::InvalidUpvalueKind(4)
+        );
+
+        // A local and an upvalue whose names were dropped, dumped as null
+        // strings.
+        let names = b"\x82\x61\x80\x86\x82\x82\x78";
+        let at = chunk.windows(names.len()).position(|w| w == names).unwrap();
+        let mut data = chunk[..at].to_vec();
+        data.extend(b"\x80\x80\x86\x82\x80");
+        data.extend(&chunk[at + names.len()..]);
+        let main = undump(&data).unwrap();
+        let f = &main.functions()[0];
+        assert_eq!(f.locvars()[0].varname(), None);
+        assert_eq!(f.upvalue_names(), [None, Some("y".into())]);
+        assert_eq!(f.upvalue_name(0), None);
+        assert_eq!(f.upvalue_name(1), Some(&"y".into()));
+    }
+}
@@ -0,0 +1,651 @@
+//! Reader for LuaJIT 2.0 and 2.1 bytecode dumps (lj_bcread.c).
+//!
+//! A dump lists prototypes children first: each is pushed on a stack, and
+//! its parent's `GcConstant::Child` constants pop them back off, so the main
+//! function comes last.
+
+use crate::instruction::luajit::{Instruction, Version};
+use crate::undump::{
+    Constant, Context, Endianness, Error, ErrorKind, Limit, Limits, LuaString, LuacBuf,
+};
+use bytes::Buf;
+
+pub const BCDUMP_F_BE: u32 = 0x01;
+pub const BCDUMP_F_STRIP: u32 = 0x02;
+pub const BCDUMP_F_FFI: u32 = 0x04;
+/// Since LuaJIT 2.1: two-slot frames, as used by 64-bit GC builds.
+pub const BCDUMP_F_FR2: u32 = 0x08;
+
+/// Names of the internal variables of `for` loops, stored in varinfo as
+/// bytes 1 to 6 instead of strings.
+const VARNAMES: [&str; 6] = [
+    "(for index)",
+    "(for limit)",
+    "(for step)",
+    "(for generator)",
+    "(for state)",
+    "(for control)",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: Version,
+    pub flags: u32,
+    /// `None` if stripped.
+    pub chunkname: Option<LuaString>,
+}
+
+impl Header {
+    pub fn endianness(&self) -> Endianness {
+        if self.flags & BCDUMP_F_BE != 0 {
+            Endianness::Big
+        } else {
+            Endianness::Little
+        }
+    }
+    pub fn is_stripped(&self) -> bool {
+        self.flags & BCDUMP_F_STRIP != 0
+    }
+}
+
+/// How a closure finds an upvalue when it is created.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UpvalRef {
+    /// Whether `index` is a slot of the enclosing function, rather than one
+    /// of its upvalues.
+    pub(crate) local: bool,
+    pub(crate) immutable: bool,
+    pub(crate) index: u16,
+}
+
+impl UpvalRef {
+    pub fn local(&self) -> bool {
+        self.local
+    }
+    pub fn immutable(&self) -> bool {
+        self.immutable
+    }
+    /// The slot or upvalue index, depending on `local`.
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+}
+
+impl From<u16> for UpvalRef {
+    fn from(uv: u16) -> UpvalRef {
+        UpvalRef {
+            local: uv & 0x8000 != 0,
+            immutable: uv & 0x4000 != 0,
+            index: uv & 0x3fff,
+        }
+    }
+}
+
+/// A garbage-collected constant; instructions index these from the end.
+#[derive(Debug, PartialEq)]
+pub enum GcConstant {
+    Child(Function),
+    /// A template for `TDUP`.
+    Table {
+        array: Vec<Constant>,
+        hash: Vec<(Constant, Constant)>,
+    },
+    /// FFI `int64_t` cdata.
+    I64(i64),
+    /// FFI `uint64_t` cdata.
+    U64(u64),
+    /// FFI `complex` cdata: real and imaginary parts.
+    Complex(f64, f64),
+    String(LuaString),
+}
+
+/// A local variable. Its pcs count the `FUNCF` header, so `startpc` 1 is
+/// `code[0]`.
+#[derive(Debug, PartialEq)]
+pub struct VarInfo {
+    pub(crate) name: LuaString,
+    pub(crate) startpc: u32,
+    pub(crate) endpc: u32,
+}
+
+impl VarInfo {
+    pub fn name(&self) -> &LuaString {
+        &self.name
+    }
+    /// The first instruction where the variable is active.
+    pub fn startpc(&self) -> u32 {
+        self.startpc
+    }
+    /// The first instruction where the variable is dead.
+    pub fn endpc(&self) -> u32 {
+        self.endpc
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct DebugInfo {
+    pub(crate) first_line: u32,
+    pub(crate) num_line: u32,
+    /// Per instruction, the line relative to `first_line`.
+    pub(crate) lineinfo: Vec<u32>,
+    pub(crate) upvalue_names: Vec<LuaString>,
+    pub(crate) varinfo: Vec<VarInfo>,
+}
+
+impl DebugInfo {
+    pub fn first_line(&self) -> u32 {
+        self.first_line
+    }
+    pub fn num_line(&self) -> u32 {
+        self.num_line
+    }
+    /// Per instruction, the line relative to `first_line`.
+    pub fn lineinfo(&self) -> &[u32] {
+        &self.lineinfo
+    }
+    pub fn upvalue_names(&self) -> &[LuaString] {
+        &self.upvalue_names
+    }
+    /// Local variables, in order of declaration.
+    pub fn varinfo(&self) -> &[VarInfo] {
+        &self.varinfo
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Function {
+    /// `PROTO_VARARG` etc. from lj_obj.h.
+    pub(crate) flags: u8,
+    pub(crate) num_params: u8,
+    pub(crate) framesize: u8,
+    /// The instructions after the `FUNCF` or `FUNCV` header, which is not
+    /// dumped.
+    pub(crate) code: Vec<u32>,
+    pub(crate) upvalues: Vec<UpvalRef>,
+    pub(crate) kgc: Vec<GcConstant>,
+    /// Number constants: `Constant::Integer` or `Constant::Number`.
+    pub(crate) knum: Vec<Constant>,
+    /// `None` if stripped.
+    pub(crate) debug: Option<DebugInfo>,
+}
+
+impl Function {
+    pub fn instructions(
+        &self,
+        version: Version,
+    ) -> impl Iterator<Item = anyhow::Result<Instruction>> + '_ {
+        self.code
+            .iter()
+            .map(move |&i| Instruction::decode(i, version))
+    }
+    /// `PROTO_VARARG` etc. from lj_obj.h.
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+    pub fn num_params(&self) -> u8 {
+        self.num_params
+    }
+    pub fn framesize(&self) -> u8 {
+        self.framesize
+    }
+    /// The raw instruction words; see `instructions` to decode them.
+    pub fn code(&self) -> &[u32] {
+        &self.code
+    }
+    pub fn upvalues(&self) -> &[UpvalRef] {
+        &self.upvalues
+    }
+    /// GC constants, including the child prototypes; see `children`.
+    pub fn kgc(&self) -> &[GcConstant] {
+        &self.kgc
+    }
+    /// Number constants: `Constant::Integer` or `Constant::Number`.
+    pub fn knum(&self) -> &[Constant] {
+        &self.knum
+    }
+    /// `None` if stripped.
+    pub fn debug(&self) -> Option<&DebugInfo> {
+        self.debug.as_ref()
+    }
+    /// The child prototypes, in the order of their constants.
+    pub fn children(&self) -> impl Iterator<Item = &Function> {
+        self.kgc.iter().filter_map(|k| match k {
+            GcConstant::Child(f) => Some(f),
+            _ => None,
+        })
+    }
+    /// The source line of the instruction at `pc`, if not stripped.
+    pub fn line(&self, pc: usize) -> Option<u32> {
+        let debug = self.debug.as_ref()?;
+        debug.first_line.checked_add(*debug.lineinfo.get(pc)?)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Chunk {
+    pub header: Header,
+    pub main: Function,
+}
+
+trait LuajitBuf: LuacBuf {
+    fn get_uleb128(&mut self, cx: &Context, what: &'static str) -> Result<u64, Error> {
+        let offset = self.offset(cx);
+        let mut v: u64 = 0;
+        for shift in (0..35).step_by(7) {
+            self.need(cx, 1, what)?;
+            let b = self.get_u8();
+            v |= u64::from(b & 0x7f) << shift;
+            if b < 0x80 {
+                return Ok(v);
+            }
+        }
+        Err(self.error(cx, offset, ErrorKind::IntOutOfRange(v)))
+    }
+    fn get_u32_uleb128(&mut self, cx: &Context, what: &'static str) -> Result<u32, Error> {
+        let offset = self.offset(cx);
+        let v = self.get_uleb128(cx, what)?;
+        u32::try_from(v).map_err(|_| self.error(cx, offset, ErrorKind::IntOutOfRange(v)))
+    }
+    /// Reads a ULEB128 delta from `pc`, returning the pc it leads to.
+    fn get_pc_delta(&mut self, cx: &Context, pc: u32) -> Result<u32, Error> {
+        let offset = self.offset(cx);
+        let delta = self.get_u32_uleb128(cx, "debug varinfo")?;
+        pc.checked_add(delta).ok_or_else(|| {
+            let kind = ErrorKind::IntOutOfRange(u64::from(pc) + u64::from(delta));
+            self.error(cx, offset, kind)
+        })
+    }
+    fn get_bytes(
+        &mut self,
+        cx: &Context,
+        len: usize,
+        what: &'static str,
+    ) -> Result<LuaString, Error> {
+        self.need(cx, len, what)?;
+        self.charge(cx, len)?;
+        let str = LuaString::from(&self.chunk()[..len]);
+        self.advance(len);
+        Ok(str)
+    }
+    fn get_zstring(&mut self, cx: &Context, what: &'static str) -> Result<LuaString, Error> {
+        match self.chunk().iter().position(|&b| b == 0) {
+            Some(len) => {
+                let str = self.get_bytes(cx, len, what)?;
+                self.advance(1);
+                Ok(str)
+            }
+            None => Err(self.error(cx, cx.len, ErrorKind::Truncated(what))),
+        }
+    }
+    /// Reads a 64-bit value as two ULEB128s, the low half first.
+    fn get_lohi(&mut self, cx: &Context, what: &'static str) -> Result<u64, Error> {
+        let lo = self.get_u32_uleb128(cx, what)?;
+        let hi = self.get_u32_uleb128(cx, what)?;
+        Ok(u64::from(hi) << 32 | u64::from(lo))
+    }
+    fn get_double(&mut self, cx: &Context, what: &'static str) -> Result<f64, Error> {
+        Ok(f64::from_bits(self.get_lohi(cx, what)?))
+    }
+    fn get_ktabk(&mut self, cx: &Context) -> Result<Constant, Error> {
+        Ok(match self.get_u32_uleb128(cx, "table constant")? {
+            0 => Constant::Nil,
+            1 => Constant::Boolean(false),
+            2 => Constant::Boolean(true),
+            3 => Constant::Integer((self.get_u32_uleb128(cx, "table constant")? as i32).into()),
+            4 => Constant::Number(self.get_double(cx, "table constant")?),
+            tp => Constant::String(self.get_bytes(cx, tp as usize - 5, "table constant")?),
+        })
+    }
+    /// Reads a GC constant, popping children off `stack` and raising `height`
+    /// to one more than theirs.
+    fn get_kgc(
+        &mut self,
+        cx: &Context,
+        stack: &mut Vec<(Function, usize)>,
+        height: &mut usize,
+    ) -> Result<GcConstant, Error> {
+        let offset = self.offset(cx);
+        let what = "GC constants";
+        Ok(match self.get_u32_uleb128(cx, what)? {
+            0 => match stack.pop() {
+                Some((child, child_height)) => {
+                    *height = (*height).max(child_height + 1);
+                    if *height > cx.limits.max_depth {
+                        return Err(self.limit(cx, Limit::Depth, *height));
+                    }
+                    GcConstant::Child(child)
+                }
+                None => return Err(self.error(cx, offset, ErrorKind::UnbalancedProtos(0))),
+            },
+            1 => {
+                let narray = self.get_u32_uleb128(cx, what)?;
+                let nhash = self.get_u32_uleb128(cx, what)?;
+                let mut array = self.alloc(cx, narray as usize)?;
+                for _ in 0..narray {
+                    array.push(self.get_ktabk(cx)?);
+                }
+                let mut hash = self.alloc(cx, nhash as usize)?;
+                for _ in 0..nhash {
+                    hash.push((self.get_ktabk(cx)?, self.get_ktabk(cx)?));
+                }
+                GcConstant::Table { array, hash }
+            }
+            2 => GcConstant::I64(self.get_lohi(cx, what)? as i64),
+            3 => GcConstant::U64(self.get_lohi(cx, what)?),
+            4 => GcConstant::Complex(self.get_double(cx, what)?, self.get_double(cx, what)?),
+            tp => GcConstant::String(self.get_bytes(cx, tp as usize - 5, what)?),
+        })
+    }
+    fn get_knum(&mut self, cx: &Context) -> Result<Constant, Error> {
+        let what = "number constants";
+        self.need(cx, 1, what)?;
+        let isnum = self.chunk()[0] & 1 != 0;
+        let lo = (self.get_uleb128(cx, what)? >> 1) as u32;
+        Ok(if isnum {
+            let hi = self.get_u32_uleb128(cx, what)?;
+            Constant::Number(f64::from_bits(u64::from(hi) << 32 | u64::from(lo)))
+        } else {
+            Constant::Integer((lo as i32).into())
+        })
+    }
+    fn get_debug(
+        &mut self,
+        cx: &Context,
+        ncode: usize,
+        nuv: usize,
+        first_line: u32,
+        num_line: u32,
+    ) -> Result<DebugInfo, Error> {
+        let width = match num_line {
+            0..256 => 1,
+            256..65536 => 2,
+            _ => 4,
+        };
+        self.need(cx, ncode * usize::from(width), "debug lineinfo")?;
+        let mut lineinfo = self.alloc(cx, ncode)?;
+        for _ in 0..ncode {
+            let offset = self.offset(cx);
+            let line = self.get_sized(cx, width);
+            if u64::from(first_line) + line > u64::from(u32::MAX) {
+                let kind = ErrorKind::IntOutOfRange(u64::from(first_line) + line);
+                return Err(self.error(cx, offset, kind));
+            }
+            lineinfo.push(line as u32);
+        }
+        let mut upvalue_names = self.alloc(cx, nuv)?;
+        for _ in 0..nuv {
+            upvalue_names.push(self.get_zstring(cx, "debug upvalue names")?);
+        }
+        let mut varinfo = vec![];
+        let mut lastpc = 0;
+        loop {
+            self.need(cx, 1, "debug varinfo")?;
+            let name = match self.chunk()[0] {
+                0 => {
+                    self.advance(1);
+                    break;
+                }
+                vn @ 1..=6 => {
+                    self.advance(1);
+                    VARNAMES[usize::from(vn) - 1].into()
+                }
+                _ => self.get_zstring(cx, "debug varinfo")?,
+            };
+            let startpc = self.get_pc_delta(cx, lastpc)?;
+            let endpc = self.get_pc_delta(cx, startpc)?;
+            lastpc = startpc;
+            varinfo.push(VarInfo {
+                name,
+                startpc,
+                endpc,
+            });
+        }
+        Ok(DebugInfo {
+            first_line,
+            num_line,
+            lineinfo,
+            upvalue_names,
+            varinfo,
+        })
+    }
+    /// Reads a prototype, returning it with the height of its subtree of
+    /// children; see `get_kgc`.
+    fn get_proto(
+        &mut self,
+        cx: &Context,
+        h: &Header,
+        stack: &mut Vec<(Function, usize)>,
+    ) -> Result<(Function, usize), Error> {
+        self.need(cx, 4, "prototype header")?;
+        let flags = self.get_u8();
+        let num_params = self.get_u8();
+        let framesize = self.get_u8();
+        let sizeuv = usize::from(self.get_u8());
+        let sizekgc = self.get_u32_uleb128(cx, "prototype header")? as usize;
+        let sizekn = self.get_u32_uleb128(cx, "prototype header")? as usize;
+        let sizebc = self.get_u32_uleb128(cx, "prototype header")? as usize;
+        let (mut sizedbg, mut first_line, mut num_line) = (0, 0, 0);
+        if !h.is_stripped() {
+            sizedbg = self.get_u32_uleb128(cx, "prototype header")?;
+            if sizedbg != 0 {
+                first_line = self.get_u32_uleb128(cx, "prototype header")?;
+                num_line = self.get_u32_uleb128(cx, "prototype header")?;
+            }
+        }
+        self.need(cx, 4 * sizebc + 2 * sizeuv, "prototype code")?;
+        let mut code = self.alloc(cx, sizebc)?;
+        for _ in 0..sizebc {
+            code.push(self.get_sized(cx, 4) as u32);
+        }
+        let mut upvalues = self.alloc(cx, sizeuv)?;
+        for _ in 0..sizeuv {
+            upvalues.push(UpvalRef::from(self.get_sized(cx, 2) as u16));
+        }
+        let mut kgc = self.alloc(cx, sizekgc)?;
+        let mut height = 0;
+        for _ in 0..sizekgc {
+            kgc.push(self.get_kgc(cx, stack, &mut height)?);
+        }
+        let mut knum = self.alloc(cx, sizekn)?;
+        for _ in 0..sizekn {
+            knum.push(self.get_knum(cx)?);
+        }
+        let debug = match sizedbg {
+            0 => None,
+            _ => Some(self.get_debug(cx, sizebc, sizeuv, first_line, num_line)?),
+        };
+        let fun = Function {
+            flags,
+            num_params,
+            framesize,
+            code,
+            upvalues,
+            kgc,
+            knum,
+            debug,
+        };
+        Ok((fun, height))
+    }
+    fn get_header_ljbc(&mut self, cx: &mut Context) -> Result<Header, Error> {
+        self.need(cx, 4, "header")?;
+        if self.chunk()[..3] != *b"\x1bLJ" {
+            return Err(self.error(cx, 0, ErrorKind::BadSignature));
+        }
+        self.advance(3);
+        let version = self.get_header_byte(cx, "LuaJIT bytecode version", |v| {
+            Version::try_from(v).is_ok()
+        })?;
+        let version = Version::try_from(version).unwrap();
+        let offset = self.offset(cx);
+        let flags = self.get_u32_uleb128(cx, "header")?;
+        let known = match version {
+            Version::LuaJit20 => BCDUMP_F_FFI * 2 - 1,
+            Version::LuaJit21 => BCDUMP_F_FR2 * 2 - 1,
+        };
+        if flags & !known != 0 {
+            return Err(self.error(cx, offset, ErrorKind::BadFlags(flags & !known)));
+        }
+        let mut h = Header {
+            version,
+            flags,
+            chunkname: None,
+        };
+        if !h.is_stripped() {
+            let len = self.get_u32_uleb128(cx, "chunk name")? as usize;
+            h.chunkname = Some(self.get_bytes(cx, len, "chunk name")?);
+        }
+        cx.header.endianness = h.endianness();
+        Ok(h)
+    }
+}
+impl LuajitBuf for &[u8] {}
+
+/// Reads just the dump header, e.g. to inspect a dump before loading it.
+pub fn undump_header(data: &[u8]) -> Result<Header, Error> {
+    let mut p = data;
+    p.get_header_ljbc(&mut Context::new(data))
+}
+
+pub fn undump_chunk(data: &[u8]) -> Result<Chunk, Error> {
+    undump_chunk_with_limits(data, Limits::default())
+}
+
+pub fn undump_chunk_with_limits(data: &[u8], limits: Limits) -> Result<Chunk, Error> {
+    let mut p = data;
+    let mut cx = Context::with_limits(data, limits);
+    let header = p.get_header_ljbc(&mut cx)?;
+    let mut stack = vec![];
+    loop {
+        let len = p.get_u32_uleb128(&cx, "prototype length")? as usize;
+        if len == 0 {
+            break;
+        }
+        p.need(&cx, len, "prototype")?;
+        let start = p.offset(&cx);
+        let proto = p.get_proto(&cx, &header, &mut stack)?;
+        let actual = p.offset(&cx) - start;
+        if actual != len {
+            let kind = ErrorKind::ProtoLength {
+                declared: len,
+                actual,
+            };
+            return Err(p.error(&cx, start, kind));
+        }
+        stack.push(proto);
+    }
+    if stack.len() != 1 {
+        return Err(p.error(&cx, p.offset(&cx), ErrorKind::UnbalancedProtos(stack.len())));
+    }
+    p.finish(&cx)?;
+    Ok(Chunk {
+        header,
+        main: stack.pop().unwrap().0,
+    })
+}
+
+pub fn undump(data: &[u8]) -> Result<Function, Error> {
+    Ok(undump_chunk(data)?.main)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::luajit::OpCode;
+    use crate::undump::luajit::*;
+
+    #[test]
+    fn test() {
+        // local t = {1, 2.5, "x", k = true}
+        // local function f(a)
+        //   for i = 1, a do t[i] = i * 0.5 end
+        //   return t, 3000000000
+        // end
+        // return f(2)
+        let chunk = b"\
+\x1b\x4c\x4a\x02\x08\x06\x40\x6a\x2e\x6c\x75\x61\x67\x00\x01\x07\
+\x01\x00\x02\x0b\x1f\x02\x03\x29\x01\x01\x00\x12\x02\x00\x00\x29\
+\x03\x01\x00\x4d\x01\x04\x80\x2d\x05\x00\x00\x18\x06\x00\x04\x3c\
+\x06\x04\x05\x4f\x01\xfc\x7f\x2d\x01\x00\x00\x2a\x02\x01\x00\x4a\
+\x01\x03\x00\x00\xc0\x01\x80\x80\x80\xff\x03\x81\x80\x80\x80\x18\
+\x8b\xb4\x99\x8f\x04\x01\x01\x01\x01\x01\x01\x01\x01\x02\x02\x02\
+\x74\x00\x61\x00\x00\x0c\x01\x04\x05\x02\x00\x05\x03\x00\x05\x69\
+\x00\x01\x03\x00\x44\x03\x00\x05\x00\x02\x00\x06\x0f\x00\x07\x35\
+\x00\x00\x00\x33\x01\x01\x00\x12\x02\x01\x00\x29\x04\x02\x00\x32\
+\x00\x00\x80\x44\x02\x02\x00\x00\x01\x04\x01\x00\x03\x01\x04\x00\
+\x80\x80\x90\x80\x04\x06\x78\x06\x6b\x02\x01\x05\x06\x06\x06\x06\
+\x74\x00\x02\x05\x66\x00\x01\x04\x00\x00";
+        let c = undump_chunk(chunk).unwrap();
+        assert_eq!(c.header.version, Version::LuaJit21);
+        assert_eq!(c.header.flags, BCDUMP_F_FR2);
+        assert_eq!(c.header.chunkname, Some("@j.lua".into()));
+        let main = &c.main;
+        assert_eq!(
+            main.kgc[1],
+            GcConstant::Table {
+                array: vec![
+                    Constant::Nil,
+                    Constant::Integer(1),
+                    Constant::Number(2.5),
+                    Constant::String("x".into())
+                ],
+                hash: vec![(Constant::String("k".into()), Constant::Boolean(true))],
+            }
+        );
+        let ops: Vec<_> = main
+            .instructions(c.header.version)
+            .map(|i| i.unwrap().op)
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                OpCode::TDup,
+                OpCode::FNew,
+                OpCode::Mov,
+                OpCode::KShort,
+                OpCode::UClo,
+                OpCode::CallT
+            ]
+        );
+        let f = main.children().next().unwrap();
+        assert_eq!(f.knum(), [Constant::Number(0.5), Constant::Number(3e9)]);
+        assert_eq!(
+            f.upvalues,
+            vec![UpvalRef {
+                local: true,
+                immutable: true,
+                index: 0
+            }]
+        );
+        assert_eq!(f.line(0), Some(3));
+        assert_eq!(f.line(10), Some(4));
+        let debug = f.debug().unwrap();
+        assert_eq!(debug.upvalue_names(), ["t".into()]);
+        let names: Vec<_> = debug
+            .varinfo()
+            .iter()
+            .map(|v| v.name().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            ["a", "(for index)", "(for limit)", "(for step)", "i"]
+        );
+        // Only the unknown flags are reported, however far up they are.
+        let mut data = chunk[..4].to_vec();
+        data.extend([0x88, 0x02]);
+        data.extend(&chunk[5..]);
+        let e = undump(&data).unwrap_err();
+        assert_eq!((e.kind, e.offset), (ErrorKind::BadFlags(0x100), 4));
+        let mut data = chunk.to_vec();
+        data[12] = 0x30;
+        assert_eq!(
+            undump(&data).unwrap_err(),
+            Error {
+                kind: ErrorKind::ProtoLength {
+                    declared: 0x30,
+                    actual: 0x67
+                },
+                offset: 13,
+                path: vec![],
+            }
+        );
+    }
+}
@@ -0,0 +1,196 @@
+//! Byte spans of the fields of a Lua 5.1 chunk, recorded while undumping.
+
+use std::ops::Range;
+
+/// A range of byte offsets into the chunk.
+pub type Span = Range<usize>;
+
+/// The spans of a length-prefixed list and of each of its items.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ListSpans<T = Span> {
+    pub count: Span,
+    pub items: Vec<T>,
+}
+
+/// The header's fields, which are each one byte but for the signature.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderSpans {
+    pub signature: Span,
+    pub version: Span,
+    pub format: Span,
+    pub endianness: Span,
+    pub sizeof_int: Span,
+    pub sizeof_size_t: Span,
+    pub sizeof_instruction: Span,
+    pub sizeof_number: Span,
+    pub integral: Span,
+}
+
+impl Default for HeaderSpans {
+    fn default() -> HeaderSpans {
+        HeaderSpans {
+            signature: 0..4,
+            version: 4..5,
+            format: 5..6,
+            endianness: 6..7,
+            sizeof_int: 7..8,
+            sizeof_size_t: 8..9,
+            sizeof_instruction: 9..10,
+            sizeof_number: 10..11,
+            integral: 11..12,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LocVarSpans {
+    pub varname: Span,
+    pub startpc: Span,
+    pub endpc: Span,
+}
+
+/// The spans of a function's fields, with its children's in `funs`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FunctionSpans {
+    /// The whole function, children included.
+    pub whole: Span,
+    pub source: Span,
+    pub line_defined: Span,
+    pub last_line_defined: Span,
+    pub nups: Span,
+    pub num_params: Span,
+    pub is_vararg: Span,
+    pub maxstacksize: Span,
+    pub code: ListSpans,
+    pub constants: ListSpans,
+    pub funs: ListSpans<FunctionSpans>,
+    pub lineinfo: ListSpans,
+    pub locvars: ListSpans<LocVarSpans>,
+    pub upvalues: ListSpans,
+}
+
+/// Spans parallel to a `Chunk`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChunkSpans {
+    pub header: HeaderSpans,
+    pub main: FunctionSpans,
+}
+
+impl ChunkSpans {
+    /// Every field's span with a label naming it, e.g. `main.1.code[3]` for
+    /// the fourth instruction of the main function's second child, ordered
+    /// by offset. Together the spans cover the whole chunk.
+    pub fn labels(&self) -> Vec<(Span, String)> {
+        let h = &self.header;
+        let mut labels: Vec<_> = [
+            (&h.signature, "signature"),
+            (&h.version, "version"),
+            (&h.format, "format"),
+            (&h.endianness, "endianness"),
+            (&h.sizeof_int, "sizeof(int)"),
+            (&h.sizeof_size_t, "sizeof(size_t)"),
+            (&h.sizeof_instruction, "sizeof(Instruction)"),
+            (&h.sizeof_number, "sizeof(lua_Number)"),
+            (&h.integral, "integral"),
+        ]
+        .into_iter()
+        .map(|(span, name)| (span.clone(), format!("header.{}", name)))
+        .collect();
+        function_labels(&mut labels, &self.main, "main");
+        labels.sort_by_key(|(span, _)| span.start);
+        labels
+    }
+    /// The label of the field containing byte `offset`; see `labels`.
+    pub fn find(&self, offset: usize) -> Option<String> {
+        self.labels()
+            .into_iter()
+            .find(|(span, _)| span.contains(&offset))
+            .map(|(_, label)| label)
+    }
+}
+
+fn list_labels(labels: &mut Vec<(Span, String)>, list: &ListSpans, path: &str) {
+    labels.push((list.count.clone(), format!("{}.count", path)));
+    for (i, span) in list.items.iter().enumerate() {
+        labels.push((span.clone(), format!("{}[{}]", path, i)));
+    }
+}
+
+fn function_labels(labels: &mut Vec<(Span, String)>, f: &FunctionSpans, path: &str) {
+    for (span, name) in [
+        (&f.source, "source"),
+        (&f.line_defined, "line_defined"),
+        (&f.last_line_defined, "last_line_defined"),
+        (&f.nups, "nups"),
+        (&f.num_params, "num_params"),
+        (&f.is_vararg, "is_vararg"),
+        (&f.maxstacksize, "maxstacksize"),
+    ] {
+        labels.push((span.clone(), format!("{}.{}", path, name)));
+    }
+    list_labels(labels, &f.code, &format!("{}.code", path));
+    list_labels(labels, &f.constants, &format!("{}.constants", path));
+    labels.push((f.funs.count.clone(), format!("{}.funs.count", path)));
+    for (i, child) in f.funs.items.iter().enumerate() {
+        function_labels(labels, child, &format!("{}.{}", path, i));
+    }
+    list_labels(labels, &f.lineinfo, &format!("{}.lineinfo", path));
+    labels.push((f.locvars.count.clone(), format!("{}.locvars.count", path)));
+    for (i, l) in f.locvars.items.iter().enumerate() {
+        for (span, name) in [
+            (&l.varname, "varname"),
+            (&l.startpc, "startpc"),
+            (&l.endpc, "endpc"),
+        ] {
+            labels.push((span.clone(), format!("{}.locvars[{}].{}", path, i, name)));
+        }
+    }
+    list_labels(labels, &f.upvalues, &format!("{}.upvalues", path));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::undump::{undump, undump_with_spans};
+
+    #[test]
+    fn test() {
+        // local a = 1
+        // return function(b) return a + b end
+        let closure = b"\
+\x1b\x4c\x75\x61\x51\x00\x01\x04\x08\x04\x08\x00\x07\x00\x00\x00\
+\x00\x00\x00\x00\x40\x63\x2e\x6c\x75\x61\x00\x00\x00\x00\x00\x00\
+\x00\x00\x00\x00\x00\x02\x02\x05\x00\x00\x00\x01\x00\x00\x00\x64\
+\x00\x00\x00\x00\x00\x00\x00\x5e\x00\x00\x01\x1e\x00\x80\x00\x01\
+\x00\x00\x00\x03\x00\x00\x00\x00\x00\x00\xf0\x3f\x01\x00\x00\x00\
+\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\
+\x01\x01\x00\x02\x04\x00\x00\x00\x44\x00\x00\x00\x4c\x00\x80\x00\
+\x5e\x00\x00\x01\x1e\x00\x80\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+\x04\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\
+\x02\x00\x00\x00\x01\x00\x00\x00\x02\x00\x00\x00\x00\x00\x00\x00\
+\x62\x00\x00\x00\x00\x00\x03\x00\x00\x00\x01\x00\x00\x00\x02\x00\
+\x00\x00\x00\x00\x00\x00\x61\x00\x05\x00\x00\x00\x01\x00\x00\x00\
+\x02\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\
+\x01\x00\x00\x00\x02\x00\x00\x00\x00\x00\x00\x00\x61\x00\x01\x00\
+\x00\x00\x04\x00\x00\x00\x00\x00\x00\x00";
+        let (chunk, spans) = undump_with_spans(closure).unwrap();
+        assert_eq!(chunk.main, undump(closure).unwrap());
+        let labels = spans.labels();
+        let mut end = 0;
+        for (span, label) in &labels {
+            assert_eq!(span.start, end, "{}", label);
+            end = span.end;
+        }
+        assert_eq!(end, closure.len());
+        assert_eq!(spans.find(37).as_deref(), Some("main.is_vararg"));
+        assert_eq!(spans.find(45).as_deref(), Some("main.code[0]"));
+        let child = &spans.main.funs.items[0];
+        assert_eq!(child.whole.end, spans.main.lineinfo.count.start);
+        assert!(closure[child.locvars.items[0].varname.clone()].ends_with(b"b\0"));
+        let upvalue = child.upvalues.items[0].clone();
+        assert_eq!(
+            spans.find(upvalue.start).as_deref(),
+            Some("main.0.upvalues[0]")
+        );
+        assert!(closure[upvalue].ends_with(b"a\0"));
+    }
+}
@@ -0,0 +1,178 @@
+//! Undumping Lua 5.1 chunks from a stream rather than a slice.
+
+use crate::undump::{Chunk, Context, Error, ErrorKind, Limit, Limits, LuacBuf};
+use bytes::Buf;
+use std::io::Read;
+
+/// The bytes read from `reader` but not yet consumed. It only reads as far
+/// as `need` asks, so it never reads past the end of the chunk.
+struct StreamBuf<R> {
+    reader: R,
+    buf: Vec<u8>,
+    pos: usize,
+    /// How many bytes were consumed before `buf[0]`.
+    consumed: usize,
+}
+
+impl<R: Read> Buf for StreamBuf<R> {
+    /// Only counts bytes already read; see `need`.
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+    fn chunk(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+    fn advance(&mut self, cnt: usize) {
+        assert!(cnt <= self.remaining());
+        self.pos += cnt;
+    }
+}
+
+impl<R: Read> LuacBuf for StreamBuf<R> {
+    fn offset(&self, _: &Context) -> usize {
+        self.consumed + self.pos
+    }
+    fn need(&mut self, cx: &Context, n: usize, what: &'static str) -> Result<(), Error> {
+        let Some(missing) = n.checked_sub(self.remaining()).filter(|&m| m > 0) else {
+            return Ok(());
+        };
+        // Unlike a slice, a stream does not bound how much a bogus length
+        // can make us read, so the allocation limit has to. This reports
+        // `Limit::Alloc` where a slice that short would report `Truncated`.
+        if n > cx.limits.max_alloc {
+            return Err(self.limit(cx, Limit::Alloc, n));
+        }
+        // Drop consumed bytes only once they are most of the buffer, so that
+        // each byte is moved a bounded number of times.
+        if self.pos > self.buf.len() / 2 {
+            self.buf.drain(..self.pos);
+            self.consumed += self.pos;
+            self.pos = 0;
+        }
+        let offset = self.offset(cx);
+        match (&mut self.reader)
+            .take(missing as u64)
+            .read_to_end(&mut self.buf)
+        {
+            Ok(read) if read == missing => Ok(()),
+            Ok(_) => Err(self.error(cx, offset, ErrorKind::Truncated(what))),
+            Err(e) => Err(self.error(cx, offset, ErrorKind::Io(e.kind()))),
+        }
+    }
+    /// Whatever follows the chunk is left in the stream for the caller.
+    fn finish(&self, _: &Context) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Adapts a `lua_Reader`-style callback, which returns the chunk piece by
+/// piece and then an empty piece, to `Read`.
+struct PieceReader<F> {
+    next: F,
+    piece: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<F: FnMut() -> std::io::Result<Vec<u8>>> Read for PieceReader<F> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.piece.len() {
+            if self.done {
+                return Ok(0);
+            }
+            self.piece = (self.next)()?;
+            self.pos = 0;
+            self.done = self.piece.is_empty();
+        }
+        let n = out.len().min(self.piece.len() - self.pos);
+        out[..n].copy_from_slice(&self.piece[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Undumps a chunk from `reader`, reading no further than the chunk's end so
+/// that the caller can go on to whatever follows it. Each read asks for no
+/// more than is needed, which is slow on unbuffered readers. A `&mut
+/// BufReader` avoids that; it may read ahead of the chunk's end, but keeps
+/// those bytes for the caller to read from it next.
+///
+/// Unlike `undump_chunk`, a length over `max_alloc` fails with
+/// `Limit::Alloc` before anything is read, even if the stream would have
+/// ended sooner, in which case `undump_chunk` reports `Truncated`.
+pub fn undump_read(reader: impl Read) -> Result<Chunk, Error> {
+    undump_read_with_limits(reader, Limits::default())
+}
+
+pub fn undump_read_with_limits(reader: impl Read, limits: Limits) -> Result<Chunk, Error> {
+    let mut p = StreamBuf {
+        reader,
+        buf: vec![],
+        pos: 0,
+        consumed: 0,
+    };
+    let mut cx = Context::with_limits(&[], limits);
+    p.get_header(&mut cx, 0x51)?;
+    let main = p.get_function(&mut cx)?;
+    Ok(Chunk {
+        header: cx.header,
+        main,
+    })
+}
+
+/// Undumps a chunk handed over piece by piece, like `lua_load` with a
+/// `lua_Reader`: `reader` returns each successive piece, then an empty one.
+pub fn undump_load(reader: impl FnMut() -> std::io::Result<Vec<u8>>) -> Result<Chunk, Error> {
+    undump_read(PieceReader {
+        next: reader,
+        piece: vec![],
+        pos: 0,
+        done: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::undump::stream::*;
+    use crate::undump::undump_chunk;
+    use std::io::Cursor;
+
+    #[test]
+    fn test() {
+        // local t = {1, 2}
+        // return #t
+        let table = b"\
+\x1b\x4c\x75\x61\x51\x00\x01\x04\x08\x04\x08\x00\x07\x00\x00\x00\
+\x00\x00\x00\x00\x40\x73\x2e\x6c\x75\x61\x00\x00\x00\x00\x00\x00\
+\x00\x00\x00\x00\x00\x02\x03\x07\x00\x00\x00\x0a\x00\x00\x01\x41\
+\x00\x00\x00\x81\x40\x00\x00\x22\x40\x00\x01\x54\x00\x00\x00\x5e\
+\x00\x00\x01\x1e\x00\x80\x00\x02\x00\x00\x00\x03\x00\x00\x00\x00\
+\x00\x00\xf0\x3f\x03\x00\x00\x00\x00\x00\x00\x00\x40\x00\x00\x00\
+\x00\x07\x00\x00\x00\x01\x00\x00\x00\x01\x00\x00\x00\x01\x00\x00\
+\x00\x01\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\x00\x02\x00\x00\
+\x00\x01\x00\x00\x00\x02\x00\x00\x00\x00\x00\x00\x00\x74\x00\x04\
+\x00\x00\x00\x06\x00\x00\x00\x00\x00\x00\x00";
+        let expected = undump_chunk(table).unwrap();
+        let mut data = table.to_vec();
+        data.extend(b"rest");
+        let mut cursor = Cursor::new(&data);
+        assert_eq!(undump_read(&mut cursor).unwrap(), expected);
+        assert_eq!(cursor.position(), table.len() as u64);
+        let mut reader = std::io::BufReader::new(Cursor::new(&data));
+        assert_eq!(undump_read(&mut reader).unwrap(), expected);
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"rest");
+        let mut pieces = data.chunks(3);
+        let chunk = undump_load(|| Ok(pieces.next().unwrap_or_default().to_vec())).unwrap();
+        assert_eq!(chunk, expected);
+        for len in [0, 20, 100, table.len() - 1] {
+            assert_eq!(
+                undump_read(&table[..len]).unwrap_err(),
+                undump_chunk(&table[..len]).unwrap_err()
+            );
+        }
+        let err = undump_load(|| Err(std::io::ErrorKind::ConnectionReset.into())).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Io(std::io::ErrorKind::ConnectionReset));
+    }
+}
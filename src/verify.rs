@@ -0,0 +1,416 @@
+//! Semantic checks on Lua 5.1 code, after `luaG_checkcode` in ldebug.c.
+//!
+//! Undumping only checks that a chunk is well formed; this checks that its
+//! code cannot index registers, constants, upvalues or code out of range.
+
+use crate::instruction::{Instruction, OpArgMask, OpCode, OpMode, Rk};
+use crate::undump::{Constant, Function, VarargFlags};
+use std::fmt::{Display, Formatter};
+
+/// Most registers a function may use (`MAXSTACK` in llimits.h).
+pub const MAXSTACK: u8 = 250;
+
+/// What is wrong with a function or one of its instructions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    StackTooLarge(u8),
+    /// The fixed parameters, plus `arg` if any, do not fit in the stack.
+    ParamsExceedStack(usize),
+    /// `VarargFlags::NEEDSARG` without `VarargFlags::HASARG`.
+    NeedsArgWithoutHasArg,
+    /// More upvalue names than upvalues.
+    UpvalueNames(usize),
+    /// Line info that is neither empty nor one line per instruction.
+    LineInfoLength(usize),
+    /// The last instruction is not a RETURN, or there are none.
+    MissingReturn,
+    InvalidOpcode(u32),
+    RegisterOutOfRange(usize),
+    ConstantOutOfRange(usize),
+    UpvalueOutOfRange(u16),
+    /// An argument the opcode does not use is not zero.
+    UnusedArgument(u16),
+    /// A jump, or the skip of a test or LOADBOOL, lands outside the code.
+    JumpOutOfRange(i64),
+    /// A jump lands on the extra word of a SETLIST with C == 0.
+    JumpIntoSetList(usize),
+    /// A test is not followed by a JMP.
+    MissingJump,
+    GlobalNotString(u32),
+    /// A CONCAT of fewer than two values.
+    ConcatOperands,
+    /// A TFORLOOP with no loop variables.
+    ForInVariables,
+    /// An instruction leaving an open number of results is not followed by
+    /// one that consumes them.
+    OpenResults,
+    /// A SETLIST with C == 0 lacks the word holding its batch number.
+    MissingSetListBatch,
+    FunctionOutOfRange(usize),
+    /// A CLOSURE is followed by fewer than its child's number of upvalues in
+    /// MOVE or GETUPVAL pseudo-instructions.
+    ClosureUpvalues(u8),
+    /// A VARARG in a function that is not vararg, or uses `arg` instead.
+    NotVararg,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::StackTooLarge(n) => write!(f, "stack size {} exceeds {}", n, MAXSTACK),
+            Problem::ParamsExceedStack(n) => write!(f, "{} parameters exceed stack size", n),
+            Problem::NeedsArgWithoutHasArg => f.write_str("NEEDSARG without HASARG"),
+            Problem::UpvalueNames(n) => write!(f, "{} upvalue names for fewer upvalues", n),
+            Problem::LineInfoLength(n) => write!(f, "line info length {}", n),
+            Problem::MissingReturn => f.write_str("code does not end with RETURN"),
+            Problem::InvalidOpcode(raw) => write!(f, "invalid instruction {:#010x}", raw),
+            Problem::RegisterOutOfRange(r) => write!(f, "register {} out of range", r),
+            Problem::ConstantOutOfRange(k) => write!(f, "constant {} out of range", k),
+            Problem::UpvalueOutOfRange(u) => write!(f, "upvalue {} out of range", u),
+            Problem::UnusedArgument(x) => write!(f, "unused argument is {}", x),
+            Problem::JumpOutOfRange(pc) => write!(f, "jump to {} out of range", pc + 1),
+            Problem::JumpIntoSetList(pc) => {
+                write!(f, "jump to {} into SETLIST batch number", pc + 1)
+            }
+            Problem::MissingJump => f.write_str("test not followed by JMP"),
+            Problem::GlobalNotString(k) => write!(f, "global name constant {} not a string", k),
+            Problem::ConcatOperands => f.write_str("CONCAT of fewer than two values"),
+            Problem::ForInVariables => f.write_str("TFORLOOP without variables"),
+            Problem::OpenResults => f.write_str("open results not consumed"),
+            Problem::MissingSetListBatch => f.write_str("SETLIST batch number missing"),
+            Problem::FunctionOutOfRange(n) => write!(f, "function {} out of range", n),
+            Problem::ClosureUpvalues(n) => {
+                write!(
+                    f,
+                    "CLOSURE not followed by {} upvalue pseudo-instructions",
+                    n
+                )
+            }
+            Problem::NotVararg => f.write_str("VARARG in non-vararg function"),
+        }
+    }
+}
+
+/// A problem found by `verify`, located by the path of child function
+/// indices from the main function and, unless it is about the function as a
+/// whole, by pc.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub path: Vec<usize>,
+    pub pc: Option<usize>,
+    pub problem: Problem,
+}
+
+impl Display for Diagnostic {
+    /// Numbers instructions from 1, as `Listing` does.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "main")?;
+        for i in &self.path {
+            write!(f, ".{}", i)?;
+        }
+        if let Some(pc) = self.pc {
+            write!(f, " [{}]", pc + 1)?;
+        }
+        write!(f, ": {}", self.problem)
+    }
+}
+
+/// Checks `fun` and its children as `luaG_checkcode` would, but reports
+/// every problem rather than stopping at the first. An empty result means
+/// the reference implementation would have accepted the code.
+pub fn verify(fun: &Function) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    verify_function(&mut diagnostics, fun, &mut vec![]);
+    diagnostics
+}
+
+fn verify_function(diagnostics: &mut Vec<Diagnostic>, fun: &Function, path: &mut Vec<usize>) {
+    let mut checker = Checker {
+        fun,
+        path,
+        diagnostics,
+    };
+    checker.precheck();
+    checker.check_code();
+    for (i, child) in fun.funs.iter().enumerate() {
+        path.push(i);
+        verify_function(diagnostics, child, path);
+        path.pop();
+    }
+}
+
+struct Checker<'a> {
+    fun: &'a Function,
+    path: &'a [usize],
+    diagnostics: &'a mut Vec<Diagnostic>,
+}
+
+impl Checker<'_> {
+    fn fail(&mut self, pc: Option<usize>, problem: Problem) {
+        self.diagnostics.push(Diagnostic {
+            path: self.path.to_vec(),
+            pc,
+            problem,
+        });
+    }
+    fn check(&mut self, pc: usize, ok: bool, problem: Problem) {
+        if !ok {
+            self.fail(Some(pc), problem);
+        }
+    }
+    /// Registers may be computed as `a + b - 1` and the like, hence `i64`;
+    /// any negative register passes, as in C.
+    fn reg(&mut self, pc: usize, r: i64) {
+        if r >= self.fun.maxstacksize.into() {
+            self.fail(Some(pc), Problem::RegisterOutOfRange(r as usize));
+        }
+    }
+    fn arg(&mut self, pc: usize, x: u16, mode: OpArgMask) {
+        match mode {
+            OpArgMask::N => self.check(pc, x == 0, Problem::UnusedArgument(x)),
+            OpArgMask::U => {}
+            OpArgMask::R => self.reg(pc, x.into()),
+            OpArgMask::K => match Rk::from(x) {
+                Rk::Constant(k) => {
+                    let k = usize::from(k);
+                    self.check(
+                        pc,
+                        k < self.fun.constants.len(),
+                        Problem::ConstantOutOfRange(k),
+                    )
+                }
+                Rk::Register(r) => self.reg(pc, r.into()),
+            },
+        }
+    }
+    fn op(&self, pc: usize) -> Option<OpCode> {
+        let raw = *self.fun.code.get(pc)?;
+        Instruction::decode(raw).ok().map(|i| i.op)
+    }
+    /// `luaG_checkopenop`: whether the instruction after `pc` takes an open
+    /// number of values.
+    fn open_op(&mut self, pc: usize) {
+        let ok = match self
+            .fun
+            .code
+            .get(pc + 1)
+            .map(|&raw| Instruction::decode(raw))
+        {
+            Some(Ok(i)) => {
+                matches!(
+                    i.op,
+                    OpCode::Call | OpCode::TailCall | OpCode::Return | OpCode::SetList
+                ) && i.b == 0
+            }
+            _ => false,
+        };
+        self.check(pc, ok, Problem::OpenResults);
+    }
+    fn precheck(&mut self) {
+        let fun = self.fun;
+        if fun.maxstacksize > MAXSTACK {
+            self.fail(None, Problem::StackTooLarge(fun.maxstacksize));
+        }
+        let has_arg = fun.is_vararg.contains(VarargFlags::HASARG);
+        let params = usize::from(fun.num_params) + usize::from(has_arg);
+        if params > fun.maxstacksize.into() {
+            self.fail(None, Problem::ParamsExceedStack(params));
+        }
+        if fun.is_vararg.contains(VarargFlags::NEEDSARG) && !has_arg {
+            self.fail(None, Problem::NeedsArgWithoutHasArg);
+        }
+        if fun.upvalues.len() > fun.nups.into() {
+            self.fail(None, Problem::UpvalueNames(fun.upvalues.len()));
+        }
+        if !fun.lineinfo.is_empty() && fun.lineinfo.len() != fun.code.len() {
+            self.fail(None, Problem::LineInfoLength(fun.lineinfo.len()));
+        }
+        let last = fun.code.len().checked_sub(1);
+        if last.and_then(|pc| self.op(pc)) != Some(OpCode::Return) {
+            self.fail(None, Problem::MissingReturn);
+        }
+    }
+    fn check_code(&mut self) {
+        let fun = self.fun;
+        let size = fun.code.len() as i64;
+        let mut pc = 0;
+        while pc < fun.code.len() {
+            let raw = fun.code[pc];
+            let Ok(i) = Instruction::decode(raw) else {
+                self.fail(Some(pc), Problem::InvalidOpcode(raw));
+                pc += 1;
+                continue;
+            };
+            let (a, b, c) = (i64::from(i.a), i64::from(i.b), i64::from(i.c));
+            self.reg(pc, a);
+            match i.op.mode() {
+                OpMode::ABC => {
+                    self.arg(pc, i.b, i.op.b_mode());
+                    self.arg(pc, i.c, i.op.c_mode());
+                }
+                OpMode::ABx if i.op.b_mode() == OpArgMask::K => {
+                    let k = i.bx() as usize;
+                    self.check(pc, k < fun.constants.len(), Problem::ConstantOutOfRange(k));
+                }
+                OpMode::AsBx if i.op.b_mode() == OpArgMask::R => {
+                    self.jump(pc, pc as i64 + 1 + i64::from(i.sbx()))
+                }
+                _ => {}
+            }
+            if i.op.is_test() {
+                self.check(
+                    pc,
+                    pc as i64 + 2 < size,
+                    Problem::JumpOutOfRange(pc as i64 + 2),
+                );
+                self.check(
+                    pc,
+                    self.op(pc + 1) == Some(OpCode::Jmp),
+                    Problem::MissingJump,
+                );
+            }
+            match i.op {
+                OpCode::LoadBool if c == 1 => self.jump(pc, pc as i64 + 2),
+                OpCode::GetUpval | OpCode::SetUpval => {
+                    self.check(pc, b < fun.nups.into(), Problem::UpvalueOutOfRange(i.b))
+                }
+                OpCode::GetGlobal | OpCode::SetGlobal => {
+                    let k = i.bx();
+                    let ok = matches!(
+                        fun.constants.get(k as usize),
+                        None | Some(Constant::String(_))
+                    );
+                    self.check(pc, ok, Problem::GlobalNotString(k));
+                }
+                OpCode::Self_ => self.reg(pc, a + 1),
+                OpCode::Concat => self.check(pc, b < c, Problem::ConcatOperands),
+                OpCode::TForLoop => {
+                    self.check(pc, c >= 1, Problem::ForInVariables);
+                    self.reg(pc, a + 2 + c);
+                }
+                OpCode::ForLoop | OpCode::ForPrep => self.reg(pc, a + 3),
+                OpCode::Call | OpCode::TailCall => {
+                    if b != 0 {
+                        self.reg(pc, a + b - 1);
+                    }
+                    match c - 1 {
+                        -1 => self.open_op(pc),
+                        0 => {}
+                        results => self.reg(pc, a + results - 1),
+                    }
+                }
+                OpCode::Return if b - 1 > 0 => self.reg(pc, a + b - 2),
+                OpCode::SetList => {
+                    if b > 0 {
+                        self.reg(pc, a + b);
+                    }
+                    if c == 0 {
+                        let ok = pc + 2 < fun.code.len();
+                        self.check(pc, ok, Problem::MissingSetListBatch);
+                        pc += 1;
+                    }
+                }
+                OpCode::Closure => self.closure(pc, i.bx() as usize),
+                OpCode::VarArg => {
+                    let flags = fun.is_vararg;
+                    let ok = flags.contains(VarargFlags::ISVARARG)
+                        && !flags.contains(VarargFlags::NEEDSARG);
+                    self.check(pc, ok, Problem::NotVararg);
+                    if b == 0 {
+                        self.open_op(pc);
+                    }
+                    self.reg(pc, a + b - 2);
+                }
+                _ => {}
+            }
+            pc += 1;
+        }
+    }
+    fn jump(&mut self, pc: usize, dest: i64) {
+        if !(0..self.fun.code.len() as i64).contains(&dest) {
+            self.fail(Some(pc), Problem::JumpOutOfRange(dest));
+            return;
+        }
+        // The words before `dest` that look like SETLISTs with C == 0
+        // alternate between instructions and batch numbers, so `dest` is a
+        // batch number if there is an odd run of them.
+        let dest = dest as usize;
+        let run = (0..dest)
+            .rev()
+            .take_while(|&j| {
+                matches!(
+                    Instruction::decode(self.fun.code[j]),
+                    Ok(i) if i.op == OpCode::SetList && i.c == 0
+                )
+            })
+            .count();
+        self.check(pc, run % 2 == 0, Problem::JumpIntoSetList(dest));
+    }
+    fn closure(&mut self, pc: usize, index: usize) {
+        let Some(child) = self.fun.funs.get(index) else {
+            self.fail(Some(pc), Problem::FunctionOutOfRange(index));
+            return;
+        };
+        let nups = usize::from(child.nups);
+        let ok =
+            (1..=nups).all(|j| matches!(self.op(pc + j), Some(OpCode::Move | OpCode::GetUpval)));
+        self.check(pc, ok, Problem::ClosureUpvalues(child.nups));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::OpCode::*;
+    use crate::verify::*;
+
+    #[test]
+    fn test() {
+        let abc = |op, a, b, c| Instruction::abc(op, a, b, c).encode();
+        let child = Function {
+            nups: 1,
+            maxstacksize: 2,
+            code: vec![abc(GetUpval, 0, 0, 0), abc(Return, 0, 2, 0)],
+            lineinfo: vec![1],
+            ..Default::default()
+        };
+        let fun = Function {
+            is_vararg: VarargFlags::ISVARARG,
+            maxstacksize: 2,
+            code: vec![
+                Instruction::abx(LoadK, 0, 1).encode(),
+                abc(Move, 5, 0, 0),
+                Instruction::abx(Closure, 0, 0).encode(),
+                abc(SetList, 0, 1, 0),
+                1,
+                Instruction::asbx(Jmp, 0, -2).encode(),
+                Instruction::asbx(Jmp, 0, 10).encode(),
+                abc(Return, 0, 1, 0),
+            ],
+            constants: vec![Constant::Nil],
+            funs: vec![child],
+            ..Default::default()
+        };
+        let diagnostic = |path: &[usize], pc, problem| Diagnostic {
+            path: path.to_vec(),
+            pc,
+            problem,
+        };
+        let diagnostics = verify(&fun);
+        assert_eq!(
+            diagnostics,
+            vec![
+                diagnostic(&[], Some(0), Problem::ConstantOutOfRange(1)),
+                diagnostic(&[], Some(1), Problem::RegisterOutOfRange(5)),
+                diagnostic(&[], Some(2), Problem::ClosureUpvalues(1)),
+                diagnostic(&[], Some(5), Problem::JumpIntoSetList(4)),
+                diagnostic(&[], Some(6), Problem::JumpOutOfRange(17)),
+                diagnostic(&[0], None, Problem::LineInfoLength(1)),
+            ]
+        );
+        assert_eq!(
+            diagnostics[3].to_string(),
+            "main [6]: jump to 5 into SETLIST batch number"
+        );
+        assert_eq!(diagnostics[5].to_string(), "main.0: line info length 1");
+    }
+}